@@ -1,6 +1,6 @@
 use charabia::{Segment, Tokenize};
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
-use nostr_db::{Event, EventIndex};
+use nostr_db::{Event, EventIndex, FromEventData};
 use std::{hash::Hasher, str::FromStr, time::Duration};
 use twox_hash::XxHash32;
 
@@ -52,6 +52,11 @@ fn bench_event(c: &mut Criterion) {
 
     group.bench_function("to_str", |b| b.iter(|| black_box(event.to_string())));
 
+    // the stored json is sent as is
+    group.bench_function("json_data_to_string", |b| {
+        b.iter(|| black_box(String::from_data(json.trim()).unwrap()))
+    });
+
     let index_event = event.index();
     let index_bytes = event.index().to_bytes().unwrap();
     // println!("index bytes len: {}", index_bytes.len());
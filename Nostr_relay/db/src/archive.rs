@@ -0,0 +1,729 @@
+//! Cold archive of old events.
+//!
+//! The archived events are stored in immutable segment files, sorted by (created_at, id).
+//! The events are packed in compressed blocks, and the sparse index of the blocks
+//! is stored at the end of the segment file.
+//!
+//! The bloom filters of the ids and the authors skip the segments for the id and author filters.
+//!
+//! Segment layout:
+//! `[block]...[index entry]...[id bloom][author bloom][index offset u64][number of blocks u64]
+//! [id bloom length u64][author bloom length u64][magic]`
+//!
+//! Block record: `[created_at u64][id 32 bytes][json length u32][json]`
+//!
+//! The compressed blocks are encrypted by the data key of the database when set,
+//! the sparse index and the bloom filters stay in plain text like the index of the database.
+
+use crate::{
+    cipher::{self, DataKey},
+    error::Error,
+    partition::PartitionStream,
+    Event, Filter, FromEventData, IndexTags,
+};
+use nostr_kv::scanner::TimeKey;
+use parking_lot::RwLock;
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+const MAGIC: &[u8; 8] = b"NOSTRAR2";
+const EXT: &str = "seg";
+// the uncompressed size of a block
+const BLOCK_SIZE: usize = 64 * 1024;
+const INDEX_ENTRY_SIZE: usize = 8 + 8 + 32 + 8 + 8 + 4;
+const FOOTER_SIZE: usize = 8 + 8 + 8 + 8 + 8;
+// about 1% false positive
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u64 = 7;
+
+// the finalizer of splitmix64
+fn mix(mut h: u64) -> u64 {
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// The bloom filter of the 32 bytes keys
+#[derive(Debug)]
+struct Bloom {
+    bits: Vec<u8>,
+}
+
+impl Bloom {
+    fn new(keys: usize) -> Self {
+        Self {
+            bits: vec![0; (keys * BLOOM_BITS_PER_KEY).div_ceil(8).max(8)],
+        }
+    }
+
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let len = (self.bits.len() * 8) as u64;
+        let h1 = key
+            .chunks(8)
+            .fold(0, |h, w| mix(h ^ u64::from_le_bytes(w.try_into().unwrap())));
+        let h2 = mix(h1) | 1;
+        (0..BLOOM_HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    fn insert(&mut self, key: &[u8; 32]) {
+        for pos in self.positions(key).collect::<Vec<_>>() {
+            self.bits[pos / 8] |= 1 << (pos % 8);
+        }
+    }
+
+    /// False if the key is not in the set, the key of other size may be in the set
+    fn contains(&self, key: &[u8]) -> bool {
+        key.len() != 32
+            || self
+                .positions(key)
+                .all(|pos| self.bits[pos / 8] & (1 << (pos % 8)) != 0)
+    }
+}
+
+/// The sparse index entry of a block
+#[derive(Debug, Clone)]
+struct Block {
+    // the first key of the block
+    since: u64,
+    id: [u8; 32],
+    // the created_at of the last event
+    until: u64,
+    offset: u64,
+    len: u64,
+    events: u32,
+}
+
+impl Block {
+    fn encode(&self) -> Vec<u8> {
+        [
+            &self.since.to_be_bytes()[..],
+            &self.until.to_be_bytes()[..],
+            &self.id[..],
+            &self.offset.to_be_bytes()[..],
+            &self.len.to_be_bytes()[..],
+            &self.events.to_be_bytes()[..],
+        ]
+        .concat()
+    }
+
+    fn decode(b: &[u8]) -> Result<Self> {
+        Ok(Self {
+            since: u64::from_be_bytes(b[0..8].try_into()?),
+            until: u64::from_be_bytes(b[8..16].try_into()?),
+            id: b[16..48].try_into()?,
+            offset: u64::from_be_bytes(b[48..56].try_into()?),
+            len: u64::from_be_bytes(b[56..64].try_into()?),
+            events: u32::from_be_bytes(b[64..68].try_into()?),
+        })
+    }
+
+    fn overlap(&self, since: Option<u64>, until: Option<u64>) -> bool {
+        since.unwrap_or(0) <= self.until && self.since <= until.unwrap_or(u64::MAX)
+    }
+}
+
+/// An immutable archive file
+#[derive(Debug)]
+pub struct ArchiveSegment {
+    pub path: PathBuf,
+    /// the created_at of the oldest event
+    pub since: u64,
+    /// the created_at of the newest event
+    pub until: u64,
+    /// number of events
+    pub events: u64,
+    blocks: Vec<Block>,
+    ids: Bloom,
+    authors: Bloom,
+}
+
+impl ArchiveSegment {
+    fn open(path: PathBuf) -> Result<Self> {
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        let invalid = || Error::Invalid(format!("invalid archive segment {:?}", path));
+        if size < FOOTER_SIZE as u64 {
+            return Err(invalid());
+        }
+        let mut footer = [0u8; FOOTER_SIZE];
+        file.seek(SeekFrom::Start(size - FOOTER_SIZE as u64))?;
+        file.read_exact(&mut footer)?;
+        if &footer[32..40] != MAGIC {
+            return Err(invalid());
+        }
+        let offset = u64::from_be_bytes(footer[0..8].try_into()?);
+        let num = u64::from_be_bytes(footer[8..16].try_into()?);
+        let ids_len = u64::from_be_bytes(footer[16..24].try_into()?);
+        let authors_len = u64::from_be_bytes(footer[24..32].try_into()?);
+        // the index, the bloom filters and the footer fill the file after the blocks
+        let end = num
+            .checked_mul(INDEX_ENTRY_SIZE as u64)
+            .and_then(|len| offset.checked_add(len))
+            .and_then(|end| end.checked_add(ids_len))
+            .and_then(|end| end.checked_add(authors_len))
+            .and_then(|end| end.checked_add(FOOTER_SIZE as u64));
+        if end != Some(size) || ids_len == 0 || authors_len == 0 {
+            return Err(invalid());
+        }
+        let mut index = vec![0u8; num as usize * INDEX_ENTRY_SIZE];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut index)?;
+        let blocks = index
+            .chunks(INDEX_ENTRY_SIZE)
+            .map(Block::decode)
+            .collect::<Result<Vec<_>>>()?;
+        if blocks
+            .iter()
+            .any(|b| !matches!(b.offset.checked_add(b.len), Some(end) if end <= offset))
+        {
+            return Err(invalid());
+        }
+        let mut read_bloom = |len: u64| -> Result<Bloom> {
+            let mut bits = vec![0u8; len as usize];
+            file.read_exact(&mut bits)?;
+            Ok(Bloom { bits })
+        };
+        let ids = read_bloom(ids_len)?;
+        let authors = read_bloom(authors_len)?;
+        Ok(Self {
+            since: blocks.first().map(|b| b.since).unwrap_or_default(),
+            until: blocks.last().map(|b| b.until).unwrap_or_default(),
+            events: blocks.iter().map(|b| b.events as u64).sum(),
+            path,
+            blocks,
+            ids,
+            authors,
+        })
+    }
+
+    /// False if no archived event may match the ids and the authors of the filter
+    fn may_match(&self, filter: &Filter) -> bool {
+        (filter.ids.is_empty() || filter.ids.iter().any(|id| self.ids.contains(id)))
+            && (filter.authors.is_empty()
+                || filter.authors.iter().any(|a| self.authors.contains(a)))
+    }
+
+    /// Find the archived event by the id and the created time
+    fn get(&self, time: u64, id: &[u8; 32]) -> Result<Option<Record>> {
+        if time < self.since || time > self.until || !self.ids.contains(id) {
+            return Ok(None);
+        }
+        let mut file = None;
+        for block in self
+            .blocks
+            .iter()
+            .filter(|b| b.overlap(Some(time), Some(time)))
+        {
+            if file.is_none() {
+                file = Some(File::open(&self.path)?);
+            }
+            let records = self.read_block(file.as_mut().unwrap(), block)?;
+            if let Some(record) = records.into_iter().find(|r| &r.id == id) {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Write the sorted records to a new segment file
+    fn create(path: PathBuf, records: &[Record], key: Option<&DataKey>) -> Result<Self> {
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        let mut blocks = vec![];
+        let mut offset = 0u64;
+        let mut start = 0;
+        while start < records.len() {
+            let mut raw = vec![];
+            let mut end = start;
+            while end < records.len() && (end == start || raw.len() < BLOCK_SIZE) {
+                records[end].encode(&mut raw);
+                end += 1;
+            }
+            let bytes = encode_block(raw, key)?;
+            file.write_all(&bytes)?;
+            blocks.push(Block {
+                since: records[start].time,
+                id: records[start].id,
+                until: records[end - 1].time,
+                offset,
+                len: bytes.len() as u64,
+                events: (end - start) as u32,
+            });
+            offset += bytes.len() as u64;
+            start = end;
+        }
+        for block in &blocks {
+            file.write_all(&block.encode())?;
+        }
+        // the delegators are matched by the authors filter
+        let mut ids = Bloom::new(records.len());
+        let mut authors = Bloom::new(records.len());
+        for record in records {
+            ids.insert(&record.id);
+            let event = Event::from_data(&record.json)?;
+            authors.insert(event.pubkey());
+            if let Some(delegator) = event.index().delegator() {
+                authors.insert(delegator);
+            }
+        }
+        file.write_all(&ids.bits)?;
+        file.write_all(&authors.bits)?;
+        file.write_all(&offset.to_be_bytes())?;
+        file.write_all(&(blocks.len() as u64).to_be_bytes())?;
+        file.write_all(&(ids.bits.len() as u64).to_be_bytes())?;
+        file.write_all(&(authors.bits.len() as u64).to_be_bytes())?;
+        file.write_all(MAGIC)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Self::open(path)
+    }
+
+    fn read_raw_block(&self, file: &mut File, block: &Block) -> Result<Vec<u8>> {
+        let mut bytes = vec![0u8; block.len as usize];
+        file.seek(SeekFrom::Start(block.offset))?;
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn read_block(&self, file: &mut File, block: &Block) -> Result<Vec<Record>> {
+        let raw = decode_block(self.read_raw_block(file, block)?)?;
+        let mut records = Vec::with_capacity(block.events as usize);
+        let mut pos = 0;
+        while pos < raw.len() {
+            let (record, len) = Record::decode(&raw[pos..])?;
+            records.push(record);
+            pos += len;
+        }
+        Ok(records)
+    }
+}
+
+// the block marker: 0 raw, 1 compressed, 2 compressed and encrypted
+fn encode_block(raw: Vec<u8>, key: Option<&DataKey>) -> Result<Vec<u8>> {
+    let mut bytes = zstd::encode_all(&raw[..], 5)?;
+    let marker = match key {
+        Some(key) => {
+            bytes = key.encrypt(&bytes, &[]);
+            2
+        }
+        None => 1,
+    };
+    bytes.push(marker);
+    Ok(bytes)
+}
+
+// the id of the key encrypting the block, none if not encrypted
+fn block_key_id(bytes: &[u8]) -> Option<u32> {
+    match bytes.split_last() {
+        Some((2, bytes)) => cipher::key_id(bytes, 0),
+        _ => None,
+    }
+}
+
+fn decode_block(mut bytes: Vec<u8>) -> Result<Vec<u8>> {
+    match bytes.pop() {
+        Some(0) => Ok(bytes),
+        Some(1) => Ok(zstd::decode_all(&bytes[..])?),
+        Some(2) => Ok(zstd::decode_all(&cipher::decrypt(&bytes, 0)?[..])?),
+        _ => Err(Error::Invalid("invalid archive block".to_owned())),
+    }
+}
+
+/// An archived event
+#[derive(Debug, Clone)]
+pub(crate) struct Record {
+    pub time: u64,
+    pub id: [u8; 32],
+    pub json: Vec<u8>,
+}
+
+impl Record {
+    pub fn new(event: &Event, json: Vec<u8>) -> Self {
+        Self {
+            time: event.created_at(),
+            id: *event.id(),
+            json,
+        }
+    }
+
+    pub fn cmp(&self, other: &Self) -> Ordering {
+        self.time
+            .cmp(&other.time)
+            .then_with(|| self.id.cmp(&other.id))
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.time.to_be_bytes());
+        buf.extend_from_slice(&self.id);
+        buf.extend_from_slice(&(self.json.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.json);
+    }
+
+    fn decode(b: &[u8]) -> Result<(Self, usize)> {
+        if b.len() < 44 {
+            return Err(Error::InvalidLength);
+        }
+        let len = u32::from_be_bytes(b[40..44].try_into()?) as usize;
+        if b.len() < 44 + len {
+            return Err(Error::InvalidLength);
+        }
+        Ok((
+            Self {
+                time: u64::from_be_bytes(b[0..8].try_into()?),
+                id: b[8..40].try_into()?,
+                json: b[44..44 + len].to_vec(),
+            },
+            44 + len,
+        ))
+    }
+
+    pub fn data<J: FromEventData>(&self) -> Result<J> {
+        if J::only_id() {
+            J::from_data(self.id)
+        } else {
+            J::from_data(&self.json)
+        }
+        .map_err(|e| Error::Message(e.to_string()))
+    }
+}
+
+// the records of the partitions are merged by the scanner group
+impl TimeKey for Record {
+    fn time(&self) -> u64 {
+        self.time
+    }
+
+    fn cmp(&self, other: &Self) -> Ordering {
+        Record::cmp(self, other)
+    }
+
+    fn change_time(&self, key: &[u8], _time: u64) -> Vec<u8> {
+        // only used by the scanner to seek
+        key.to_vec()
+    }
+}
+
+struct ArchiveInner {
+    // none if the archive is disabled
+    dir: Option<PathBuf>,
+    // sorted by the file name
+    segments: RwLock<Vec<Arc<ArchiveSegment>>>,
+}
+
+/// The archive segments of a database
+#[derive(Clone)]
+pub struct Archive {
+    inner: Arc<ArchiveInner>,
+}
+
+impl Archive {
+    /// Load the segments in the directory, the directory is created on the first write
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut segments = vec![];
+        if dir.is_dir() {
+            let mut paths = fs::read_dir(&dir)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            paths.retain(|p| p.extension().is_some_and(|e| e == EXT));
+            paths.sort();
+            for path in paths {
+                segments.push(Arc::new(ArchiveSegment::open(path)?));
+            }
+        }
+        Ok(Self {
+            inner: Arc::new(ArchiveInner {
+                dir: Some(dir),
+                segments: RwLock::new(segments),
+            }),
+        })
+    }
+
+    /// The empty archive which can not write segments
+    pub fn disabled() -> Self {
+        Self {
+            inner: Arc::new(ArchiveInner {
+                dir: None,
+                segments: RwLock::new(vec![]),
+            }),
+        }
+    }
+
+    pub fn segments(&self) -> Vec<Arc<ArchiveSegment>> {
+        self.inner.segments.read().clone()
+    }
+
+    /// Get the archived event by the id and the created time
+    pub(crate) fn get(&self, time: u64, id: &[u8; 32]) -> Result<Option<Record>> {
+        for segment in self.inner.segments.read().iter() {
+            if let Some(record) = segment.get(time, id)? {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The time range of all archived events
+    pub fn range(&self) -> Option<(u64, u64)> {
+        let segments = self.inner.segments.read();
+        let since = segments.iter().map(|s| s.since).min()?;
+        let until = segments.iter().map(|s| s.until).max()?;
+        Some((since, until))
+    }
+
+    /// Write the records to a new segment, the blocks are encrypted by the key if set
+    pub(crate) fn write(
+        &self,
+        mut records: Vec<Record>,
+        key: Option<&DataKey>,
+    ) -> Result<Option<Arc<ArchiveSegment>>> {
+        if records.is_empty() {
+            return Ok(None);
+        }
+        records.sort_by(|a, b| a.cmp(b));
+        let dir = self
+            .inner
+            .dir
+            .as_ref()
+            .ok_or_else(|| Error::Message("the archive is disabled".to_owned()))?;
+        fs::create_dir_all(dir)?;
+        let mut segments = self.inner.segments.write();
+        let seq = segments.len() as u64 + 1;
+        let path = dir.join(format!(
+            "{:010}-{:010}-{:06}.{}",
+            records[0].time,
+            records[records.len() - 1].time,
+            seq,
+            EXT
+        ));
+        let segment = Arc::new(ArchiveSegment::create(path, &records, key)?);
+        segments.push(segment.clone());
+        Ok(Some(segment))
+    }
+
+    /// Rewrite the segments which have the blocks not encrypted with the key, decrypt them if no key.
+    /// A segment is replaced by the new file of the same name, return the number of rewritten events.
+    pub(crate) fn rekey(&self, key: Option<&DataKey>) -> Result<usize> {
+        let id = key.map(|k| k.id);
+        let mut total = 0;
+        let mut segments = self.inner.segments.write();
+        for segment in segments.iter_mut() {
+            let mut file = File::open(&segment.path)?;
+            let mut outdated = false;
+            for block in segment.blocks.iter() {
+                if block_key_id(&segment.read_raw_block(&mut file, block)?) != id {
+                    outdated = true;
+                    break;
+                }
+            }
+            if !outdated {
+                continue;
+            }
+            let mut records = vec![];
+            for block in segment.blocks.iter() {
+                records.extend(segment.read_block(&mut file, block)?);
+            }
+            *segment = Arc::new(ArchiveSegment::create(segment.path.clone(), &records, key)?);
+            total += records.len();
+        }
+        Ok(total)
+    }
+
+    /// Iterate the archived events matching the filter, return `None` if no segment in the time range.
+    /// The matched records out of the archive (the historical versions) and the events of the partitions
+    /// are merged in the same order, the segments are skipped if `segments` is false.
+    /// The archived events are matched with the indexed tags of the database.
+    pub(crate) fn iter(
+        &self,
+        filter: &Filter,
+        segments: bool,
+        mut records: Vec<Record>,
+        partitions: Option<PartitionStream>,
+        index_tags: &IndexTags,
+    ) -> Option<ArchiveIter> {
+        let mut streams = self
+            .inner
+            .segments
+            .read()
+            .iter()
+            .filter(|s| {
+                segments
+                    && filter.since.unwrap_or(0) <= s.until
+                    && s.since <= filter.until.unwrap_or(u64::MAX)
+                    && s.may_match(filter)
+            })
+            .map(|s| SegmentStream {
+                range: (s.since, s.until),
+                segment: Some(s.clone()),
+                partition: None,
+                file: None,
+                next_block: 0,
+                buffer: VecDeque::new(),
+                done: false,
+            })
+            .collect::<Vec<_>>();
+        streams.extend(partitions.map(|p| SegmentStream {
+            range: p.range,
+            segment: None,
+            partition: Some(p),
+            file: None,
+            next_block: 0,
+            buffer: VecDeque::new(),
+            done: false,
+        }));
+        if !records.is_empty() {
+            records.sort_by(|a, b| a.cmp(b));
+            if filter.desc {
+                records.reverse();
+            }
+            let times = records.iter().map(|r| r.time);
+            streams.push(SegmentStream {
+                range: (
+                    times.clone().min().unwrap_or_default(),
+                    times.max().unwrap_or_default(),
+                ),
+                segment: None,
+                partition: None,
+                file: None,
+                next_block: 0,
+                buffer: records.into(),
+                done: true,
+            });
+        }
+        if streams.is_empty() {
+            return None;
+        }
+        let since = streams.iter().map(|s| s.range.0).min().unwrap_or_default();
+        let until = streams.iter().map(|s| s.range.1).max().unwrap_or_default();
+        Some(ArchiveIter {
+            filter: filter.clone(),
+            index_tags: index_tags.clone(),
+            streams,
+            heads: vec![],
+            since,
+            until,
+            scanned: 0,
+        })
+    }
+}
+
+// the stream without segment and partition only has the buffered records
+struct SegmentStream {
+    range: (u64, u64),
+    segment: Option<Arc<ArchiveSegment>>,
+    partition: Option<PartitionStream>,
+    file: Option<File>,
+    next_block: usize,
+    buffer: VecDeque<Record>,
+    done: bool,
+}
+
+impl SegmentStream {
+    fn next(
+        &mut self,
+        filter: &Filter,
+        index_tags: &IndexTags,
+        scanned: &mut u64,
+    ) -> Result<Option<Record>> {
+        loop {
+            if let Some(record) = self.buffer.pop_front() {
+                return Ok(Some(record));
+            }
+            if let Some(partition) = self.partition.as_mut() {
+                let record = partition.next(scanned)?;
+                self.done = record.is_none();
+                return Ok(record);
+            }
+            let segment = match &self.segment {
+                Some(segment) if !self.done && self.next_block < segment.blocks.len() => segment,
+                _ => {
+                    self.done = true;
+                    return Ok(None);
+                }
+            };
+            let n = self.next_block;
+            self.next_block += 1;
+            // blocks are sorted by time, read from the end for descending order
+            let block = if filter.desc {
+                &segment.blocks[segment.blocks.len() - 1 - n]
+            } else {
+                &segment.blocks[n]
+            };
+            if !block.overlap(filter.since, filter.until) {
+                continue;
+            }
+            if self.file.is_none() {
+                self.file = Some(File::open(&segment.path)?);
+            }
+            let mut records = segment.read_block(self.file.as_mut().unwrap(), block)?;
+            *scanned += records.len() as u64;
+            if filter.desc {
+                records.reverse();
+            }
+            for record in records {
+                if filter.since.is_some_and(|t| record.time < t)
+                    || filter.until.is_some_and(|t| record.time > t)
+                {
+                    continue;
+                }
+                let mut event = Event::from_data(&record.json)?;
+                event.reindex(index_tags)?;
+                if filter.r#match(event.index()) && filter.verify_long_tags(event.tags()) {
+                    self.buffer.push_back(record);
+                }
+            }
+        }
+    }
+}
+
+/// Merge the matched events of the segments in time order
+pub(crate) struct ArchiveIter {
+    filter: Filter,
+    index_tags: IndexTags,
+    streams: Vec<SegmentStream>,
+    // the next record of each stream
+    heads: Vec<Option<Record>>,
+    /// the time range of the segments
+    pub since: u64,
+    pub until: u64,
+    /// number of scanned records
+    pub scanned: u64,
+}
+
+impl ArchiveIter {
+    pub fn next(&mut self) -> Result<Option<Record>> {
+        if self.heads.is_empty() {
+            for stream in self.streams.iter_mut() {
+                self.heads
+                    .push(stream.next(&self.filter, &self.index_tags, &mut self.scanned)?);
+            }
+        }
+        let mut found: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some(record) = head {
+                let better = match found.and_then(|f| self.heads[f].as_ref()) {
+                    Some(cur) if self.filter.desc => record.cmp(cur).is_gt(),
+                    Some(cur) => record.cmp(cur).is_lt(),
+                    None => true,
+                };
+                if better {
+                    found = Some(i);
+                }
+            }
+        }
+        if let Some(i) = found {
+            let next = self.streams[i].next(&self.filter, &self.index_tags, &mut self.scanned)?;
+            Ok(std::mem::replace(&mut self.heads[i], next))
+        } else {
+            Ok(None)
+        }
+    }
+}
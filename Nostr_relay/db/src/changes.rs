@@ -0,0 +1,192 @@
+//! Change data capture of the stored events.
+//!
+//! The stored events are ordered by the uid sequence, the deletions get new sequence numbers
+//! from the same counter and are logged in the change tree `[seq u64] -> [uid u64][id 32 bytes]`.
+//! The events moved to the archive or a partition are logged with a trailing `1` byte.
+
+use crate::{
+    db::{get_event_by_uid, u64_from_bytes},
+    error::Error,
+    Event, FromEventData,
+};
+use nostr_kv::store::{Transaction, Tree};
+use serde::Deserialize;
+use std::{marker::PhantomData, ops::Bound, str::FromStr};
+
+/// A change of the stored events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<J> {
+    /// The stored event, the seq is the uid of the event
+    Put { seq: u64, event: J },
+    /// The deleted event
+    Delete { seq: u64, uid: u64, id: [u8; 32] },
+    /// The event moved to the archive or a partition, still readable from the database
+    Move { seq: u64, uid: u64, id: [u8; 32] },
+}
+
+impl<J> Change<J> {
+    pub fn seq(&self) -> u64 {
+        match self {
+            Change::Put { seq, .. } => *seq,
+            Change::Delete { seq, .. } => *seq,
+            Change::Move { seq, .. } => *seq,
+        }
+    }
+}
+
+impl Change<String> {
+    /// One json line, `{"seq":1,"op":"put","event":{..}}`, `{"seq":2,"op":"delete","uid":1,"id":".."}`
+    /// or `{"seq":3,"op":"move","uid":1,"id":".."}`
+    pub fn to_json(&self) -> String {
+        match self {
+            Change::Put { seq, event } => {
+                format!(r#"{{"seq":{},"op":"put","event":{}}}"#, seq, event)
+            }
+            Change::Delete { seq, uid, id } => format!(
+                r#"{{"seq":{},"op":"delete","uid":{},"id":"{}"}}"#,
+                seq,
+                uid,
+                hex::encode(id)
+            ),
+            Change::Move { seq, uid, id } => format!(
+                r#"{{"seq":{},"op":"move","uid":{},"id":"{}"}}"#,
+                seq,
+                uid,
+                hex::encode(id)
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ChangeLine {
+    seq: u64,
+    op: String,
+    event: Option<Event>,
+    uid: Option<u64>,
+    #[serde(default, with = "hex::serde")]
+    id: [u8; 32],
+}
+
+impl FromStr for Change<Event> {
+    type Err = Error;
+
+    /// Parse the json line of [`Change::to_json`]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let line: ChangeLine = serde_json::from_str(s)?;
+        match (line.op.as_str(), line.event, line.uid) {
+            ("put", Some(event), _) => Ok(Change::Put {
+                seq: line.seq,
+                event,
+            }),
+            ("delete", _, Some(uid)) => Ok(Change::Delete {
+                seq: line.seq,
+                uid,
+                id: line.id,
+            }),
+            ("move", _, Some(uid)) => Ok(Change::Move {
+                seq: line.seq,
+                uid,
+                id: line.id,
+            }),
+            _ => Err(Error::Invalid(format!("change {}", line.op))),
+        }
+    }
+}
+
+pub(crate) fn encode_change(uid: &[u8], id: &[u8], moved: bool) -> Vec<u8> {
+    if moved {
+        [uid, id, &[1]].concat()
+    } else {
+        [uid, id].concat()
+    }
+}
+
+/// Merge the stored events and the deletions in the sequence order
+pub struct ChangeIter<'txn, R: Transaction + 'txn, J> {
+    reader: &'txn R,
+    t_meta: Tree,
+    t_data: Tree,
+    t_index: Tree,
+    puts: R::Iter<'txn>,
+    dels: R::Iter<'txn>,
+    put: Option<u64>,
+    del: Option<(u64, &'txn [u8])>,
+    _r: PhantomData<J>,
+}
+
+impl<'txn, R: Transaction, J: FromEventData> ChangeIter<'txn, R, J> {
+    pub(crate) fn new(
+        reader: &'txn R,
+        t_meta: &Tree,
+        t_data: &Tree,
+        t_index: &Tree,
+        t_change: &Tree,
+        since: u64,
+    ) -> Self {
+        let from = since.to_be_bytes();
+        Self {
+            reader,
+            t_meta: t_meta.clone(),
+            t_data: t_data.clone(),
+            t_index: t_index.clone(),
+            puts: reader.iter_from(t_index, Bound::Included(&from), false),
+            dels: reader.iter_from(t_change, Bound::Included(&from), false),
+            put: None,
+            del: None,
+            _r: PhantomData,
+        }
+    }
+
+    fn next_inner(&mut self) -> Result<Option<Change<J>>, Error> {
+        if self.put.is_none() {
+            if let Some(item) = self.puts.next() {
+                self.put = Some(u64_from_bytes(item?.0)?);
+            }
+        }
+        if self.del.is_none() {
+            if let Some(item) = self.dels.next() {
+                let (k, v) = item?;
+                self.del = Some((u64_from_bytes(k)?, v));
+            }
+        }
+        let put_first = match (self.put, self.del) {
+            (Some(put), Some((del, _))) => put < del,
+            (Some(_), None) => true,
+            _ => false,
+        };
+        if put_first {
+            let seq = self.put.take().unwrap();
+            let event = get_event_by_uid(
+                self.reader,
+                &self.t_meta,
+                &self.t_data,
+                &self.t_index,
+                seq.to_be_bytes(),
+            )?
+            .ok_or_else(|| Error::Message("event data not found".to_owned()))?;
+            Ok(Some(Change::Put { seq, event }))
+        } else if let Some((seq, v)) = self.del.take() {
+            if v.len() != 40 && v.len() != 41 {
+                return Err(Error::InvalidLength);
+            }
+            let uid = u64_from_bytes(&v[0..8])?;
+            let id = v[8..40].try_into()?;
+            Ok(Some(if v.len() == 41 {
+                Change::Move { seq, uid, id }
+            } else {
+                Change::Delete { seq, uid, id }
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<'txn, R: Transaction, J: FromEventData> Iterator for ChangeIter<'txn, R, J> {
+    type Item = Result<Change<J>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_inner().transpose()
+    }
+}
@@ -0,0 +1,205 @@
+//! Encryption of the stored event data at rest.
+//!
+//! Each record is encrypted by XChaCha20-Poly1305 with a random 24 bytes nonce,
+//! the dictionary id of the compressed data is authenticated as the associated data.
+//! The keys are registered in the process like the zstd dictionaries,
+//! so the event data can be decoded by [`crate::FromEventData`] without the database.
+//! The index keys stay in plain text to keep them orderable, see [`crate::Db::set_tag_key`] for the tag values.
+
+use crate::error::Error;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hmac::{Hmac, Mac};
+use parking_lot::RwLock;
+use secp256k1::rand::{thread_rng, RngCore};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
+
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+// [tag][nonce][key id u32]
+const TRAILER_SIZE: usize = TAG_SIZE + NONCE_SIZE + 4;
+
+/// HMAC-SHA256 of the concatenated parts
+pub(crate) fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("hmac takes any key size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// A 32 bytes key for encrypting the event data
+#[derive(Clone, PartialEq, Eq)]
+pub struct DataKey {
+    /// the id is derived from the key, stored with the encrypted data to find the key
+    pub id: u32,
+    key: [u8; 32],
+}
+
+impl Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DataKey").field("id", &self.id).finish()
+    }
+}
+
+impl DataKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        let id = hmac(&key, &[b"key id"]);
+        Self {
+            id: u32::from_be_bytes(id[0..4].try_into().unwrap()),
+            key,
+        }
+    }
+
+    /// Generate a random key
+    pub fn generate() -> Self {
+        let mut key = [0u8; 32];
+        thread_rng().fill_bytes(&mut key);
+        Self::new(key)
+    }
+
+    /// Read the key file of 64 hex characters or 32 raw bytes
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let bytes = std::fs::read(path)?;
+        match std::str::from_utf8(&bytes) {
+            Ok(s) if s.trim().len() == 64 => s.trim().parse(),
+            _ => Ok(Self::new(
+                bytes.try_into().map_err(|_| Error::InvalidLength)?,
+            )),
+        }
+    }
+
+    /// The key in hex
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.key)
+    }
+
+    /// Derive the key for the usage, such as the tag value hash
+    pub fn derive(&self, usage: &str) -> [u8; 32] {
+        hmac(&self.key, &[usage.as_bytes()])
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.derive("data").into())
+    }
+
+    /// `[ciphertext][tag 16][nonce 24][key id u32][extra]`,
+    /// the extra data is authenticated but not encrypted.
+    pub(crate) fn encrypt(&self, data: &[u8], extra: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        thread_rng().fill_bytes(&mut nonce);
+        let mut bytes = self
+            .cipher()
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: data,
+                    aad: extra,
+                },
+            )
+            .expect("the data size is in the limit");
+        bytes.reserve(NONCE_SIZE + 4 + extra.len());
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.extend_from_slice(extra);
+        bytes
+    }
+
+    // `[ciphertext][tag 16][nonce 24]`
+    fn decrypt(&self, bytes: &[u8], extra: &[u8]) -> Result<Vec<u8>, Error> {
+        let (ciphertext, nonce) = bytes.split_at(bytes.len() - NONCE_SIZE);
+        self.cipher()
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: extra,
+                },
+            )
+            .map_err(|_| Error::Invalid("encrypted data authentication failed".to_owned()))
+    }
+}
+
+impl FromStr for DataKey {
+    type Err = Error;
+
+    /// Parse the key of 64 hex characters
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut key = [0u8; 32];
+        hex::decode_to_slice(s.trim(), &mut key)?;
+        Ok(Self::new(key))
+    }
+}
+
+fn registry() -> &'static RwLock<HashMap<u32, Arc<DataKey>>> {
+    static KEYS: OnceLock<RwLock<HashMap<u32, Arc<DataKey>>>> = OnceLock::new();
+    KEYS.get_or_init(Default::default)
+}
+
+/// Register the key for decrypting the data, such as the old keys before the rotation
+pub fn register_key(key: DataKey) -> Arc<DataKey> {
+    let key = Arc::new(key);
+    registry().write().insert(key.id, key.clone());
+    key
+}
+
+/// The key id of the data encrypted by [`DataKey::encrypt`] with the extra data size
+pub(crate) fn key_id(bytes: &[u8], extra: usize) -> Option<u32> {
+    let end = bytes.len().checked_sub(extra)?;
+    let start = end.checked_sub(4)?;
+    (start >= TRAILER_SIZE - 4).then(|| u32::from_be_bytes(bytes[start..end].try_into().unwrap()))
+}
+
+/// Decrypt the data of [`DataKey::encrypt`] with the extra data size, by the registered key
+pub(crate) fn decrypt(bytes: &[u8], extra: usize) -> Result<Vec<u8>, Error> {
+    let id = key_id(bytes, extra).ok_or(Error::InvalidLength)?;
+    let key = registry()
+        .read()
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| Error::Invalid(format!("data key {:08x} not found", id)))?;
+    let (bytes, extra) = bytes.split_at(bytes.len() - extra);
+    key.decrypt(&bytes[..bytes.len() - 4], extra)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt() -> Result<(), Error> {
+        let key = DataKey::generate();
+        let data = b"{\"content\":\"hello\"}";
+        let bytes = key.encrypt(data, b"ex");
+        assert_eq!(bytes.len(), data.len() + TRAILER_SIZE + 2);
+        assert_ne!(&bytes[..data.len()], data);
+        assert_ne!(bytes, key.encrypt(data, b"ex"));
+        assert_eq!(key_id(&bytes, 2), Some(key.id));
+
+        // not registered
+        assert!(decrypt(&bytes, 2).is_err());
+        register_key(key.clone());
+        assert_eq!(decrypt(&bytes, 2)?, data);
+
+        let mut tampered = bytes.clone();
+        tampered[0] ^= 1;
+        assert!(decrypt(&tampered, 2).is_err());
+        let mut tampered = bytes.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&tampered, 2).is_err());
+        assert!(decrypt(&bytes[1..], 2).is_err());
+
+        assert_eq!(key.to_hex().parse::<DataKey>()?, key);
+        assert!("abc".parse::<DataKey>().is_err());
+        Ok(())
+    }
+}
@@ -1,14 +1,31 @@
 use crate::{
+    archive::{Archive, ArchiveIter, Record},
+    changes::{encode_change, Change, ChangeIter},
+    cipher::{hmac, register_key, DataKey},
+    dict::{self, Dictionary},
     error::Error,
-    key::{concat, concat_sep, encode_replace_key, u16_to_ver, u64_to_ver, IndexKey},
-    ArchivedEventIndex, Event, EventIndex, Filter, FromEventData, Stats,
+    event::{data_dictionary, data_key_id, now},
+    history::{decode_history_key, encode_history_key, HistoryOptions},
+    key::{
+        concat, concat_sep, encode_replace_key, encode_stat_key, index_stat_key, u16_to_ver,
+        u64_to_ver, IndexKey, STAT_EVENT_AUTHOR, STAT_EVENT_DICT, STAT_EVENT_KIND, STAT_EVENT_RAW,
+        STAT_EVENT_TOTAL, STAT_KIND, STAT_PUBKEY, STAT_PUBKEY_KIND, STAT_TAG, STAT_TOTAL,
+        STAT_WORD,
+    },
+    partition::{Partition, Partitions, Period},
+    tag::{IndexTags, MAX_TAG_VALUE_SIZE},
+    ArchivedEventIndex, Counter, Cursor, EnvStats, Estimate, Event, EventIndex, EventStats,
+    Explain, Filter, FromEventData, Plan, Stats,
 };
 use nostr_kv::{
-    lmdb::{Db as Lmdb, Iter as LmdbIter, *},
-    scanner::{Group, GroupItem, MatchResult, Scanner},
+    lmdb::{Db as Lmdb, *},
+    memory::Memory,
+    scanner::{Group, GroupItem, MatchResult, PrefetchPool, Scanner, ScannerWatcher, TimeKey},
 };
 
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     marker::PhantomData,
     ops::Bound,
     path::Path,
@@ -19,7 +36,13 @@ use std::{
     time::{Duration, Instant},
 };
 
+use parking_lot::{Mutex, RwLock};
+
 type Result<T, E = Error> = core::result::Result<T, E>;
+// the found authors and the last scanned author
+type AuthorScan = (Vec<[u8; 32]>, Option<[u8; 32]>);
+// the parallel scan options with the shared threads
+type ParallelPool = (Arc<ParallelScan>, PrefetchPool);
 
 pub fn upper(mut key: Vec<u8>) -> Option<Vec<u8>> {
     key.iter().rposition(|&x| x < u8::MAX).map(|position| {
@@ -29,12 +52,34 @@ pub fn upper(mut key: Vec<u8>) -> Option<Vec<u8>> {
     })
 }
 
-const MAX_TAG_VALUE_SIZE: usize = 255;
-const DB_VERSION: &str = "3";
+const DB_VERSION: &str = "5";
+// the replace keys of the addressable events are changed since version 4
+const DB_VERSION_3: &str = "3";
+// the tombstones of the deleted events are keyed by the deleted id and the deleter since version 5
+const DB_VERSION_4: &str = "4";
+const STATS_VERSION: &str = "3";
+// the meta key of the current dictionary id
+const META_DICT: &str = "dict";
+// the meta key of the next sequence number of the primary change log to apply
+const META_REPLICA: &str = "replica";
+// the meta key of the fingerprint of the tag key the tag index is built with
+const META_TAG_KEY: &str = "tag_key";
+// the meta key of the additional indexed tag names, separated by 0
+const META_INDEX_TAGS: &str = "index_tags";
+// the meta key prefix of the authors written by the authenticated sessions
+const META_AUTHENTICATED: &str = "authenticated";
+// the usage of the tag key derived from the configured key
+const TAG_KEY_USAGE: &str = "tag value";
+
+// lmdb max_key_size 511 bytes, we only index tag value length < 255
+const MAX_REPLACE_KEY_SIZE: usize = MAX_TAG_VALUE_SIZE + 8 + 32;
+
+// the tombstone of the coordinate deleted by the `a` tag in t_deletion
+const DELETION_ADDRESS: &str = "a";
 
 #[derive(Clone)]
-pub struct Db {
-    inner: Lmdb,
+pub struct Db<S = Lmdb> {
+    inner: S,
     #[allow(unused)]
     // save meta data
     t_meta: Tree,
@@ -57,13 +102,192 @@ pub struct Db {
     t_tag: Tree,
     t_deletion: Tree,
     t_replacement: Tree,
+    // the superseded versions of the replaceable events
+    t_history: Tree,
     t_expiration: Tree,
     // word time
     t_word: Tree,
+    // index cardinality statistics for the query planner
+    t_stat: Tree,
+    // the deletions in the sequence of the uid for the change data capture
+    t_change: Tree,
     seq: Arc<AtomicU64>,
+    // cold storage of the old events
+    archive: Archive,
+    // the old events moved to an environment per period
+    partitions: Partitions,
+    // the trained zstd dictionary for compressing the new events
+    dict: Arc<RwLock<Option<Arc<Dictionary>>>>,
+    // keep the superseded versions when enabled
+    history: Arc<RwLock<Option<Arc<HistoryOptions>>>>,
+    // encrypt the new event data when set
+    data_key: Arc<RwLock<Option<Arc<DataKey>>>>,
+    // prefetch the large OR filters in the shared threads when set
+    parallel: Arc<RwLock<Option<ParallelPool>>>,
+    // the indexed tag names longer than a letter
+    index_tags: IndexTags,
+    // the index cardinality changes of the write transaction, written once at the commit
+    stat_deltas: Arc<Mutex<HashMap<Vec<u8>, i64>>>,
+    // the ids of the moved events deleted by the write transaction, deleted from the partitions after the commit
+    partition_dels: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+/// Scan the index keys of the large OR filters, such as hundreds of authors, in parallel threads.
+/// The scanners are split to the threads, each thread reads its own snapshot
+/// and merges its scanners in order, the keys are prefetched to the bounded buffers.
+/// The threads are shared by the queries, `threads * max_queries` threads hold a read transaction
+/// at most, a query scans serially when the other queries use all the threads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParallelScan {
+    /// the number of threads per query
+    pub threads: usize,
+    /// the max number of the queries scanning in parallel at the same time
+    pub max_queries: usize,
+    /// the min number of scanners to scan in parallel
+    pub min_scanners: usize,
+    /// the number of keys buffered per thread
+    pub buffer: usize,
+}
+
+impl Default for ParallelScan {
+    fn default() -> Self {
+        Self {
+            threads: 4,
+            max_queries: 4,
+            min_scanners: 64,
+            buffer: 256,
+        }
+    }
+}
+
+/// When the committed transactions are durable on the disk, the flags of the lmdb environment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// sync the data and the meta page on each commit
+    #[default]
+    Sync,
+    /// sync the data on each commit and the meta page by [`Db::flush`],
+    /// a system crash may undo the last commit, `MDB_NOMETASYNC`
+    NoMetaSync,
+    /// sync by [`Db::flush`] only, a system crash may undo the commits since the last flush, `MDB_NOSYNC`
+    NoSync,
+}
+
+impl Durability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Durability::Sync => "sync",
+            Durability::NoMetaSync => "no_meta_sync",
+            Durability::NoSync => "no_sync",
+        }
+    }
+
+    /// Whether the commit is durable, or the [`Db::flush`] is required
+    pub fn sync_on_commit(&self) -> bool {
+        *self == Durability::Sync
+    }
+}
+
+// the size of the stored event data for the statistics
+struct DataSize {
+    bytes: u64,
+    // the size of the uncompressed record
+    raw: u64,
+    // compressed with the dictionary
+    dict: bool,
+}
+
+impl DataSize {
+    fn new(raw: u64, data: &[u8]) -> Self {
+        Self {
+            bytes: data.len() as u64,
+            raw,
+            dict: data_dictionary(data).is_some(),
+        }
+    }
+}
+
+fn dict_meta_key(id: u32) -> Vec<u8> {
+    concat_sep(META_DICT, id.to_be_bytes())
+}
+
+fn deletion_address_key(replace_key: &[u8]) -> Vec<u8> {
+    concat_sep(DELETION_ADDRESS, replace_key)
+}
+
+fn sort_authors(authors: &mut Vec<([u8; 32], Counter)>, top: usize) {
+    authors.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(&b.0)));
+    authors.truncate(top);
+}
+
+// describe the scanner group built by the plan, return the structure and the number of scanners
+fn group_structure(plan: Plan, filter: &Filter) -> (String, u64) {
+    let or = |len: usize| (format!("or({})", len), len as u64);
+    match plan {
+        Plan::Search => (
+            format!("and({})", filter.words.len()),
+            filter.words.len() as u64,
+        ),
+        Plan::Ids => or(filter.ids.len()),
+        Plan::Tag => {
+            let subs = filter
+                .tags
+                .values()
+                .map(|values| format!("or({})", values.len()))
+                .collect::<Vec<_>>();
+            let scanners = filter.tags.values().map(|v| v.len() as u64).sum();
+            (format!("and({})", subs.join(", ")), scanners)
+        }
+        Plan::AuthorKind => or(filter.authors.len() * filter.kinds.len()),
+        Plan::Author => or(filter.authors.len()),
+        Plan::Kind => or(filter.kinds.len()),
+        Plan::Time | Plan::Expiration => or(1),
+    }
 }
 
-fn u64_from_bytes(bytes: &[u8]) -> Result<u64, Error> {
+// the fingerprint of the tag key stored in the meta, none if the tag values are plain
+fn tag_key_fingerprint(key: Option<&[u8; 32]>) -> Option<[u8; 32]> {
+    key.map(|k| hmac(k, &[b"fingerprint"]))
+}
+
+// the same tag only has one index entry, remove the duplicates for the statistics
+fn tag_keys<K: AsRef<[u8]>, I: Iterator<Item = (K, K)>>(tags: I, time: u64) -> Vec<Vec<u8>> {
+    let mut keys = tags
+        .map(|(k, v)| IndexKey::encode_tag(k, v, time))
+        .collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// Whether the stored replaceable event can be replaced by the new event.
+/// If two events have the same timestamp, the event with the lowest id (first in lexical order) SHOULD be retained, and the other discarded.
+pub(crate) fn replaceable_by(old: &Event, new: &Event) -> bool {
+    !(new.created_at() < old.created_at()
+        || (new.created_at() == old.created_at() && new.id() > old.id()))
+}
+
+/// Report [`Error::ScanTimeout`] if the scan time exceeds the timeout, check every `check_step` scans
+pub(crate) fn scan_watcher(
+    timeout: Duration,
+    check_step: u64,
+) -> impl FnMut(u64) -> Result<(), Error> + Clone + 'static {
+    let start = Instant::now();
+    let mut last = check_step;
+    move |count| {
+        if count > last {
+            // check
+            if start.elapsed() > timeout {
+                return Err(Error::ScanTimeout);
+            }
+            last = count + check_step;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn u64_from_bytes(bytes: &[u8]) -> Result<u64, Error> {
     Ok(u64::from_be_bytes(bytes.try_into()?))
 }
 
@@ -71,32 +295,52 @@ fn u16_from_bytes(bytes: &[u8]) -> Result<u16, Error> {
     Ok(u16::from_be_bytes(bytes.try_into()?))
 }
 
-// Get the latest seq from db
-fn latest_seq(db: &Lmdb, tree: &Tree) -> Result<u64, Error> {
-    let txn = db.reader()?;
-    let mut iter = txn.iter_from(tree, Bound::Unbounded::<Vec<u8>>, true);
-    if let Some(item) = iter.next() {
-        let (k, _) = item?;
-        u64_from_bytes(k)
-    } else {
-        Ok(0)
-    }
+fn encode_counter(counter: &Counter) -> Vec<u8> {
+    [counter.count.to_be_bytes(), counter.bytes.to_be_bytes()].concat()
 }
 
-#[cfg(feature = "zstd")]
-fn encode_event(event: &Event) -> Result<Vec<u8>> {
-    let json = event.to_json()?;
-    let mut json = zstd::encode_all(json.as_bytes(), 5).map_err(Error::Io)?;
-    json.push(1);
-    Ok(json)
+fn decode_counter(bytes: &[u8]) -> Result<Counter, Error> {
+    if bytes.len() != 16 {
+        return Err(Error::InvalidLength);
+    }
+    Ok(Counter {
+        count: u64_from_bytes(&bytes[0..8])?,
+        bytes: u64_from_bytes(&bytes[8..16])?,
+    })
 }
-#[cfg(not(feature = "zstd"))]
-fn encode_event(event: &Event) -> Result<String> {
-    event.to_json()
+
+// Get the next seq from db, after the latest uid and change
+fn next_seq<S: Store>(db: &S, trees: &[&Tree]) -> Result<u64, Error> {
+    let txn = db.reader()?;
+    let mut next = 0;
+    for tree in trees {
+        let mut iter = txn.iter_from(tree, Bound::Unbounded::<Vec<u8>>, true);
+        if let Some(item) = iter.next() {
+            let (k, _) = item?;
+            next = next.max(u64_from_bytes(k)? + 1);
+        }
+    }
+    Ok(next)
 }
 
-impl Db {
-    fn del_event(&self, writer: &mut Writer, event: &Event, uid: &[u8]) -> Result<(), Error> {
+impl<S: Store> Db<S> {
+    fn del_event(
+        &self,
+        writer: &mut S::Writer<'_>,
+        event: &Event,
+        uid: &[u8],
+    ) -> Result<(), Error> {
+        self.remove_event(writer, event, uid, false)
+    }
+
+    // remove the event and its indexes, logged as a move or a deletion in the change log
+    fn remove_event(
+        &self,
+        writer: &mut S::Writer<'_>,
+        event: &Event,
+        uid: &[u8],
+        moved: bool,
+    ) -> Result<(), Error> {
         let index_event = event.index();
         let time = index_event.created_at();
         let kind = index_event.kind();
@@ -109,12 +353,36 @@ impl Db {
             writer.del(&self.t_uid_word, uid, None)?;
             let word = unsafe { rkyv::archived_root::<Vec<Vec<u8>>>(&bytes) };
             for item in word.as_slice() {
-                writer.del(&self.t_word, IndexKey::encode_word(item, time), Some(uid))?;
+                self.del_index(
+                    writer,
+                    &self.t_word,
+                    STAT_WORD,
+                    IndexKey::encode_word(item, time),
+                    uid,
+                )?;
             }
         }
 
+        if let Some(data) = writer.get(&self.t_data, uid)? {
+            let size = DataSize::new(event.record()?.len() as u64, data);
+            self.incr_event_stats(writer, kind, pubkey, &size, false)?;
+        }
+
+        // the tags indexed when stored, the indexed names may be changed since
+        let indexed = match writer.get(&self.t_index, uid)? {
+            Some(bytes) => EventIndex::from_bytes(bytes)?,
+            None => index_event.clone(),
+        };
         writer.del(&self.t_data, uid, None)?;
         writer.del(&self.t_index, uid, None)?;
+
+        // change log
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        writer.put(
+            &self.t_change,
+            u64_to_ver(seq),
+            encode_change(uid, index_event.id(), moved),
+        )?;
         writer.del(&self.t_id_uid, index_event.id(), None)?;
 
         writer.del(
@@ -123,47 +391,66 @@ impl Db {
             Some(uid),
         )?;
 
-        writer.del(&self.t_kind, IndexKey::encode_kind(kind, time), Some(uid))?;
+        self.del_index(
+            writer,
+            &self.t_kind,
+            STAT_KIND,
+            IndexKey::encode_kind(kind, time),
+            uid,
+        )?;
 
-        writer.del(
+        self.del_index(
+            writer,
             &self.t_pubkey,
+            STAT_PUBKEY,
             IndexKey::encode_pubkey(pubkey, time),
-            Some(uid),
+            uid,
         )?;
-        writer.del(
+        self.del_index(
+            writer,
             &self.t_pubkey_kind,
+            STAT_PUBKEY_KIND,
             IndexKey::encode_pubkey_kind(pubkey, kind, time),
-            Some(uid),
+            uid,
         )?;
 
         if let Some(delegator) = index_event.delegator() {
-            writer.del(
+            self.del_index(
+                writer,
                 &self.t_pubkey,
+                STAT_PUBKEY,
                 IndexKey::encode_pubkey(delegator, time),
-                Some(uid),
+                uid,
             )?;
-            writer.del(
+            self.del_index(
+                writer,
                 &self.t_pubkey_kind,
+                STAT_PUBKEY_KIND,
                 IndexKey::encode_pubkey_kind(delegator, kind, time),
-                Some(uid),
+                uid,
             )?;
         }
 
-        writer.del(&self.t_created_at, IndexKey::encode_time(time), Some(uid))?;
+        self.del_index(
+            writer,
+            &self.t_created_at,
+            STAT_TOTAL,
+            IndexKey::encode_time(time),
+            uid,
+        )?;
 
         let tagval = concat(uid, kind.to_be_bytes());
-        for tag in index_event.tags() {
-            writer.del(
-                &self.t_tag,
-                IndexKey::encode_tag(&tag.0, &tag.1, time),
-                Some(&tagval),
-            )?;
+        for key in tag_keys(indexed.tags().iter().map(|t| (&t.0, &t.1)), time) {
+            self.del_index(writer, &self.t_tag, STAT_TAG, key, &tagval)?;
         }
 
-        // replacement index
+        // replacement index, the key may be taken by the other version
         if let Some(k) = encode_replace_key(index_event.kind(), index_event.pubkey(), event.tags())
         {
-            writer.del(&self.t_replacement, k, None)?;
+            if k.len() <= MAX_REPLACE_KEY_SIZE && writer.get(&self.t_replacement, &k)? == Some(uid)
+            {
+                writer.del(&self.t_replacement, k, None)?;
+            }
         }
 
         // expiration
@@ -176,7 +463,7 @@ impl Db {
 
     fn put_event(
         &self,
-        writer: &mut Writer,
+        writer: &mut S::Writer<'_>,
         event: &Event,
         uid: &Vec<u8>,
         replace_key: &Option<Vec<u8>>,
@@ -185,9 +472,10 @@ impl Db {
 
         // put event
         let time = index_event.created_at();
-        let json = encode_event(event)?;
+        let (data, raw_size) = self.encode_data(event)?;
+        let size = DataSize::new(raw_size as u64, &data);
 
-        writer.put(&self.t_data, uid, json)?;
+        writer.put(&self.t_data, uid, data)?;
 
         // put index
         let bytes = index_event.to_bytes()?;
@@ -197,44 +485,73 @@ impl Db {
         let kind = index_event.kind();
         let pubkey = index_event.pubkey();
 
+        self.incr_event_stats(writer, kind, pubkey, &size, true)?;
+
         writer.put(&self.t_id_uid, index_event.id(), uid)?;
 
         writer.put(&self.t_id, IndexKey::encode_id(index_event.id(), time), uid)?;
 
-        writer.put(&self.t_kind, IndexKey::encode_kind(kind, time), uid)?;
+        self.put_index(
+            writer,
+            &self.t_kind,
+            STAT_KIND,
+            IndexKey::encode_kind(kind, time),
+            uid,
+        )?;
 
-        writer.put(&self.t_pubkey, IndexKey::encode_pubkey(pubkey, time), uid)?;
-        writer.put(
+        self.put_index(
+            writer,
+            &self.t_pubkey,
+            STAT_PUBKEY,
+            IndexKey::encode_pubkey(pubkey, time),
+            uid,
+        )?;
+        self.put_index(
+            writer,
             &self.t_pubkey_kind,
+            STAT_PUBKEY_KIND,
             IndexKey::encode_pubkey_kind(pubkey, kind, time),
             uid,
         )?;
 
         if let Some(delegator) = index_event.delegator() {
-            writer.put(
+            self.put_index(
+                writer,
                 &self.t_pubkey,
+                STAT_PUBKEY,
                 IndexKey::encode_pubkey(delegator, time),
                 uid,
             )?;
-            writer.put(
+            self.put_index(
+                writer,
                 &self.t_pubkey_kind,
+                STAT_PUBKEY_KIND,
                 IndexKey::encode_pubkey_kind(delegator, kind, time),
                 uid,
             )?;
         }
 
-        writer.put(&self.t_created_at, IndexKey::encode_time(time), uid)?;
+        self.put_index(
+            writer,
+            &self.t_created_at,
+            STAT_TOTAL,
+            IndexKey::encode_time(time),
+            uid,
+        )?;
 
         let tagval = concat(uid, kind.to_be_bytes());
         for tag in index_event.tags() {
             let key = &tag.0;
             let v = &tag.1;
             // tag[0] == 'e'
+            // the tombstone of the referenced event by the deleter, checked by the archived events
             if kind == 5 && key[0] == 101 {
-                writer.put(&self.t_deletion, concat(index_event.id(), v), uid)?;
+                writer.put(&self.t_deletion, concat(v, index_event.pubkey()), uid)?;
             }
+        }
+        for key in tag_keys(index_event.tags().iter().map(|t| (&t.0, &t.1)), time) {
             // Provide pubkey kind for filter
-            writer.put(&self.t_tag, IndexKey::encode_tag(key, v, time), &tagval)?;
+            self.put_index(writer, &self.t_tag, STAT_TAG, key, &tagval)?;
         }
 
         // replacement index
@@ -255,15 +572,160 @@ impl Db {
                 rkyv::to_bytes::<_, 256>(words).map_err(|e| Error::Serialization(e.to_string()))?;
             writer.put(&self.t_uid_word, uid, bytes)?;
             for item in words {
-                writer.put(&self.t_word, IndexKey::encode_word(item, time), uid)?;
+                self.put_index(
+                    writer,
+                    &self.t_word,
+                    STAT_WORD,
+                    IndexKey::encode_word(item, time),
+                    uid,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // put the index and increase the cardinality of the index key
+    fn put_index<K: AsRef<[u8]>>(
+        &self,
+        writer: &mut S::Writer<'_>,
+        tree: &Tree,
+        stat: u8,
+        key: K,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        let key = key.as_ref();
+        writer.put(tree, key, value)?;
+        self.incr_stat(index_stat_key(stat, key), true);
+        Ok(())
+    }
+
+    // delete the index and decrease the cardinality of the index key
+    fn del_index<K: AsRef<[u8]>>(
+        &self,
+        writer: &mut S::Writer<'_>,
+        tree: &Tree,
+        stat: u8,
+        key: K,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        let key = key.as_ref();
+        writer.del(tree, key, Some(value))?;
+        self.incr_stat(index_stat_key(stat, key), false);
+        Ok(())
+    }
+
+    fn incr_stat(&self, key: Vec<u8>, incr: bool) {
+        *self.stat_deltas.lock().entry(key).or_default() += if incr { 1 } else { -1 };
+    }
+
+    // write the index cardinality changes of the transaction, saturate at zero
+    fn write_stats(&self, writer: &mut S::Writer<'_>) -> Result<(), Error> {
+        let deltas = std::mem::take(&mut *self.stat_deltas.lock());
+        for (key, delta) in deltas {
+            if delta == 0 {
+                continue;
+            }
+            let old = match writer.get(&self.t_stat, &key)? {
+                Some(v) => u64_from_bytes(v)?,
+                None => 0,
+            };
+            let num = old.saturating_add_signed(delta);
+            if num == 0 {
+                writer.del(&self.t_stat, key, None)?;
+            } else {
+                writer.put(&self.t_stat, key, num.to_be_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    // update the number of events and bytes in total, per kind and per author
+    fn incr_event_stats(
+        &self,
+        writer: &mut S::Writer<'_>,
+        kind: u16,
+        pubkey: &[u8; 32],
+        size: &DataSize,
+        incr: bool,
+    ) -> Result<(), Error> {
+        let bytes = size.bytes;
+        let mut keys = vec![
+            encode_stat_key(STAT_EVENT_TOTAL, []),
+            encode_stat_key(STAT_EVENT_KIND, u16_to_ver(kind)),
+            encode_stat_key(STAT_EVENT_AUTHOR, pubkey),
+        ];
+        if size.dict {
+            keys.push(encode_stat_key(STAT_EVENT_DICT, []));
+        }
+        for key in keys {
+            let mut counter = match writer.get(&self.t_stat, &key)? {
+                Some(v) => decode_counter(v)?,
+                None => Counter::default(),
+            };
+            if incr {
+                counter.count += 1;
+                counter.bytes += bytes;
+            } else {
+                counter.count = counter.count.saturating_sub(1);
+                counter.bytes = counter.bytes.saturating_sub(bytes);
+            }
+            if counter.count == 0 {
+                writer.del(&self.t_stat, key, None)?;
+            } else {
+                writer.put(&self.t_stat, key, encode_counter(&counter))?;
             }
         }
+
+        let key = encode_stat_key(STAT_EVENT_RAW, []);
+        let old = self.get_stat(writer, key.clone())?;
+        let raw = if incr {
+            old + size.raw
+        } else {
+            old.saturating_sub(size.raw)
+        };
+        writer.put(&self.t_stat, key, raw.to_be_bytes())?;
         Ok(())
     }
+
+    fn get_stat<T: Transaction>(&self, txn: &T, key: Vec<u8>) -> Result<u64, Error> {
+        match txn.get(&self.t_stat, key)? {
+            Some(v) => u64_from_bytes(v),
+            None => Ok(0),
+        }
+    }
+
+    // sum the cardinality of the index key prefixes
+    fn sum_stat<T: Transaction, I: IntoIterator<Item = Vec<u8>>>(
+        &self,
+        txn: &T,
+        stat: u8,
+        prefixes: I,
+    ) -> Result<u64, Error> {
+        let mut sum = 0;
+        for prefix in prefixes {
+            sum += self.get_stat(txn, encode_stat_key(stat, prefix))?;
+        }
+        Ok(sum)
+    }
+
+    // sum the cardinality of all the statistics keys starting with the prefix
+    fn sum_stat_range<T: Transaction>(&self, txn: &T, stat: u8, prefix: &[u8]) -> Result<u64> {
+        let prefix = encode_stat_key(stat, prefix);
+        let mut sum = 0;
+        for item in txn.iter_from(&self.t_stat, Bound::Included(&prefix), false) {
+            let (k, v) = item?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            sum += u64_from_bytes(v)?;
+        }
+        Ok(sum)
+    }
 }
 
 fn get_event<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
     reader: &T,
+    meta_tree: &Tree,
     id_tree: &Tree,
     data_tree: &Tree,
     index_tree: &Tree,
@@ -271,7 +733,7 @@ fn get_event<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
 ) -> Result<Option<(Vec<u8>, R)>, Error> {
     let uid = get_uid(reader, id_tree, event_id)?;
     if let Some(uid) = uid {
-        let event = get_event_by_uid(reader, data_tree, index_tree, &uid)?;
+        let event = get_event_by_uid(reader, meta_tree, data_tree, index_tree, &uid)?;
         if let Some(event) = event {
             return Ok(Some((uid, event)));
         }
@@ -279,8 +741,9 @@ fn get_event<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
     Ok(None)
 }
 
-fn get_event_by_uid<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
+pub(crate) fn get_event_by_uid<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
     reader: &T,
+    meta_tree: &Tree,
     data_tree: &Tree,
     index_tree: &Tree,
     uid: K,
@@ -297,6 +760,7 @@ fn get_event_by_uid<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
     } else {
         let v = reader.get(data_tree, uid)?;
         if let Some(v) = v {
+            load_dictionary(reader, meta_tree, v)?;
             return Ok(Some(
                 R::from_data(v).map_err(|e| Error::Message(e.to_string()))?,
             ));
@@ -305,6 +769,22 @@ fn get_event_by_uid<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
     Ok(None)
 }
 
+// register the dictionary of the data if it is trained after the database is opened
+pub(crate) fn load_dictionary<T: Transaction>(
+    reader: &T,
+    meta_tree: &Tree,
+    data: &[u8],
+) -> Result<(), Error> {
+    if let Some(id) = data_dictionary(data) {
+        if !dict::registered(id) {
+            if let Some(bytes) = reader.get(meta_tree, dict_meta_key(id))? {
+                dict::register(bytes);
+            }
+        }
+    }
+    Ok(())
+}
+
 fn decode_event_index(v: Option<&[u8]>) -> Result<Option<&ArchivedEventIndex>, Error> {
     if let Some(v) = v {
         return Ok(Some(EventIndex::from_zeroes(v)?));
@@ -330,6 +810,145 @@ pub enum CheckEventResult {
 }
 
 impl Db {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_flags(path.as_ref(), 0)
+    }
+
+    /// Open with the lmdb environment flags
+    pub(crate) fn open_with_flags(path: &Path, flags: u32) -> Result<Self> {
+        let inner = Lmdb::open_with(path, Some(20), Some(100), Some(1_000_000_000_000), flags)?;
+        let mut db = Self::open_store(inner, Archive::open(path.join("archive"))?)?;
+        db.partitions = Partitions::open(path.join("partitions"))?;
+        Ok(db)
+    }
+
+    /// The time partitions of the old events, sorted by time
+    pub fn partitions(&self) -> Vec<Partition> {
+        self.partitions.list()
+    }
+
+    /// Move the events created before the start of the period which contains `before` to the partitions,
+    /// an environment per period. The replaceable and expiring events are kept in the hot storage.
+    /// Scan at most `batch` events after the cursor, return the number of moved events
+    /// and the cursor of the last scanned event to continue from, none if all are scanned.
+    /// The events are committed to the partition before deleting from the hot storage,
+    /// it is safe to run again after a crash.
+    pub fn partition_before(
+        &self,
+        period: Period,
+        before: u64,
+        cursor: Option<Cursor>,
+        batch: usize,
+    ) -> Result<(usize, Option<Cursor>)> {
+        let until = match period.start(before).checked_sub(1) {
+            Some(until) => until,
+            None => return Ok((0, None)),
+        };
+        let batch = batch.max(1);
+        let mut events = vec![];
+        let mut scanned = 0;
+        let mut next = None;
+        {
+            let reader = self.reader()?;
+            let filter = Filter {
+                until: Some(until),
+                cursor,
+                ..Default::default()
+            };
+            let mut iter = self.iter_plan::<Event, _>(&reader, &filter, Plan::Time)?;
+            iter.archive = None;
+            for event in iter.by_ref() {
+                let event = event?;
+                scanned += 1;
+                if event.index().expiration().is_none()
+                    && encode_replace_key(event.kind(), event.pubkey(), event.tags()).is_none()
+                {
+                    events.push(event);
+                }
+                // the kept events are skipped by the cursor next time
+                if scanned >= batch {
+                    next = iter.cursor();
+                    break;
+                }
+            }
+        }
+        // group by the partition, the events are in time order
+        let mut groups: Vec<(u64, Db, Vec<Event>)> = vec![];
+        for event in events {
+            let (start, db) = self
+                .partitions
+                .get_or_create(period, event.created_at(), |db| {
+                    db.set_data_key(self.data_key().map(|k| (*k).clone()));
+                    db.set_derived_tag_key(self.index_tags.key())?;
+                    db.set_index_tags(self.index_tags.names())
+                })?;
+            match groups.last_mut() {
+                Some((last, _, group)) if *last == start => group.push(event),
+                _ => groups.push((start, db, vec![event])),
+            }
+        }
+        let mut total = 0;
+        for (_, db, events) in groups {
+            db.batch_put(&events)?;
+            db.flush()?;
+            self.batch_move(events.iter().map(|e| e.id()))?;
+            total += events.len();
+        }
+        Ok((total, next))
+    }
+
+    /// Remove the partition and its environment
+    pub fn drop_partition(&self, name: &str) -> Result<Partition> {
+        self.partitions.drop_partition(name)
+    }
+
+    /// Move the environment of the partition to the directory, it can be opened by [`Db::open`]
+    pub fn archive_partition<P: AsRef<Path>>(&self, name: &str, dir: P) -> Result<Partition> {
+        self.partitions.archive_partition(name, dir)
+    }
+
+    /// Change the durability of the next commits
+    pub fn set_durability(&self, durability: Durability) -> Result<()> {
+        self.inner
+            .set_flags(ffi::MDB_NOSYNC | ffi::MDB_NOMETASYNC, false)?;
+        match durability {
+            Durability::Sync => {}
+            Durability::NoMetaSync => self.inner.set_flags(ffi::MDB_NOMETASYNC, true)?,
+            Durability::NoSync => self.inner.set_flags(ffi::MDB_NOSYNC, true)?,
+        }
+        Ok(())
+    }
+
+    /// The durability of the commits
+    pub fn durability(&self) -> Result<Durability> {
+        let flags = self.inner.flags()?;
+        Ok(if flags & ffi::MDB_NOSYNC != 0 {
+            Durability::NoSync
+        } else if flags & ffi::MDB_NOMETASYNC != 0 {
+            Durability::NoMetaSync
+        } else {
+            Durability::Sync
+        })
+    }
+
+    /// The statistics of the lmdb environment and the trees
+    pub fn env_stats(&self) -> Result<EnvStats> {
+        Ok(EnvStats {
+            env: self.inner.stat()?,
+            info: self.inner.info()?,
+            trees: self.inner.tree_stats()?,
+        })
+    }
+}
+
+impl Db<Memory> {
+    /// Open an empty database in memory, the events can not be archived without a directory
+    pub fn memory() -> Result<Self> {
+        Self::open_store(Memory::new(), Archive::disabled())
+    }
+}
+
+impl<S: Store> Db<S> {
     pub fn flush(&self) -> Result<()> {
         self.inner.flush()?;
         Ok(())
@@ -337,46 +956,255 @@ impl Db {
 
     /// check db version, return [`Error::VersionMismatch`] when db schema changed
     pub fn check_schema(&self) -> Result<()> {
-        let mut writer = self.inner.writer()?;
+        let mut writer = self.writer()?;
         let old = writer.get(&self.t_meta, "version")?;
         if let Some(old) = old {
-            if old != DB_VERSION.as_bytes() {
+            if old == DB_VERSION_3.as_bytes() {
+                self.migrate_replacement(&mut writer)?;
+                self.migrate_deletion(&mut writer)?;
+                writer.put(&self.t_meta, "version", DB_VERSION)?;
+            } else if old == DB_VERSION_4.as_bytes() {
+                self.migrate_deletion(&mut writer)?;
+                writer.put(&self.t_meta, "version", DB_VERSION)?;
+            } else if old != DB_VERSION.as_bytes() {
                 return Err(Error::VersionMismatch);
             }
         } else {
             writer.put(&self.t_meta, "version", DB_VERSION)?;
         }
-        writer.commit()?;
+        self.commit(writer)?;
+
+        // the statistics is missing when the database is created by the old version
+        let outdated = {
+            let reader = self.inner.reader()?;
+            reader.get(&self.t_meta, "stats")? != Some(STATS_VERSION.as_bytes())
+        };
+        if outdated {
+            self.rebuild_stats()?;
+        }
         Ok(())
     }
 
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let inner = Lmdb::open_with(path, Some(20), Some(100), Some(1_000_000_000_000), 0)?;
+    /// Recompute the replace keys of the addressable events with the `d` tag in any position,
+    /// keep the newest event of the same key and delete the others, return the number of deleted events.
+    fn migrate_replacement(&self, writer: &mut S::Writer<'_>) -> Result<usize> {
+        let mut keys = vec![];
+        for item in writer.iter(&self.t_replacement) {
+            let (k, _) = item?;
+            if k.len() >= 34 && (30_000..40_000).contains(&u16_from_bytes(&k[32..34])?) {
+                keys.push(k.to_vec());
+            }
+        }
+        for k in keys {
+            writer.del(&self.t_replacement, k, None)?;
+        }
 
-        let default_opts = 0;
-        // let integer_default_opts = ffi::MDB_INTEGERKEY;
-        let integer_default_opts = 0;
+        // the newest event of the key, compared by created_at and the lowest id
+        let mut latest: HashMap<Vec<u8>, (Vec<u8>, u64, [u8; 32])> = HashMap::new();
+        let mut expired = vec![];
+        for item in writer.iter(&self.t_index) {
+            let (uid, v) = item?;
+            let event = EventIndex::from_zeroes(v)?;
+            if !(30_000..40_000).contains(&event.kind()) {
+                continue;
+            }
+            let e: Event =
+                get_event_by_uid(writer, &self.t_meta, &self.t_data, &self.t_index, uid)?
+                    .ok_or_else(|| Error::Message("event data not found".to_owned()))?;
+            let k = match encode_replace_key(e.kind(), e.pubkey(), e.tags()) {
+                Some(k) if k.len() <= MAX_REPLACE_KEY_SIZE => k,
+                _ => continue,
+            };
+            let cur = (uid.to_vec(), e.created_at(), *e.id());
+            match latest.get_mut(&k) {
+                Some(old) => {
+                    if (cur.1, std::cmp::Reverse(cur.2)) > (old.1, std::cmp::Reverse(old.2)) {
+                        expired.push(std::mem::replace(old, cur).0);
+                    } else {
+                        expired.push(cur.0);
+                    }
+                }
+                None => {
+                    latest.insert(k, cur);
+                }
+            }
+        }
 
-        // let index_opts = ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED | ffi::MDB_INTEGERDUP;
-        let index_opts = ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED;
+        let count = expired.len();
+        for uid in expired {
+            let e: Option<Event> =
+                get_event_by_uid(writer, &self.t_meta, &self.t_data, &self.t_index, &uid)?;
+            if let Some(e) = e {
+                self.del_event(writer, &e, &uid)?;
+            }
+        }
+        for (k, (uid, _, _)) in latest {
+            writer.put(&self.t_replacement, k, uid)?;
+        }
+        Ok(count)
+    }
 
-        // let integer_index_opts =
-        // ffi::MDB_DUPSORT | ffi::MDB_INTEGERKEY | ffi::MDB_DUPFIXED | ffi::MDB_INTEGERDUP;
-        // lmdb interger needs check byte order. little-endian
-        let integer_index_opts = ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED;
+    /// Rewrite the tombstones of the `e` tags from the stored deletion events,
+    /// keyed by the deleted id and the deleter instead of the deletion id and the deleted id.
+    fn migrate_deletion(&self, writer: &mut S::Writer<'_>) -> Result<()> {
+        let address = deletion_address_key(&[]);
+        let mut keys = vec![];
+        for item in writer.iter(&self.t_deletion) {
+            let (k, _) = item?;
+            if k.len() == 64 && !k.starts_with(&address) {
+                keys.push(k.to_vec());
+            }
+        }
+        for k in keys {
+            writer.del(&self.t_deletion, k, None)?;
+        }
 
-        let t_data = inner.open_tree(Some("t_data"), integer_default_opts)?;
-        let t_meta = inner.open_tree(Some("t_meta"), default_opts)?;
+        let mut tombstones = vec![];
+        for item in writer.iter(&self.t_index) {
+            let (uid, v) = item?;
+            let event = EventIndex::from_zeroes(v)?;
+            if event.kind() != 5 {
+                continue;
+            }
+            for tag in event.tags().iter() {
+                if tag.0[0] == 101 {
+                    tombstones.push((concat(&tag.1, event.pubkey()), uid.to_vec()));
+                }
+            }
+        }
+        for (k, uid) in tombstones {
+            writer.put(&self.t_deletion, k, uid)?;
+        }
+        Ok(())
+    }
 
-        Ok(Self {
-            seq: Arc::new(AtomicU64::new(latest_seq(&inner, &t_data)?)),
-            t_data,
-            t_meta,
-            t_index: inner.open_tree(Some("t_index"), integer_default_opts)?,
-            t_id_uid: inner.open_tree(Some("t_id_uid"), default_opts)?,
-            t_uid_word: inner.open_tree(Some("t_uid_word"), default_opts)?,
-            t_deletion: inner.open_tree(Some("t_deletion"), default_opts)?,
+    /// Rebuild the index cardinality statistics by scanning the index trees
+    pub fn rebuild_stats(&self) -> Result<()> {
+        let reader = self.inner.reader()?;
+        let mut writer = self.writer()?;
+        writer.clear(&self.t_stat)?;
+        for (tree, stat) in [
+            (&self.t_created_at, STAT_TOTAL),
+            (&self.t_kind, STAT_KIND),
+            (&self.t_pubkey, STAT_PUBKEY),
+            (&self.t_pubkey_kind, STAT_PUBKEY_KIND),
+            (&self.t_tag, STAT_TAG),
+            (&self.t_word, STAT_WORD),
+        ] {
+            // the index keys are sorted, count the same prefix in sequence
+            let mut last: Option<Vec<u8>> = None;
+            let mut num = 0u64;
+            for item in reader.iter(tree) {
+                let (k, _) = item?;
+                let key = index_stat_key(stat, k);
+                if last.as_ref() != Some(&key) {
+                    if let Some(last) = last.take() {
+                        writer.put(&self.t_stat, last, num.to_be_bytes())?;
+                    }
+                    last = Some(key);
+                    num = 0;
+                }
+                num += 1;
+            }
+            if let Some(last) = last {
+                writer.put(&self.t_stat, last, num.to_be_bytes())?;
+            }
+        }
+
+        let mut total = Counter::default();
+        let mut compressed = Counter::default();
+        let mut raw = 0u64;
+        let mut kinds: HashMap<u16, Counter> = HashMap::new();
+        let mut authors: HashMap<[u8; 32], Counter> = HashMap::new();
+        for item in reader.iter(&self.t_index) {
+            let (uid, v) = item?;
+            let event = EventIndex::from_zeroes(v)?;
+            let data = reader.get(&self.t_data, uid)?.unwrap_or_default();
+            let bytes = data.len() as u64;
+            if !data.is_empty() {
+                load_dictionary(&reader, &self.t_meta, data)?;
+                raw += Event::from_data(data)?.record()?.len() as u64;
+            }
+            if data_dictionary(data).is_some() {
+                compressed.count += 1;
+                compressed.bytes += bytes;
+            }
+            for counter in [
+                &mut total,
+                kinds.entry(event.kind()).or_default(),
+                authors.entry(*event.pubkey()).or_default(),
+            ] {
+                counter.count += 1;
+                counter.bytes += bytes;
+            }
+        }
+        if total.count > 0 {
+            writer.put(
+                &self.t_stat,
+                encode_stat_key(STAT_EVENT_TOTAL, []),
+                encode_counter(&total),
+            )?;
+            writer.put(
+                &self.t_stat,
+                encode_stat_key(STAT_EVENT_RAW, []),
+                raw.to_be_bytes(),
+            )?;
+        }
+        if compressed.count > 0 {
+            writer.put(
+                &self.t_stat,
+                encode_stat_key(STAT_EVENT_DICT, []),
+                encode_counter(&compressed),
+            )?;
+        }
+        for (kind, counter) in kinds {
+            writer.put(
+                &self.t_stat,
+                encode_stat_key(STAT_EVENT_KIND, u16_to_ver(kind)),
+                encode_counter(&counter),
+            )?;
+        }
+        for (pubkey, counter) in authors {
+            writer.put(
+                &self.t_stat,
+                encode_stat_key(STAT_EVENT_AUTHOR, pubkey),
+                encode_counter(&counter),
+            )?;
+        }
+        writer.put(&self.t_meta, "stats", STATS_VERSION)?;
+        self.commit(writer)?;
+        Ok(())
+    }
+
+    /// Open the database in the store, the archive keeps the old events out of the store
+    pub fn open_store(inner: S, archive: Archive) -> Result<Self> {
+        let default_opts = 0;
+        // let integer_default_opts = ffi::MDB_INTEGERKEY;
+        let integer_default_opts = 0;
+
+        // let index_opts = ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED | ffi::MDB_INTEGERDUP;
+        let index_opts = ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED;
+
+        // let integer_index_opts =
+        // ffi::MDB_DUPSORT | ffi::MDB_INTEGERKEY | ffi::MDB_DUPFIXED | ffi::MDB_INTEGERDUP;
+        // lmdb interger needs check byte order. little-endian
+        let integer_index_opts = ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED;
+
+        let t_data = inner.open_tree(Some("t_data"), integer_default_opts)?;
+        let t_meta = inner.open_tree(Some("t_meta"), default_opts)?;
+        let t_change = inner.open_tree(Some("t_change"), integer_default_opts)?;
+
+        let db = Self {
+            seq: Arc::new(AtomicU64::new(next_seq(&inner, &[&t_data, &t_change])?)),
+            t_data,
+            t_meta,
+            t_change,
+            t_index: inner.open_tree(Some("t_index"), integer_default_opts)?,
+            t_id_uid: inner.open_tree(Some("t_id_uid"), default_opts)?,
+            t_uid_word: inner.open_tree(Some("t_uid_word"), default_opts)?,
+            t_deletion: inner.open_tree(Some("t_deletion"), default_opts)?,
             t_replacement: inner.open_tree(Some("t_replacement"), default_opts)?,
+            t_history: inner.open_tree(Some("t_history"), default_opts)?,
             t_id: inner.open_tree(Some("t_id"), default_opts)?,
             t_pubkey: inner.open_tree(Some("t_pubkey"), index_opts)?,
             t_kind: inner.open_tree(Some("t_kind"), index_opts)?,
@@ -385,24 +1213,393 @@ impl Db {
             t_tag: inner.open_tree(Some("t_tag"), ffi::MDB_DUPSORT | ffi::MDB_DUPFIXED)?,
             t_expiration: inner.open_tree(Some("t_expiration"), integer_index_opts)?,
             t_word: inner.open_tree(Some("t_word"), index_opts)?,
+            t_stat: inner.open_tree(Some("t_stat"), default_opts)?,
+            archive,
+            partitions: Partitions::disabled(),
+            dict: Arc::new(RwLock::new(None)),
+            history: Arc::new(RwLock::new(None)),
+            data_key: Arc::new(RwLock::new(None)),
+            parallel: Arc::new(RwLock::new(None)),
+            index_tags: IndexTags::default(),
+            stat_deltas: Arc::new(Mutex::new(HashMap::new())),
+            partition_dels: Arc::new(Mutex::new(Vec::new())),
 
             inner,
-        })
+        };
+        db.load_dictionaries()?;
+        db.load_index_tags()?;
+        Ok(db)
+    }
+
+    // register all the dictionaries for decoding the data, and use the current one for encoding
+    fn load_dictionaries(&self) -> Result<()> {
+        let reader = self.reader()?;
+        let prefix = concat_sep(META_DICT, []);
+        for item in reader.iter_from(&self.t_meta, Bound::Included(&prefix), false) {
+            let (k, v) = item?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            dict::register(v);
+        }
+        if let Some(id) = reader.get(&self.t_meta, META_DICT)? {
+            let id = u32::from_be_bytes(id.try_into()?);
+            let bytes = reader
+                .get(&self.t_meta, dict_meta_key(id))?
+                .ok_or_else(|| Error::Invalid(format!("zstd dictionary {} not found", id)))?;
+            *self.dict.write() = Some(dict::register(bytes));
+        }
+        Ok(())
+    }
+
+    // encode with the dictionary trained by another process after the database is opened
+    fn refresh_dictionary<T: Transaction>(&self, txn: &T) -> Result<()> {
+        let id = match txn.get(&self.t_meta, META_DICT)? {
+            Some(id) => u32::from_be_bytes(id.try_into()?),
+            None => return Ok(()),
+        };
+        if self.dict.read().as_ref().map(|d| d.id) != Some(id) {
+            let bytes = txn
+                .get(&self.t_meta, dict_meta_key(id))?
+                .ok_or_else(|| Error::Invalid(format!("zstd dictionary {} not found", id)))?;
+            *self.dict.write() = Some(dict::register(bytes));
+        }
+        Ok(())
+    }
+
+    /// The trained dictionary for compressing the new events
+    pub fn dictionary(&self) -> Option<Arc<Dictionary>> {
+        self.dict.read().clone()
+    }
+
+    /// Train a zstd dictionary from the latest `samples` events, the new events are compressed with it.
+    /// Return the dictionary id.
+    pub fn train_dictionary(&self, samples: usize, max_size: usize) -> Result<u32> {
+        let bytes = {
+            let reader = self.reader()?;
+            let mut records = vec![];
+            for item in reader.iter_from(&self.t_data, Bound::Unbounded::<Vec<u8>>, true) {
+                if records.len() >= samples {
+                    break;
+                }
+                let (_, v) = item?;
+                load_dictionary(&reader, &self.t_meta, v)?;
+                let event = Event::from_data(v)?;
+                records.push(match event.binary_record()? {
+                    Some(bytes) => bytes,
+                    None => event.record()?.into_owned(),
+                });
+            }
+            dict::train(&records, max_size)?
+        };
+        let dict = dict::register(&bytes);
+        let mut writer = self.writer()?;
+        writer.put(&self.t_meta, dict_meta_key(dict.id), &bytes)?;
+        writer.put(&self.t_meta, META_DICT, dict.id.to_be_bytes())?;
+        self.commit(writer)?;
+        let id = dict.id;
+        *self.dict.write() = Some(dict);
+        Ok(id)
+    }
+
+    /// Rewrite the stored events which are not compressed with the current dictionary,
+    /// commit every `batch` events. Return the number of rewritten events.
+    pub fn compress(&self, batch: usize) -> Result<usize> {
+        let dict = self
+            .dictionary()
+            .ok_or_else(|| Error::Message("no zstd dictionary, train it first".to_owned()))?;
+        self.rewrite_data(&self.t_data, batch, |v| data_dictionary(v) != Some(dict.id))
+    }
+
+    /// Set the key for encrypting the new events, none to store them unencrypted.
+    /// The key is registered for decrypting, register the old keys by [`crate::register_key`].
+    pub fn set_data_key(&self, key: Option<DataKey>) {
+        for db in self.partitions.dbs() {
+            db.set_data_key(key.clone());
+        }
+        *self.data_key.write() = key.map(register_key);
+    }
+
+    /// Set the additional indexed tag names, the single-letter tags are always indexed.
+    /// The names are stored and loaded when the database is opened.
+    /// The events stored before are not indexed by the new names.
+    pub fn set_index_tags<I: IntoIterator<Item = N>, N: Into<String>>(
+        &self,
+        names: I,
+    ) -> Result<()> {
+        let names = names.into_iter().map(Into::into).collect::<Vec<String>>();
+        for db in self.partitions.dbs() {
+            db.set_index_tags(names.clone())?;
+        }
+        self.index_tags.set(names)?;
+        let value = self.index_tags.names().join("\0");
+        let mut writer = self.writer()?;
+        if writer.get(&self.t_meta, META_INDEX_TAGS)? != Some(value.as_bytes()) {
+            writer.put(&self.t_meta, META_INDEX_TAGS, value)?;
+        }
+        self.commit(writer)
+    }
+
+    // the indexed tag names stored by the last `set_index_tags`
+    fn load_index_tags(&self) -> Result<()> {
+        let reader = self.reader()?;
+        if let Some(value) = reader.get(&self.t_meta, META_INDEX_TAGS)? {
+            let value = std::str::from_utf8(value)
+                .map_err(|_| Error::Invalid("invalid index tag names".to_owned()))?;
+            self.index_tags
+                .set(value.split('\0').filter(|name| !name.is_empty()))?;
+        }
+        Ok(())
+    }
+
+    /// The additional indexed tag names and the tag key
+    pub fn index_tags(&self) -> &IndexTags {
+        &self.index_tags
+    }
+
+    /// Index the tag values by the hash keyed with the key, none to index the plain values.
+    /// The `e` tag values are kept for the deletion. The tag index is built with one key,
+    /// a different key is refused if any event is stored, rebuild the index by [`Db::reindex_tags`].
+    pub fn set_tag_key(&self, key: Option<&DataKey>) -> Result<()> {
+        self.set_derived_tag_key(key.map(|k| k.derive(TAG_KEY_USAGE)))
+    }
+
+    fn set_derived_tag_key(&self, key: Option<[u8; 32]>) -> Result<()> {
+        for db in self.partitions.dbs() {
+            db.set_derived_tag_key(key)?;
+        }
+        let fingerprint = tag_key_fingerprint(key.as_ref());
+        let mut writer = self.writer()?;
+        if writer.get(&self.t_meta, META_TAG_KEY)? != fingerprint.as_ref().map(|f| &f[..]) {
+            if writer.iter(&self.t_index).next().is_some() {
+                return Err(Error::Message(
+                    "the tag index is built with another tag key, rebuild it by `rnostr rekey`"
+                        .to_owned(),
+                ));
+            }
+            match fingerprint {
+                Some(f) => writer.put(&self.t_meta, META_TAG_KEY, f)?,
+                None => writer.del(&self.t_meta, META_TAG_KEY, None)?,
+            }
+        }
+        self.commit(writer)?;
+        self.index_tags.set_key(key);
+        Ok(())
     }
 
-    pub fn writer(&self) -> Result<Writer> {
-        Ok(self.inner.writer()?)
+    /// Rebuild the tag index of the stored events with the tag key, none to index the plain values.
+    /// Commit every `batch` events, return the number of reindexed events.
+    /// The key is stored after all the events are reindexed, run it again if interrupted.
+    pub fn reindex_tags(&self, key: Option<&DataKey>, batch: usize) -> Result<usize> {
+        self.reindex_derived_tags(key.map(|k| k.derive(TAG_KEY_USAGE)), batch)
     }
 
-    pub fn reader(&self) -> Result<Reader> {
+    fn reindex_derived_tags(&self, key: Option<[u8; 32]>, batch: usize) -> Result<usize> {
+        let mut total = 0;
+        for db in self.partitions.dbs() {
+            total += db.reindex_derived_tags(key, batch)?;
+            db.flush()?;
+        }
+        self.index_tags.set_key(key);
+        let batch = batch.max(1);
+        let mut last: Option<Vec<u8>> = None;
+        loop {
+            let mut writer = self.writer()?;
+            let mut items = vec![];
+            {
+                let from = match &last {
+                    Some(k) => Bound::Excluded(k),
+                    None => Bound::Unbounded,
+                };
+                for item in writer.iter_from(&self.t_index, from, false) {
+                    let (k, v) = item?;
+                    items.push((k.to_vec(), EventIndex::from_bytes(v)?));
+                    if items.len() >= batch {
+                        break;
+                    }
+                }
+            }
+            for (uid, old) in items.iter() {
+                let event: Event =
+                    get_event_by_uid(&writer, &self.t_meta, &self.t_data, &self.t_index, uid)?
+                        .ok_or_else(|| Error::Message("event data not found".to_owned()))?;
+                let event = self.indexed_event(&event)?;
+                let index = event.index();
+                if index.tags() == old.tags() {
+                    continue;
+                }
+                let time = old.created_at();
+                let tagval = concat(uid, old.kind().to_be_bytes());
+                for key in tag_keys(old.tags().iter().map(|t| (&t.0, &t.1)), time) {
+                    self.del_index(&mut writer, &self.t_tag, STAT_TAG, key, &tagval)?;
+                }
+                for key in tag_keys(index.tags().iter().map(|t| (&t.0, &t.1)), time) {
+                    self.put_index(&mut writer, &self.t_tag, STAT_TAG, key, &tagval)?;
+                }
+                writer.put(&self.t_index, uid, index.to_bytes()?)?;
+                total += 1;
+            }
+            let done = items.len() < batch;
+            if done {
+                match tag_key_fingerprint(key.as_ref()) {
+                    Some(f) => writer.put(&self.t_meta, META_TAG_KEY, f)?,
+                    None => writer.del(&self.t_meta, META_TAG_KEY, None)?,
+                }
+            }
+            last = items.pop().map(|(uid, _)| uid);
+            self.commit(writer)?;
+            if done {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    // the event indexed by the additional tag names and the tag key
+    fn indexed_event<'a>(&self, event: &'a Event) -> Result<Cow<'a, Event>> {
+        if self.index_tags.is_empty() && !self.index_tags.is_keyed() {
+            return Ok(Cow::Borrowed(event));
+        }
+        let mut event = event.clone();
+        event.reindex(&self.index_tags)?;
+        Ok(Cow::Owned(event))
+    }
+
+    // the filter with the tags of the additional indexed names and hashed by the tag key
+    fn indexed_filter<'a>(&self, filter: &'a Filter) -> Cow<'a, Filter> {
+        if filter.named_tags.is_empty() && (filter.tags.is_empty() || !self.index_tags.is_keyed()) {
+            return Cow::Borrowed(filter);
+        }
+        let mut filter = filter.clone();
+        filter.index_tags(&self.index_tags);
+        Cow::Owned(filter)
+    }
+
+    /// The key for encrypting the new events
+    pub fn data_key(&self) -> Option<Arc<DataKey>> {
+        self.data_key.read().clone()
+    }
+
+    /// Rewrite the stored events, the superseded versions, the events of the partitions
+    /// and the archive segments which are not encrypted with the current key,
+    /// decrypt them if no key is set. The old keys must be registered.
+    /// Commit every `batch` events, return the number of rewritten records.
+    pub fn rekey(&self, batch: usize) -> Result<usize> {
+        let mut total = 0;
+        for db in self.partitions.dbs() {
+            total += db.rekey(batch)?;
+            db.flush()?;
+        }
+        let id = self.data_key().map(|k| k.id);
+        total += self.rewrite_data(&self.t_data, batch, |v| data_key_id(v) != id)?;
+        total += self.rewrite_data(&self.t_history, batch, |v| data_key_id(v) != id)?;
+        total += self.archive.rekey(self.data_key().as_deref())?;
+        Ok(total)
+    }
+
+    // encode with the current dictionary and key
+    fn encode_data(&self, event: &Event) -> Result<(Vec<u8>, usize)> {
+        event.encode_data(self.dict.read().as_deref(), self.data_key.read().as_deref())
+    }
+
+    // re-encode the selected event data of the tree in batches, keep the statistics of the stored events
+    fn rewrite_data<F: Fn(&[u8]) -> bool>(
+        &self,
+        tree: &Tree,
+        batch: usize,
+        select: F,
+    ) -> Result<usize> {
+        let stats = std::ptr::eq(tree, &self.t_data);
+        let batch = batch.max(1);
+        let mut last: Option<Vec<u8>> = None;
+        let mut total = 0;
+        loop {
+            let mut writer = self.writer()?;
+            self.refresh_dictionary(&writer)?;
+            let mut items = vec![];
+            let mut scanned = 0;
+            {
+                let from = match &last {
+                    Some(k) => Bound::Excluded(k),
+                    None => Bound::Unbounded,
+                };
+                for item in writer.iter_from(tree, from, false) {
+                    let (k, v) = item?;
+                    last = Some(k.to_vec());
+                    scanned += 1;
+                    if select(v) {
+                        items.push((k.to_vec(), v.to_vec()));
+                    }
+                    if scanned >= batch {
+                        break;
+                    }
+                }
+            }
+            for (uid, old) in items.iter() {
+                load_dictionary(&writer, &self.t_meta, old)?;
+                let event = Event::from_data(old)?;
+                let (data, raw_size) = self.encode_data(&event)?;
+                if stats {
+                    let raw_size = raw_size as u64;
+                    let (kind, pubkey) = (event.kind(), event.pubkey());
+                    let old_size = DataSize::new(raw_size, old);
+                    self.incr_event_stats(&mut writer, kind, pubkey, &old_size, false)?;
+                    self.incr_event_stats(
+                        &mut writer,
+                        kind,
+                        pubkey,
+                        &DataSize::new(raw_size, &data),
+                        true,
+                    )?;
+                }
+                writer.put(tree, uid, data)?;
+            }
+            self.commit(writer)?;
+            total += items.len();
+            if scanned < batch {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Begin the write transaction, commit it by [`Db::commit`] to write the index statistics
+    pub fn writer(&self) -> Result<S::Writer<'_>> {
+        let writer = self.inner.writer()?;
+        // the changes of an aborted transaction
+        self.stat_deltas.lock().clear();
+        self.partition_dels.lock().clear();
+        Ok(writer)
+    }
+
+    pub fn reader(&self) -> Result<S::Reader<'_>> {
         Ok(self.inner.reader()?)
     }
 
-    pub fn commit<T: Transaction>(&self, txn: T) -> Result<()> {
-        Ok(txn.commit()?)
+    /// The read transaction which does not borrow the database
+    pub fn owned_reader(&self) -> Result<S::OwnedReader> {
+        Ok(self.inner.owned_reader()?)
+    }
+
+    /// Commit the write transaction, then delete the moved events deleted by it from their partitions.
+    /// The partitions are other environments, the events deleted by a deletion event stay hidden
+    /// by its tombstone if their deletion fails, the others can be deleted again.
+    pub fn commit(&self, mut writer: S::Writer<'_>) -> Result<()> {
+        self.write_stats(&mut writer)?;
+        writer.commit()?;
+        let ids = std::mem::take(&mut *self.partition_dels.lock());
+        if !ids.is_empty() {
+            self.partitions.del(&ids)?;
+        }
+        Ok(())
     }
 
-    pub fn put<E: AsRef<Event>>(&self, writer: &mut Writer, event: E) -> Result<CheckEventResult> {
+    pub fn put<E: AsRef<Event>>(
+        &self,
+        writer: &mut S::Writer<'_>,
+        event: E,
+    ) -> Result<CheckEventResult> {
+        let event = self.indexed_event(event.as_ref())?;
         let event = event.as_ref();
         let mut count = 0;
 
@@ -413,6 +1610,7 @@ impl Db {
         }
         // let id: Vec<u8> = pad_start(event.id(), 32);
         let event_id = event.id();
+        self.refresh_dictionary(writer)?;
         let pubkey = event.pubkey();
 
         // Check duplicate event.
@@ -421,76 +1619,39 @@ impl Db {
             if get_uid(writer, &self.t_id_uid, event_id)?.is_some() {
                 return Ok(CheckEventResult::Duplicate);
             }
+            // dup in the partition of the time or the archive
+            if self.partitions.contains(event)?
+                || self.archive.get(event.created_at(), event_id)?.is_some()
+            {
+                return Ok(CheckEventResult::Duplicate);
+            }
         }
 
         // check deleted in db
-        if writer
-            .get(&self.t_deletion, concat(event_id, pubkey))?
-            .is_some()
-        {
+        if self.is_deleted(writer, event_id, pubkey)? || self.is_deleted_address(writer, event)? {
             return Ok(CheckEventResult::Deleted);
         }
 
         // [NIP-09](https://nips.be/9)
         // delete event
-        if event.kind() == 5 {
-            for tag in event.index().tags() {
-                if tag.0 == b"e" {
-                    // let key = hex::decode(&tag.1).map_err(|e| Error::Hex(e))?;
-                    let key = &tag.1;
-                    let r = get_event::<Event, _, _>(
-                        writer,
-                        &self.t_id_uid,
-                        &self.t_data,
-                        &self.t_index,
-                        key,
-                    )?;
-                    if let Some((uid, e)) = r {
-                        // check author or deletion event
-                        // check delegator
-                        if (e.pubkey() == event.pubkey()
-                            || e.index().delegator() == Some(event.pubkey()))
-                            && e.kind() != 5
-                        {
-                            count += 1;
-                            self.del_event(writer, &e, &uid)?;
-                        }
-                    }
-                }
-            }
-        }
+        count += self.del_referenced(writer, event)?;
 
         // check replacement event
         let replace_key = encode_replace_key(event.kind(), event.pubkey(), event.tags());
 
         if let Some(replace_key) = replace_key.as_ref() {
-            // lmdb max_key_size 511 bytes
-            // we only index tag value length < 255
-            if replace_key.len() > MAX_TAG_VALUE_SIZE + 8 + 32 {
+            if replace_key.len() > MAX_REPLACE_KEY_SIZE {
                 return Ok(CheckEventResult::Invald("invalid replace key".to_owned()));
             }
 
             // replace in the db
-            let v = writer.get(&self.t_replacement, replace_key)?;
-            if let Some(v) = v {
-                let uid = v.to_vec();
-                // let t = &v[0..8];
-                // let t = u64_from_bytes(t);
-                // if event.created_at() < t {
-                //     continue;
-                // }
-                let e: Option<Event> = get_event_by_uid(writer, &self.t_data, &self.t_index, &uid)?;
-                if let Some(e) = e {
-                    // If two events have the same timestamp, the event with the lowest id (first in lexical order) SHOULD be retained, and the other discarded.
-                    if event.created_at() < e.created_at()
-                        || (event.created_at() == e.created_at() && event.id() > e.id())
-                    {
-                        return Ok(CheckEventResult::ReplaceIgnored);
-                    }
-                    // del old
-                    count += 1;
-                    self.del_event(writer, &e, &uid)?;
+            if let Some((uid, e)) = self.get_replaced_by_key(writer, replace_key)? {
+                if !replaceable_by(&e, event) {
+                    return Ok(CheckEventResult::ReplaceIgnored);
                 }
+                // del old
+                count += 1;
+                self.supersede(writer, &e, &uid)?;
             }
         }
 
@@ -502,84 +1663,796 @@ impl Db {
         Ok(CheckEventResult::Ok(count))
     }
 
+    /// Check whether the event was deleted by a deletion event, [NIP-09](https://nips.be/9)
+    pub fn is_deleted<T: Transaction>(
+        &self,
+        txn: &T,
+        event_id: &[u8],
+        pubkey: &[u8],
+    ) -> Result<bool> {
+        Ok(txn
+            .get(&self.t_deletion, concat(event_id, pubkey))?
+            .is_some())
+    }
+
+    /// Check whether the replaceable event was deleted by the `a` tag of a deletion event
+    /// created at or after it, [NIP-09](https://nips.be/9)
+    pub fn is_deleted_address<T: Transaction>(&self, txn: &T, event: &Event) -> Result<bool> {
+        if let Some(replace_key) = encode_replace_key(event.kind(), event.pubkey(), event.tags()) {
+            if let Some(v) = txn.get(&self.t_deletion, deletion_address_key(&replace_key))? {
+                return Ok(u64_from_bytes(v)? >= event.created_at());
+            }
+        }
+        Ok(false)
+    }
+
+    /// Delete the events referenced by the `e` and `a` tags of a deletion event,
+    /// return the number of deleted events. [NIP-09](https://nips.be/9)
+    /// The moved events are deleted from their partitions by [`Db::commit`].
+    pub fn del_referenced(&self, writer: &mut S::Writer<'_>, event: &Event) -> Result<usize> {
+        let mut count = 0;
+        if event.kind() != 5 {
+            return Ok(count);
+        }
+        for tag in event.index().tags() {
+            if tag.0 == b"e" {
+                let key = &tag.1;
+                let r = get_event::<Event, _, _>(
+                    writer,
+                    &self.t_meta,
+                    &self.t_id_uid,
+                    &self.t_data,
+                    &self.t_index,
+                    key,
+                )?;
+                // check author or deletion event
+                // check delegator
+                let deletable = |e: &Event| {
+                    (e.pubkey() == event.pubkey() || e.index().delegator() == Some(event.pubkey()))
+                        && e.kind() != 5
+                };
+                if let Some((uid, e)) = r {
+                    if deletable(&e) {
+                        count += 1;
+                        self.del_event(writer, &e, &uid)?;
+                    }
+                } else if let Some(e) = self.partitions.get::<Event, _>(key)? {
+                    // the moved events are deleted at the commit, skipped by the tombstone before it
+                    if deletable(&e) {
+                        count += 1;
+                        self.partition_dels.lock().push(key.clone());
+                    }
+                }
+            }
+        }
+
+        // the versions of the coordinate up to the deletion time, only the author can delete
+        for coordinate in event.coordinates() {
+            let replace_key = match coordinate.replace_key() {
+                Some(k)
+                    if &coordinate.pubkey == event.pubkey() && k.len() <= MAX_REPLACE_KEY_SIZE =>
+                {
+                    k
+                }
+                _ => continue,
+            };
+            if let Some((uid, e)) = self.get_replaced_by_key(writer, &replace_key)? {
+                if e.created_at() <= event.created_at() {
+                    count += 1;
+                    self.del_event(writer, &e, &uid)?;
+                }
+            }
+            count += self.del_history(writer, &replace_key, Some(event.created_at()))?;
+            // reject the older re-publications, keep the latest deletion time
+            let key = deletion_address_key(&replace_key);
+            let newer = match writer.get(&self.t_deletion, &key)? {
+                Some(time) => u64_from_bytes(time)? < event.created_at(),
+                None => true,
+            };
+            if newer {
+                writer.put(&self.t_deletion, &key, event.created_at().to_be_bytes())?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Get the stored replaceable event which has the same replace key as the event
+    pub fn get_replaced<T: Transaction>(&self, txn: &T, event: &Event) -> Result<Option<Event>> {
+        if let Some(replace_key) = encode_replace_key(event.kind(), event.pubkey(), event.tags()) {
+            Ok(self.get_replaced_by_key(txn, &replace_key)?.map(|(_, e)| e))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn get_replaced_by_key<T: Transaction>(
+        &self,
+        txn: &T,
+        replace_key: &[u8],
+    ) -> Result<Option<(Vec<u8>, Event)>> {
+        if let Some(v) = txn.get(&self.t_replacement, replace_key)? {
+            let uid = v.to_vec();
+            let e: Option<Event> =
+                get_event_by_uid(txn, &self.t_meta, &self.t_data, &self.t_index, &uid)?;
+            Ok(e.map(|e| (uid, e)))
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn get<R: FromEventData, K: AsRef<[u8]>, T: Transaction>(
         &self,
         txn: &T,
         event_id: K,
     ) -> Result<Option<R>> {
-        let event = get_event(txn, &self.t_id_uid, &self.t_data, &self.t_index, event_id)?;
-        Ok(event.map(|e| e.1))
+        let event = get_event(
+            txn,
+            &self.t_meta,
+            &self.t_id_uid,
+            &self.t_data,
+            &self.t_index,
+            &event_id,
+        )?;
+        match event {
+            Some(e) => Ok(Some(e.1)),
+            None => self.partitions.get(event_id),
+        }
     }
 
-    pub fn del<K: AsRef<[u8]>>(&self, writer: &mut Writer, event_id: K) -> Result<bool> {
+    pub fn del<K: AsRef<[u8]>>(&self, writer: &mut S::Writer<'_>, event_id: K) -> Result<bool> {
         if let Some((uid, event)) = get_event::<Event, _, _>(
             writer,
+            &self.t_meta,
             &self.t_id_uid,
             &self.t_data,
             &self.t_index,
-            event_id,
+            &event_id,
         )? {
             self.del_event(writer, &event, &uid)?;
             Ok(true)
+        } else if self
+            .partitions
+            .get::<Vec<u8>, _>(event_id.as_ref())?
+            .is_some()
+        {
+            // the moved event is deleted from its partition at the commit
+            self.partition_dels.lock().push(event_id.as_ref().to_vec());
+            Ok(true)
         } else {
             Ok(false)
         }
     }
 
+    fn supersede(&self, writer: &mut S::Writer<'_>, event: &Event, uid: &[u8]) -> Result<()> {
+        let options = self.history.read().clone();
+        let replace_key = encode_replace_key(event.kind(), event.pubkey(), event.tags());
+        if let (Some(options), Some(replace_key)) = (options, replace_key) {
+            if options.keep(event.kind()) {
+                let now = now();
+                if !options.expired(event.kind(), event.created_at(), now) {
+                    if let Some(data) = writer.get(&self.t_data, uid)? {
+                        let data = data.to_vec();
+                        let key = encode_history_key(&replace_key, event.created_at(), event.id());
+                        writer.put(&self.t_history, key, data)?;
+                    }
+                }
+                self.prune_versions(writer, &replace_key, &options, now)?;
+            }
+        }
+        self.del_event(writer, event, uid)
+    }
+
+    // the history keys of the replace key, the longer replace key has the same prefix
+    fn history_keys<T: Transaction>(&self, txn: &T, replace_key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let mut keys = vec![];
+        for item in txn.iter_from(&self.t_history, Bound::Included(replace_key), false) {
+            let (k, _) = item?;
+            if !k.starts_with(replace_key) {
+                break;
+            }
+            if decode_history_key(k).is_some_and(|(r, _)| r == replace_key) {
+                keys.push(k.to_vec());
+            }
+        }
+        Ok(keys)
+    }
+
+    fn prune_versions(
+        &self,
+        writer: &mut S::Writer<'_>,
+        replace_key: &[u8],
+        options: &HistoryOptions,
+        now: u64,
+    ) -> Result<usize> {
+        let kind = u16_from_bytes(&replace_key[32..34])?;
+        let mut count = 0;
+        for key in self.history_keys(writer, replace_key)? {
+            if decode_history_key(&key).is_some_and(|(_, t)| options.expired(kind, t, now)) {
+                writer.del(&self.t_history, key, None)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // delete the versions created before or at the time, all versions if none
+    fn del_history(
+        &self,
+        writer: &mut S::Writer<'_>,
+        replace_key: &[u8],
+        until: Option<u64>,
+    ) -> Result<usize> {
+        let mut count = 0;
+        for key in self.history_keys(writer, replace_key)? {
+            if decode_history_key(&key).is_some_and(|(_, t)| t <= until.unwrap_or(u64::MAX)) {
+                writer.del(&self.t_history, key, None)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// The stored and deleted events since the sequence number (inclusive) in order,
+    /// the sequence number of the stored event is the uid.
+    pub fn changes_since<'txn, J: FromEventData, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        seq: u64,
+    ) -> ChangeIter<'txn, T, J> {
+        ChangeIter::new(
+            txn,
+            &self.t_meta,
+            &self.t_data,
+            &self.t_index,
+            &self.t_change,
+            seq,
+        )
+    }
+
+    /// Delete the change log of the deletions before the sequence number, return the number of deleted logs
+    pub fn truncate_changes(&self, before: u64) -> Result<usize> {
+        let mut writer = self.writer()?;
+        let mut keys = vec![];
+        for item in writer.iter(&self.t_change) {
+            let (k, _) = item?;
+            if u64_from_bytes(k)? >= before {
+                break;
+            }
+            keys.push(k.to_vec());
+        }
+        let count = keys.len();
+        for k in keys {
+            writer.del(&self.t_change, k, None)?;
+        }
+        self.commit(writer)?;
+        Ok(count)
+    }
+
+    /// Apply a change of the primary change log to the follower database, remember the position.
+    /// Return the result of the stored event, none for the deletion.
+    pub fn apply_change(
+        &self,
+        writer: &mut S::Writer<'_>,
+        change: &Change<Event>,
+    ) -> Result<Option<CheckEventResult>> {
+        let result = match change {
+            Change::Put { event, .. } => Some(self.put(writer, event)?),
+            // the deletions of the replaced and referenced events are done by the put
+            Change::Delete { id, .. } => {
+                self.del(writer, id)?;
+                None
+            }
+            // keep the local copy, moved by the own archive or partition settings
+            Change::Move { .. } => None,
+        };
+        writer.put(&self.t_meta, META_REPLICA, (change.seq() + 1).to_be_bytes())?;
+        Ok(result)
+    }
+
+    /// The next sequence number of the primary change log to apply by [`Db::apply_change`]
+    pub fn replica_since<T: Transaction>(&self, txn: &T) -> Result<u64> {
+        match txn.get(&self.t_meta, META_REPLICA)? {
+            Some(v) => u64_from_bytes(v),
+            None => Ok(0),
+        }
+    }
+
+    /// Scan the large OR filters in parallel threads, none to scan in the query thread.
+    /// The threads read the latest committed snapshot instead of the transaction of the query.
+    pub fn set_parallel_scan(&self, options: Option<ParallelScan>) -> Result<(), Error> {
+        let parallel = match options {
+            Some(options) => {
+                let pool = PrefetchPool::new(options.threads * options.max_queries)?;
+                Some((Arc::new(options), pool))
+            }
+            None => None,
+        };
+        *self.parallel.write() = parallel;
+        Ok(())
+    }
+
+    pub fn parallel_scan(&self) -> Option<Arc<ParallelScan>> {
+        self.parallel
+            .read()
+            .as_ref()
+            .map(|(options, _)| options.clone())
+    }
+
+    /// Keep the superseded versions of the replaceable events, none to delete them when replaced
+    pub fn set_history(&self, options: Option<HistoryOptions>) {
+        *self.history.write() = options.map(Arc::new);
+    }
+
+    /// The options of keeping the superseded versions
+    pub fn history_options(&self) -> Option<Arc<HistoryOptions>> {
+        self.history.read().clone()
+    }
+
+    /// The superseded versions of the replaceable event, newest first.
+    /// The replace key can be built by [`crate::Coordinate::replace_key`].
+    pub fn history<J: FromEventData, T: Transaction>(
+        &self,
+        txn: &T,
+        replace_key: &[u8],
+    ) -> Result<Vec<J>> {
+        let mut versions = vec![];
+        for key in self.history_keys(txn, replace_key)?.into_iter().rev() {
+            if let Some(v) = txn.get(&self.t_history, key)? {
+                load_dictionary(txn, &self.t_meta, v)?;
+                versions.push(J::from_data(v).map_err(|e| Error::Message(e.to_string()))?);
+            }
+        }
+        Ok(versions)
+    }
+
+    /// Delete the expired versions by the retention of the history options, return the number of deleted versions
+    pub fn prune_history(&self, now: u64) -> Result<usize> {
+        let options = match self.history_options() {
+            Some(options) => options,
+            None => return Ok(0),
+        };
+        let mut writer = self.writer()?;
+        let mut keys = vec![];
+        for item in writer.iter(&self.t_history) {
+            let (k, _) = item?;
+            if let Some((replace_key, time)) = decode_history_key(k) {
+                if options.expired(u16_from_bytes(&replace_key[32..34])?, time, now) {
+                    keys.push(k.to_vec());
+                }
+            }
+        }
+        let count = keys.len();
+        for k in keys {
+            writer.del(&self.t_history, k, None)?;
+        }
+        self.commit(writer)?;
+        Ok(count)
+    }
+
+    // the superseded versions matching the filter, seek by the full authors and kinds
+    fn history_records<T: Transaction>(&self, txn: &T, filter: &Filter) -> Result<Vec<Record>> {
+        if !filter.is_history_bounded() {
+            return Err(Error::Invalid(
+                "the history query requires the full authors and kinds".to_owned(),
+            ));
+        }
+        let mut records = vec![];
+        for author in filter.authors.iter() {
+            for kind in filter.kinds.iter() {
+                let prefix = concat(author, kind.to_be_bytes());
+                for item in txn.iter_from(&self.t_history, Bound::Included(&prefix), false) {
+                    let (k, v) = item?;
+                    if !k.starts_with(&prefix) {
+                        break;
+                    }
+                    if let Some((_, time)) = decode_history_key(k) {
+                        if filter.since.is_some_and(|t| time < t)
+                            || filter.until.is_some_and(|t| time > t)
+                        {
+                            continue;
+                        }
+                    }
+                    load_dictionary(txn, &self.t_meta, v)?;
+                    let event = Event::from_data(v)?;
+                    let event = self.indexed_event(&event)?;
+                    if filter.r#match(event.index()) && filter.verify_long_tags(event.tags()) {
+                        records.push(Record::new(&event, v.to_vec()));
+                    }
+                }
+            }
+        }
+        // only the latest versions can be returned with the limit
+        if let Some(limit) = filter.limit {
+            records.sort_by(|a, b| b.cmp(a));
+            records.truncate(limit as usize);
+        }
+        Ok(records)
+    }
+
     pub fn batch_put<II, N>(&self, events: II) -> Result<usize>
     where
         II: IntoIterator<Item = N>,
         N: AsRef<Event>,
     {
-        let mut writer = self.inner.writer()?;
+        let mut writer = self.writer()?;
         let mut events = events.into_iter().collect::<Vec<N>>();
 
         // sort for check dup
         events.sort_by(|a, b| a.as_ref().id().cmp(b.as_ref().id()));
         let mut count = 0;
 
-        for (i, event) in events.iter().enumerate() {
-            let event = event.as_ref();
-            // dup in the input events
-            if i != 0 && event.id() == events[i - 1].as_ref().id() {
-                continue;
-            }
-            if let CheckEventResult::Ok(c) = self.put(&mut writer, event)? {
-                count += c;
-            }
+        for (i, event) in events.iter().enumerate() {
+            let event = event.as_ref();
+            // dup in the input events
+            if i != 0 && event.id() == events[i - 1].as_ref().id() {
+                continue;
+            }
+            if let CheckEventResult::Ok(c) = self.put(&mut writer, event)? {
+                count += c;
+            }
+        }
+
+        self.commit(writer)?;
+        Ok(count)
+    }
+
+    pub fn batch_get<R: FromEventData, II, N>(&self, event_ids: II) -> Result<Vec<R>>
+    where
+        II: IntoIterator<Item = N>,
+        N: AsRef<[u8]>,
+    {
+        let reader = self.reader()?;
+        let mut events = vec![];
+        for id in event_ids.into_iter() {
+            let r = self.get::<R, _, _>(&reader, &id)?;
+            if let Some(e) = r {
+                events.push(e);
+            }
+        }
+        Ok(events)
+    }
+
+    /// Delete the events, return the number of deleted events
+    pub fn batch_del<II, N>(&self, event_ids: II) -> Result<usize>
+    where
+        II: IntoIterator<Item = N>,
+        N: AsRef<[u8]>,
+    {
+        let mut writer = self.writer()?;
+        let mut num = 0;
+        for id in event_ids.into_iter() {
+            if self.del(&mut writer, &id)? {
+                num += 1;
+            }
+        }
+        self.commit(writer)?;
+        Ok(num)
+    }
+
+    // remove the events moved to the archive or a partition, the followers keep them
+    fn batch_move<II, N>(&self, event_ids: II) -> Result<()>
+    where
+        II: IntoIterator<Item = N>,
+        N: AsRef<[u8]>,
+    {
+        let mut writer = self.writer()?;
+        for id in event_ids.into_iter() {
+            if let Some((uid, event)) = get_event::<Event, _, _>(
+                &writer,
+                &self.t_meta,
+                &self.t_id_uid,
+                &self.t_data,
+                &self.t_index,
+                &id,
+            )? {
+                self.remove_event(&mut writer, &event, &uid, true)?;
+            }
+        }
+        self.commit(writer)?;
+        Ok(())
+    }
+
+    /// The cold archive of the old events
+    pub fn archive(&self) -> &Archive {
+        &self.archive
+    }
+
+    /// Move the events created before the time to the archive, write a segment every `segment_size` events.
+    /// The replaceable and expiring events are kept in the hot storage. Return the number of moved events.
+    pub fn archive_before(&self, before: u64, segment_size: usize) -> Result<usize> {
+        let mut total = 0;
+        let mut since = 0;
+        if before == 0 {
+            return Ok(total);
+        }
+        loop {
+            let mut records = vec![];
+            {
+                let reader = self.reader()?;
+                let filter = Filter {
+                    since: Some(since),
+                    until: Some(before - 1),
+                    ..Default::default()
+                };
+                let mut iter = self.iter_plan::<String, _>(&reader, &filter, Plan::Time)?;
+                iter.archive = None;
+                for json in iter {
+                    let json = json?;
+                    let event = Event::from_data(&json)?;
+                    since = event.created_at();
+                    // the expiring events are deleted from the hot storage when expired
+                    if event.index().expiration().is_some()
+                        || encode_replace_key(event.kind(), event.pubkey(), event.tags()).is_some()
+                    {
+                        continue;
+                    }
+                    records.push(Record::new(&event, json.into_bytes()));
+                    if records.len() >= segment_size {
+                        break;
+                    }
+                }
+            }
+            if records.is_empty() {
+                break;
+            }
+            let full = records.len() >= segment_size;
+            let ids = records.iter().map(|r| r.id).collect::<Vec<_>>();
+            // delete after the segment is written, the events may be duplicated in both if crash
+            self.archive.write(records, self.data_key().as_deref())?;
+            self.batch_move(&ids)?;
+            total += ids.len();
+            if !full {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    /// The number of events and bytes in total, per kind and the top authors by number of events.
+    /// It scans the statistics of all authors when `top_authors` is not 0.
+    pub fn stats(&self, top_authors: usize) -> Result<EventStats> {
+        let reader = self.reader()?;
+        let mut stats = EventStats::default();
+        if let Some(v) = reader.get(&self.t_stat, encode_stat_key(STAT_EVENT_TOTAL, []))? {
+            stats.total = decode_counter(v)?;
+        }
+        stats.raw_bytes = self.get_stat(&reader, encode_stat_key(STAT_EVENT_RAW, []))?;
+        if let Some(v) = reader.get(&self.t_stat, encode_stat_key(STAT_EVENT_DICT, []))? {
+            stats.compressed = decode_counter(v)?;
+        }
+        stats.dictionary = self.dictionary().map(|d| d.id);
+
+        let prefix = [STAT_EVENT_KIND];
+        for item in reader.iter_from(&self.t_stat, Bound::Included(&prefix), false) {
+            let (k, v) = item?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            stats
+                .kinds
+                .push((u16_from_bytes(&k[1..])?, decode_counter(v)?));
+        }
+
+        if top_authors > 0 {
+            let prefix = [STAT_EVENT_AUTHOR];
+            let mut authors = vec![];
+            for item in reader.iter_from(&self.t_stat, Bound::Included(&prefix), false) {
+                let (k, v) = item?;
+                if !k.starts_with(&prefix) {
+                    break;
+                }
+                authors.push((k[1..].try_into()?, decode_counter(v)?));
+                // keep the memory small
+                if authors.len() >= top_authors * 2 + 1000 {
+                    sort_authors(&mut authors, top_authors);
+                }
+            }
+            sort_authors(&mut authors, top_authors);
+            stats.authors = authors;
+        }
+        Ok(stats)
+    }
+
+    /// The number of events by the author, from the statistics without scanning.
+    /// The events delegated to the author are included when the kinds are given.
+    pub fn count_author<T: Transaction>(
+        &self,
+        txn: &T,
+        pubkey: &[u8; 32],
+        kinds: &[u16],
+    ) -> Result<u64> {
+        if kinds.is_empty() {
+            Ok(
+                match txn.get(&self.t_stat, encode_stat_key(STAT_EVENT_AUTHOR, pubkey))? {
+                    Some(v) => decode_counter(v)?.count,
+                    None => 0,
+                },
+            )
+        } else {
+            self.sum_stat(
+                txn,
+                STAT_PUBKEY_KIND,
+                kinds.iter().map(|k| concat(pubkey, u16_to_ver(*k))),
+            )
+        }
+    }
+
+    /// Record the author writes by a NIP-42 authenticated session, keep the time of the first write
+    pub fn put_authenticated(&self, writer: &mut S::Writer<'_>, pubkey: &[u8; 32]) -> Result<()> {
+        let key = concat_sep(META_AUTHENTICATED, pubkey);
+        if writer.get(&self.t_meta, &key)?.is_none() {
+            writer.put(&self.t_meta, key, now().to_be_bytes())?;
         }
+        Ok(())
+    }
 
-        writer.commit()?;
-        Ok(count)
+    /// Whether the author has written by an authenticated session
+    pub fn is_authenticated<T: Transaction>(&self, txn: &T, pubkey: &[u8; 32]) -> Result<bool> {
+        Ok(txn
+            .get(&self.t_meta, concat_sep(META_AUTHENTICATED, pubkey))?
+            .is_some())
     }
 
-    pub fn batch_get<R: FromEventData, II, N>(&self, event_ids: II) -> Result<Vec<R>>
-    where
-        II: IntoIterator<Item = N>,
-        N: AsRef<[u8]>,
-    {
-        let reader = self.reader()?;
-        let mut events = vec![];
-        for id in event_ids.into_iter() {
-            let r = self.get::<R, _, _>(&reader, &id)?;
-            if let Some(e) = r {
-                events.push(e);
+    /// Scan the per author and kind counters for the authors having more than `max` events of the kinds,
+    /// all kinds when empty. The scan continues after the author `after` and stops after `limit` authors,
+    /// return the found authors and the last scanned author, `None` when the scan is finished.
+    pub fn authors_over<T: Transaction>(
+        &self,
+        txn: &T,
+        kinds: &[u16],
+        max: u64,
+        after: Option<&[u8; 32]>,
+        limit: usize,
+    ) -> Result<AuthorScan> {
+        let stat = if kinds.is_empty() {
+            STAT_EVENT_AUTHOR
+        } else {
+            STAT_PUBKEY_KIND
+        };
+        let prefix = [stat];
+        // the keys of an author end before the max kind
+        let start = match after {
+            Some(author) => encode_stat_key(stat, concat(author, [u8::MAX; 2])),
+            None => prefix.to_vec(),
+        };
+        let bound = if after.is_some() {
+            Bound::Excluded(&start)
+        } else {
+            Bound::Included(&start)
+        };
+        let mut found = vec![];
+        let mut current: Option<([u8; 32], u64)> = None;
+        let mut scanned = 0;
+        for item in txn.iter_from(&self.t_stat, bound, false) {
+            let (k, v) = item?;
+            if !k.starts_with(&prefix) || k.len() < 33 {
+                break;
+            }
+            let author: [u8; 32] = k[1..33].try_into()?;
+            let count = if kinds.is_empty() {
+                decode_counter(v)?.count
+            } else if kinds.contains(&u16_from_bytes(&k[33..])?) {
+                u64_from_bytes(v)?
+            } else {
+                0
+            };
+            match &mut current {
+                Some((last, sum)) if *last == author => *sum += count,
+                _ => {
+                    if let Some((last, sum)) = current.take() {
+                        if sum > max {
+                            found.push(last);
+                        }
+                        scanned += 1;
+                        if scanned >= limit {
+                            return Ok((found, Some(last)));
+                        }
+                    }
+                    current = Some((author, count));
+                }
             }
         }
-        Ok(events)
+        if let Some((last, sum)) = current {
+            if sum > max {
+                found.push(last);
+            }
+        }
+        Ok((found, None))
     }
 
-    pub fn batch_del<II, N>(&self, event_ids: II) -> Result<()>
-    where
-        II: IntoIterator<Item = N>,
-        N: AsRef<[u8]>,
-    {
-        let mut writer = self.inner.writer()?;
-        for id in event_ids.into_iter() {
-            self.del(&mut writer, &id)?;
+    /// Estimate the cost of the candidate indexes for the filter, the cheapest first.
+    pub fn estimate<T: Transaction>(&self, txn: &T, filter: &Filter) -> Result<Vec<Estimate>> {
+        let filter = self.indexed_filter(filter);
+        let filter = filter.as_ref();
+        if filter.search.as_ref().is_some() {
+            let entries = self.sum_stat(
+                txn,
+                STAT_WORD,
+                filter.words.iter().map(|w| concat_sep(w, [])),
+            )?;
+            return Ok(vec![Estimate::new(
+                Plan::Search,
+                entries,
+                filter.words.len() as u64,
+                MatchIndex::new(Plan::Search, filter).post_filter(),
+                filter.limit,
+                entries,
+            )]);
         }
-        writer.commit()?;
-        Ok(())
+
+        // (plan, entries, scanners)
+        let mut candidates = vec![];
+        if !filter.ids.is_empty() {
+            let len = filter.ids.len() as u64;
+            candidates.push((Plan::Ids, len, len));
+        }
+        if !filter.tags.is_empty() {
+            let mut entries = 0;
+            let mut scanners = 0;
+            for (key, values) in filter.tags.iter() {
+                entries += self.sum_stat(
+                    txn,
+                    STAT_TAG,
+                    values
+                        .iter()
+                        .map(|v| concat_sep(concat_sep(key, v), vec![])),
+                )?;
+                scanners += values.len() as u64;
+            }
+            candidates.push((Plan::Tag, entries, scanners));
+        }
+        // the author prefixes can't be estimated by the kind
+        if !filter.authors.is_empty()
+            && !filter.kinds.is_empty()
+            && Filter::is_full(&filter.authors)
+        {
+            let mut prefixes = vec![];
+            for author in filter.authors.iter() {
+                for kind in filter.kinds.iter() {
+                    prefixes.push(concat(author, u16_to_ver(*kind)));
+                }
+            }
+            let scanners = prefixes.len() as u64;
+            let entries = self.sum_stat(txn, STAT_PUBKEY_KIND, prefixes)?;
+            candidates.push((Plan::AuthorKind, entries, scanners));
+        }
+        if !filter.authors.is_empty() {
+            let mut entries = 0;
+            for author in filter.authors.iter() {
+                entries += if author.len() == 32 {
+                    self.get_stat(txn, encode_stat_key(STAT_PUBKEY, author))?
+                } else {
+                    self.sum_stat_range(txn, STAT_PUBKEY, author)?
+                };
+            }
+            candidates.push((Plan::Author, entries, filter.authors.len() as u64));
+        }
+        if !filter.kinds.is_empty() {
+            let entries =
+                self.sum_stat(txn, STAT_KIND, filter.kinds.iter().map(|k| u16_to_ver(*k)))?;
+            candidates.push((Plan::Kind, entries, filter.kinds.len() as u64));
+        }
+        let total = self.get_stat(txn, encode_stat_key(STAT_TOTAL, []))?;
+        candidates.push((Plan::Time, total, 1));
+
+        // every index covers all the matched events
+        let matches = candidates.iter().map(|c| c.1).min().unwrap_or_default();
+        let mut list = candidates
+            .into_iter()
+            .map(|(plan, entries, scanners)| {
+                Estimate::new(
+                    plan,
+                    entries,
+                    scanners,
+                    MatchIndex::new(plan, filter).post_filter(),
+                    filter.limit,
+                    matches,
+                )
+            })
+            .collect::<Vec<_>>();
+        // stable sort, keep the default priority for the same cost
+        list.sort_by_key(|e| e.cost);
+        Ok(list)
     }
 
     /// iter events by filter
@@ -588,50 +2461,120 @@ impl Db {
         txn: &'txn T,
         filter: &Filter,
     ) -> Result<Iter<'txn, T, J>> {
-        if filter.search.as_ref().is_some() {
-            let match_index = if !filter.ids.is_empty()
-                || !filter.tags.is_empty()
-                || !filter.authors.is_empty()
-                || !filter.kinds.is_empty()
-            {
-                MatchIndex::All
-            } else {
-                MatchIndex::None
-            };
-            Iter::new_word(self, txn, filter, &self.t_word, match_index)
-        } else if !filter.ids.is_empty() {
-            let match_index = if !filter.tags.is_empty()
-                || !filter.authors.is_empty()
-                || !filter.kinds.is_empty()
-            {
-                MatchIndex::All
-            } else {
-                MatchIndex::None
-            };
-            Iter::new_prefix(self, txn, filter, &filter.ids, &self.t_id, match_index)
-        } else if !filter.tags.is_empty() {
-            let match_index = if !filter.authors.is_empty() {
-                MatchIndex::Pubkey
-            } else {
-                MatchIndex::None
-            };
-            Iter::new_tag(self, txn, filter, &self.t_tag, match_index)
-        } else if !filter.authors.is_empty() && !filter.kinds.is_empty() {
-            Iter::new_author_kind(self, txn, filter, &self.t_pubkey_kind, MatchIndex::None)
-        } else if !filter.authors.is_empty() {
-            Iter::new_prefix(
+        let plan = self
+            .estimate(txn, filter)?
+            .first()
+            .map(|e| e.plan)
+            .unwrap_or_default();
+        self.iter_plan(txn, filter, plan)
+    }
+
+    /// iter the events by filter which can be deleted by [`Db::del`],
+    /// the immutable archive segments and the history versions are not read
+    pub fn iter_deletable<'txn, J: FromEventData, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        filter: &Filter,
+    ) -> Result<Iter<'txn, T, J>> {
+        let plan = self
+            .estimate(txn, filter)?
+            .first()
+            .map(|e| e.plan)
+            .unwrap_or_default();
+        self.iter_source(txn, filter, plan, false)
+    }
+
+    /// iter events by filter with the specified index
+    pub fn iter_plan<'txn, J: FromEventData, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        filter: &Filter,
+        plan: Plan,
+    ) -> Result<Iter<'txn, T, J>> {
+        self.iter_source(txn, filter, plan, true)
+    }
+
+    // merge the events of the archive segments or only the hot storage and the partitions
+    fn iter_source<'txn, J: FromEventData, T: Transaction>(
+        &self,
+        txn: &'txn T,
+        filter: &Filter,
+        plan: Plan,
+        archived: bool,
+    ) -> Result<Iter<'txn, T, J>> {
+        let filter = self.indexed_filter(filter);
+        let filter = filter.as_ref();
+        let match_index = MatchIndex::new(plan, filter);
+        let mut iter = match plan {
+            Plan::Search => Iter::new_word(self, txn, filter, &self.t_word, match_index),
+            Plan::Ids => Iter::new_prefix(self, txn, filter, &filter.ids, &self.t_id, match_index),
+            Plan::Tag => Iter::new_tag(self, txn, filter, &self.t_tag, match_index),
+            Plan::AuthorKind => {
+                Iter::new_author_kind(self, txn, filter, &self.t_pubkey_kind, match_index)
+            }
+            Plan::Author => Iter::new_prefix(
                 self,
                 txn,
                 filter,
                 &filter.authors,
                 &self.t_pubkey,
-                MatchIndex::None,
-            )
-        } else if !filter.kinds.is_empty() {
-            Iter::new_kind(self, txn, filter, &self.t_kind, MatchIndex::None)
-        } else {
-            Iter::new_time(self, txn, filter, &self.t_created_at, MatchIndex::None)
+                match_index,
+            ),
+            Plan::Kind => Iter::new_kind(self, txn, filter, &self.t_kind, match_index),
+            Plan::Time => Iter::new_time(self, txn, filter, &self.t_created_at, match_index),
+            Plan::Expiration => Iter::new_time(self, txn, filter, &self.t_expiration, match_index),
+        }?;
+        iter.plan = plan;
+        // the archive has no word index, the expiring events are not moved out
+        if !matches!(plan, Plan::Expiration) {
+            let search = matches!(plan, Plan::Search);
+            let filter = filter.resumed();
+            let history = if filter.history && !search && archived {
+                self.history_records(txn, &filter)?
+            } else {
+                vec![]
+            };
+            let partitions = self.partitions.stream(&filter)?;
+            iter.archive = self.archive.iter(
+                &filter,
+                !search && archived,
+                history,
+                partitions,
+                &self.index_tags,
+            );
         }
+        Ok(iter)
+    }
+
+    /// Explain how the filter executes, run the query for the actual stats.
+    /// Report [`Error::ScanTimeout`] if the scan time exceeds the timeout.
+    pub fn explain(&self, filter: &Filter, timeout: Option<Duration>) -> Result<Explain> {
+        let filter = self.indexed_filter(filter);
+        let filter = filter.as_ref();
+        let reader = self.reader()?;
+        let candidates = self.estimate(&reader, filter)?;
+        let estimate = candidates[0].clone();
+        let mut iter = self.iter_plan::<String, _>(&reader, filter, estimate.plan)?;
+        if let Some(timeout) = timeout {
+            iter.scan_time(timeout, 2000);
+        }
+        let mut size = 0;
+        for event in iter.by_ref() {
+            event?;
+            size += 1;
+        }
+        let (group, scanners) = group_structure(estimate.plan, filter);
+        Ok(Explain {
+            plan: estimate.plan,
+            tree: estimate.plan.tree(),
+            group,
+            scanners,
+            post_filter: estimate.post_filter,
+            estimate,
+            candidates,
+            actual: iter.stats(),
+            size,
+        })
     }
 
     /// iter expired events
@@ -645,7 +2588,7 @@ impl Db {
             until,
             ..Default::default()
         };
-        Iter::new_time(self, txn, &filter, &self.t_expiration, MatchIndex::None)
+        self.iter_plan(txn, &filter, Plan::Expiration)
     }
 
     /// iter ephemeral events
@@ -682,7 +2625,9 @@ impl Db {
             }),
         );
         group.add(Box::new(scanner))?;
-        Iter::new(self, txn, &filter, group, MatchIndex::None)
+        let mut iter = Iter::new(self, txn, &filter, group, MatchIndex::None)?;
+        iter.plan = Plan::Kind;
+        Ok(iter)
     }
 }
 
@@ -702,6 +2647,35 @@ enum MatchIndex {
 }
 
 impl MatchIndex {
+    // the conditions not covered by the index need to be matched from the index data
+    fn new(plan: Plan, filter: &Filter) -> Self {
+        let ids = !filter.ids.is_empty();
+        let tags = !filter.tags.is_empty();
+        let authors = !filter.authors.is_empty();
+        let kinds = !filter.kinds.is_empty();
+        let all = match plan {
+            Plan::Search => ids || tags || authors || kinds,
+            Plan::Ids => tags || authors || kinds,
+            // kinds are matched in the tag scanner
+            Plan::Tag if ids => true,
+            Plan::Tag if authors => return MatchIndex::Pubkey,
+            Plan::Tag => false,
+            Plan::AuthorKind => ids || tags,
+            Plan::Author => ids || tags || kinds,
+            Plan::Kind => ids || tags || authors,
+            Plan::Time | Plan::Expiration => ids || tags || authors || kinds,
+        };
+        if all {
+            MatchIndex::All
+        } else {
+            MatchIndex::None
+        }
+    }
+
+    fn post_filter(&self) -> bool {
+        !matches!(self, MatchIndex::None)
+    }
+
     fn r#match(&self, filter: &Filter, event: &ArchivedEventIndex) -> bool {
         match &self {
             MatchIndex::Pubkey => {
@@ -717,8 +2691,11 @@ where
     R: Transaction,
 {
     reader: &'txn R,
+    view_meta: Tree,
     view_data: Tree,
     view_index: Tree,
+    view_id: Tree,
+    view_deletion: Tree,
     group: Group<'txn, IndexKey, Error>,
     get_data: u64,
     get_index: u64,
@@ -727,6 +2704,147 @@ where
     _r: PhantomData<J>,
     // need get index data for filter
     match_index: MatchIndex,
+    plan: Plan,
+    // number of returned events
+    count: u64,
+    // fall through to the cold archive, merge the events in time order
+    archive: Option<ArchiveIter>,
+    hot: Option<(IndexKey, J)>,
+    cold: Option<Record>,
+    hot_done: bool,
+    // the position of the last returned event
+    cursor: Option<Cursor>,
+}
+
+// Expand the prefixes to the full 32 bytes keys in the index tree, the index key starts with the full key
+fn full_keys<R: Transaction>(
+    reader: &R,
+    tree: &Tree,
+    prefixes: &[Vec<u8>],
+) -> Result<Vec<Vec<u8>>, Error> {
+    let mut keys = vec![];
+    for prefix in prefixes {
+        if prefix.len() == 32 {
+            keys.push(prefix.clone());
+            continue;
+        }
+        let mut from = prefix.clone();
+        loop {
+            let key = match reader.iter_from(tree, Bound::Included(&from), false).next() {
+                Some(item) => {
+                    let (k, _) = item?;
+                    if !k.starts_with(prefix) || k.len() < 32 {
+                        break;
+                    }
+                    k[0..32].to_vec()
+                }
+                None => break,
+            };
+            // skip to the next key
+            let next = upper(key.clone());
+            keys.push(key);
+            match next {
+                Some(next) => from = next,
+                None => break,
+            }
+        }
+    }
+    keys.sort();
+    keys.dedup();
+    Ok(keys)
+}
+
+// the index key of the cursor, all the stored events of the cursor time are skipped after an archived event
+fn resume_key(filter: &Filter) -> Option<IndexKey> {
+    filter.cursor.map(|c| match (c.id, filter.desc) {
+        (None, _) => IndexKey::new(c.time, c.uid),
+        (Some(_), true) => IndexKey::new(c.time, 0),
+        (Some(_), false) => IndexKey::new(c.time, u64::MAX),
+    })
+}
+
+// scan the keys starting with the prefix
+fn prefix_scanner<'a, R: Transaction>(
+    reader: &'a R,
+    view: &Tree,
+    key: Vec<u8>,
+    prefix: Vec<u8>,
+    filter: &Filter,
+) -> Scanner<'a, IndexKey, Error, R::Iter<'a>> {
+    let iter = create_iter(reader, view, &prefix, filter.desc);
+    Scanner::new(
+        iter,
+        key,
+        prefix,
+        filter.desc,
+        filter.since,
+        filter.until,
+        Box::new(|s, r| {
+            Ok(if r.0.starts_with(&s.prefix) {
+                MatchResult::Found(IndexKey::from(r.0, r.1)?)
+            } else {
+                MatchResult::Stop
+            })
+        }),
+    )
+    .resume_after(resume_key(filter))
+}
+
+// the OR group of the `(key, prefix)` scanners, prefetched in parallel if there are many
+fn prefix_group<'txn, R: Transaction, S: Store>(
+    kv_db: &Db<S>,
+    reader: &'txn R,
+    filter: &Filter,
+    view: &Tree,
+    scans: Vec<(Vec<u8>, Vec<u8>)>,
+) -> Result<Group<'txn, IndexKey, Error>, Error> {
+    let mut group = Group::new(filter.desc, false, false);
+    // scan serially when the other queries use the threads
+    let parallel = kv_db.parallel.read().as_ref().and_then(|(options, pool)| {
+        if options.threads > 1 && scans.len() >= options.min_scanners.max(2) {
+            Some((options.clone(), pool.reserve(options.threads)?))
+        } else {
+            None
+        }
+    });
+    match parallel {
+        Some((options, mut permit)) => {
+            let size = scans.len().div_ceil(options.threads);
+            for chunk in scans.chunks(size) {
+                let (store, view, filter, chunk) = (
+                    kv_db.inner.clone(),
+                    view.clone(),
+                    filter.clone(),
+                    chunk.to_vec(),
+                );
+                let prefetch = permit.spawn(options.buffer, move |sender| {
+                    let reader = match store.reader() {
+                        Ok(reader) => reader,
+                        Err(err) => {
+                            sender.send(Err(err.into()), 0);
+                            return;
+                        }
+                    };
+                    let mut group = Group::new(filter.desc, false, false);
+                    for (key, prefix) in chunk {
+                        let scanner = prefix_scanner(&reader, &view, key, prefix, &filter);
+                        if let Err(err) = group.add(Box::new(scanner)) {
+                            sender.send(Err(err), 0);
+                            return;
+                        }
+                    }
+                    sender.send_all(group);
+                })?;
+                group.add(Box::new(prefetch))?;
+            }
+        }
+        _ => {
+            for (key, prefix) in scans {
+                group.add(Box::new(prefix_scanner(reader, view, key, prefix, filter)))?;
+            }
+        }
+    }
+    Ok(group)
 }
 
 fn create_iter<'a, R: Transaction>(
@@ -734,7 +2852,7 @@ fn create_iter<'a, R: Transaction>(
     tree: &Tree,
     prefix: &Vec<u8>,
     reverse: bool,
-) -> LmdbIter<'a> {
+) -> R::Iter<'a> {
     if reverse {
         let start = upper(prefix.clone())
             .map(Bound::Excluded)
@@ -751,15 +2869,18 @@ where
     J: FromEventData,
 {
     fn new(
-        kv_db: &Db,
+        kv_db: &Db<impl Store>,
         reader: &'txn R,
         filter: &Filter,
         group: Group<'txn, IndexKey, Error>,
         match_index: MatchIndex,
     ) -> Result<Self, Error> {
         Ok(Self {
+            view_meta: kv_db.t_meta.clone(),
             view_data: kv_db.t_data.clone(),
             view_index: kv_db.t_index.clone(),
+            view_id: kv_db.t_id_uid.clone(),
+            view_deletion: kv_db.t_deletion.clone(),
             reader,
             group,
             get_data: 0,
@@ -768,12 +2889,19 @@ where
             // checker: None,
             _r: PhantomData,
             match_index,
+            plan: Plan::default(),
+            count: 0,
+            archive: None,
+            hot: None,
+            cold: None,
+            hot_done: false,
+            cursor: None,
         })
     }
 
     /// Filter from timestamp index
     fn new_time(
-        kv_db: &Db,
+        kv_db: &Db<impl Store>,
         reader: &'txn R,
         filter: &Filter,
         view: &Tree,
@@ -795,13 +2923,14 @@ where
             filter.since,
             filter.until,
             Box::new(|_, r| Ok(MatchResult::Found(IndexKey::from(r.0, r.1)?))),
-        );
+        )
+        .resume_after(resume_key(filter));
         group.add(Box::new(scanner))?;
         Self::new(kv_db, reader, filter, group, match_index)
     }
 
     fn new_kind(
-        kv_db: &Db,
+        kv_db: &Db<impl Store>,
         reader: &'txn R,
         filter: &Filter,
         view: &Tree,
@@ -826,14 +2955,15 @@ where
                         MatchResult::Stop
                     })
                 }),
-            );
+            )
+            .resume_after(resume_key(filter));
             group.add(Box::new(scanner))?;
         }
         Self::new(kv_db, reader, filter, group, match_index)
     }
 
     fn new_tag(
-        kv_db: &Db,
+        kv_db: &Db<impl Store>,
         reader: &'txn R,
         filter: &Filter,
         view: &Tree,
@@ -874,7 +3004,8 @@ where
                             MatchResult::Stop
                         })
                     }),
-                );
+                )
+                .resume_after(resume_key(filter));
                 sub.add(Box::new(scanner))?;
             }
             group.add(Box::new(sub))?;
@@ -883,75 +3014,41 @@ where
     }
 
     fn new_author_kind(
-        kv_db: &Db,
+        kv_db: &Db<impl Store>,
         reader: &'txn R,
         filter: &Filter,
         view: &Tree,
         match_index: MatchIndex,
     ) -> Result<Self, Error> {
-        let mut group = Group::new(filter.desc, false, false);
-
-        for author in filter.authors.iter() {
+        let mut scans = vec![];
+        for author in full_keys(reader, &kv_db.t_pubkey, &filter.authors)?.iter() {
             for kind in filter.kinds.iter() {
-                let prefix: Vec<u8> = concat(author, u16_to_ver(*kind));
-                let iter = create_iter(reader, view, &prefix, filter.desc);
-                let scanner = Scanner::new(
-                    iter,
-                    author.to_vec(),
-                    prefix,
-                    filter.desc,
-                    filter.since,
-                    filter.until,
-                    Box::new(|s, r| {
-                        Ok(if r.0.starts_with(&s.prefix) {
-                            MatchResult::Found(IndexKey::from(r.0, r.1)?)
-                        } else {
-                            MatchResult::Stop
-                        })
-                    }),
-                );
-                group.add(Box::new(scanner))?;
+                scans.push((author.to_vec(), concat(author, u16_to_ver(*kind))));
             }
         }
-
+        let group = prefix_group(kv_db, reader, filter, view, scans)?;
         Self::new(kv_db, reader, filter, group, match_index)
     }
 
     fn new_prefix(
-        kv_db: &Db,
+        kv_db: &Db<impl Store>,
         reader: &'txn R,
         filter: &Filter,
-        ids: &[[u8; 32]],
+        ids: &[Vec<u8>],
         view: &Tree,
         match_index: MatchIndex,
     ) -> Result<Self, Error> {
-        let mut group = Group::new(filter.desc, false, false);
-
-        for id in ids.iter() {
-            let prefix = id.to_vec();
-            let iter = create_iter(reader, view, &prefix, filter.desc);
-            let scanner = Scanner::new(
-                iter,
-                prefix.clone(),
-                prefix,
-                filter.desc,
-                filter.since,
-                filter.until,
-                Box::new(move |s, r| {
-                    Ok(if r.0.starts_with(&s.prefix) {
-                        MatchResult::Found(IndexKey::from(r.0, r.1)?)
-                    } else {
-                        MatchResult::Stop
-                    })
-                }),
-            );
-            group.add(Box::new(scanner))?;
-        }
+        // the keys of a prefix are not in time order, scan each full key
+        let scans = full_keys(reader, view, ids)?
+            .into_iter()
+            .map(|prefix| (prefix.clone(), prefix))
+            .collect();
+        let group = prefix_group(kv_db, reader, filter, view, scans)?;
         Self::new(kv_db, reader, filter, group, match_index)
     }
 
     fn new_word(
-        kv_db: &Db,
+        kv_db: &Db<impl Store>,
         reader: &'txn R,
         filter: &Filter,
         view: &Tree,
@@ -977,7 +3074,8 @@ where
                         MatchResult::Stop
                     })
                 }),
-            );
+            )
+            .resume_after(resume_key(filter));
             group.add(Box::new(scanner))?;
         }
         Self::new(kv_db, reader, filter, group, match_index)
@@ -986,12 +3084,33 @@ where
     fn document(&self, key: &IndexKey) -> Result<Option<J>, Error> {
         get_event_by_uid::<J, _, _>(
             self.reader,
+            &self.view_meta,
             &self.view_data,
             &self.view_index,
             key.uid().to_be_bytes(),
         )
     }
 
+    // verify the original values of the long tags found by the hash index
+    fn verify(&mut self, key: &IndexKey) -> Result<bool, Error> {
+        if self.filter.long_tags.is_empty() {
+            return Ok(true);
+        }
+        self.get_data += 1;
+        Ok(
+            match get_event_by_uid::<Event, _, _>(
+                self.reader,
+                &self.view_meta,
+                &self.view_data,
+                &self.view_index,
+                key.uid().to_be_bytes(),
+            )? {
+                Some(event) => self.filter.verify_long_tags(event.tags()),
+                None => false,
+            },
+        )
+    }
+
     fn index_data(&self, key: &IndexKey) -> Result<Option<&'txn [u8]>, Error> {
         let v = self.reader.get(&self.view_index, key.uid().to_be_bytes())?;
         Ok(v)
@@ -1005,23 +3124,26 @@ where
         }
     }
 
-    fn next_inner(&mut self) -> Result<Option<J>, Error> {
+    fn next_inner(&mut self) -> Result<Option<(IndexKey, J)>, Error> {
         while let Some(item) = self.group.next() {
             let key = item?;
             if matches!(self.match_index, MatchIndex::None) {
+                if !self.verify(&key)? {
+                    continue;
+                }
                 self.get_data += 1;
                 if let Some(event) = self.document(&key)? {
-                    return Ok(Some(event));
+                    return Ok(Some((key, event)));
                 }
             } else {
                 let data = self.index_data(&key)?;
                 let event = decode_event_index(data)?;
                 self.get_index += 1;
                 if let Some(event) = event {
-                    if self.match_index.r#match(&self.filter, event) {
+                    if self.match_index.r#match(&self.filter, event) && self.verify(&key)? {
                         self.get_data += 1;
                         if let Some(event) = self.document(&key)? {
-                            return Ok(Some(event));
+                            return Ok(Some((key, event)));
                         }
                     }
                 }
@@ -1029,6 +3151,77 @@ where
         }
         Ok(None)
     }
+
+    fn next_hot(&mut self, hot: Option<(IndexKey, J)>) -> Option<(u64, u64, J)> {
+        hot.map(|(key, event)| {
+            self.cursor = Some(Cursor {
+                time: key.time(),
+                uid: key.uid(),
+                id: None,
+            });
+            (key.time(), key.uid(), event)
+        })
+    }
+
+    fn next_merged(&mut self) -> Result<Option<(u64, u64, J)>, Error> {
+        if self.archive.is_none() {
+            let hot = self.next_inner()?;
+            return Ok(self.next_hot(hot));
+        }
+        if self.hot.is_none() && !self.hot_done {
+            self.hot = self.next_inner()?;
+            self.hot_done = self.hot.is_none();
+        }
+        let archive = self.archive.as_mut().unwrap();
+        let desc = self.filter.desc;
+        // the archived events are older in most cases, only read the archive when needed
+        let need_cold = match &self.hot {
+            Some((key, _)) if desc => key.time() <= archive.until,
+            Some((key, _)) => key.time() >= archive.since,
+            None => true,
+        };
+        if need_cold && self.cold.is_none() {
+            self.cold = next_cold(
+                archive,
+                &self.filter,
+                self.reader,
+                &self.view_id,
+                &self.view_deletion,
+            )?;
+        }
+        let cold_first = match (&self.hot, &self.cold) {
+            (Some((key, _)), Some(record)) if desc => record.time > key.time(),
+            (Some((key, _)), Some(record)) => record.time < key.time(),
+            (None, Some(_)) => true,
+            _ => false,
+        };
+        if cold_first {
+            let record = self.cold.take().unwrap();
+            self.get_data += 1;
+            self.cursor = Some(Cursor {
+                time: record.time,
+                uid: 0,
+                id: Some(record.id),
+            });
+            Ok(Some((record.time, 0, record.data()?)))
+        } else {
+            let hot = self.hot.take();
+            Ok(self.next_hot(hot))
+        }
+    }
+
+    /// next event with the created time and uid, for merging the events of iterators
+    pub(crate) fn next_key(&mut self) -> Option<Result<(u64, u64, J), Error>> {
+        if self.limit(self.count) {
+            None
+        } else {
+            let item = self.next_merged().transpose();
+            if let Some(Ok(_)) = item {
+                self.count += 1;
+            }
+            item
+        }
+    }
 }
 
 impl<'txn, R, J> Iter<'txn, R, J>
@@ -1038,35 +3231,42 @@ where
 {
     /// Limit the total scan time and report [`Error::ScanTimeout`] if it is exceeded
     pub fn scan_time(&mut self, timeout: Duration, check_step: u64) {
-        let start = Instant::now();
-        let mut last = check_step;
-        self.group.watcher(Box::new(move |count| {
-            if count > last {
-                // check
-                if start.elapsed() > timeout {
-                    return Err(Error::ScanTimeout);
-                }
-                last = count + check_step;
-            }
-            Ok(())
-        }));
+        self.group
+            .watcher(Box::new(scan_watcher(timeout, check_step)));
+    }
+
+    pub(crate) fn watcher(&mut self, watcher: Box<dyn ScannerWatcher<Error>>) {
+        self.group.watcher(watcher);
+    }
+
+    /// The position of the last returned event for resuming by [`Filter::cursor`]
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.cursor
     }
 
     /// The stats after scan
     pub fn stats(&self) -> Stats {
         Stats {
-            scan_index: self.group.scan_times,
+            plan: self.plan,
+            scan_index: self.group.scan_times + self.archive_scanned(),
             get_data: self.get_data,
             get_index: self.get_index,
         }
     }
 
+    fn archive_scanned(&self) -> u64 {
+        self.archive.as_ref().map(|a| a.scanned).unwrap_or_default()
+    }
+
     /// only count iter size
     pub fn size(mut self) -> Result<(u64, Stats)> {
         let mut len = 0;
         while let Some(item) = self.group.next() {
             let key = item?;
             if matches!(self.match_index, MatchIndex::None) {
+                if !self.verify(&key)? {
+                    continue;
+                }
                 len += 1;
                 if self.limit(len) {
                     break;
@@ -1076,7 +3276,7 @@ where
                 let event = decode_event_index(data)?;
                 self.get_index += 1;
                 if let Some(event) = event {
-                    if self.match_index.r#match(&self.filter, event) {
+                    if self.match_index.r#match(&self.filter, event) && self.verify(&key)? {
                         len += 1;
                         if self.limit(len) {
                             break;
@@ -1085,17 +3285,78 @@ where
                 }
             }
         }
+        if let Some(mut archive) = self.archive.take() {
+            while !self.limit(len)
+                && next_cold(
+                    &mut archive,
+                    &self.filter,
+                    self.reader,
+                    &self.view_id,
+                    &self.view_deletion,
+                )?
+                .is_some()
+            {
+                len += 1;
+            }
+            self.archive = Some(archive);
+        }
         Ok((
             len,
             Stats {
+                plan: self.plan,
                 get_data: 0,
                 get_index: self.get_index,
-                scan_index: self.group.scan_times,
+                scan_index: self.group.scan_times + self.archive_scanned(),
             },
         ))
     }
 }
 
+// skip the archived events not after the cursor of an archived event in the same second,
+// the events still in the hot storage after a crash while moving,
+// and the events deleted by the author or the delegator after archived
+fn next_cold<R: Transaction>(
+    archive: &mut ArchiveIter,
+    filter: &Filter,
+    reader: &R,
+    t_id_uid: &Tree,
+    t_deletion: &Tree,
+) -> Result<Option<Record>, Error> {
+    while let Some(record) = archive.next()? {
+        if get_uid(reader, t_id_uid, record.id)?.is_some() {
+            continue;
+        }
+        let event = Event::from_data(&record.json)?;
+        let deleted = |pubkey: &[u8]| -> Result<bool, Error> {
+            Ok(reader.get(t_deletion, concat(record.id, pubkey))?.is_some())
+        };
+        if deleted(event.pubkey())?
+            || event
+                .index()
+                .delegator()
+                .map_or(Ok(false), |d| deleted(d))?
+        {
+            continue;
+        }
+        if let Some(Cursor {
+            time, id: Some(id), ..
+        }) = &filter.cursor
+        {
+            if record.time == *time
+                && (if filter.desc {
+                    record.id >= *id
+                } else {
+                    record.id <= *id
+                })
+            {
+                continue;
+            }
+        }
+        return Ok(Some(record));
+    }
+    Ok(None)
+}
+
 impl<'txn, R, J> Iterator for Iter<'txn, R, J>
 where
     R: Transaction,
@@ -1103,17 +3364,13 @@ where
 {
     type Item = Result<J, Error>;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.limit(self.get_data) {
-            None
-        } else {
-            self.next_inner().transpose()
-        }
+        self.next_key().map(|r| r.map(|(_, _, event)| event))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::upper;
+    use super::*;
 
     #[test]
     pub fn test_upper_fn() {
@@ -1126,4 +3383,140 @@ mod tests {
         assert_eq!(upper(vec![1, 2, 3, 255, 5]), Some(vec![1, 2, 3, 255, 6]));
         assert_eq!(upper(vec![255, 2, 3, 4, 5]), Some(vec![255, 2, 3, 4, 6]));
     }
+
+    #[test]
+    pub fn test_migrate_replacement() -> Result<()> {
+        let dir = tempfile::Builder::new()
+            .prefix("nostr-db-test-migrate")
+            .tempdir()
+            .unwrap();
+        let db = Db::open(dir.path())?;
+        db.check_schema()?;
+        let event = |index: u8, created_at: u64, tags: Vec<Vec<&str>>| {
+            let tags = tags
+                .into_iter()
+                .map(|t| t.into_iter().map(|s| s.to_owned()).collect())
+                .collect();
+            Event::new(
+                [index; 32],
+                [1; 32],
+                created_at,
+                30001,
+                tags,
+                "".to_owned(),
+                [0; 64],
+            )
+        };
+        let latest = event(1, 2, vec![vec!["d", "a"]])?;
+        let older = event(2, 1, vec![vec!["t", "x"], vec!["d", "a"]])?;
+        let other = event(3, 1, vec![vec!["t", "y"], vec!["d", "b"]])?;
+
+        // the version 3 replace keys only check the first tag
+        let mut writer = db.writer()?;
+        db.put(&mut writer, &latest)?;
+        let key = encode_replace_key(30001, &[1; 32], &[]).unwrap();
+        for e in [&older, &other] {
+            let seq = u64_to_ver(db.seq.fetch_add(1, Ordering::SeqCst));
+            db.put_event(&mut writer, e, &seq, &Some(key.clone()))?;
+        }
+        writer.put(&db.t_meta, "version", DB_VERSION_3)?;
+        db.commit(writer)?;
+
+        db.check_schema()?;
+        let reader = db.reader()?;
+        assert_eq!(
+            reader.get(&db.t_meta, "version")?,
+            Some(DB_VERSION.as_bytes())
+        );
+        assert!(db.get::<Event, _, _>(&reader, older.id())?.is_none());
+        assert_eq!(db.get_replaced(&reader, &older)?.unwrap().id(), latest.id());
+        assert_eq!(db.get_replaced(&reader, &other)?.unwrap().id(), other.id());
+        assert!(reader.get(&db.t_replacement, &key)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_migrate_deletion() -> Result<()> {
+        let dir = tempfile::Builder::new()
+            .prefix("nostr-db-test-migrate-deletion")
+            .tempdir()
+            .unwrap();
+        let db = Db::open(dir.path())?;
+        db.check_schema()?;
+        let deleted = [2; 32];
+        let deletion = Event::new(
+            [1; 32],
+            [1; 32],
+            1,
+            5,
+            vec![vec!["e".to_owned(), hex::encode(deleted)]],
+            "".to_owned(),
+            [0; 64],
+        )?;
+        let mut writer = db.writer()?;
+        db.put(&mut writer, &deletion)?;
+        // the version 4 tombstone is keyed by the deletion id and the deleted id
+        let key = concat(deletion.id(), deleted);
+        let uid = writer
+            .get(&db.t_deletion, concat(deleted, deletion.pubkey()))?
+            .unwrap()
+            .to_vec();
+        writer.del(&db.t_deletion, concat(deleted, deletion.pubkey()), None)?;
+        writer.put(&db.t_deletion, &key, uid)?;
+        writer.put(&db.t_meta, "version", DB_VERSION_4)?;
+        db.commit(writer)?;
+
+        let reader = db.reader()?;
+        assert!(!db.is_deleted(&reader, &deleted, deletion.pubkey())?);
+        drop(reader);
+        db.check_schema()?;
+        let reader = db.reader()?;
+        assert_eq!(
+            reader.get(&db.t_meta, "version")?,
+            Some(DB_VERSION.as_bytes())
+        );
+        assert!(db.is_deleted(&reader, &deleted, deletion.pubkey())?);
+        assert!(reader.get(&db.t_deletion, &key)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_refresh_dictionary() -> Result<()> {
+        use std::str::FromStr;
+        let db = Db::memory()?;
+        let events = (0..200u8)
+            .map(|i| {
+                Event::from_str(&format!(
+                    r#"{{"id":"{}","pubkey":"{}","created_at":{},"kind":1,"tags":[["t","nostr"]],"content":"good morning nostr, this is the note number {}","sig":"{}"}}"#,
+                    hex::encode([i; 32]),
+                    hex::encode([1u8; 32]),
+                    i,
+                    i,
+                    hex::encode([0u8; 64])
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        db.batch_put(&events[..150])?;
+
+        // trained by another process
+        let samples = events
+            .iter()
+            .map(|e| e.to_json())
+            .collect::<Result<Vec<_>>>()?;
+        let bytes = dict::train(&samples, 2048)?;
+        let id = Dictionary::id_of(&bytes);
+        let mut writer = db.writer()?;
+        writer.put(&db.t_meta, dict_meta_key(id), &bytes)?;
+        writer.put(&db.t_meta, META_DICT, id.to_be_bytes())?;
+        db.commit(writer)?;
+        assert!(db.dictionary().is_none());
+
+        db.batch_put(&events[150..])?;
+        assert_eq!(db.dictionary().map(|d| d.id), Some(id));
+        let reader = db.reader()?;
+        let uid = get_uid(&reader, &db.t_id_uid, events[160].id())?.unwrap();
+        let data = reader.get(&db.t_data, uid)?.unwrap();
+        assert_eq!(data_dictionary(data), Some(id));
+        Ok(())
+    }
 }
@@ -0,0 +1,92 @@
+//! Zstd dictionary for compressing the small events.
+//!
+//! The dictionaries are registered in the process when the database is opened,
+//! so the event data can be decoded by [`crate::FromEventData`] without the database.
+//! The dictionaries trained by another process are registered on the first read of their data.
+
+use crate::error::Error;
+use parking_lot::RwLock;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock},
+};
+use zstd::{
+    bulk::{Compressor, Decompressor},
+    dict::{DecoderDictionary, EncoderDictionary},
+};
+
+const LEVEL: i32 = 5;
+
+/// A trained zstd dictionary
+pub struct Dictionary {
+    /// the id is derived from the dictionary content
+    pub id: u32,
+    pub size: usize,
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl Dictionary {
+    pub fn id_of(bytes: &[u8]) -> u32 {
+        let hash: [u8; 32] = Sha256::digest(bytes).into();
+        u32::from_be_bytes(hash[0..4].try_into().unwrap())
+    }
+
+    /// `[compressed][uncompressed length u32][dictionary id u32]`
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut compressor = Compressor::with_prepared_dictionary(&self.encoder)?;
+        let mut bytes = compressor.compress(data)?;
+        bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Ok(bytes)
+    }
+}
+
+fn registry() -> &'static RwLock<HashMap<u32, Arc<Dictionary>>> {
+    static DICTIONARIES: OnceLock<RwLock<HashMap<u32, Arc<Dictionary>>>> = OnceLock::new();
+    DICTIONARIES.get_or_init(Default::default)
+}
+
+/// Whether the dictionary is registered
+pub(crate) fn registered(id: u32) -> bool {
+    registry().read().contains_key(&id)
+}
+
+/// Register the dictionary for decoding
+pub(crate) fn register(bytes: &[u8]) -> Arc<Dictionary> {
+    let id = Dictionary::id_of(bytes);
+    if let Some(dict) = registry().read().get(&id) {
+        return dict.clone();
+    }
+    let dict = Arc::new(Dictionary {
+        id,
+        size: bytes.len(),
+        encoder: EncoderDictionary::copy(bytes, LEVEL),
+        decoder: DecoderDictionary::copy(bytes),
+    });
+    registry().write().insert(id, dict.clone());
+    dict
+}
+
+/// Decompress the data of [`Dictionary::compress`]
+pub(crate) fn decompress(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    if bytes.len() < 8 {
+        return Err(Error::InvalidLength);
+    }
+    let (data, tail) = bytes.split_at(bytes.len() - 8);
+    let len = u32::from_be_bytes(tail[0..4].try_into()?) as usize;
+    let id = u32::from_be_bytes(tail[4..8].try_into()?);
+    let dict = registry()
+        .read()
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| Error::Invalid(format!("zstd dictionary {} not found", id)))?;
+    let mut decompressor = Decompressor::with_prepared_dictionary(&dict.decoder)?;
+    Ok(decompressor.decompress(data, len)?)
+}
+
+/// Train a dictionary from the samples
+pub(crate) fn train<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<Vec<u8>, Error> {
+    Ok(zstd::dict::from_samples(samples, max_size)?)
+}
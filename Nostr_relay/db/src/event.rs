@@ -1,4 +1,9 @@
-use crate::error::Error;
+use crate::{
+    cipher::{self, DataKey},
+    dict::{self, Dictionary},
+    error::Error,
+    tag::{index_value, is_index_tag, protect_value, Coordinate, IndexTags},
+};
 use rkyv::{
     vec::ArchivedVec, AlignedVec, Archive, Archived, Deserialize as RkyvDeserialize,
     Serialize as RkyvSerialize,
@@ -8,6 +13,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
 use std::{
+    borrow::Cow,
+    collections::HashSet,
     fmt::Display,
     str::FromStr,
     time::{SystemTime, UNIX_EPOCH},
@@ -77,7 +84,7 @@ impl EventIndex {
         kind: u16,
         tags: &Vec<Vec<String>>,
     ) -> Result<Self, Error> {
-        let (tags, expiration, delegator) = Self::build_index_tags(tags)?;
+        let (tags, expiration, delegator) = Self::build_index_tags(tags, &HashSet::new(), None)?;
         Ok(Self {
             id,
             pubkey,
@@ -89,7 +96,12 @@ impl EventIndex {
         })
     }
 
-    pub fn build_index_tags(tags: &Vec<Vec<String>>) -> Result<BuildTags, Error> {
+    /// Build the index of the single-letter tags and the additional names
+    pub fn build_index_tags(
+        tags: &Vec<Vec<String>>,
+        names: &HashSet<String>,
+        key: Option<&[u8; 32]>,
+    ) -> Result<BuildTags, Error> {
         let mut t = vec![];
         let mut expiration = None;
         let mut delegator = None;
@@ -107,28 +119,21 @@ impl EventIndex {
                     delegator = Some(h);
                 }
 
-                let key = tag[0].as_bytes().to_vec();
-                // only index key length 1
-                // 0 will break the index separator, ignore
-                if key.len() == 1 && key[0] != 0 {
-                    let v;
+                if is_index_tag(&tag[0], names) {
+                    let name = tag[0].as_bytes().to_vec();
                     // fixed length 32 e and p
-                    if tag[0] == "e" || tag[0] == "p" {
+                    let v = if tag[0] == "e" || tag[0] == "p" {
                         let h = hex::decode(&tag[1])?;
                         if h.len() != 32 {
                             return Err(Error::Invalid("invalid e or p tag value".to_string()));
                         }
-                        v = h;
+                        h
                     } else {
-                        v = tag[1].as_bytes().to_vec();
-                        // 0 will break the index separator, ignore
-                        // lmdb max_key_size 511 bytes
-                        // we only index tag value length < 255
-                        if v.contains(&0) || v.len() > 255 {
-                            continue;
-                        }
+                        // lmdb max_key_size 511 bytes, the long value is indexed by hash
+                        index_value(tag[1].as_bytes()).into_owned()
                     };
-                    t.push((key, v));
+                    let v = protect_value(key, &name, v);
+                    t.push((name, v));
                 }
             }
         }
@@ -238,7 +243,6 @@ struct _Event {
 }
 
 /// The default event document.
-// TODO: validate index tag value length 255
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(try_from = "_Event")]
 pub struct Event {
@@ -256,6 +260,10 @@ pub struct Event {
 
     #[serde(skip)]
     pub words: Vec<Vec<u8>>,
+
+    // the original json text of the event object
+    #[serde(skip)]
+    raw: Option<String>,
 }
 
 impl TryFrom<_Event> for Event {
@@ -274,6 +282,7 @@ impl TryFrom<_Event> for Event {
             )?,
             tags: value.tags,
             words: Default::default(),
+            raw: None,
         };
         Ok(event)
     }
@@ -296,6 +305,7 @@ impl Event {
             sig,
             index,
             words: Default::default(),
+            raw: None,
         };
         Ok(event)
     }
@@ -325,22 +335,26 @@ impl AsRef<Event> for Event {
 impl FromStr for Event {
     type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(serde_json::from_str(s)?)
+        Self::from_raw(s.trim().to_owned())
     }
 }
 
 impl Display for Event {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let str = serde_json::to_string(&self).unwrap();
-        f.write_str(&str)?;
-        Ok(())
+        match &self.raw {
+            Some(raw) => f.write_str(raw),
+            None => f.write_str(&serde_json::to_string(&self).unwrap()),
+        }
     }
 }
 
 impl TryInto<String> for Event {
     type Error = Error;
     fn try_into(self) -> Result<String, Self::Error> {
-        Ok(serde_json::to_string(&self)?)
+        match self.raw {
+            Some(raw) => Ok(raw),
+            None => Ok(serde_json::to_string(&self)?),
+        }
     }
 }
 
@@ -367,54 +381,197 @@ impl FromEventData for Vec<u8> {
 /// Get the json string
 impl FromEventData for String {
     type Err = Error;
-    fn from_data<S: AsRef<[u8]>>(json: S) -> Result<Self, Self::Err> {
-        let (t, bytes) = parse_data_type(json.as_ref());
-        if t == 1 {
-            #[cfg(feature = "zstd")]
-            {
-                let bytes = zstd::decode_all(bytes)?;
-                Ok(unsafe { String::from_utf8_unchecked(bytes) })
-            }
-            #[cfg(not(feature = "zstd"))]
-            {
-                Err(Error::Invalid("Need zstd feature".to_owned()))
-            }
-        } else {
-            Ok(unsafe { String::from_utf8_unchecked(bytes.to_vec()) })
+    fn from_data<S: AsRef<[u8]>>(data: S) -> Result<Self, Self::Err> {
+        let bytes = decode_data(data.as_ref())?;
+        Ok(unsafe { String::from_utf8_unchecked(bytes.into_owned()) })
+    }
+}
+
+// the data type marker at the end of the event data, the json text is sent without decoding
+// and the binary record is rendered to the original json text.
+
+// the json text: plain, compressed by the old versions built with the zstd feature,
+// and compressed with the trained dictionary `[compressed data][dictionary id u32]`
+const DATA_JSON: u8 = 0;
+const DATA_JSON_ZSTD: u8 = 1;
+const DATA_JSON_ZSTD_DICT: u8 = 2;
+// the binary record of [`encode_binary`]: plain and compressed with the trained dictionary
+const DATA_BINARY: u8 = 3;
+const DATA_BINARY_ZSTD_DICT: u8 = 4;
+// the encrypted data with the type marker: `[encrypted data][trailer]` and
+// `[encrypted data][trailer][dictionary id u32]`, see [`DataKey::encrypt`]
+const DATA_ENCRYPTED: u8 = 5;
+const DATA_ENCRYPTED_DICT: u8 = 6;
+
+fn parse_data_type(data: &[u8]) -> (u8, &[u8]) {
+    if !data.is_empty() {
+        let last = data.len() - 1;
+        let t = data[last];
+        // the json data ends with '}'
+        if t <= DATA_ENCRYPTED_DICT {
+            return (t, &data[0..last]);
+        }
+    }
+    (DATA_JSON, data)
+}
+
+// the json text of the stored data
+fn decode_data(data: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+    let (t, bytes) = parse_data_type(data);
+    Ok(match t {
+        DATA_JSON => Cow::Borrowed(bytes),
+        DATA_JSON_ZSTD => Cow::Owned(zstd::decode_all(bytes)?),
+        DATA_JSON_ZSTD_DICT => Cow::Owned(dict::decompress(bytes)?),
+        DATA_BINARY => Cow::Owned(render_binary(bytes)?),
+        DATA_BINARY_ZSTD_DICT => Cow::Owned(render_binary(&dict::decompress(bytes)?)?),
+        DATA_ENCRYPTED | DATA_ENCRYPTED_DICT => {
+            let extra = if t == DATA_ENCRYPTED_DICT { 4 } else { 0 };
+            Cow::Owned(decode_data(&cipher::decrypt(bytes, extra)?)?.into_owned())
+        }
+        _ => return Err(Error::Invalid(format!("unknown event data type {}", t))),
+    })
+}
+
+/// Whether the stored data is compressed with the dictionary
+pub(crate) fn data_dictionary(data: &[u8]) -> Option<u32> {
+    let (t, bytes) = parse_data_type(data);
+    if (t == DATA_JSON_ZSTD_DICT || t == DATA_BINARY_ZSTD_DICT || t == DATA_ENCRYPTED_DICT)
+        && bytes.len() >= 4
+    {
+        Some(u32::from_be_bytes(
+            bytes[bytes.len() - 4..].try_into().unwrap(),
+        ))
+    } else {
+        None
+    }
+}
+
+/// The id of the key encrypting the stored data
+pub(crate) fn data_key_id(data: &[u8]) -> Option<u32> {
+    match parse_data_type(data) {
+        (DATA_ENCRYPTED, bytes) => cipher::key_id(bytes, 0),
+        (DATA_ENCRYPTED_DICT, bytes) => cipher::key_id(bytes, 4),
+        _ => None,
+    }
+}
+
+// `[id 32][pubkey 32][sig 64]`
+const BINARY_VALUES: usize = 128;
+// the values and the offsets u32 of them in the text
+const BINARY_HEADER: usize = BINARY_VALUES + 12;
+
+/// Encode the binary record of the json text, the hex values of the id, pubkey and sig are
+/// stored as bytes and cut out of the text, so the original text can be rendered exactly.
+/// `[id 32][pubkey 32][sig 64][id offset u32][pubkey offset u32][sig offset u32][text]`,
+/// the offsets are the positions of the values in the text without them.
+/// None if the values are not found as the lowercase hex in the text.
+fn encode_binary(json: &[u8], values: [&[u8]; 3]) -> Option<Vec<u8>> {
+    let mut found = [(0usize, 0usize, 0usize); 3];
+    for (i, (key, value)) in ["id", "pubkey", "sig"].iter().zip(values).enumerate() {
+        let hex = hex::encode(value);
+        found[i] = (find_value(json, key, hex.as_bytes())?, hex.len(), i);
+    }
+    found.sort_unstable();
+
+    let mut offsets = [0u32; 3];
+    let mut text = Vec::with_capacity(json.len());
+    let mut last = 0;
+    for (pos, len, i) in found {
+        if pos < last {
+            return None;
+        }
+        text.extend_from_slice(&json[last..pos]);
+        offsets[i] = text.len() as u32;
+        last = pos + len;
+    }
+    text.extend_from_slice(&json[last..]);
+
+    let mut buf = Vec::with_capacity(BINARY_HEADER + text.len());
+    for value in values {
+        buf.extend_from_slice(value);
+    }
+    for offset in offsets {
+        buf.extend_from_slice(&offset.to_be_bytes());
+    }
+    buf.extend_from_slice(&text);
+    Some(buf)
+}
+
+// the position of the hex string value of the key in the json object
+fn find_value(json: &[u8], key: &str, hex: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while let Some(i) = json[start..].windows(hex.len()).position(|w| w == hex) {
+        let pos = start + i;
+        if is_value_of(&json[..pos], key) {
+            return Some(pos);
         }
+        start = pos + 1;
+    }
+    None
+}
+
+// the text before the value ends with `"key":"`, the quotes in the strings are escaped
+fn is_value_of(before: &[u8], key: &str) -> bool {
+    fn trim_end(s: &[u8]) -> &[u8] {
+        let len = s
+            .iter()
+            .rposition(|c| !c.is_ascii_whitespace())
+            .map_or(0, |i| i + 1);
+        &s[..len]
     }
+    let Some(before) = before.strip_suffix(b"\"") else {
+        return false;
+    };
+    let Some(before) = trim_end(before).strip_suffix(b":") else {
+        return false;
+    };
+    trim_end(before)
+        .strip_suffix(b"\"")
+        .and_then(|b| b.strip_suffix(key.as_bytes()))
+        .is_some_and(|b| b.ends_with(b"\""))
 }
 
-fn parse_data_type(json: &[u8]) -> (u8, &[u8]) {
-    if !json.is_empty() {
-        let last = json.len() - 1;
-        let t = json[last];
-        if t == 0 || t == 1 {
-            return (t, &json[0..last]);
+/// Render the json text of the binary record of [`encode_binary`]
+fn render_binary(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    if bytes.len() < BINARY_HEADER {
+        return Err(Error::InvalidLength);
+    }
+    let text = &bytes[BINARY_HEADER..];
+    let mut values = [
+        (0usize, &bytes[0..32]),
+        (0, &bytes[32..64]),
+        (0, &bytes[64..BINARY_VALUES]),
+    ];
+    for (i, value) in values.iter_mut().enumerate() {
+        let start = BINARY_VALUES + i * 4;
+        value.0 = u32::from_be_bytes(bytes[start..start + 4].try_into()?) as usize;
+    }
+    values.sort_unstable();
+
+    let mut json = Vec::with_capacity(text.len() + BINARY_VALUES * 2);
+    let mut last = 0;
+    for (offset, value) in values {
+        if offset > text.len() {
+            return Err(Error::InvalidLength);
         }
+        json.extend_from_slice(&text[last..offset]);
+        json.extend_from_slice(hex::encode(value).as_bytes());
+        last = offset;
     }
-    (0, json)
+    json.extend_from_slice(&text[last..]);
+    Ok(json)
 }
 
-/// Parse the json string to event object
+/// Parse the stored data to event object
 impl FromEventData for Event {
     type Err = Error;
-    /// decode the json data to event object
-    fn from_data<S: AsRef<[u8]>>(json: S) -> Result<Self, Self::Err> {
-        let (t, bytes) = parse_data_type(json.as_ref());
-        if t == 1 {
-            #[cfg(feature = "zstd")]
-            {
-                let bytes = zstd::decode_all(bytes)?;
-                Ok(serde_json::from_slice(&bytes)?)
-            }
-            #[cfg(not(feature = "zstd"))]
-            {
-                Err(Error::Invalid("Need zstd feature".to_owned()))
-            }
-        } else {
-            Ok(serde_json::from_slice(bytes)?)
-        }
+    /// decode the json data to event object, keep the stored json text
+    fn from_data<S: AsRef<[u8]>>(data: S) -> Result<Self, Self::Err> {
+        let bytes = decode_data(data.as_ref())?;
+        Event::from_raw(
+            String::from_utf8(bytes.into_owned())
+                .map_err(|e| Error::Deserialization(e.to_string()))?,
+        )
     }
 }
 
@@ -430,15 +587,105 @@ impl Event {
 }
 
 impl Event {
-    /// to json string
+    /// Parse the json text of the event object, the text is kept as the original json
+    pub fn from_raw(raw: String) -> Result<Self, Error> {
+        let mut event: Event = serde_json::from_str(&raw)?;
+        event.raw = Some(raw);
+        Ok(event)
+    }
+
+    /// The original json text of the event object, present when the event is parsed from json
+    pub fn raw(&self) -> Option<&str> {
+        self.raw.as_deref()
+    }
+
+    /// to json string, the original json text is preferred
     pub fn to_json(&self) -> Result<String, Error> {
-        Ok(serde_json::to_string(&self)?)
+        match &self.raw {
+            Some(raw) => Ok(raw.clone()),
+            None => Ok(serde_json::to_string(&self)?),
+        }
+    }
+
+    /// The uncompressed json record to store, the original json text is preferred
+    pub(crate) fn record(&self) -> Result<Cow<'_, [u8]>, Error> {
+        Ok(match &self.raw {
+            Some(raw) => Cow::Borrowed(raw.as_bytes()),
+            None => Cow::Owned(serde_json::to_vec(&self)?),
+        })
+    }
+
+    /// The binary record to store, the json text is rendered from it when read.
+    /// None if the hex values of the id, pubkey and sig are not found in the json record.
+    pub(crate) fn binary_record(&self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(encode_binary(
+            &self.record()?,
+            [self.id(), self.pubkey(), self.sig()],
+        ))
+    }
+
+    /// Encode the record to the stored data with the data type marker at the end,
+    /// the binary record is preferred to the json record,
+    /// compress with the trained dictionary and encrypt with the key if present.
+    /// The small events are only compressed well with the dictionary, see [`crate::Db::train_dictionary`].
+    /// Return the data and the size of the uncompressed json record.
+    pub(crate) fn encode_data(
+        &self,
+        dict: Option<&Dictionary>,
+        key: Option<&DataKey>,
+    ) -> Result<(Vec<u8>, usize), Error> {
+        let json = self.record()?;
+        let len = json.len();
+        let (bytes, binary) = match encode_binary(&json, [self.id(), self.pubkey(), self.sig()]) {
+            Some(bytes) => (Cow::Owned(bytes), true),
+            None => (json, false),
+        };
+        let (mut data, t) = if let Some(dict) = dict {
+            let t = if binary {
+                DATA_BINARY_ZSTD_DICT
+            } else {
+                DATA_JSON_ZSTD_DICT
+            };
+            (dict.compress(&bytes)?, t)
+        } else if binary {
+            (bytes.into_owned(), DATA_BINARY)
+        } else {
+            (bytes.into_owned(), DATA_JSON)
+        };
+        data.push(t);
+        if let Some(key) = key {
+            // keep the dictionary id readable for the stats
+            data = match data_dictionary(&data) {
+                Some(id) => {
+                    let mut data = key.encrypt(&data, &id.to_be_bytes());
+                    data.push(DATA_ENCRYPTED_DICT);
+                    data
+                }
+                None => {
+                    let mut data = key.encrypt(&data, &[]);
+                    data.push(DATA_ENCRYPTED);
+                    data
+                }
+            };
+        }
+        Ok((data, len))
     }
 
     pub fn index(&self) -> &EventIndex {
         &self.index
     }
 
+    /// Index the additional tag names of the database too and hash the values with its tag key,
+    /// only the single-letter plain tags are indexed when parsed
+    pub fn reindex(&mut self, index_tags: &IndexTags) -> Result<(), Error> {
+        let names = index_tags.read();
+        let key = index_tags.key();
+        if !names.is_empty() || key.is_some() {
+            self.index.tags = EventIndex::build_index_tags(&self.tags, &names, key.as_ref())?.0;
+        }
+        Ok(())
+    }
+
     pub fn id(&self) -> &[u8; 32] {
         &self.index.id
     }
@@ -467,6 +714,15 @@ impl Event {
         &self.tags
     }
 
+    /// Parse the coordinates of the `a` tags, the malformed values are ignored
+    pub fn coordinates(&self) -> Vec<Coordinate> {
+        self.tags
+            .iter()
+            .filter(|tag| tag.len() > 1 && tag[0] == "a")
+            .filter_map(|tag| Coordinate::from_str(&tag[1]).ok())
+            .collect()
+    }
+
     pub fn content(&self) -> &String {
         &self.content
     }
@@ -556,15 +812,24 @@ impl Event {
         }
     }
 
-    pub fn validate(&self, now: u64, older: u64, newer: u64) -> Result<(), Error> {
+    /// The cheap checks of the expiration and the creation time
+    pub fn check(&self, now: u64, older: u64, newer: u64) -> Result<(), Error> {
         if self.index.is_expired(now) {
             return Err(Error::Invalid("event is expired".to_owned()));
         }
-        self.verify_time(now, older, newer)?;
+        self.verify_time(now, older, newer)
+    }
+
+    /// Verify the id hash, the signature and the delegation, it's expensive
+    pub fn verify(&self) -> Result<(), Error> {
         self.verify_id()?;
         self.verify_sign()?;
-        self.verify_delegation()?;
-        Ok(())
+        self.verify_delegation()
+    }
+
+    pub fn validate(&self, now: u64, older: u64, newer: u64) -> Result<(), Error> {
+        self.check(now, older, newer)?;
+        self.verify()
     }
 }
 
@@ -633,6 +898,86 @@ mod tests {
     use serde_json::Value;
     use std::str::FromStr;
 
+    #[test]
+    fn event_data() -> Result<()> {
+        let note = r#"{"content":"Good morning everyone 😃","created_at":1680690006,"id":"332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d","kind":1,"pubkey":"7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef","sig":"ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f","tags":[["t","nostr"],["p"],[]]}"#;
+        let event = Event::from_str(&format!(" {}\n", note))?;
+        let e2 = Event::new(
+            *event.id(),
+            *event.pubkey(),
+            event.created_at(),
+            event.kind(),
+            event.tags().clone(),
+            event.content().clone(),
+            *event.sig(),
+        )?;
+        assert_eq!(e2.raw(), None);
+        assert_eq!(e2.to_json()?, serde_json::to_string(&event)?);
+
+        // the binary record without the hex values
+        let bytes = event.binary_record()?.unwrap();
+        assert_eq!(bytes.len(), note.len() - 256 + BINARY_HEADER);
+        assert_eq!(&bytes[0..32], event.id());
+        assert_eq!(render_binary(&bytes)?, note.as_bytes());
+        assert!(render_binary(&bytes[0..BINARY_HEADER - 1]).is_err());
+        let mut data = bytes.clone();
+        data.push(DATA_BINARY);
+        assert_eq!(String::from_data(&data)?, note);
+        assert_eq!(Event::from_data(&data)?.raw(), Some(note));
+
+        // the values are located by the keys, the pubkey in the tags and the content is kept
+        let pubkey = event.pubkey_str();
+        let json = format!(
+            r#"{{"content":"\"pubkey\":\"{pubkey}\"","tags":[["p","{pubkey}"]], "pubkey" : "{pubkey}","id":"{}","sig":"{}","kind":1,"created_at":1}}"#,
+            event.id_str(),
+            hex::encode(event.sig())
+        );
+        let bytes =
+            encode_binary(json.as_bytes(), [event.id(), event.pubkey(), event.sig()]).unwrap();
+        assert_eq!(bytes.len(), json.len() - 256 + BINARY_HEADER);
+        assert_eq!(render_binary(&bytes)?, json.as_bytes());
+        // the uppercase hex is kept in the json record
+        let upper = note.replace(&event.id_str(), &event.id_str().to_uppercase());
+        assert!(
+            encode_binary(upper.as_bytes(), [event.id(), event.pubkey(), event.sig()]).is_none()
+        );
+
+        // the rendered json is stored without the original text
+        let (data, len) = e2.encode_data(None, None)?;
+        assert_eq!(len, e2.to_json()?.len());
+        assert_eq!(parse_data_type(&data).0, DATA_BINARY);
+        assert_eq!(String::from_data(&data)?, e2.to_json()?);
+
+        // the original json is stored exactly
+        assert_eq!(event.raw(), Some(note));
+        assert_eq!(event.to_json()?, note);
+        let (data, len) = event.encode_data(None, None)?;
+        assert_eq!(len, note.len());
+        assert!(data.len() < note.len());
+        assert_eq!(String::from_data(&data)?, note);
+        assert_eq!(Event::from_data(&data)?.raw(), Some(note));
+
+        // the old json data
+        let json = event.to_json()?;
+        assert_eq!(Event::from_data(&json)?.id(), event.id());
+        assert_eq!(String::from_data(&json)?, json);
+        let mut zstd_json = zstd::encode_all(json.as_bytes(), 5)?;
+        zstd_json.push(DATA_JSON_ZSTD);
+        assert_eq!(String::from_data(&zstd_json)?, json);
+
+        // encrypted data
+        let key = cipher::register_key(DataKey::generate());
+        let (data, _) = event.encode_data(None, Some(&key))?;
+        assert_eq!(data_key_id(&data), Some(key.id));
+        assert_eq!(data_dictionary(&data), None);
+        assert!(!data.windows(5).any(|w| w == b"nostr"));
+        assert_eq!(String::from_data(&data)?, note);
+        let (data, _) = e2.encode_data(None, Some(&key))?;
+        assert_eq!(Event::from_data(&data)?.to_json()?, e2.to_json()?);
+        assert_eq!(data_key_id(&event.encode_data(None, None)?.0), None);
+        Ok(())
+    }
+
     #[test]
     fn index_event() -> Result<()> {
         let note = r#"
@@ -1,8 +1,18 @@
-use crate::{error::Error, ArchivedEventIndex, EventIndex};
+use crate::{
+    error::Error,
+    tag::{
+        index_value, is_hashed, is_index_tag, is_tag_name, protect_value, Coordinate, IndexTags,
+    },
+    ArchivedEventIndex, EventIndex,
+};
 use serde::Deserialize;
 use serde_json::Value;
 use std::cmp::Ord;
-use std::{collections::HashMap, ops::Deref, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Deref,
+    str::FromStr,
+};
 
 /// The sort list contains unduplicated and sorted items
 #[derive(PartialEq, Eq, Debug, Clone, Default)]
@@ -16,6 +26,17 @@ impl<T: Ord> From<Vec<T>> for SortList<T> {
     }
 }
 
+/// The full 32 bytes ids
+impl From<Vec<[u8; 32]>> for SortList<Vec<u8>> {
+    fn from(value: Vec<[u8; 32]>) -> Self {
+        value
+            .into_iter()
+            .map(|v| v.to_vec())
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
 impl<T: Ord> SortList<T> {
     pub fn contains(&self, item: &T) -> bool {
         self.binary_search(item).is_ok()
@@ -36,6 +57,48 @@ impl<T> Deref for SortList<T> {
     }
 }
 
+/// The position of an event in the iteration order, an opaque hex string for the clients.
+///
+/// The events of the same second are ordered by the uid, the archived events have no uid
+/// and are ordered by the id after the stored ones.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Cursor {
+    pub time: u64,
+    pub uid: u64,
+    /// the id of the archived event
+    pub id: Option<[u8; 32]>,
+}
+
+impl FromStr for Cursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s)?;
+        if bytes.len() != 16 && bytes.len() != 48 {
+            return Err(Error::Invalid("invalid cursor".to_owned()));
+        }
+        Ok(Self {
+            time: u64::from_be_bytes(bytes[0..8].try_into()?),
+            uid: u64::from_be_bytes(bytes[8..16].try_into()?),
+            id: if bytes.len() == 48 {
+                Some(bytes[16..48].try_into()?)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut bytes = [self.time.to_be_bytes(), self.uid.to_be_bytes()].concat();
+        if let Some(id) = &self.id {
+            bytes.extend_from_slice(id);
+        }
+        f.write_str(&hex::encode(bytes))
+    }
+}
+
 /// Events filter
 ///
 /// [NIP-01](https://nips.be/1)
@@ -44,11 +107,11 @@ impl<T> Deref for SortList<T> {
 #[derive(PartialEq, Eq, Debug, Clone, Default, Deserialize)]
 #[serde(try_from = "_Filter")]
 pub struct Filter {
-    /// a list of event ids
-    pub ids: SortList<[u8; 32]>,
+    /// a list of event ids or prefixes
+    pub ids: SortList<Vec<u8>>,
 
-    /// a list of pubkeys, the pubkey of an event must be one of these
-    pub authors: SortList<[u8; 32]>,
+    /// a list of pubkeys or prefixes, the pubkey of an event must be one of these
+    pub authors: SortList<Vec<u8>>,
 
     /// a list of a kind numbers
     pub kinds: SortList<u16>,
@@ -64,11 +127,35 @@ pub struct Filter {
     ///
     pub tags: HashMap<Vec<u8>, SortList<Vec<u8>>>,
 
+    /// The original values of the tags which have the long values indexed by hash,
+    /// the events found by the hash are verified with them
+    pub long_tags: HashMap<Vec<u8>, SortList<String>>,
+
+    /// The tags of the names longer than a letter, queried only if the names are indexed
+    /// by the database, see [`Filter::index_tags`]
+    pub named_tags: HashMap<String, Vec<String>>,
+
     /// Query by time descending order
     pub desc: bool,
 
+    /// Include the superseded versions of the replaceable events kept in the history
+    pub history: bool,
+
+    /// Resume after the position of the last returned event, the extension field `"cursor"`
+    pub cursor: Option<Cursor>,
+
+    /// Whether the position should be reported, the field `"cursor"` is set even if empty
+    pub paginate: bool,
+
+    /// The shortest hex length of the ids and authors prefixes as requested,
+    /// the odd length prefixes are expanded so it is lost in the decoded bytes
+    pub prefix_len: Option<usize>,
+
     #[serde(skip)]
     pub words: Vec<Vec<u8>>,
+
+    /// The tag values before hashed with the tag key of the database, set by [`Filter::index_tags`]
+    pub plain_tags: Option<HashMap<Vec<u8>, SortList<Vec<u8>>>>,
 }
 
 impl FromStr for Filter {
@@ -89,15 +176,51 @@ struct _Filter {
     pub limit: Option<u64>,
     pub keywords: Vec<String>,
     pub search: Option<String>,
+    pub history: bool,
+    pub cursor: Option<String>,
     #[serde(flatten)]
     pub tags: HashMap<String, Value>,
 }
 
+/// The full 32 bytes hex or a hex prefix
 #[derive(Deserialize)]
 #[serde(transparent)]
-struct _HexString {
-    #[serde(with = "hex::serde")]
-    hex: [u8; 32],
+struct _HexString(String);
+
+impl _HexString {
+    /// Decode to the byte prefixes, the odd length prefix is expanded to 16 prefixes
+    fn prefixes(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let s = self.0.as_str();
+        if s.is_empty() || s.len() > 64 {
+            return Err(Error::Invalid("invalid hex prefix length".to_string()));
+        }
+        // the string is sliced by the byte index
+        if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::Invalid("invalid hex prefix".to_string()));
+        }
+        if s.len() & 1 == 0 {
+            return Ok(vec![hex::decode(s)?]);
+        }
+        let last = s.len() - 1;
+        let mut bytes = hex::decode(&s[0..last])?;
+        let nibble = u8::from_str_radix(&s[last..], 16)?;
+        bytes.push(nibble << 4);
+        Ok((0..16)
+            .map(|i| {
+                let mut prefix = bytes.clone();
+                prefix[last / 2] |= i;
+                prefix
+            })
+            .collect())
+    }
+}
+
+fn decode_prefixes(list: Vec<_HexString>) -> Result<SortList<Vec<u8>>, Error> {
+    let mut prefixes = vec![];
+    for item in list {
+        prefixes.append(&mut item.prefixes()?);
+    }
+    Ok(prefixes.into())
 }
 
 impl TryFrom<_Filter> for Filter {
@@ -111,17 +234,27 @@ impl TryFrom<_Filter> for Filter {
 
         // only use valid tag, has prefix "#", string item, not empty
         let mut tags = HashMap::new();
+        let mut long_tags = HashMap::new();
+        let mut named_tags = HashMap::new();
         for item in filter.tags {
             let key = item.0;
-            if let Some(key) = key.strip_prefix('#') {
-                let key = key.as_bytes();
-                // only index for key len 1
-                if key.len() == 1 {
+            if let Some(name) = key.strip_prefix('#') {
+                let key = name.as_bytes();
+                if !is_index_tag(name, &HashSet::new()) {
+                    if is_tag_name(name) {
+                        if let Ok(values) = Vec::<String>::deserialize(&item.1) {
+                            if !values.is_empty() {
+                                named_tags.insert(name.to_owned(), values);
+                            }
+                        }
+                    }
+                } else {
                     let val = Vec::<String>::deserialize(&item.1)?;
                     let mut list = vec![];
-                    for s in val {
+                    let mut hashed = false;
+                    for s in val.iter() {
                         if key == b"e" || key == b"p" {
-                            let h = hex::decode(&s)?;
+                            let h = hex::decode(s)?;
                             if h.len() != 32 {
                                 // ignore
                                 return Err(Error::Invalid("invalid e or p tag value".to_string()));
@@ -129,41 +262,51 @@ impl TryFrom<_Filter> for Filter {
                                 list.push(h);
                             }
                         } else {
-                            list.push(s.into_bytes());
-                            // if s.len() < 255 {
-                            // } else {
-                            //     return Err(Error::Invald("invalid value length".to_string()));
-                            // }
+                            if key == b"a" {
+                                Coordinate::from_str(s)?;
+                            }
+                            let v = index_value(s.as_bytes());
+                            hashed |= is_hashed(&v);
+                            list.push(v.into_owned());
                         }
                     }
                     if !list.is_empty() {
+                        if hashed {
+                            long_tags.insert(key.to_vec(), val.into());
+                        }
                         tags.insert(key.to_vec(), list.into());
                     }
                 }
             }
         }
 
+        let prefix_len = filter
+            .ids
+            .iter()
+            .chain(filter.authors.iter())
+            .map(|s| s.0.len())
+            .min();
         let f = Filter {
-            ids: filter
-                .ids
-                .into_iter()
-                .map(|s| s.hex)
-                .collect::<Vec<_>>()
-                .into(),
-            authors: filter
-                .authors
-                .into_iter()
-                .map(|s| s.hex)
-                .collect::<Vec<_>>()
-                .into(),
+            prefix_len,
+            ids: decode_prefixes(filter.ids)?,
+            authors: decode_prefixes(filter.authors)?,
             kinds: filter.kinds.into(),
             since: filter.since,
             until: filter.until,
             limit: filter.limit,
             search,
             tags,
+            long_tags,
+            named_tags,
             desc: filter.limit.is_some(),
+            history: filter.history,
+            paginate: filter.cursor.is_some(),
+            cursor: match filter.cursor.as_deref() {
+                None | Some("") => None,
+                Some(s) => Some(Cursor::from_str(s)?),
+            },
             words: vec![],
+            plain_tags: None,
         };
 
         Ok(f)
@@ -182,6 +325,20 @@ impl Filter {
         }
     }
 
+    /// Narrow the time range to start from the cursor time
+    pub fn resumed(&self) -> Filter {
+        let mut filter = self.clone();
+        if let Some(cursor) = &self.cursor {
+            let time = cursor.time;
+            if self.desc {
+                filter.until = Some(self.until.map_or(time, |t| t.min(time)));
+            } else {
+                filter.since = Some(self.since.map_or(time, |t| t.max(time)));
+            }
+        }
+        filter
+    }
+
     pub fn default_limit(&mut self, limit: u64) {
         if self.limit.is_none() {
             self.limit = Some(limit);
@@ -189,32 +346,95 @@ impl Filter {
     }
 
     pub fn set_tags(&mut self, tags: HashMap<String, Vec<String>>) {
-        let mut t = HashMap::new();
-        for item in tags {
-            let key = item.0.into_bytes();
-            // only index for key len 1
-            if key.len() == 1 {
-                let val = item
-                    .1
-                    .into_iter()
-                    .map(|s| s.into_bytes())
-                    // only index tag value length < 255
-                    .filter(|s| s.len() < 255)
-                    .collect::<Vec<_>>();
-                if !key.is_empty() && !val.is_empty() {
-                    t.insert(key, val.into());
-                }
+        self.tags.clear();
+        self.plain_tags = None;
+        self.long_tags.clear();
+        self.named_tags.clear();
+        for (name, values) in tags {
+            if values.is_empty() {
+                continue;
             }
+            if is_index_tag(&name, &HashSet::new()) {
+                self.insert_tag(name, values);
+            } else if is_tag_name(&name) {
+                self.named_tags.insert(name, values);
+            }
+        }
+    }
+
+    /// Query the named tags indexed by the database too, the others are ignored.
+    /// The tag values are hashed with the tag key of the database if set,
+    /// the filter can be indexed by another database again.
+    pub fn index_tags(&mut self, index_tags: &IndexTags) {
+        if let Some(tags) = self.plain_tags.take() {
+            self.tags = tags;
+        }
+        let indexed = {
+            let names = index_tags.read();
+            self.named_tags
+                .iter()
+                .filter(|(name, _)| names.contains(*name))
+                .map(|(name, values)| (name.clone(), values.clone()))
+                .collect::<Vec<_>>()
+        };
+        for (name, values) in indexed {
+            self.insert_tag(name, values);
+        }
+        if let Some(key) = index_tags.key() {
+            let hashed = self
+                .tags
+                .iter()
+                .map(|(name, values)| {
+                    let values = values
+                        .iter()
+                        .map(|v| protect_value(Some(&key), name, v.clone()))
+                        .collect::<Vec<_>>();
+                    (name.clone(), values.into())
+                })
+                .collect();
+            self.plain_tags = Some(std::mem::replace(&mut self.tags, hashed));
+        }
+    }
+
+    fn insert_tag(&mut self, name: String, values: Vec<String>) {
+        let key = name.into_bytes();
+        let val = values
+            .iter()
+            .map(|s| index_value(s.as_bytes()).into_owned())
+            .collect::<Vec<_>>();
+        let hashed = val.iter().any(|v| is_hashed(v));
+        if hashed {
+            self.long_tags.insert(key.clone(), values.into());
         }
-        self.tags = t;
+        self.tags.insert(key, val.into());
     }
 
-    pub fn match_id(ids: &SortList<[u8; 32]>, id: &[u8; 32]) -> bool {
-        ids.is_empty() || ids.contains(id)
+    /// Verify the original values of the long tags indexed by hash
+    pub fn verify_long_tags(&self, tags: &[Vec<String>]) -> bool {
+        self.long_tags.iter().all(|(name, values)| {
+            tags.iter().any(|tag| {
+                tag.len() > 1 && tag[0].as_bytes() == name.as_slice() && values.contains(&tag[1])
+            })
+        })
+    }
+
+    /// Match the full id or the prefixes
+    pub fn match_id(ids: &SortList<Vec<u8>>, id: &[u8; 32]) -> bool {
+        ids.is_empty() || ids.contains2(id) || ids.iter().any(|p| p.len() < 32 && id.starts_with(p))
+    }
+
+    /// Whether the history query is bounded by the full authors and the kinds
+    pub fn is_history_bounded(&self) -> bool {
+        !self.authors.is_empty() && Self::is_full(&self.authors) && !self.kinds.is_empty()
+    }
+
+    /// Whether all the items are full 32 bytes, not prefixes
+    pub fn is_full(ids: &SortList<Vec<u8>>) -> bool {
+        ids.iter().all(|p| p.len() == 32)
     }
 
     pub fn match_author(
-        authors: &SortList<[u8; 32]>,
+        authors: &SortList<Vec<u8>>,
         pubkey: &[u8; 32],
         delegator: Option<&[u8; 32]>,
     ) -> bool {
@@ -299,7 +519,7 @@ mod tests {
     use std::{collections::HashMap, str::FromStr};
 
     use super::Filter;
-    use crate::{filter::SortList, ArchivedEventIndex, Event, EventIndex};
+    use crate::{filter::SortList, tag::IndexTags, ArchivedEventIndex, Event, EventIndex};
     use anyhow::Result;
 
     #[test]
@@ -328,7 +548,7 @@ mod tests {
           }
         "###;
         let mut filter: Filter = serde_json::from_str(note)?;
-        let li = SortList::from(vec![[0x12; 32], [0xab; 32], [0xcd; 32]]);
+        let li = SortList::from(vec![vec![0x12; 32], vec![0xab; 32], vec![0xcd; 32]]);
         let tags: SortList<Vec<u8>> = ["ab", "cd", "12"]
             .iter()
             .map(|s| s.as_bytes().to_vec())
@@ -336,6 +556,7 @@ mod tests {
             .into();
         assert_eq!(&filter.ids, &li);
         assert_eq!(&filter.authors, &li);
+        assert!(Filter::is_full(&filter.ids));
         assert_eq!(&filter.kinds, &SortList::from(vec![1, 2]));
         assert_eq!(filter.until, Some(5));
         assert_eq!(filter.since, Some(3));
@@ -387,6 +608,52 @@ mod tests {
         );
         assert!(filter.tags.get(&"d".to_string().into_bytes()).is_none());
 
+        // the additional names are queried only if indexed
+        let mut filter =
+            Filter::from_str(r##"{"#alt": ["ab"], "#poll_r": ["cd"], "#x\u0000": ["ef"]}"##)?;
+        assert!(filter.tags.is_empty());
+        assert_eq!(filter.named_tags.len(), 2);
+        let names = IndexTags::default();
+        names.set(["alt"])?;
+        filter.index_tags(&names);
+        assert_eq!(
+            filter.tags.get(b"alt".as_slice()),
+            Some(&SortList::from(vec![b"ab".to_vec()]))
+        );
+        assert!(!filter.tags.contains_key(b"poll_r".as_slice()));
+
+        // the values are hashed by the tag key, indexed again from the plain values
+        names.set_key(Some([1; 32]));
+        filter.index_tags(&names);
+        let hashed = filter.tags.get(b"alt".as_slice()).cloned();
+        assert_ne!(hashed, Some(SortList::from(vec![b"ab".to_vec()])));
+        filter.index_tags(&names);
+        assert_eq!(filter.tags.get(b"alt".as_slice()).cloned(), hashed);
+        names.set_key(None);
+        filter.index_tags(&names);
+        assert_eq!(
+            filter.tags.get(b"alt".as_slice()),
+            Some(&SortList::from(vec![b"ab".to_vec()]))
+        );
+
+        // prefix
+        let filter = Filter::from_str(r#"{"ids": ["abcd", "abc"], "authors": ["12"]}"#)?;
+        assert_eq!(filter.ids.len(), 16);
+        assert!(filter.ids.contains(&vec![0xab, 0xcd]));
+        assert!(filter.ids.contains(&vec![0xab, 0xc0]));
+        assert!(filter.ids.contains(&vec![0xab, 0xcf]));
+        assert_eq!(&filter.authors, &SortList::from(vec![vec![0x12]]));
+        assert_eq!(filter.prefix_len, Some(2));
+        assert!(!Filter::is_full(&filter.ids));
+        let filter = Filter::from_str(r#"{"ids": ["abcd", "abc"]}"#)?;
+        assert_eq!(filter.prefix_len, Some(3));
+        assert_eq!(Filter::from_str("{}")?.prefix_len, None);
+        assert!(Filter::from_str(r#"{"ids": ["xy"]}"#).is_err());
+        assert!(Filter::from_str(r#"{"ids": ["aé"]}"#).is_err());
+        assert!(Filter::from_str(r#"{"authors": ["abé"]}"#).is_err());
+        assert!(Filter::from_str(r#"{"authors": ["+a"]}"#).is_err());
+        assert!(Filter::from_str(r#"{"ids": [""]}"#).is_err());
+
         // search
         let note = r###"
         {
@@ -550,6 +817,30 @@ mod tests {
             archived,
         )?;
 
+        // prefix
+        check_match(
+            r###"
+        {
+            "ids": ["332747c0fa", "abab"],
+            "authors": ["7abf5"]
+        }
+        "###,
+            true,
+            &event,
+            archived,
+        )?;
+
+        check_match(
+            r###"
+        {
+            "authors": ["7abf6"]
+        }
+        "###,
+            false,
+            &event,
+            archived,
+        )?;
+
         Ok(())
     }
 
@@ -0,0 +1,70 @@
+//! Historical versions of the replaceable events.
+//!
+//! The superseded versions are moved out of the indexes to the history tree when the
+//! history is enabled, keyed by `[replace key][created_at u64][id 32 bytes]`.
+
+use std::collections::HashMap;
+
+/// The options of keeping the superseded versions of the replaceable events
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryOptions {
+    /// The retention seconds of the versions, none to keep forever
+    pub retention: Option<u64>,
+    /// The retention seconds of the kinds instead of the default, `Some(0)` to not keep the versions
+    pub kinds: HashMap<u16, Option<u64>>,
+}
+
+impl HistoryOptions {
+    /// The retention seconds of the kind
+    pub fn retention(&self, kind: u16) -> Option<u64> {
+        self.kinds.get(&kind).copied().unwrap_or(self.retention)
+    }
+
+    /// Whether the versions of the kind are kept
+    pub fn keep(&self, kind: u16) -> bool {
+        self.retention(kind) != Some(0)
+    }
+
+    /// Whether the version created at the time is expired
+    pub fn expired(&self, kind: u16, created_at: u64, now: u64) -> bool {
+        self.retention(kind)
+            .is_some_and(|r| created_at.saturating_add(r) < now)
+    }
+}
+
+pub(crate) fn encode_history_key(replace_key: &[u8], time: u64, id: &[u8]) -> Vec<u8> {
+    [replace_key, &time.to_be_bytes()[..], id].concat()
+}
+
+/// Split the history key to the replace key and created time
+pub(crate) fn decode_history_key(key: &[u8]) -> Option<(&[u8], u64)> {
+    // pubkey and kind at least
+    if key.len() < 32 + 2 + 8 + 32 {
+        return None;
+    }
+    let (replace_key, tail) = key.split_at(key.len() - 40);
+    Some((replace_key, u64::from_be_bytes(tail[0..8].try_into().ok()?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options() {
+        let options = HistoryOptions {
+            retention: Some(10),
+            kinds: HashMap::from([(0, None), (3, Some(0))]),
+        };
+        assert!(options.keep(0));
+        assert!(!options.keep(3));
+        assert!(options.keep(30023));
+        assert!(!options.expired(0, 1, 100));
+        assert!(options.expired(30023, 1, 100));
+        assert!(!options.expired(30023, 95, 100));
+
+        let key = encode_history_key(&[1; 35], 20, &[2; 32]);
+        assert_eq!(decode_history_key(&key), Some((&[1u8; 35][..], 20)));
+        assert_eq!(decode_history_key(&key[1..40]), None);
+    }
+}
@@ -26,6 +26,10 @@ pub struct IndexKey {
 }
 
 impl IndexKey {
+    pub fn new(time: u64, uid: u64) -> Self {
+        Self { time, uid }
+    }
+
     pub fn encode_time(time: u64) -> Vec<u8> {
         time.to_be_bytes().to_vec()
     }
@@ -95,6 +99,32 @@ impl TimeKey for IndexKey {
     }
 }
 
+// index cardinality statistics key type
+pub const STAT_TOTAL: u8 = 0;
+pub const STAT_KIND: u8 = 1;
+pub const STAT_PUBKEY: u8 = 2;
+pub const STAT_PUBKEY_KIND: u8 = 3;
+pub const STAT_TAG: u8 = 4;
+pub const STAT_WORD: u8 = 5;
+// event statistics key type, the value is the number of events and bytes
+pub const STAT_EVENT_TOTAL: u8 = 6;
+pub const STAT_EVENT_KIND: u8 = 7;
+pub const STAT_EVENT_AUTHOR: u8 = 8;
+// the bytes of the uncompressed records
+pub const STAT_EVENT_RAW: u8 = 9;
+// the number of events and bytes compressed with the dictionary
+pub const STAT_EVENT_DICT: u8 = 10;
+
+/// The statistics key of an index key prefix, the prefix is the index key without time
+pub fn encode_stat_key<K: AsRef<[u8]>>(t: u8, prefix: K) -> Vec<u8> {
+    [&[t][..], prefix.as_ref()].concat()
+}
+
+/// The statistics key of an index key, remove the time at the end
+pub fn index_stat_key(t: u8, key: &[u8]) -> Vec<u8> {
+    encode_stat_key(t, &key[0..key.len() - 8])
+}
+
 pub fn u64_to_ver(num: u64) -> Vec<u8> {
     num.to_be_bytes().to_vec()
 }
@@ -113,15 +143,12 @@ pub fn encode_replace_key(kind: u16, pubkey: &[u8; 32], tags: &[Vec<String>]) ->
     } else if (30_000..40_000).contains(&kind) {
         let k = u16_to_ver(kind);
         let p: &[u8] = pubkey.as_ref();
+        // the first `d` tag in any position, the missing value is empty
         let tag = tags
-            .get(0)
-            .map(|tag| {
-                if tag.len() > 1 && tag[0] == "d" {
-                    tag.get(1).unwrap().clone()
-                } else {
-                    "".to_owned()
-                }
-            })
+            .iter()
+            .find(|tag| tag.first().is_some_and(|name| name == "d"))
+            .and_then(|tag| tag.get(1))
+            .map(|v| v.as_str())
             .unwrap_or_default();
         Some([p, &k[..], tag.as_bytes()].concat())
     } else {
@@ -207,6 +234,19 @@ mod tests {
         assert_eq!(ind.uid, uid_num);
         assert_eq!(ind.time, time);
 
+        assert_eq!(
+            index_stat_key(STAT_TOTAL, &IndexKey::encode_time(time)),
+            encode_stat_key(STAT_TOTAL, [])
+        );
+        assert_eq!(
+            index_stat_key(STAT_KIND, &IndexKey::encode_kind(kind, time)),
+            encode_stat_key(STAT_KIND, u16_to_ver(kind))
+        );
+        assert_eq!(
+            index_stat_key(STAT_TAG, &IndexKey::encode_tag(tag_key, tag_val, time)),
+            encode_stat_key(STAT_TAG, concat_sep(concat_sep(tag_key, tag_val), []))
+        );
+
         Ok(())
     }
 
@@ -240,5 +280,15 @@ mod tests {
         assert_eq!(r.1, 30001);
         assert_eq!(r.2, "m".as_bytes());
         assert_eq!(r.3, 10);
+
+        // the d tag is not the first
+        let tags = vec![
+            vec!["t".to_owned(), "n".to_owned()],
+            vec!["d".to_owned(), "m".to_owned()],
+            vec!["d".to_owned(), "o".to_owned()],
+        ];
+        assert_eq!(encode_replace_key(30001, &pubkey, &tags), Some(k));
+        let k = encode_replace_key(30001, &pubkey, &[vec!["d".to_owned()]]).unwrap();
+        assert_eq!(decode_replace_key(&k, &time).unwrap().2, empty);
     }
 }
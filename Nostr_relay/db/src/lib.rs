@@ -1,27 +1,83 @@
 //! Nostr event database
 
+mod archive;
+mod changes;
+mod cipher;
 mod db;
+mod dict;
 mod error;
 mod event;
 mod filter;
+mod history;
 mod key;
+mod partition;
+mod plan;
+mod shard;
+mod tag;
 pub use secp256k1;
 
 pub use {
-    db::CheckEventResult, db::Db, db::Iter, error::Error, event::now, event::ArchivedEventIndex,
-    event::Event, event::EventIndex, event::FromEventData, filter::Filter, filter::SortList,
+    archive::Archive, archive::ArchiveSegment, changes::Change, changes::ChangeIter,
+    cipher::register_key, cipher::DataKey, db::CheckEventResult, db::Db, db::Durability, db::Iter,
+    db::ParallelScan, dict::Dictionary, error::Error, event::now, event::ArchivedEventIndex,
+    event::Event, event::EventIndex, event::FromEventData, filter::Cursor, filter::Filter,
+    filter::SortList, history::HistoryOptions, partition::Partition, partition::Period,
+    plan::Estimate, plan::Explain, plan::Plan, shard::ShardIter, shard::ShardReader,
+    shard::ShardedDb, tag::Coordinate, tag::IndexTags,
 };
 
 pub use nostr_kv as kv;
 
 /// Stats of query
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize)]
 pub struct Stats {
+    /// the index chosen by the query planner
+    pub plan: Plan,
     pub scan_index: u64,
     pub get_data: u64,
     pub get_index: u64,
 }
 
+/// Number of events and bytes of the event data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counter {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Statistics of the stored events
+#[derive(Debug, Clone, Default)]
+pub struct EventStats {
+    pub total: Counter,
+    /// sorted by kind
+    pub kinds: Vec<(u16, Counter)>,
+    /// the top authors by number of events
+    pub authors: Vec<([u8; 32], Counter)>,
+    /// the size of the uncompressed records
+    pub raw_bytes: u64,
+    /// the events compressed with the trained dictionary
+    pub compressed: Counter,
+    /// the current dictionary id
+    pub dictionary: Option<u32>,
+}
+
+/// Statistics of the lmdb environment
+#[derive(Debug, Clone, Default)]
+pub struct EnvStats {
+    /// the main tree
+    pub env: kv::lmdb::Stat,
+    pub info: kv::lmdb::EnvInfo,
+    /// the named trees sorted by name
+    pub trees: Vec<(String, kv::lmdb::Stat)>,
+}
+
+impl EnvStats {
+    /// The bytes of the map used by the pages
+    pub fn used_bytes(&self) -> u64 {
+        self.info.used_bytes(self.env.page_size)
+    }
+}
+
 #[cfg(feature = "search")]
 use charabia::Segment;
 
@@ -0,0 +1,519 @@
+//! Time partitions of the old events, the events of each closed period are moved
+//! to their own lmdb environment in a sub directory named by the period.
+//! The whole partition can be dropped or archived by removing the environment,
+//! the disk space is returned to the OS immediately.
+
+use crate::{archive::Record, error::Error, Db, Event, Filter, FromEventData, Iter};
+use nostr_kv::{
+    lmdb::{ffi, OwnedReader},
+    scanner::{Group, GroupItem, ScannerWatcher},
+};
+use parking_lot::RwLock;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+    sync::Arc,
+};
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+const DAY: u64 = 86_400;
+
+/// The time span of a partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Day,
+    #[default]
+    Month,
+    Year,
+}
+
+impl FromStr for Period {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Period::Day),
+            "month" => Ok(Period::Month),
+            "year" => Ok(Period::Year),
+            _ => Err(Error::Invalid(format!("unknown partition period {}", s))),
+        }
+    }
+}
+
+// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
+
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let m = m as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn timestamp(y: i64, m: u32, d: u32) -> u64 {
+    (days_from_civil(y, m, d).max(0) as u64) * DAY
+}
+
+impl Period {
+    /// The start time of the partition which contains the time
+    pub fn start(&self, time: u64) -> u64 {
+        let (y, m, d) = civil_from_days((time / DAY) as i64);
+        match self {
+            Period::Day => timestamp(y, m, d),
+            Period::Month => timestamp(y, m, 1),
+            Period::Year => timestamp(y, 1, 1),
+        }
+    }
+
+    /// The start time of the next partition
+    pub fn end(&self, start: u64) -> u64 {
+        let (y, m, _) = civil_from_days((start / DAY) as i64);
+        match self {
+            Period::Day => self.start(start) + DAY,
+            Period::Month if m == 12 => timestamp(y + 1, 1, 1),
+            Period::Month => timestamp(y, m + 1, 1),
+            Period::Year => timestamp(y + 1, 1, 1),
+        }
+    }
+
+    /// The directory name of the partition, eg: `2023-05` for month
+    pub fn name(&self, start: u64) -> String {
+        let (y, m, d) = civil_from_days((start / DAY) as i64);
+        match self {
+            Period::Day => format!("{:04}-{:02}-{:02}", y, m, d),
+            Period::Month => format!("{:04}-{:02}", y, m),
+            Period::Year => format!("{:04}", y),
+        }
+    }
+
+    /// Parse the start time from the directory name
+    pub fn parse(&self, name: &str) -> Option<u64> {
+        let parts = name
+            .split('-')
+            .map(|s| s.parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let (y, m, d) = match (self, parts.as_slice()) {
+            (Period::Day, [y, m, d]) => (*y, *m, *d),
+            (Period::Month, [y, m]) => (*y, *m, 1),
+            (Period::Year, [y]) => (*y, 1, 1),
+            _ => return None,
+        };
+        if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+            return None;
+        }
+        let start = timestamp(y as i64, m, d);
+        // reject the invalid date, eg: 2023-02-30
+        if self.name(start) == name {
+            Some(start)
+        } else {
+            None
+        }
+    }
+}
+
+/// A partition of the old events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    pub path: PathBuf,
+    /// the events created at in `[start, end)`
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Partition {
+    // the period is detected from the name
+    fn parse(dir: &Path, name: &str) -> Option<Self> {
+        [Period::Day, Period::Month, Period::Year]
+            .into_iter()
+            .find_map(|period| Some(Self::new(dir, period, period.parse(name)?)))
+    }
+
+    fn new(dir: &Path, period: Period, start: u64) -> Self {
+        let name = period.name(start);
+        Self {
+            path: dir.join(&name),
+            start,
+            end: period.end(start),
+            name,
+        }
+    }
+
+    fn overlap(&self, since: Option<u64>, until: Option<u64>) -> bool {
+        since.unwrap_or(0) < self.end && self.start <= until.unwrap_or(u64::MAX)
+    }
+}
+
+// the read transactions are not bound to the thread,
+// the streams of the iterators alive at the same time in a thread read the same partition
+fn open_partition(path: &Path) -> Result<Db> {
+    let db = Db::open_with_flags(path, ffi::MDB_NOTLS)?;
+    db.check_schema()?;
+    Ok(db)
+}
+
+struct PartitionsInner {
+    // none if the partitions are disabled
+    dir: Option<PathBuf>,
+    // sorted by the start time
+    parts: RwLock<BTreeMap<u64, (Partition, Db)>>,
+}
+
+/// The time partitions of a database
+#[derive(Clone)]
+pub(crate) struct Partitions {
+    inner: Arc<PartitionsInner>,
+}
+
+impl Partitions {
+    /// Open the partitions in the directory, the directory is created on the first move
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut parts = BTreeMap::new();
+        if dir.is_dir() {
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let name = entry.file_name();
+                if let Some(part) = name.to_str().and_then(|n| Partition::parse(&dir, n)) {
+                    let db = open_partition(&part.path)?;
+                    parts.insert(part.start, (part, db));
+                }
+            }
+        }
+        Ok(Self {
+            inner: Arc::new(PartitionsInner {
+                dir: Some(dir),
+                parts: RwLock::new(parts),
+            }),
+        })
+    }
+
+    /// No partitions, the events can not be moved without a directory
+    pub fn disabled() -> Self {
+        Self {
+            inner: Arc::new(PartitionsInner {
+                dir: None,
+                parts: RwLock::new(BTreeMap::new()),
+            }),
+        }
+    }
+
+    /// All partitions, sorted by time
+    pub fn list(&self) -> Vec<Partition> {
+        self.inner
+            .parts
+            .read()
+            .values()
+            .map(|(part, _)| part.clone())
+            .collect()
+    }
+
+    /// The databases of all partitions, sorted by time
+    pub fn dbs(&self) -> Vec<Db> {
+        self.inner
+            .parts
+            .read()
+            .values()
+            .map(|(_, db)| db.clone())
+            .collect()
+    }
+
+    /// The start time and the database of the partition which contains the time
+    pub fn find(&self, time: u64) -> Option<(u64, Db)> {
+        let parts = self.inner.parts.read();
+        let (start, (part, db)) = parts.range(..=time).next_back()?;
+        (time < part.end).then(|| (*start, db.clone()))
+    }
+
+    /// Get the database of the partition which contains the time, create it by the period if not exists.
+    /// The partition of a day is created instead if the period overlaps the existing partitions.
+    pub fn get_or_create<F: Fn(&Db) -> Result<()>>(
+        &self,
+        period: Period,
+        time: u64,
+        init: F,
+    ) -> Result<(u64, Db)> {
+        if let Some(found) = self.find(time) {
+            return Ok(found);
+        }
+        let dir = self
+            .inner
+            .dir
+            .as_ref()
+            .ok_or_else(|| Error::Message("the partitions are disabled".to_owned()))?;
+        let mut parts = self.inner.parts.write();
+        // created by another thread
+        if let Some((start, (part, db))) = parts.range(..=time).next_back() {
+            if time < part.end {
+                return Ok((*start, db.clone()));
+            }
+        }
+        let mut part = Partition::new(dir, period, period.start(time));
+        if parts
+            .values()
+            .any(|(p, _)| p.start < part.end && part.start < p.end)
+        {
+            part = Partition::new(dir, Period::Day, Period::Day.start(time));
+        }
+        let db = open_partition(&part.path)?;
+        init(&db)?;
+        let start = part.start;
+        parts.insert(start, (part, db.clone()));
+        Ok((start, db))
+    }
+
+    fn remove(&self, name: &str) -> Result<(Partition, Db)> {
+        let mut parts = self.inner.parts.write();
+        let start = parts
+            .iter()
+            .find(|(_, (p, _))| p.name == name)
+            .map(|(start, _)| *start)
+            .ok_or_else(|| Error::Invalid(format!("partition {} not found", name)))?;
+        Ok(parts.remove(&start).unwrap())
+    }
+
+    /// Drop the whole partition, the open readers still can read it
+    pub fn drop_partition(&self, name: &str) -> Result<Partition> {
+        let (part, _) = self.remove(name)?;
+        fs::remove_dir_all(&part.path)?;
+        Ok(part)
+    }
+
+    /// Move the whole partition to the directory, it can be opened by [`Db::open`]
+    pub fn archive_partition<P: AsRef<Path>>(&self, name: &str, dir: P) -> Result<Partition> {
+        let (part, db) = self.remove(name)?;
+        fs::create_dir_all(dir.as_ref())?;
+        let to = dir.as_ref().join(&part.name);
+        if let Err(err) = fs::rename(&part.path, &to) {
+            // keep the partition available
+            self.inner.parts.write().insert(part.start, (part, db));
+            return Err(err.into());
+        }
+        Ok(Partition { path: to, ..part })
+    }
+
+    /// Get the event from the partitions, the newest partition first
+    pub fn get<R: FromEventData, K: AsRef<[u8]>>(&self, event_id: K) -> Result<Option<R>> {
+        for db in self.dbs().iter().rev() {
+            let reader = db.reader()?;
+            if let Some(event) = db.get(&reader, event_id.as_ref())? {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Delete the events from the partitions which contain them, one transaction per partition
+    pub fn del(&self, event_ids: &[Vec<u8>]) -> Result<usize> {
+        let mut count = 0;
+        for db in self.dbs() {
+            let mut writer = db.writer()?;
+            let mut deleted = 0;
+            for id in event_ids {
+                if db.del(&mut writer, id)? {
+                    deleted += 1;
+                }
+            }
+            if deleted > 0 {
+                db.commit(writer)?;
+                count += deleted;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Whether the event is stored in the partition of its created time
+    pub fn contains(&self, event: &Event) -> Result<bool> {
+        if let Some((_, db)) = self.find(event.created_at()) {
+            let reader = db.reader()?;
+            return Ok(db.get::<Vec<u8>, _, _>(&reader, event.id())?.is_some());
+        }
+        Ok(false)
+    }
+
+    /// Merge the events of the partitions in the time range of the filter,
+    /// none if no partition overlaps it
+    pub fn stream(&self, filter: &Filter) -> Result<Option<PartitionStream>> {
+        let mut filter = filter.clone();
+        // the position of the archived records is checked by the merge
+        filter.cursor = None;
+        filter.limit = None;
+        filter.history = false;
+        let parts = self.inner.parts.read();
+        let mut group = Group::new(filter.desc, false, false);
+        let mut range: Option<(u64, u64)> = None;
+        for (part, db) in parts
+            .values()
+            .filter(|(part, _)| part.overlap(filter.since, filter.until))
+        {
+            group.add(Box::new(PartitionItem::new(db, &filter)?))?;
+            let (since, until) = range.unwrap_or((part.start, part.end - 1));
+            range = Some((since.min(part.start), until.max(part.end - 1)));
+        }
+        Ok(range.map(|range| PartitionStream {
+            group,
+            range,
+            scanned: 0,
+        }))
+    }
+}
+
+// the iterator of a partition, it borrows the read transaction owned by itself
+struct PartitionItem {
+    // dropped before the transaction
+    iter: Iter<'static, OwnedReader, String>,
+    _txn: Rc<OwnedReader>,
+    desc: bool,
+    // the events of the same time, sorted by the id as the archived records
+    buffer: VecDeque<Record>,
+    // the first event of the next time
+    pending: Option<Record>,
+    scanned: u64,
+    cur_times: u64,
+}
+
+impl PartitionItem {
+    fn new(db: &Db, filter: &Filter) -> Result<Self> {
+        let txn = Rc::new(db.owned_reader()?);
+        // SAFETY: the transaction is kept alive by the item and the iterator is dropped first
+        let reader = unsafe { &*Rc::as_ptr(&txn) };
+        Ok(Self {
+            iter: db.iter::<String, _>(reader, filter)?,
+            _txn: txn,
+            desc: filter.desc,
+            buffer: VecDeque::new(),
+            pending: None,
+            scanned: 0,
+            cur_times: 0,
+        })
+    }
+
+    fn read(&mut self) -> Result<Option<Record>> {
+        match self.iter.next() {
+            Some(json) => {
+                let json = json?;
+                let event = Event::from_data(&json)?;
+                Ok(Some(Record::new(&event, json.into_bytes())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>> {
+        if self.buffer.is_empty() {
+            let first = match self.pending.take() {
+                Some(record) => record,
+                None => match self.read()? {
+                    Some(record) => record,
+                    None => return Ok(None),
+                },
+            };
+            let time = first.time;
+            self.buffer.push_back(first);
+            while let Some(record) = self.read()? {
+                if record.time != time {
+                    self.pending = Some(record);
+                    break;
+                }
+                self.buffer.push_back(record);
+            }
+            let desc = self.desc;
+            self.buffer.make_contiguous().sort_by(|a, b| {
+                let ord = a.cmp(b);
+                if desc {
+                    ord.reverse()
+                } else {
+                    ord
+                }
+            });
+        }
+        Ok(self.buffer.pop_front())
+    }
+}
+
+impl Iterator for PartitionItem {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.next_record().transpose();
+        let scanned = self.iter.stats().scan_index;
+        self.cur_times = scanned - self.scanned;
+        self.scanned = scanned;
+        record
+    }
+}
+
+impl GroupItem<'static, Record, Error> for PartitionItem {
+    fn watcher(&mut self, watcher: Box<dyn ScannerWatcher<Error>>) {
+        self.iter.watcher(watcher);
+    }
+
+    fn cur_times(&self) -> u64 {
+        self.cur_times
+    }
+}
+
+/// The matched events of the partitions in the filter order,
+/// the events of the same time are sorted by (created_at, id) for merging.
+pub(crate) struct PartitionStream {
+    group: Group<'static, Record, Error>,
+    /// the time range of the partitions
+    pub range: (u64, u64),
+    scanned: u64,
+}
+
+impl PartitionStream {
+    pub fn next(&mut self, scanned: &mut u64) -> Result<Option<Record>> {
+        let record = self.group.next().transpose()?;
+        *scanned += self.group.scan_times - self.scanned;
+        self.scanned = self.group.scan_times;
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn period() {
+        // 2023-05-17T10:00:00Z
+        let time = 1_684_317_600;
+        let p = Period::Month;
+        let start = p.start(time);
+        assert_eq!(p.name(start), "2023-05");
+        assert_eq!(p.parse("2023-05"), Some(start));
+        assert_eq!(p.name(p.end(start)), "2023-06");
+        assert_eq!(p.name(p.end(p.start(1_702_000_000))), "2024-01");
+        assert_eq!(Period::Day.name(Period::Day.start(time)), "2023-05-17");
+        assert_eq!(Period::Year.name(Period::Year.start(time)), "2023");
+        assert_eq!(Period::Day.end(0), 86_400);
+        assert!(Period::Day.parse("2023-02-30").is_none());
+        assert!(Period::Month.parse("2023-05-01").is_none());
+        assert!(Period::Year.parse("data.mdb").is_none());
+        assert_eq!(Period::from_str("year").unwrap(), Period::Year);
+        assert!(Period::from_str("week").is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use crate::Stats;
+use serde::Serialize;
+
+/// The index chosen by the query planner
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Plan {
+    /// word index, [NIP-50](https://nips.be/50)
+    Search,
+    /// id index
+    Ids,
+    /// tag index, the kinds are matched in the scanner
+    Tag,
+    /// pubkey and kind index
+    AuthorKind,
+    /// pubkey index
+    Author,
+    /// kind index
+    Kind,
+    /// created_at index
+    #[default]
+    Time,
+    /// expiration index
+    Expiration,
+}
+
+impl Plan {
+    /// The name of the index tree
+    pub fn tree(&self) -> &'static str {
+        match self {
+            Plan::Search => "t_word",
+            Plan::Ids => "t_id",
+            Plan::Tag => "t_tag",
+            Plan::AuthorKind => "t_pubkey_kind",
+            Plan::Author => "t_pubkey",
+            Plan::Kind => "t_kind",
+            Plan::Time => "t_created_at",
+            Plan::Expiration => "t_expiration",
+        }
+    }
+}
+
+/// The estimated cost of a plan
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Estimate {
+    pub plan: Plan,
+    /// total number of index entries under the scanned keys
+    pub entries: u64,
+    /// number of scanners in the group
+    pub scanners: u64,
+    /// need get index data for matching the filter
+    pub post_filter: bool,
+    /// estimated number of scanned index entries
+    pub scan_index: u64,
+    /// estimated number of get index data
+    pub get_index: u64,
+    pub cost: u64,
+}
+
+impl Estimate {
+    /// `matches` is the upper bound of the number of matched events
+    pub fn new(
+        plan: Plan,
+        entries: u64,
+        scanners: u64,
+        post_filter: bool,
+        limit: Option<u64>,
+        matches: u64,
+    ) -> Self {
+        let scan_index = match limit {
+            // stop after the limit is reached, assume the matched events are evenly distributed
+            Some(limit) if post_filter => {
+                entries.min(limit.saturating_mul(entries) / matches.max(1))
+            }
+            Some(limit) => entries.min(limit),
+            None => entries,
+        };
+        let get_index = if post_filter { scan_index } else { 0 };
+        Self {
+            plan,
+            entries,
+            scanners,
+            post_filter,
+            scan_index,
+            get_index,
+            // every scanner needs a seek
+            cost: scan_index + get_index + scanners,
+        }
+    }
+}
+
+/// How a filter executes
+#[derive(Debug, Clone, Serialize)]
+pub struct Explain {
+    pub plan: Plan,
+    /// the scanned index tree
+    pub tree: &'static str,
+    /// the structure of the scanner group, `and` is the intersection and `or` is the union.
+    /// eg: `and(or(2), or(1))`
+    pub group: String,
+    /// number of scanners in the group
+    pub scanners: u64,
+    /// need get index data for matching the filter
+    pub post_filter: bool,
+    /// the estimate of the chosen plan
+    pub estimate: Estimate,
+    /// all the candidate plans, the cheapest first
+    pub candidates: Vec<Estimate>,
+    /// the stats after scan
+    pub actual: Stats,
+    /// number of matched events
+    pub size: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree() {
+        assert_eq!(Plan::default().tree(), "t_created_at");
+        assert_eq!(Plan::AuthorKind.tree(), "t_pubkey_kind");
+    }
+
+    #[test]
+    fn estimate() {
+        let e = Estimate::new(Plan::Kind, 100, 1, false, None, 100);
+        assert_eq!(e.scan_index, 100);
+        assert_eq!(e.cost, 101);
+
+        let e = Estimate::new(Plan::Kind, 100, 1, false, Some(10), 100);
+        assert_eq!(e.scan_index, 10);
+        assert_eq!(e.get_index, 0);
+
+        let e = Estimate::new(Plan::Time, 1000, 1, true, Some(10), 100);
+        assert_eq!(e.scan_index, 100);
+        assert_eq!(e.get_index, 100);
+
+        let e = Estimate::new(Plan::Time, 1000, 1, true, Some(10), 0);
+        assert_eq!(e.scan_index, 1000);
+    }
+}
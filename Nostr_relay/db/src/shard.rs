@@ -0,0 +1,541 @@
+//! Sharded database, the events are stored in one environment per pubkey hash.
+//! Each shard has its own write lock, the shards can be written concurrently.
+//!
+//! The event id commits to the pubkey, the duplicate, deletion and replacement checks
+//! are in the shard of the author, except the deletion by the [NIP-26](https://nips.be/26)
+//! delegator which is applied to all shards.
+
+use crate::{
+    db::scan_watcher, error::Error, CheckEventResult, Cursor, Db, Event, Explain, Filter,
+    FromEventData, Iter, Stats,
+};
+use nostr_kv::{
+    lmdb::{OwnedReader, Writer},
+    scanner::{Group, GroupItem, ScannerWatcher, TimeKey},
+};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+/// The shard index is kept in the high byte of the cursor uid
+const SHARD_SHIFT: u32 = 56;
+const UID_MASK: u64 = (1 << SHARD_SHIFT) - 1;
+pub const MAX_SHARDS: usize = 256;
+
+/// The database split by the pubkey hash.
+/// A single shard is the plain database in the path,
+/// the multiple shards are in the sub directories named `shard-{index}`.
+#[derive(Clone)]
+pub struct ShardedDb {
+    shards: Arc<Vec<Arc<Db>>>,
+}
+
+// stable across the builds, the vanity pubkeys with the same prefix are spread
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl std::fmt::Debug for ShardedDb {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShardedDb")
+            .field("shards", &self.shards.len())
+            .finish()
+    }
+}
+
+fn shard_path(path: &Path, index: usize) -> PathBuf {
+    path.join(format!("shard-{}", index))
+}
+
+// the number of the shards in the path, 0 if no database
+fn existing_shards(path: &Path) -> Result<usize> {
+    if !path.exists() {
+        return Ok(0);
+    }
+    let mut n = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir()
+            && entry
+                .file_name()
+                .to_str()
+                .is_some_and(|n| n.starts_with("shard-"))
+        {
+            n += 1;
+        }
+    }
+    Ok(if n == 0 && path.join("data.mdb").exists() {
+        1
+    } else {
+        n
+    })
+}
+
+impl ShardedDb {
+    /// Open the shards, the number of shards can't be changed after the events were stored,
+    /// re-import the events to change it.
+    pub fn open<P: AsRef<Path>>(path: P, num: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let num = num.max(1);
+        if num > MAX_SHARDS {
+            return Err(Error::Invalid(format!(
+                "the number of shards should be at most {}",
+                MAX_SHARDS
+            )));
+        }
+        let existing = existing_shards(&path)?;
+        if existing != 0 && existing != num {
+            return Err(Error::Invalid(format!(
+                "the events are stored in {} shards, can't open with {} shards",
+                existing, num
+            )));
+        }
+
+        let paths = if num == 1 {
+            vec![path.clone()]
+        } else {
+            (0..num).map(|i| shard_path(&path, i)).collect()
+        };
+        let shards = paths
+            .into_iter()
+            .map(|p| {
+                let db = Db::open(p)?;
+                db.check_schema()?;
+                Ok(Arc::new(db))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            shards: Arc::new(shards),
+        })
+    }
+
+    /// Open the shards stored in the path, a single shard for a new database
+    pub fn open_existing<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let num = existing_shards(path.as_ref())?;
+        Self::open(path, num)
+    }
+
+    /// Use the opened database as the only shard
+    pub fn single(db: Arc<Db>) -> Self {
+        Self {
+            shards: Arc::new(vec![db]),
+        }
+    }
+
+    pub fn shards(&self) -> &[Arc<Db>] {
+        &self.shards
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The index of the shard which stores the events of the pubkey
+    pub fn shard_of(&self, pubkey: &[u8]) -> usize {
+        (fnv1a(pubkey) % self.shards.len() as u64) as usize
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        for db in self.shards.iter() {
+            db.flush()?;
+        }
+        Ok(())
+    }
+
+    /// The write transactions of all shards, committed by [`ShardedDb::commit`]
+    pub fn writers(&self) -> Result<Vec<Writer<'_>>> {
+        self.shards.iter().map(|db| db.writer()).collect()
+    }
+
+    pub fn commit(&self, writers: Vec<Writer<'_>>) -> Result<()> {
+        for (db, writer) in self.shards.iter().zip(writers) {
+            db.commit(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Put the event to the shard of the author in the writers of [`ShardedDb::writers`].
+    /// The deletion event is also applied to the other shards for the delegated events,
+    /// the deleted count of all shards is returned.
+    pub fn put(&self, writers: &mut [Writer<'_>], event: &Event) -> Result<CheckEventResult> {
+        let shard = self.shard_of(event.pubkey());
+        let mut result = self.shards[shard].put(&mut writers[shard], event)?;
+        if let CheckEventResult::Ok(count) = &mut result {
+            for (j, db) in self.shards.iter().enumerate() {
+                if j != shard {
+                    *count += db.del_referenced(&mut writers[j], event)?;
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Put the events to the shards of the authors.
+    /// The deletion events are also applied to the other shards for the delegated events.
+    pub fn batch_put<II, N>(&self, events: II) -> Result<usize>
+    where
+        II: IntoIterator<Item = N>,
+        N: AsRef<Event>,
+    {
+        let mut events = events.into_iter().collect::<Vec<N>>();
+        // sort for check dup
+        events.sort_by(|a, b| a.as_ref().id().cmp(b.as_ref().id()));
+        let mut writers = self.writers()?;
+        let mut count = 0;
+
+        for (i, event) in events.iter().enumerate() {
+            let event = event.as_ref();
+            // dup in the input events
+            if i != 0 && event.id() == events[i - 1].as_ref().id() {
+                continue;
+            }
+            if let CheckEventResult::Ok(c) = self.put(&mut writers, event)? {
+                count += c;
+            }
+        }
+
+        self.commit(writers)?;
+        Ok(count)
+    }
+
+    /// Get the event from all shards, the event id can't tell the shard
+    pub fn get<R: FromEventData, K: AsRef<[u8]>>(
+        &self,
+        reader: &ShardReader,
+        event_id: K,
+    ) -> Result<Option<R>> {
+        for (db, txn) in reader.shards.iter() {
+            if let Some(event) = db.get(txn, event_id.as_ref())? {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn batch_del<II, N>(&self, event_ids: II) -> Result<usize>
+    where
+        II: IntoIterator<Item = N>,
+        N: AsRef<[u8]>,
+    {
+        let ids = event_ids.into_iter().collect::<Vec<N>>();
+        let mut num = 0;
+        for db in self.shards.iter() {
+            num += db.batch_del(ids.iter().map(|id| id.as_ref()))?;
+        }
+        Ok(num)
+    }
+
+    /// Explain the filter in each shard, the timeout is for the scan of each shard
+    pub fn explain(&self, filter: &Filter, timeout: Option<Duration>) -> Result<Vec<Explain>> {
+        self.shards
+            .iter()
+            .map(|db| db.explain(filter, timeout))
+            .collect()
+    }
+
+    /// The read transactions of all shards
+    pub fn reader(&self) -> Result<ShardReader> {
+        let shards = self
+            .shards
+            .iter()
+            .map(|db| {
+                let txn = db.owned_reader()?;
+                Ok((Arc::clone(db), txn))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ShardReader { shards })
+    }
+
+    /// iter events by filter, merged in the created time order
+    pub fn iter<'txn, J: FromEventData + 'txn>(
+        &self,
+        reader: &'txn ShardReader,
+        filter: &Filter,
+    ) -> Result<ShardIter<'txn, J>> {
+        let mut iters = vec![];
+        for (shard, (db, txn)) in reader.shards.iter().enumerate() {
+            let iter = match &filter.cursor {
+                Some(cursor) if reader.shards.len() > 1 => {
+                    let filter = Filter {
+                        cursor: shard_cursor(cursor, shard, filter.desc),
+                        ..filter.clone()
+                    };
+                    db.iter::<J, _>(txn, &filter)?
+                }
+                _ => db.iter::<J, _>(txn, filter)?,
+            };
+            iters.push((shard, iter));
+        }
+        Ok(ShardIter {
+            iters,
+            group: None,
+            stats: vec![],
+            limit: filter.limit,
+            desc: filter.desc,
+            count: 0,
+            cursor: None,
+        })
+    }
+}
+
+// the cursor of the merged iterator resumed in the shard
+fn shard_cursor(cursor: &Cursor, shard: usize, desc: bool) -> Option<Cursor> {
+    let from = (cursor.uid >> SHARD_SHIFT) as usize;
+    let time = cursor.time;
+    match (shard.cmp(&from), desc) {
+        (Ordering::Equal, _) => Some(Cursor {
+            uid: cursor.uid & UID_MASK,
+            ..*cursor
+        }),
+        // all events of the cursor time were returned
+        (Ordering::Less, false) => Some(Cursor {
+            time,
+            uid: u64::MAX,
+            id: None,
+        }),
+        (Ordering::Greater, true) => Some(Cursor {
+            time,
+            uid: 0,
+            id: None,
+        }),
+        // no event of the cursor time was returned
+        (Ordering::Greater, false) => time.checked_sub(1).map(|time| Cursor {
+            time,
+            uid: u64::MAX,
+            id: None,
+        }),
+        (Ordering::Less, true) => Some(Cursor {
+            time,
+            uid: u64::MAX,
+            id: None,
+        }),
+    }
+}
+
+/// Read transactions of the shards
+pub struct ShardReader {
+    shards: Vec<(Arc<Db>, OwnedReader)>,
+}
+
+/// The merged index key of the shards
+struct ShardKey<J> {
+    time: u64,
+    shard: usize,
+    uid: u64,
+    cursor: Option<Cursor>,
+    event: J,
+}
+
+impl<J> TimeKey for ShardKey<J> {
+    fn time(&self) -> u64 {
+        self.time
+    }
+
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.time
+            .cmp(&other.time)
+            .then_with(|| self.shard.cmp(&other.shard))
+            .then_with(|| self.uid.cmp(&other.uid))
+    }
+
+    fn change_time(&self, key: &[u8], _time: u64) -> Vec<u8> {
+        // only used by the scanner to seek
+        key.to_vec()
+    }
+}
+
+struct ShardItem<'txn, J: FromEventData> {
+    iter: Iter<'txn, OwnedReader, J>,
+    shard: usize,
+    stats: Rc<RefCell<Stats>>,
+    cur_times: u64,
+}
+
+impl<'txn, J: FromEventData> Iterator for ShardItem<'txn, J> {
+    type Item = Result<ShardKey<J>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.iter.next_key();
+        let stats = self.iter.stats();
+        self.cur_times = stats.scan_index - self.stats.borrow().scan_index;
+        *self.stats.borrow_mut() = stats;
+        let shard = self.shard;
+        let cursor = self.iter.cursor().map(|c| Cursor {
+            uid: c.uid | (shard as u64) << SHARD_SHIFT,
+            ..c
+        });
+        item.map(|r| {
+            r.map(|(time, uid, event)| ShardKey {
+                time,
+                shard,
+                uid,
+                cursor,
+                event,
+            })
+        })
+    }
+}
+
+impl<'txn, J: FromEventData> GroupItem<'txn, ShardKey<J>, Error> for ShardItem<'txn, J> {
+    fn watcher(&mut self, watcher: Box<dyn ScannerWatcher<Error>>) {
+        self.iter.watcher(watcher);
+    }
+
+    fn cur_times(&self) -> u64 {
+        self.cur_times
+    }
+}
+
+/// Iterate the events of the shards in the created time order
+pub struct ShardIter<'txn, J: FromEventData> {
+    // the group is created at the first scan when there are multiple shards
+    iters: Vec<(usize, Iter<'txn, OwnedReader, J>)>,
+    group: Option<Group<'txn, ShardKey<J>, Error>>,
+    stats: Vec<Rc<RefCell<Stats>>>,
+    limit: Option<u64>,
+    desc: bool,
+    count: u64,
+    cursor: Option<Cursor>,
+}
+
+impl<'txn, J: FromEventData + 'txn> ShardIter<'txn, J> {
+    /// Limit the total scan time and report [`Error::ScanTimeout`] if it is exceeded
+    pub fn scan_time(&mut self, timeout: Duration, check_step: u64) {
+        for (_, iter) in self.iters.iter_mut() {
+            iter.scan_time(timeout, check_step);
+        }
+        if let Some(group) = &mut self.group {
+            group.watcher(Box::new(scan_watcher(timeout, check_step)));
+        }
+    }
+
+    /// The position of the last returned event for resuming by [`Filter::cursor`]
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.cursor
+    }
+
+    /// The sum of the shards stats
+    pub fn stats(&self) -> Stats {
+        let mut iter = self
+            .iters
+            .iter()
+            .map(|(_, iter)| iter.stats())
+            .chain(self.stats.iter().map(|s| s.borrow().clone()));
+        let mut stats = iter.next().unwrap_or(Stats {
+            plan: Default::default(),
+            scan_index: 0,
+            get_data: 0,
+            get_index: 0,
+        });
+        for s in iter {
+            stats.scan_index += s.scan_index;
+            stats.get_data += s.get_data;
+            stats.get_index += s.get_index;
+        }
+        stats
+    }
+
+    /// only count iter size
+    pub fn size(mut self) -> Result<(u64, Stats)> {
+        if self.group.is_some() {
+            let mut len = 0;
+            for item in self.by_ref() {
+                item?;
+                len += 1;
+            }
+            return Ok((len, self.stats()));
+        }
+        let mut len = 0;
+        let mut stats = Stats {
+            plan: self.stats().plan,
+            scan_index: 0,
+            get_data: 0,
+            get_index: 0,
+        };
+        for (_, iter) in self.iters {
+            let (size, s) = iter.size()?;
+            len += size;
+            stats.scan_index += s.scan_index;
+            stats.get_index += s.get_index;
+        }
+        if let Some(limit) = self.limit {
+            len = len.min(limit);
+        }
+        Ok((len, stats))
+    }
+
+    fn group(&mut self) -> Result<&mut Group<'txn, ShardKey<J>, Error>> {
+        if self.group.is_none() {
+            let mut group = Group::new(self.desc, false, false);
+            for (shard, iter) in self.iters.drain(..) {
+                let stats = Rc::new(RefCell::new(iter.stats()));
+                self.stats.push(stats.clone());
+                group.add(Box::new(ShardItem {
+                    iter,
+                    shard,
+                    stats,
+                    cur_times: 0,
+                }))?;
+            }
+            self.group = Some(group);
+        }
+        Ok(self.group.as_mut().unwrap())
+    }
+}
+
+impl<'txn, J: FromEventData + 'txn> Iterator for ShardIter<'txn, J> {
+    type Item = Result<J, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // no need to merge a single shard
+        if let [(_, iter)] = self.iters.as_mut_slice() {
+            let item = iter.next();
+            self.cursor = iter.cursor();
+            return item;
+        }
+        if self.limit.is_some_and(|limit| self.count >= limit) {
+            return None;
+        }
+        let item = match self.group() {
+            Ok(group) => group.next(),
+            Err(err) => return Some(Err(err)),
+        };
+        item.map(|r| {
+            r.map(|k| {
+                self.count += 1;
+                self.cursor = k.cursor;
+                k.event
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor() {
+        let cursor = Cursor {
+            time: 10,
+            uid: 5 | 1 << SHARD_SHIFT,
+            id: None,
+        };
+        let resume = |shard, desc| shard_cursor(&cursor, shard, desc).map(|c| (c.time, c.uid));
+        assert_eq!(resume(1, false), Some((10, 5)));
+        assert_eq!(resume(0, false), Some((10, u64::MAX)));
+        assert_eq!(resume(2, false), Some((9, u64::MAX)));
+        assert_eq!(resume(0, true), Some((10, u64::MAX)));
+        assert_eq!(resume(2, true), Some((10, 0)));
+    }
+}
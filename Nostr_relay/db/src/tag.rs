@@ -0,0 +1,227 @@
+//! The indexed tags
+//!
+//! The single-letter tags are always indexed, the additional tag names can be configured per database.
+//! The long tag values are indexed by hash because of the lmdb max key size.
+//! The `a` tag values are validated as [`Coordinate`].
+//! The tag values can be replaced by the hash keyed with the tag key of the database
+//! when the data is encrypted, the equal values still match.
+
+use crate::{cipher::hmac, error::Error, key::encode_replace_key};
+use parking_lot::{RwLock, RwLockReadGuard};
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, collections::HashSet, fmt::Display, str::FromStr, sync::Arc};
+
+/// The tag value longer than this is indexed by hash
+pub const MAX_TAG_VALUE_SIZE: usize = 255;
+/// The max length of the additional tag name
+pub const MAX_TAG_NAME_SIZE: usize = 64;
+
+// the marker of the hashed value, it never appears in utf-8 strings
+const HASH_MARKER: u8 = 0xff;
+
+/// The additional indexed tag names and the tag key of a database, the single-letter tags are always indexed.
+/// The events stored before are not indexed by the new names.
+#[derive(Clone, Default)]
+pub struct IndexTags {
+    names: Arc<RwLock<HashSet<String>>>,
+    // the derived key of the keyed hash of the tag values
+    key: Arc<RwLock<Option<[u8; 32]>>>,
+}
+
+// hide the key in the log
+impl std::fmt::Debug for IndexTags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IndexTags")
+            .field("names", &self.names())
+            .field("key", &self.key.read().is_some())
+            .finish()
+    }
+}
+
+impl IndexTags {
+    /// Set the additional indexed tag names
+    pub fn set<I: IntoIterator<Item = S>, S: Into<String>>(&self, names: I) -> Result<(), Error> {
+        let mut set = HashSet::new();
+        for name in names {
+            let name = name.into();
+            if !is_tag_name(&name) {
+                return Err(Error::Invalid(format!("invalid index tag name {:?}", name)));
+            }
+            set.insert(name);
+        }
+        *self.names.write() = set;
+        Ok(())
+    }
+
+    /// The sorted additional indexed tag names
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.names.read().iter().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.read().is_empty()
+    }
+
+    /// Whether the tag values are indexed by the keyed hash
+    pub fn is_keyed(&self) -> bool {
+        self.key.read().is_some()
+    }
+
+    pub(crate) fn read(&self) -> RwLockReadGuard<'_, HashSet<String>> {
+        self.names.read()
+    }
+
+    pub(crate) fn key(&self) -> Option<[u8; 32]> {
+        *self.key.read()
+    }
+
+    pub(crate) fn set_key(&self, key: Option<[u8; 32]>) {
+        *self.key.write() = key;
+    }
+}
+
+/// Whether the name can be indexed
+pub(crate) fn is_tag_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_TAG_NAME_SIZE && !name.contains('\0')
+}
+
+/// Whether the tag name is indexed, the single-letter tags or the additional names
+pub(crate) fn is_index_tag(name: &str, names: &HashSet<String>) -> bool {
+    // 0 will break the index separator
+    (name.len() == 1 && name != "\0") || names.contains(name)
+}
+
+/// The value in the index, the long value or the value containing the separator 0 is replaced by the hash
+pub(crate) fn index_value(value: &[u8]) -> Cow<'_, [u8]> {
+    if value.len() > MAX_TAG_VALUE_SIZE || value.contains(&0) {
+        let hash: [u8; 32] = Sha256::digest(value).into();
+        Cow::Owned([&[HASH_MARKER][..], &hash[..]].concat())
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// The value in the index after [`index_value`] or the decoded `e` and `p` values,
+/// replaced by the keyed hash if the tag key is set. The `e` tag values are kept for the deletion.
+pub(crate) fn protect_value(key: Option<&[u8; 32]>, name: &[u8], value: Vec<u8>) -> Vec<u8> {
+    match key {
+        Some(key) if name != b"e" => hmac(key, &[name, &[0], &value]).to_vec(),
+        _ => value,
+    }
+}
+
+/// Whether the index value is the hash of the original value
+pub(crate) fn is_hashed(value: &[u8]) -> bool {
+    value.len() == 33 && value[0] == HASH_MARKER
+}
+
+/// The coordinate `<kind>:<pubkey>:<d>` of the replaceable or addressable event in the `a` tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coordinate {
+    pub kind: u16,
+    pub pubkey: [u8; 32],
+    /// the `d` tag value, empty for the replaceable event
+    pub identifier: String,
+}
+
+impl Coordinate {
+    /// The replace key of the addressed event, none if the kind is not replaceable
+    pub fn replace_key(&self) -> Option<Vec<u8>> {
+        let tags = [vec!["d".to_owned(), self.identifier.clone()]];
+        encode_replace_key(self.kind, &self.pubkey, &tags)
+    }
+}
+
+impl FromStr for Coordinate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::Invalid("invalid a tag value".to_owned());
+        let mut parts = s.splitn(3, ':');
+        let (kind, pubkey, identifier) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(kind), Some(pubkey), Some(identifier)) => (kind, pubkey, identifier),
+            _ => return Err(invalid()),
+        };
+        // the canonical form, the same coordinate has the same index value
+        let kind = u16::from_str(kind)
+            .ok()
+            .filter(|k| k.to_string() == kind && encode_replace_key(*k, &[0; 32], &[]).is_some())
+            .ok_or_else(invalid)?;
+        if pubkey.len() != 64
+            || !pubkey
+                .bytes()
+                .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        {
+            return Err(invalid());
+        }
+        let mut h = [0u8; 32];
+        hex::decode_to_slice(pubkey, &mut h)?;
+        Ok(Self {
+            kind,
+            pubkey: h,
+            identifier: identifier.to_owned(),
+        })
+    }
+}
+
+impl Display for Coordinate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.kind,
+            hex::encode(self.pubkey),
+            self.identifier
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value() {
+        assert_eq!(index_value(b"short").as_ref(), b"short");
+        let long = index_value(&[b'a'; 300]);
+        assert!(is_hashed(&long));
+        assert_ne!(long, index_value(&[b'a'; 301]));
+        assert!(is_hashed(&index_value(b"a\0b")));
+        assert!(!is_hashed(&[b'a'; 33]));
+        let names = IndexTags::default();
+        assert!(is_index_tag("t", &names.read()));
+        assert!(!is_index_tag("\0", &names.read()));
+        assert!(!is_index_tag("alt", &names.read()));
+        names.set(["alt"]).unwrap();
+        assert!(is_index_tag("alt", &names.read()));
+        assert_eq!(names.names(), vec!["alt"]);
+        assert!(names.set(["a\0"]).is_err());
+        assert!(names.set([""]).is_err());
+    }
+
+    #[test]
+    fn coordinate() -> Result<(), Error> {
+        let pubkey = hex::encode([1u8; 32]);
+        let s = format!("30023:{}:a:b", pubkey);
+        let c = Coordinate::from_str(&s)?;
+        assert_eq!(c.kind, 30023);
+        assert_eq!(c.pubkey, [1u8; 32]);
+        assert_eq!(c.identifier, "a:b");
+        assert_eq!(c.to_string(), s);
+        assert_eq!(
+            c.replace_key(),
+            Some([&[1u8; 32][..], &30023u16.to_be_bytes(), b"a:b"].concat())
+        );
+        assert!(Coordinate::from_str(&format!("10002:{}:", pubkey)).is_ok());
+
+        // not addressable
+        assert!(Coordinate::from_str(&format!("1:{}:", pubkey)).is_err());
+        assert!(Coordinate::from_str(&format!("030023:{}:", pubkey)).is_err());
+        assert!(Coordinate::from_str(&format!("30023:{}", pubkey)).is_err());
+        assert!(Coordinate::from_str(&format!("30023:{}:", "AB".repeat(32))).is_err());
+        assert!(Coordinate::from_str("30023:0101:").is_err());
+        Ok(())
+    }
+}
@@ -1,4 +1,8 @@
-use nostr_db::{Db, Error, Event, Filter, Stats};
+use nostr_db::kv::store::Store;
+use nostr_db::{
+    Archive, Change, CheckEventResult, Coordinate, Cursor, Db, Durability, Error, Event, Filter,
+    HistoryOptions, ParallelScan, Period, Plan, ShardedDb, Stats,
+};
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::thread::sleep;
@@ -121,6 +125,53 @@ pub fn test_events() -> Result<()> {
     Ok(())
 }
 
+// run the same queries on the stores
+fn store_queries<S: Store>(db: &Db<S>) -> Result<Vec<Vec<Vec<u8>>>> {
+    let events = (0..PER_NUM)
+        .map(|i| {
+            MyEvent {
+                id: id(70, i),
+                pubkey: author(i % 3),
+                kind: if i % 4 < 2 { 1 } else { 7 },
+                created_at: 10 + (i % 7) as u64,
+                tags: vec![vec!["t".to_owned(), format!("t{}", i % 2)]],
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(&events)?;
+    db.batch_del(vec![id(70, 1)])?;
+    let reader = db.reader()?;
+    let mut results = vec![];
+    for filter in [
+        "{}".to_owned(),
+        r#"{"kinds": [1, 7], "limit": 5}"#.to_owned(),
+        format!(r#"{{"authors": ["{}"]}}"#, hex::encode(author(1))),
+        r##"{"#t": ["t1"], "kinds": [7]}"##.to_owned(),
+        r#"{"since": 12, "until": 14}"#.to_owned(),
+    ] {
+        let filter = Filter::from_str(&filter)?;
+        results.push(
+            db.iter::<Vec<u8>, _>(&reader, &filter)?
+                .collect::<Result<Vec<_>>>()?,
+        );
+    }
+    Ok(results)
+}
+
+#[test]
+pub fn test_memory_store() -> Result<()> {
+    let db = Db::memory()?;
+    db.check_schema()?;
+    let results = store_queries(&db)?;
+    assert_eq!(results[0].len(), PER_NUM as usize - 1);
+    assert_eq!(results[1].len(), 5);
+    assert_eq!(results, store_queries(&create_db("store")?)?);
+    assert!(db.archive_before(100, 10).is_err());
+    Ok(())
+}
+
 #[test]
 pub fn test_events_unexpected() -> Result<()> {
     let db = create_db("test_events_unexpected")?;
@@ -409,6 +460,322 @@ pub fn test_events_del() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_events_del_address() -> Result<()> {
+    let db = create_db("test_events_del_address")?;
+    let prefix = 0;
+    let article = |index: u8, pubkey: [u8; 32], created_at: u64| -> Event {
+        MyEvent {
+            id: id(prefix, index),
+            pubkey,
+            kind: 30023,
+            tags: vec![vec!["d".to_owned(), "post".to_owned()]],
+            created_at,
+            ..Default::default()
+        }
+        .into()
+    };
+    let coordinate = |pubkey: [u8; 32]| format!("30023:{}:post", hex::encode(pubkey));
+    db.batch_put(vec![article(1, author(1), 10), article(2, author(2), 10)])?;
+
+    let mut writer = db.writer()?;
+    // the invalid coordinate is ignored
+    let invalid: Event = MyEvent {
+        id: id(prefix, 3),
+        pubkey: author(1),
+        kind: 5,
+        tags: vec![vec!["a".to_owned(), "1:00:".to_owned()]],
+        created_at: 20,
+        ..Default::default()
+    }
+    .into();
+    assert!(matches!(
+        db.put(&mut writer, invalid)?,
+        CheckEventResult::Ok(1)
+    ));
+
+    let deletion: Event = MyEvent {
+        id: id(prefix, 4),
+        pubkey: author(1),
+        kind: 5,
+        tags: vec![
+            vec!["a".to_owned(), coordinate(author(1))],
+            // invalid author
+            vec!["a".to_owned(), coordinate(author(2))],
+            // malformed
+            vec!["a".to_owned(), "30023:00:post".to_owned()],
+        ],
+        created_at: 20,
+        ..Default::default()
+    }
+    .into();
+    assert!(matches!(
+        db.put(&mut writer, deletion)?,
+        CheckEventResult::Ok(2)
+    ));
+    // older re-publication is rejected by the tombstone
+    assert!(matches!(
+        db.put(&mut writer, article(5, author(1), 20))?,
+        CheckEventResult::Deleted
+    ));
+    assert!(matches!(
+        db.put(&mut writer, article(6, author(1), 21))?,
+        CheckEventResult::Ok(1)
+    ));
+    db.commit(writer)?;
+    {
+        let reader = db.reader()?;
+        assert!(db.get::<Event, _, _>(&reader, id(prefix, 1))?.is_none());
+        assert!(db.get::<Event, _, _>(&reader, id(prefix, 2))?.is_some());
+        assert!(db.get::<Event, _, _>(&reader, id(prefix, 6))?.is_some());
+        let filter = Filter::from_str(&format!(r##"{{"#a":["{}"]}}"##, coordinate(author(1))))?;
+        assert_eq!(db.iter::<Event, _>(&reader, &filter)?.count(), 1);
+    }
+    assert!(Filter::from_str(r##"{"#a":["30023:00:post"]}"##).is_err());
+
+    Ok(())
+}
+
+#[test]
+pub fn test_events_history() -> Result<()> {
+    let db = create_db("test_events_history")?;
+    let prefix = 0;
+    let event = |index: u8, kind: u16, d: &str, created_at: u64| -> Event {
+        MyEvent {
+            id: id(prefix, index),
+            pubkey: author(1),
+            kind,
+            tags: vec![vec!["d".to_owned(), d.to_owned()]],
+            created_at,
+            ..Default::default()
+        }
+        .into()
+    };
+    // not kept before enabled
+    db.batch_put(vec![
+        event(1, 30023, "post", 10),
+        event(2, 30023, "post", 11),
+    ])?;
+    db.set_history(Some(HistoryOptions {
+        retention: None,
+        kinds: HashMap::from([(0, Some(0))]),
+    }));
+    db.batch_put(vec![
+        event(3, 30023, "post", 12),
+        event(4, 30023, "post", 13),
+        event(5, 30023, "post2", 12),
+        event(6, 0, "", 12),
+        event(7, 0, "", 13),
+    ])?;
+    let coordinate = Coordinate::from_str(&format!("30023:{}:post", hex::encode(author(1))))?;
+    let replace_key = coordinate.replace_key().unwrap();
+    {
+        let reader = db.reader()?;
+        let versions = db.history::<Event, _>(&reader, &replace_key)?;
+        assert_eq!(
+            versions.iter().map(|e| e.id()).collect::<Vec<_>>(),
+            vec![&id(prefix, 3), &id(prefix, 2)]
+        );
+        let mut filter = Filter {
+            authors: vec![author(1)].into(),
+            ..Default::default()
+        };
+        assert_eq!(db.iter::<Event, _>(&reader, &filter)?.count(), 3);
+        let kinds = Filter::from_str(&format!(
+            r#"{{"authors":["{}"],"kinds":[30023],"history":true}}"#,
+            hex::encode(author(1))
+        ))?;
+        let ids = db
+            .iter::<Event, _>(&reader, &kinds)?
+            .map(|e| e.map(|e| e.id()[31]))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(ids, vec![2, 5, 3, 4]);
+        // only the latest versions with the limit
+        let mut limited = kinds.clone();
+        limited.limit = Some(2);
+        limited.desc = true;
+        let ids = db
+            .iter::<Event, _>(&reader, &limited)?
+            .map(|e| e.map(|e| e.id()[31]))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(ids, vec![4, 5]);
+        // the history requires the authors and kinds
+        filter.history = true;
+        assert!(db.iter::<Event, _>(&reader, &filter).is_err());
+        filter.kinds = vec![0, 30023].into();
+        assert_eq!(db.iter::<Event, _>(&reader, &filter)?.count(), 5);
+    }
+
+    // deleted with the coordinate
+    let deletion: Event = MyEvent {
+        id: id(prefix, 8),
+        pubkey: author(1),
+        kind: 5,
+        tags: vec![vec!["a".to_owned(), coordinate.to_string()]],
+        created_at: 12,
+        ..Default::default()
+    }
+    .into();
+    db.batch_put(vec![deletion])?;
+    {
+        let reader = db.reader()?;
+        assert!(db.history::<Event, _>(&reader, &replace_key)?.is_empty());
+        assert!(db.get::<Event, _, _>(&reader, id(prefix, 4))?.is_some());
+    }
+
+    // retention
+    db.batch_put(vec![event(9, 30023, "post", 14)])?;
+    db.set_history(Some(HistoryOptions {
+        retention: Some(10),
+        kinds: HashMap::new(),
+    }));
+    assert_eq!(db.prune_history(20)?, 0);
+    assert_eq!(db.prune_history(30)?, 1);
+    Ok(())
+}
+
+#[test]
+pub fn test_changes() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-changes")
+        .tempdir()
+        .unwrap();
+    let prefix = 0;
+    let event = |index: u8, kind: u16| -> Event {
+        MyEvent {
+            id: id(prefix, index),
+            pubkey: author(1),
+            kind,
+            created_at: index as u64,
+            ..Default::default()
+        }
+        .into()
+    };
+    let changes = |db: &Db, since: u64| -> Result<Vec<(u64, bool, u8)>> {
+        let reader = db.reader()?;
+        db.changes_since::<Event, _>(&reader, since)
+            .map(|c| {
+                c.map(|c| match c {
+                    Change::Put { seq, event } => (seq, true, event.id()[31]),
+                    Change::Delete { seq, id, .. } | Change::Move { seq, id, .. } => {
+                        (seq, false, id[31])
+                    }
+                })
+            })
+            .collect()
+    };
+    {
+        let db = Db::open(dir.path())?;
+        db.batch_put(vec![event(1, 1), event(2, 1), event(3, 0)])?;
+        let mut writer = db.writer()?;
+        db.del(&mut writer, id(prefix, 1))?;
+        db.commit(writer)?;
+        // replaced
+        db.batch_put(vec![event(4, 0)])?;
+        assert_eq!(
+            changes(&db, 0)?,
+            vec![(1, true, 2), (3, false, 1), (4, false, 3), (5, true, 4)]
+        );
+        assert_eq!(changes(&db, 4)?, vec![(4, false, 3), (5, true, 4)]);
+        let reader = db.reader()?;
+        let json = db
+            .changes_since::<String, _>(&reader, 4)
+            .next()
+            .unwrap()?
+            .to_json();
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"seq":4,"op":"delete","uid":2,"id":"{}"}}"#,
+                hex::encode(id(prefix, 3))
+            )
+        );
+    }
+    {
+        // the sequence continues after reopen
+        let db = Db::open(dir.path())?;
+        db.batch_put(vec![event(5, 1)])?;
+        assert_eq!(changes(&db, 6)?, vec![(6, true, 5)]);
+        assert_eq!(db.truncate_changes(4)?, 1);
+        assert_eq!(changes(&db, 0)?.len(), 4);
+    }
+    Ok(())
+}
+
+#[test]
+pub fn test_apply_changes() -> Result<()> {
+    let primary = create_db("test_apply_changes_primary")?;
+    let follower = create_db("test_apply_changes_follower")?;
+    let event = |index: u8, kind: u16| -> Event {
+        MyEvent {
+            id: id(0, index),
+            pubkey: author(1),
+            kind,
+            created_at: index as u64,
+            ..Default::default()
+        }
+        .into()
+    };
+    // replicate by the json lines
+    let replicate = || -> Result<usize> {
+        let reader = follower.reader()?;
+        let since = follower.replica_since(&reader)?;
+        drop(reader);
+        let primary_reader = primary.reader()?;
+        let mut writer = follower.writer()?;
+        let mut count = 0;
+        for change in primary.changes_since::<String, _>(&primary_reader, since) {
+            let change = Change::<Event>::from_str(&change?.to_json())?;
+            follower.apply_change(&mut writer, &change)?;
+            count += 1;
+        }
+        follower.commit(writer)?;
+        Ok(count)
+    };
+    let ids = |db: &Db| -> Result<Vec<u8>> {
+        Ok(all(db, &Filter::default())?
+            .0
+            .iter()
+            .map(|e| e.id()[31])
+            .collect())
+    };
+
+    primary.batch_put(vec![event(1, 1), event(2, 1), event(3, 0)])?;
+    assert_eq!(replicate()?, 3);
+    assert_eq!(ids(&follower)?, ids(&primary)?);
+
+    // deleted and replaced
+    primary.batch_del(vec![id(0, 1)])?;
+    primary.batch_put(vec![event(4, 0)])?;
+    assert_eq!(replicate()?, 3);
+    assert_eq!(ids(&follower)?, vec![2, 4]);
+    assert_eq!(replicate()?, 0);
+    let reader = follower.reader()?;
+    assert_eq!(follower.replica_since(&reader)?, 6);
+    drop(reader);
+
+    // the archived event is still read from the archive, kept by the follower
+    assert_eq!(primary.archive_before(3, 10)?, 1);
+    let reader = primary.reader()?;
+    let change = primary
+        .changes_since::<String, _>(&reader, 6)
+        .next()
+        .unwrap()?;
+    assert_eq!(
+        change.to_json(),
+        format!(
+            r#"{{"seq":6,"op":"move","uid":1,"id":"{}"}}"#,
+            hex::encode(id(0, 2))
+        )
+    );
+    drop(reader);
+    assert_eq!(replicate()?, 1);
+    assert_eq!(ids(&follower)?, vec![2, 4]);
+
+    assert!(Change::<Event>::from_str(r#"{"seq":1,"op":"put"}"#).is_err());
+    Ok(())
+}
+
 #[test]
 pub fn test_events_dup() -> Result<()> {
     let db = create_db("test_events_dup")?;
@@ -634,6 +1001,56 @@ pub fn test_query_authors_by_prefix() -> Result<()> {
         .collect::<Vec<Event>>();
     db.batch_put(events)?;
 
+    let prefix = |bytes: [u8; 32], len: usize| hex::encode(&bytes[0..len]);
+    let query = |filter: String| -> Result<Vec<Event>> {
+        let filter = Filter::from_str(&filter)?;
+        Ok(all(&db, &filter)?.0)
+    };
+
+    // both authors
+    let events = query(format!(r#"{{"authors": ["{}"]}}"#, prefix(author(250), 31)))?;
+    assert_eq!(events.len(), PER_NUM as usize * 2);
+    // in time order across the authors
+    assert!(events
+        .windows(2)
+        .all(|w| w[0].created_at() <= w[1].created_at()));
+
+    let events = query(format!(
+        r#"{{"authors": ["{}"], "kinds": [1], "limit": 5}}"#,
+        prefix(author(251), 32)
+    ))?;
+    assert_eq!(events.len(), 5);
+    assert!(events.iter().all(|e| e.pubkey() == &author(251)));
+    assert_eq!(events[0].created_at(), PER_NUM as u64 - 1);
+
+    // odd length prefix
+    let events = query(format!(
+        r#"{{"authors": ["{}f"], "since": 10, "until": 19}}"#,
+        prefix(author(250), 31)
+    ))?;
+    assert_eq!(events.len(), 20);
+    let events = query(format!(
+        r#"{{"authors": ["{}0"]}}"#,
+        prefix(author(250), 31)
+    ))?;
+    assert!(events.is_empty());
+
+    // ids
+    let events = query(format!(
+        r#"{{"ids": ["{}"], "limit": 100}}"#,
+        prefix(id(35, 0), 31)
+    ))?;
+    assert_eq!(events.len(), PER_NUM as usize);
+    assert!(events
+        .windows(2)
+        .all(|w| w[0].created_at() >= w[1].created_at()));
+    let events = query(format!(
+        r#"{{"ids": ["{}", "{}"]}}"#,
+        prefix(id(35, 0), 30),
+        prefix(id(35, 0), 31)
+    ))?;
+    assert_eq!(events.len(), PER_NUM as usize * 2);
+
     Ok(())
 }
 
@@ -762,6 +1179,88 @@ pub fn test_query_authors() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_query_parallel_scan() -> Result<()> {
+    let db = create_db("test_query_parallel_scan")?;
+    let events = (0..100u8)
+        .flat_map(|a| {
+            (0..3u8).map(move |i| {
+                MyEvent {
+                    id: id(a, i),
+                    pubkey: author(a),
+                    kind: 1 + (i % 2) as u16,
+                    created_at: (a as u64 * 7 + i as u64 * 13) % 50,
+                    ..Default::default()
+                }
+                .into()
+            })
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+
+    let ids = |db: &Db, filter: &Filter| -> Result<Vec<[u8; 32]>> {
+        Ok(all(db, filter)?.0.iter().map(|e| *e.id()).collect())
+    };
+    let authors = (0..100u8).map(author).collect::<Vec<_>>();
+    let filters = [
+        Filter {
+            authors: authors.clone().into(),
+            desc: true,
+            ..Default::default()
+        },
+        Filter {
+            authors: authors.clone().into(),
+            kinds: vec![1, 2].into(),
+            desc: true,
+            limit: Some(50),
+            ..Default::default()
+        },
+        Filter {
+            authors: authors.into(),
+            kinds: vec![2].into(),
+            since: Some(10),
+            desc: false,
+            ..Default::default()
+        },
+        Filter {
+            ids: (0..100u8).map(|a| id(a, 0)).collect::<Vec<_>>().into(),
+            desc: true,
+            ..Default::default()
+        },
+    ];
+    let serial = filters
+        .iter()
+        .map(|f| ids(&db, f))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(serial[0].len(), 300);
+    assert_eq!(serial[1].len(), 50);
+    assert_eq!(serial[3].len(), 100);
+
+    db.set_parallel_scan(Some(ParallelScan {
+        threads: 3,
+        max_queries: 1,
+        min_scanners: 10,
+        buffer: 4,
+    }))?;
+    assert_eq!(db.parallel_scan().unwrap().threads, 3);
+    for (filter, expected) in filters.iter().zip(&serial) {
+        assert_eq!(&ids(&db, filter)?, expected);
+    }
+    // count and the early drop of the prefetch threads
+    assert_eq!(count(&db, &filters[0])?.0, 300);
+    let reader = db.reader()?;
+    let mut iter = db.iter::<Event, _>(&reader, &filters[0])?;
+    assert!(iter.next().is_some());
+    // the other queries scan serially while the threads are used
+    let other = std::thread::scope(|s| s.spawn(|| ids(&db, &filters[3])).join().unwrap())?;
+    assert_eq!(other, serial[3]);
+    drop(iter);
+
+    db.set_parallel_scan(None)?;
+    assert!(db.parallel_scan().is_none());
+    Ok(())
+}
+
 #[test]
 pub fn test_query_created_at() -> Result<()> {
     let db = create_db("test_query_created_at")?;
@@ -858,6 +1357,97 @@ pub fn test_query_real_time() -> Result<()> {
     Ok(())
 }
 
+#[test]
+pub fn test_query_cursor() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-cursor")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    let event = |index: u8, created_at: u64| -> Event {
+        MyEvent {
+            id: id(90, index),
+            pubkey: author(index % 2),
+            kind: 1,
+            tags: vec![vec!["t".to_owned(), "page".to_owned()]],
+            created_at,
+            ..Default::default()
+        }
+        .into()
+    };
+    db.batch_put((0..8).map(|i| event(i, 10)).collect::<Vec<_>>())?;
+    assert_eq!(db.archive_before(11, 3)?, 8);
+    // the stored events of the archived second and the following seconds
+    db.batch_put(
+        (8..20)
+            .map(|i| event(i, 10 + i as u64 % 3))
+            .collect::<Vec<_>>(),
+    )?;
+
+    // read a page of the events after the cursor
+    let page = |filter: &str,
+                cursor: Option<Cursor>,
+                size: usize|
+     -> Result<(Vec<[u8; 32]>, Option<Cursor>)> {
+        let reader = db.reader()?;
+        let mut filter = Filter::from_str(filter)?;
+        filter.cursor = cursor;
+        let mut iter = db.iter::<Event, _>(&reader, &filter)?;
+        let ids = iter
+            .by_ref()
+            .take(size)
+            .map(|e| e.map(|e| *e.id()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((ids, iter.cursor()))
+    };
+
+    for filter in [
+        "{}",
+        r#"{"limit": 100}"#,
+        r#"{"kinds": [1]}"#,
+        r#"{"kinds": [1], "limit": 100}"#,
+        r##"{"#t": ["page"], "limit": 100}"##,
+        r#"{"since": 10, "until": 11}"#,
+    ] {
+        let (all, _) = page(filter, None, 100)?;
+        assert!(all.len() > 10, "{}", filter);
+        for size in [1, 3, 7] {
+            let mut cursor = None;
+            let mut paged = vec![];
+            loop {
+                let (ids, next) = page(filter, cursor, size)?;
+                if ids.is_empty() {
+                    break;
+                }
+                paged.extend(ids);
+                cursor = next;
+            }
+            assert_eq!(paged, all, "{} {}", filter, size);
+        }
+    }
+
+    // the stored events are before the archived ones in the same second
+    let cursor = page("{}", None, 4)?.1.unwrap();
+    assert_eq!((cursor.time, cursor.id), (10, None));
+    let cursor = page("{}", None, 5)?.1.unwrap();
+    assert_eq!(
+        (cursor.time, cursor.uid, cursor.id),
+        (10, 0, Some(id(90, 0)))
+    );
+    assert_eq!(Cursor::from_str(&cursor.to_string())?, cursor);
+    let cursor = page(r#"{"limit": 1}"#, None, 1)?.1.unwrap();
+    assert_eq!((cursor.time, cursor.id), (12, None));
+    assert_eq!(Cursor::from_str(&cursor.to_string())?, cursor);
+
+    let filter = Filter::from_str(&format!(r#"{{"cursor": "{}"}}"#, cursor))?;
+    assert_eq!(filter.cursor, Some(cursor));
+    assert!(filter.paginate);
+    let filter = Filter::from_str(r#"{"cursor": ""}"#)?;
+    assert!(filter.paginate && filter.cursor.is_none());
+    assert!(Filter::from_str(r#"{"cursor": "0102"}"#).is_err());
+    Ok(())
+}
+
 #[test]
 pub fn test_query_tag() -> Result<()> {
     let db = create_db("test_query_tag")?;
@@ -1391,3 +1981,940 @@ pub fn test_events_ephemeral() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+pub fn test_query_plan() -> Result<()> {
+    let db = create_db("test_query_plan")?;
+    let tag = vec!["t".to_owned(), "common".to_owned()];
+    // many authors with the common tag
+    let events = (0..PER_NUM)
+        .map(|i| {
+            MyEvent {
+                id: id(40, i),
+                pubkey: author(i),
+                kind: 1,
+                tags: vec![tag.clone(), tag.clone()],
+                created_at: i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(events)?;
+    // the rare author
+    let rare: Event = MyEvent {
+        id: id(41, 0),
+        pubkey: author(200),
+        kind: 1,
+        tags: vec![tag.clone()],
+        created_at: 10,
+        ..Default::default()
+    }
+    .into();
+    db.batch_put(vec![rare.clone()])?;
+
+    let mut filter = Filter {
+        desc: true,
+        authors: vec![author(200)].into(),
+        ..Default::default()
+    };
+    filter.set_tags(HashMap::from([("t".to_owned(), vec!["common".to_owned()])]));
+
+    let estimates = {
+        let reader = db.reader()?;
+        db.estimate(&reader, &filter)?
+    };
+    assert_eq!(estimates[0].plan, Plan::Author);
+    assert_eq!(estimates[0].entries, 1);
+    let tag_estimate = estimates.iter().find(|e| e.plan == Plan::Tag).unwrap();
+    assert_eq!(tag_estimate.entries, PER_NUM as u64 + 1);
+
+    let e1 = all(&db, &filter)?;
+    assert_eq!(e1.1.plan, Plan::Author);
+    assert_eq!(e1.0.len(), 1);
+    assert_eq!(e1.0[0].id(), rare.id());
+    assert_eq!(e1.1.get_index, 1);
+
+    // only tag
+    let mut tag_filter = Filter::default();
+    tag_filter.set_tags(HashMap::from([("t".to_owned(), vec!["common".to_owned()])]));
+    let e1 = all(&db, &tag_filter)?;
+    assert_eq!(e1.1.plan, Plan::Tag);
+    assert_eq!(e1.0.len(), PER_NUM as usize + 1);
+
+    let explain = db.explain(&filter, None)?;
+    assert_eq!(explain.plan, Plan::Author);
+    assert_eq!(explain.tree, "t_pubkey");
+    assert_eq!(explain.group, "or(1)");
+    assert!(explain.post_filter);
+    assert_eq!(explain.size, 1);
+    assert_eq!(explain.actual.get_index, 1);
+    assert_eq!(explain.actual.get_data, 1);
+    assert_eq!(explain.candidates.len(), 3);
+
+    let explain = db.explain(&tag_filter, None)?;
+    assert_eq!(explain.plan, Plan::Tag);
+    assert_eq!(explain.group, "and(or(1))");
+    assert!(!explain.post_filter);
+    assert_eq!(explain.size, PER_NUM as u64 + 1);
+
+    // the statistics are decreased after delete
+    db.batch_del(vec![rare.id()])?;
+    let estimates = {
+        let reader = db.reader()?;
+        db.estimate(&reader, &filter)?
+    };
+    assert_eq!(estimates[0].plan, Plan::Author);
+    assert_eq!(estimates[0].entries, 0);
+    let e1 = all(&db, &filter)?;
+    assert_eq!(e1.0.len(), 0);
+
+    // the statistics of the aborted transaction are discarded
+    {
+        let mut writer = db.writer()?;
+        db.put(&mut writer, &rare)?;
+    }
+    db.batch_put(Vec::<Event>::new())?;
+    let aborted = {
+        let reader = db.reader()?;
+        db.estimate(&reader, &filter)?
+    };
+    assert_eq!(estimates, aborted);
+
+    // the rebuilt statistics are the same
+    db.rebuild_stats()?;
+    let rebuilt = {
+        let reader = db.reader()?;
+        db.estimate(&reader, &filter)?
+    };
+    assert_eq!(estimates, rebuilt);
+    let e1 = count(&db, &tag_filter)?;
+    assert_eq!(e1.0, PER_NUM as u64);
+    let reader = db.reader()?;
+    let tag_estimate = db.estimate(&reader, &tag_filter)?;
+    assert_eq!(tag_estimate[0].entries, PER_NUM as u64);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_event_stats() -> Result<()> {
+    let db = create_db("test_event_stats")?;
+    let events = (0..PER_NUM)
+        .map(|i| {
+            MyEvent {
+                id: id(50, i),
+                pubkey: author(i % 3),
+                kind: if i % 2 == 0 { 1 } else { 7 },
+                content: "stats".to_owned(),
+                created_at: i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(&events)?;
+
+    let stats = db.stats(2)?;
+    assert_eq!(stats.total.count, PER_NUM as u64);
+    assert!(stats.total.bytes > 0);
+    assert_eq!(stats.kinds.len(), 2);
+    assert_eq!(stats.kinds[0].0, 1);
+    assert_eq!(stats.kinds[0].1.count, PER_NUM as u64 / 2);
+    assert_eq!(stats.kinds[1].0, 7);
+    assert_eq!(
+        stats.kinds[0].1.bytes + stats.kinds[1].1.bytes,
+        stats.total.bytes
+    );
+    assert_eq!(stats.authors.len(), 2);
+    assert_eq!(stats.authors[0].1.count, PER_NUM as u64 / 3);
+
+    // author 0 has the most events after delete
+    db.batch_del(vec![events[1].id(), events[2].id()])?;
+    let stats = db.stats(10)?;
+    assert_eq!(stats.total.count, PER_NUM as u64 - 2);
+    assert_eq!(stats.kinds[0].1.count, PER_NUM as u64 / 2 - 1);
+    assert_eq!(stats.authors.len(), 3);
+    assert_eq!(stats.authors[0].0, author(0));
+    assert_eq!(stats.authors[0].1.count, PER_NUM as u64 / 3);
+    assert!(db.stats(0)?.authors.is_empty());
+    {
+        let reader = db.reader()?;
+        assert_eq!(
+            db.count_author(&reader, &author(0), &[])?,
+            PER_NUM as u64 / 3
+        );
+        assert_eq!(
+            db.count_author(&reader, &author(0), &[1, 7])?,
+            PER_NUM as u64 / 3
+        );
+        assert_eq!(db.count_author(&reader, &author(3), &[])?, 0);
+    }
+
+    db.rebuild_stats()?;
+    let rebuilt = db.stats(10)?;
+    assert_eq!(rebuilt.total, stats.total);
+    assert_eq!(rebuilt.kinds, stats.kinds);
+    assert_eq!(rebuilt.authors, stats.authors);
+    Ok(())
+}
+
+#[test]
+pub fn test_partition() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-partition")
+        .tempdir()
+        .unwrap();
+    let day = 86_400u64;
+    let db = Db::open(dir.path())?;
+    let mut events = (0..3u8)
+        .flat_map(|d| {
+            (0..10u8).map(move |i| {
+                MyEvent {
+                    id: id(60 + d, i),
+                    pubkey: author(i % 2),
+                    kind: 1,
+                    created_at: d as u64 * day + i as u64,
+                    ..Default::default()
+                }
+                .into()
+            })
+        })
+        .collect::<Vec<Event>>();
+    events.push(
+        MyEvent {
+            id: id(70, 1),
+            pubkey: author(5),
+            kind: 0,
+            created_at: 0,
+            ..Default::default()
+        }
+        .into(),
+    );
+    db.batch_put(&events)?;
+
+    // the replaceable event and the current day are kept in the hot storage
+    let move_all = |db: &Db, period: Period, before: u64| -> Result<usize> {
+        let mut total = 0;
+        let mut cursor = None;
+        loop {
+            let (num, next) = db.partition_before(period, before, cursor, 4)?;
+            total += num;
+            cursor = next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        Ok(total)
+    };
+    assert_eq!(move_all(&db, Period::Day, 2 * day + 5)?, 20);
+    assert_eq!(move_all(&db, Period::Day, 2 * day + 5)?, 0);
+    assert_eq!(db.partition_before(Period::Day, 0, None, 4)?, (0, None));
+    let names = db
+        .partitions()
+        .into_iter()
+        .map(|p| p.name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["1970-01-01", "1970-01-02"]);
+    assert_eq!(db.stats(0)?.total.count, 11);
+
+    let times = |db: &Db, filter: &str| -> Result<Vec<u64>> {
+        let reader = db.reader()?;
+        let filter = Filter::from_str(filter)?;
+        let times = db
+            .iter::<Event, _>(&reader, &filter)?
+            .map(|e| e.map(|e| e.created_at()))
+            .collect();
+        times
+    };
+    let all = times(&db, "{}")?;
+    assert_eq!(all.len(), 31);
+    assert!(all.windows(2).all(|w| w[0] <= w[1]));
+    // merged in created_at order
+    assert_eq!(
+        times(&db, r#"{"limit": 12}"#)?[9..],
+        [2 * day, day + 9, day + 8]
+    );
+    assert_eq!(times(&db, &format!(r#"{{"since": {}}}"#, day))?.len(), 20);
+    assert_eq!(
+        times(&db, &format!(r#"{{"until": {}, "limit": 2}}"#, day + 1))?,
+        vec![day + 1, day]
+    );
+    assert_eq!(times(&db, r#"{"kinds": [0]}"#)?, vec![0]);
+    {
+        let reader = db.reader()?;
+        let filter = Filter::from_str(r#"{"kinds": [1], "limit": 25}"#)?;
+        let iter = db.iter::<Vec<u8>, _>(&reader, &filter)?;
+        assert_eq!(iter.size()?.0, 25);
+        let mut iter = db.iter::<Vec<u8>, _>(&reader, &filter)?;
+        assert_eq!(iter.by_ref().count(), 25);
+        assert!(iter.stats().get_data >= 25);
+        let event: Option<Event> = db.get(&reader, id(61, 2))?;
+        assert_eq!(event.unwrap().created_at(), day + 2);
+    }
+
+    // resume the query in the partitions by the cursor
+    {
+        let reader = db.reader()?;
+        let mut filter = Filter::from_str(r#"{"kinds": [1], "limit": 7}"#)?;
+        let mut ids = vec![];
+        loop {
+            let mut iter = db.iter::<Event, _>(&reader, &filter)?;
+            let page = iter.by_ref().collect::<Result<Vec<_>>>()?;
+            if page.is_empty() {
+                break;
+            }
+            ids.extend(page.iter().map(|e| *e.id()));
+            filter.cursor = iter.cursor();
+        }
+        assert_eq!(ids.len(), 30);
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 30);
+    }
+
+    // the moved events are still duplicated
+    assert_eq!(db.batch_put(vec![events[0].clone()])?, 0);
+
+    // crash after the partition is committed, before deleting from the hot storage
+    drop(db);
+    let part = Db::open(dir.path().join("partitions").join("1970-01-03"))?;
+    part.batch_put(vec![events[20].clone()])?;
+    drop(part);
+    let db = Db::open(dir.path())?;
+    assert_eq!(db.partitions().len(), 3);
+    assert_eq!(times(&db, "{}")?.len(), 31);
+    assert_eq!(times(&db, r#"{"kinds": [1], "since": 172800}"#)?.len(), 10);
+
+    // drop and archive the whole partitions
+    db.drop_partition("1970-01-01")?;
+    assert!(!dir.path().join("partitions/1970-01-01").exists());
+    assert_eq!(times(&db, r#"{"kinds": [1]}"#)?.len(), 20);
+    let archive = dir.path().join("old");
+    let part = db.archive_partition("1970-01-02", &archive)?;
+    assert_eq!(part.path, archive.join("1970-01-02"));
+    assert!(db.drop_partition("1970-01-02").is_err());
+    assert_eq!(times(&db, r#"{"kinds": [1]}"#)?.len(), 10);
+
+    let archived = Db::open(&part.path)?;
+    let reader = archived.reader()?;
+    let iter = archived.iter::<Vec<u8>, _>(&reader, &Filter::default())?;
+    assert_eq!(iter.size()?.0, 10);
+
+    // the events of the day are in the existing partition
+    assert_eq!(move_all(&db, Period::Month, 40 * day)?, 10);
+    assert_eq!(db.stats(0)?.total.count, 1);
+    drop(db);
+    let db = Db::open(dir.path())?;
+    let names = db
+        .partitions()
+        .into_iter()
+        .map(|p| p.name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["1970-01-03"]);
+    assert_eq!(times(&db, "{}")?.len(), 11);
+
+    // the moved events are deleted from their partitions at the commit
+    {
+        let mut writer = db.writer()?;
+        assert!(db.del(&mut writer, id(62, 3))?);
+    }
+    assert_eq!(times(&db, "{}")?.len(), 11);
+    let mut writer = db.writer()?;
+    assert!(db.del(&mut writer, id(62, 3))?);
+    assert!(!db.del(&mut writer, id(62, 20))?);
+    db.commit(writer)?;
+    assert_eq!(times(&db, r#"{"kinds": [1]}"#)?.len(), 9);
+
+    let deletion: Event = MyEvent {
+        id: id(71, 1),
+        pubkey: author(0),
+        kind: 5,
+        tags: vec![vec!["e".to_owned(), hex::encode(id(62, 4))]],
+        created_at: 3 * day,
+        ..Default::default()
+    }
+    .into();
+    db.batch_put(vec![deletion])?;
+    assert_eq!(times(&db, r#"{"kinds": [1]}"#)?.len(), 8);
+    let part = db.partitions().remove(0);
+    drop(db);
+    let part = Db::open(&part.path)?;
+    let reader = part.reader()?;
+    let iter = part.iter::<Vec<u8>, _>(&reader, &Filter::default())?;
+    assert_eq!(iter.size()?.0, 8);
+    Ok(())
+}
+
+#[test]
+pub fn test_partition_kept_events() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-partition-kept")
+        .tempdir()
+        .unwrap();
+    let day = 86_400u64;
+    let db = Db::open(dir.path())?;
+    // more replaceable and expiring events than the batch in the same second
+    let mut events = (0..10u8)
+        .map(|i| {
+            MyEvent {
+                id: id(72, i),
+                pubkey: author(i),
+                kind: 0,
+                created_at: 5,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    events.push(
+        MyEvent {
+            id: id(73, 0),
+            pubkey: author(0),
+            kind: 1,
+            tags: vec![vec!["expiration".to_owned(), (10 * day).to_string()]],
+            created_at: 5,
+            ..Default::default()
+        }
+        .into(),
+    );
+    events.extend((1..6u8).map(|i| {
+        MyEvent {
+            id: id(73, i),
+            pubkey: author(i),
+            kind: 1,
+            created_at: 5 + i as u64 / 3,
+            ..Default::default()
+        }
+        .into()
+    }));
+    db.batch_put(&events)?;
+
+    let mut total = 0;
+    let mut runs = 0;
+    let mut cursor = None;
+    loop {
+        let (num, next) = db.partition_before(Period::Day, day, cursor, 4)?;
+        total += num;
+        runs += 1;
+        assert!(
+            runs <= 5,
+            "the scan does not continue after the kept events"
+        );
+        cursor = next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(total, 5);
+    assert_eq!(db.stats(0)?.total.count, 11);
+    assert_eq!(db.partition_before(Period::Day, day, None, 100)?, (0, None));
+    Ok(())
+}
+
+#[test]
+pub fn test_sharded() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-sharded")
+        .tempdir()
+        .unwrap();
+    let db = ShardedDb::open(dir.path(), 4)?;
+    assert_eq!(db.num_shards(), 4);
+    assert!(dir.path().join("shard-3").exists());
+    let events = (0..40u8)
+        .map(|i| {
+            MyEvent {
+                id: id(90, i),
+                pubkey: author(i % 8),
+                kind: 1,
+                created_at: 100 + (i / 2) as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    assert_eq!(db.batch_put(&events)?, 40);
+    // dup in the author shard
+    assert_eq!(db.batch_put(&events[0..2])?, 0);
+    let used = db
+        .shards()
+        .iter()
+        .map(|s| s.stats(0).map(|s| s.total.count))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(used.iter().sum::<u64>(), 40);
+    assert!(used.iter().filter(|c| **c > 0).count() > 1);
+    for i in 0..8 {
+        let shard = db.shard_of(&author(i));
+        let reader = db.shards()[shard].reader()?;
+        assert_eq!(
+            db.shards()[shard].count_author(&reader, &author(i), &[])?,
+            5
+        );
+    }
+
+    let times = |filter: &str| -> Result<Vec<u64>> {
+        let reader = db.reader()?;
+        let filter = Filter::from_str(filter)?;
+        let times = db
+            .iter::<Event>(&reader, &filter)?
+            .map(|e| e.map(|e| e.created_at()))
+            .collect();
+        times
+    };
+    let all = times("{}")?;
+    assert_eq!(all.len(), 40);
+    assert!(all.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(times(r#"{"limit": 3}"#)?, vec![119, 119, 118]);
+    assert_eq!(times(r#"{"since": 110}"#)?.len(), 20);
+    {
+        let reader = db.reader()?;
+        let iter = db.iter::<Vec<u8>>(&reader, &Filter::from_str(r#"{"limit": 25}"#)?)?;
+        assert_eq!(iter.size()?.0, 25);
+        let event: Option<Event> = db.get(&reader, id(90, 7))?;
+        assert_eq!(event.unwrap().pubkey(), &author(7));
+    }
+
+    // page through the merged events by the cursor
+    for desc in [true, false] {
+        let mut cursor = None;
+        let mut ids = vec![];
+        loop {
+            let mut filter = Filter::from_str(r#"{"limit": 3}"#)?;
+            filter.desc = desc;
+            filter.cursor = cursor;
+            let reader = db.reader()?;
+            let mut iter = db.iter::<Event>(&reader, &filter)?;
+            let page = iter.by_ref().collect::<Result<Vec<_>>>()?;
+            if page.is_empty() {
+                break;
+            }
+            ids.extend(page.iter().map(|e| e.id().to_vec()));
+            cursor = iter.cursor();
+        }
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 40);
+    }
+
+    // the delegator deletes the delegated events in the other shards
+    let delegator = (8..64u8)
+        .find(|i| db.shard_of(&author(*i)) != db.shard_of(&author(1)))
+        .unwrap();
+    let delegated: Event = MyEvent {
+        id: id(91, 1),
+        pubkey: author(1),
+        kind: 1,
+        tags: vec![vec![
+            "delegation".to_owned(),
+            hex::encode(author(delegator)),
+            "".to_owned(),
+            "".to_owned(),
+        ]],
+        created_at: 200,
+        ..Default::default()
+    }
+    .into();
+    let deletion: Event = MyEvent {
+        id: id(91, 2),
+        pubkey: author(delegator),
+        kind: 5,
+        tags: vec![vec!["e".to_owned(), hex::encode(id(91, 1))]],
+        created_at: 201,
+        ..Default::default()
+    }
+    .into();
+    assert_eq!(db.batch_put(vec![delegated])?, 1);
+    assert_eq!(db.batch_put(vec![deletion])?, 2);
+    assert_eq!(times(r#"{"kinds": [1]}"#)?.len(), 40);
+    db.batch_del(vec![id(90, 0)])?;
+    assert_eq!(times(r#"{"kinds": [1]}"#)?.len(), 39);
+    let explain = db.explain(&Filter::from_str(r#"{"kinds": [1]}"#)?, None)?;
+    assert_eq!(explain.len(), 4);
+    assert_eq!(explain.iter().map(|e| e.size).sum::<u64>(), 39);
+
+    drop(db);
+    assert!(ShardedDb::open(dir.path(), 2).is_err());
+    assert!(ShardedDb::open(dir.path(), 1).is_err());
+    assert_eq!(ShardedDb::open(dir.path(), 4)?.num_shards(), 4);
+    assert_eq!(ShardedDb::open_existing(dir.path())?.num_shards(), 4);
+    let single = tempfile::Builder::new()
+        .prefix("nostr-db-test-sharded-single")
+        .tempdir()
+        .unwrap();
+    assert_eq!(ShardedDb::open_existing(single.path())?.num_shards(), 1);
+    Ok(())
+}
+
+#[test]
+pub fn test_archive() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-archive")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    let mut events = (0..PER_NUM)
+        .map(|i| {
+            MyEvent {
+                id: id(80, i),
+                pubkey: author(i % 3),
+                kind: 1,
+                content: "archive".to_owned(),
+                created_at: 10 + i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    events.push(
+        MyEvent {
+            id: id(81, 1),
+            pubkey: author(1),
+            kind: 0,
+            created_at: 11,
+            ..Default::default()
+        }
+        .into(),
+    );
+    let expiring = Event::from(MyEvent {
+        id: id(81, 2),
+        pubkey: author(1),
+        kind: 1,
+        tags: vec![vec!["expiration".to_owned(), "50".to_owned()]],
+        created_at: 12,
+        ..Default::default()
+    });
+    db.batch_put(events.iter().chain([&expiring]))?;
+
+    // the replaceable and expiring events are kept in the hot storage
+    assert_eq!(db.archive_before(30, 8)?, 20);
+    assert_eq!(db.archive().segments().len(), 3);
+    assert_eq!(db.archive().range(), Some((10, 29)));
+    assert_eq!(db.archive_before(30, 8)?, 0);
+    assert_eq!(db.stats(0)?.total.count, 12);
+
+    // the expiring event is deleted when expired
+    let expired = |db: &Db, time: u64| -> Result<Vec<Vec<u8>>> {
+        let reader = db.reader()?;
+        let ids = db
+            .iter_expiration::<Vec<u8>, _>(&reader, Some(time))?
+            .collect();
+        ids
+    };
+    assert!(expired(&db, 49)?.is_empty());
+    assert_eq!(expired(&db, 50)?, vec![expiring.id().to_vec()]);
+    db.batch_del(expired(&db, 50)?)?;
+    assert!(db.batch_get::<Event, _, _>([expiring.id()])?.is_empty());
+    assert_eq!(db.stats(0)?.total.count, 11);
+
+    let times = |db: &Db, filter: &str| -> Result<Vec<u64>> {
+        let reader = db.reader()?;
+        let filter = Filter::from_str(filter)?;
+        let times = db
+            .iter::<Event, _>(&reader, &filter)?
+            .map(|e| e.map(|e| e.created_at()))
+            .collect();
+        times
+    };
+    let all = times(&db, "{}")?;
+    assert_eq!(all.len(), PER_NUM as usize + 1);
+    assert!(all.windows(2).all(|w| w[0] <= w[1]));
+    assert_eq!(
+        times(&db, r#"{"limit": 3}"#)?,
+        vec![39, 38, 37],
+        "only hot events"
+    );
+    assert_eq!(times(&db, r#"{"limit": 12}"#)?[10..], [29, 28]);
+    assert_eq!(times(&db, r#"{"since": 25, "until": 32}"#)?.len(), 8);
+    assert_eq!(times(&db, r#"{"kinds": [1], "until": 20}"#)?.len(), 11);
+    assert_eq!(
+        times(
+            &db,
+            &format!(
+                r#"{{"authors": ["{}"], "kinds": [1]}}"#,
+                hex::encode(author(1))
+            )
+        )?
+        .len(),
+        10
+    );
+    assert_eq!(
+        times(
+            &db,
+            &format!(r#"{{"ids": ["{}"]}}"#, hex::encode(id(80, 3)))
+        )?,
+        vec![13]
+    );
+
+    // merge the new event created in the archived time range
+    db.batch_put(vec![Event::from(MyEvent {
+        id: id(82, 1),
+        pubkey: author(1),
+        kind: 1,
+        created_at: 15,
+        ..Default::default()
+    })])?;
+    let all = times(&db, r#"{"until": 16}"#)?;
+    assert_eq!(all, vec![10, 11, 11, 12, 13, 14, 15, 15, 16]);
+
+    {
+        let reader = db.reader()?;
+        let filter = Filter::from_str(r#"{"kinds": [1], "limit": 25}"#)?;
+        let iter = db.iter::<Vec<u8>, _>(&reader, &filter)?;
+        let (size, stats) = iter.size()?;
+        assert_eq!(size, 25);
+        assert!(stats.scan_index >= 25);
+        let ids = db
+            .iter::<Vec<u8>, _>(&reader, &filter)?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(ids.len(), 25);
+        assert_eq!(ids[24], id(82, 1).to_vec());
+    }
+
+    // the segments out of the id and author blooms are not read
+    {
+        let reader = db.reader()?;
+        for filter in [
+            format!(r#"{{"ids": ["{}"]}}"#, hex::encode(id(83, 1))),
+            format!(r#"{{"authors": ["{}"]}}"#, hex::encode(author(9))),
+        ] {
+            let filter = Filter::from_str(&filter)?;
+            let (size, stats) = db.iter::<Vec<u8>, _>(&reader, &filter)?.size()?;
+            assert_eq!(size, 0);
+            assert!(stats.scan_index < 8);
+        }
+    }
+
+    // the archived events are duplicated and deleted by the tombstone
+    let archived = events[3].clone();
+    let mut writer = db.writer()?;
+    assert!(matches!(
+        db.put(&mut writer, &archived)?,
+        CheckEventResult::Duplicate
+    ));
+    db.commit(writer)?;
+    db.batch_put(vec![Event::from(MyEvent {
+        id: id(82, 2),
+        pubkey: author(0),
+        kind: 5,
+        tags: vec![vec!["e".to_owned(), hex::encode(id(80, 3))]],
+        created_at: 100,
+        ..Default::default()
+    })])?;
+    assert!(times(
+        &db,
+        &format!(r#"{{"ids": ["{}"]}}"#, hex::encode(id(80, 3)))
+    )?
+    .is_empty());
+    assert_eq!(times(&db, r#"{"kinds": [1], "until": 20}"#)?.len(), 11);
+
+    // reload the segments
+    drop(db);
+    let db = Db::open(dir.path())?;
+    assert_eq!(db.archive().segments().len(), 3);
+    assert_eq!(times(&db, "{}")?.len(), PER_NUM as usize + 2);
+
+    // the footer out of the file length is rejected
+    let segment = db.archive().segments()[0].path.clone();
+    let corrupted = dir.path().join("corrupted");
+    std::fs::create_dir(&corrupted)?;
+    let mut bytes = std::fs::read(&segment)?;
+    let len = bytes.len();
+    bytes[len - 32..len - 24].copy_from_slice(&u64::MAX.to_be_bytes());
+    std::fs::write(corrupted.join(segment.file_name().unwrap()), &bytes)?;
+    assert!(Archive::open(&corrupted).is_err());
+    std::fs::write(
+        corrupted.join(segment.file_name().unwrap()),
+        &bytes[..len - 8],
+    )?;
+    assert!(Archive::open(&corrupted).is_err());
+    Ok(())
+}
+
+#[test]
+pub fn test_dictionary() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-dictionary")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    assert!(db.compress(100).is_err(), "no dictionary");
+    let events = (0..200u32)
+        .map(|i| {
+            MyEvent {
+                id: id(90 + (i / 100) as u8, (i % 100) as u8),
+                pubkey: author((i % 5) as u8),
+                kind: 1,
+                tags: vec![vec!["t".to_owned(), format!("topic{}", i % 7)]],
+                content: format!("good morning nostr, this is the note number {}", i),
+                created_at: 10 + i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(&events[0..150])?;
+    let before = db.stats(0)?;
+    assert!(before.raw_bytes > 0);
+    assert_eq!(before.compressed.count, 0);
+    assert_eq!(before.dictionary, None);
+
+    let dict = db.train_dictionary(150, 2048)?;
+    assert_eq!(db.dictionary().map(|d| d.id), Some(dict));
+    // the new events are compressed with the dictionary
+    db.batch_put(&events[150..])?;
+    assert_eq!(db.stats(0)?.compressed.count, 50);
+
+    assert_eq!(db.compress(40)?, 150);
+    assert_eq!(db.compress(40)?, 0);
+    let stats = db.stats(0)?;
+    assert_eq!(stats.total.count, 200);
+    assert_eq!(stats.compressed, stats.total);
+    assert_eq!(stats.dictionary, Some(dict));
+    assert!(stats.total.bytes < stats.raw_bytes);
+
+    let check = |db: &Db| -> Result<()> {
+        let reader = db.reader()?;
+        let all = db
+            .iter::<Event, _>(&reader, &Filter::default())?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(all.len(), 200);
+        for (a, b) in all.iter().zip(events.iter()) {
+            assert_eq!(a.to_json()?, b.to_json()?);
+        }
+        let json = db.get::<String, _, _>(&reader, events[3].id())?;
+        assert_eq!(json, Some(events[3].to_json()?));
+        Ok(())
+    };
+    check(&db)?;
+
+    // the stats keep the same after rebuilding
+    db.rebuild_stats()?;
+    let rebuilt = db.stats(0)?;
+    assert_eq!(rebuilt.total, stats.total);
+    assert_eq!(rebuilt.compressed, stats.compressed);
+    assert_eq!(rebuilt.raw_bytes, stats.raw_bytes);
+
+    // reopen
+    drop(db);
+    let db = Db::open(dir.path())?;
+    assert_eq!(db.dictionary().map(|d| d.id), Some(dict));
+    check(&db)?;
+    db.batch_del(events.iter().map(|e| e.id()))?;
+    let stats = db.stats(0)?;
+    assert_eq!(stats.total.count, 0);
+    assert_eq!(stats.compressed.count, 0);
+    assert_eq!(stats.raw_bytes, 0);
+    Ok(())
+}
+
+#[test]
+pub fn test_env_stats() -> Result<()> {
+    let db = create_db("test_env_stats")?;
+    let events = (0..PER_NUM)
+        .map(|i| {
+            MyEvent {
+                id: id(96, i),
+                pubkey: author(1),
+                kind: 1,
+                created_at: i as u64,
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(&events)?;
+    let stats = db.env_stats()?;
+    let tree = |name: &str| stats.trees.iter().find(|t| t.0 == name).map(|t| t.1);
+    assert_eq!(tree("t_data").map(|s| s.entries), Some(PER_NUM as u64));
+    assert_eq!(tree("t_index").map(|s| s.entries), Some(PER_NUM as u64));
+    assert_eq!(stats.env.entries as usize, stats.trees.len());
+    assert!(stats.info.last_txn_id > 0);
+    assert!(stats.used_bytes() >= tree("t_data").unwrap().bytes());
+    Ok(())
+}
+
+#[test]
+pub fn test_durability() -> Result<()> {
+    let db = create_db("test_durability")?;
+    assert_eq!(db.durability()?, Durability::Sync);
+    for durability in [Durability::NoSync, Durability::NoMetaSync, Durability::Sync] {
+        db.set_durability(durability)?;
+        assert_eq!(db.durability()?, durability);
+        db.batch_put([MyEvent {
+            id: id(97, durability as u8),
+            kind: 1,
+            ..Default::default()
+        }
+        .into_and_build_words()])?;
+        db.flush()?;
+    }
+    assert!(!Durability::NoSync.sync_on_commit());
+    assert_eq!(
+        serde_json::from_str::<Durability>(r#""no_meta_sync""#).unwrap(),
+        Durability::NoMetaSync
+    );
+    assert_eq!(count(&db, &Filter::default())?.0, 3);
+    Ok(())
+}
+
+#[test]
+pub fn test_query_long_tags() -> Result<()> {
+    let db = create_db("test_query_long_tags")?;
+    db.set_index_tags(["alt", "poll_r"])?;
+    assert_eq!(db.index_tags().names(), vec!["alt", "poll_r"]);
+    assert!(db.set_index_tags(["a\0"]).is_err());
+    // the other databases don't index the names
+    assert!(create_db("test_query_long_tags_other")?
+        .index_tags()
+        .is_empty());
+    let long = "l".repeat(600);
+    let events = (0..PER_NUM)
+        .map(|i| {
+            MyEvent {
+                id: id(95, i),
+                pubkey: author(1),
+                kind: 1,
+                created_at: i as u64,
+                tags: vec![
+                    vec!["alt".to_owned(), format!("alt{}", i % 2)],
+                    vec!["t".to_owned(), format!("{}{}", long, i % 3)],
+                    vec!["other".to_owned(), "not indexed".to_owned()],
+                ],
+                ..Default::default()
+            }
+            .into()
+        })
+        .collect::<Vec<Event>>();
+    db.batch_put(&events)?;
+
+    let query = |filter: &Filter| -> Result<usize> { Ok(all(&db, filter)?.0.len()) };
+    let filter = Filter::from_str(r##"{"#alt": ["alt1"]}"##)?;
+    assert_eq!(query(&filter)?, 15);
+    let filter = Filter::from_str(r##"{"#other": ["not indexed"]}"##)?;
+    assert!(filter.tags.is_empty());
+    assert_eq!(query(&filter)?, PER_NUM as usize);
+
+    let filter = Filter::from_str(&format!(r##"{{"#t": ["{}0", "{}1"]}}"##, long, long))?;
+    assert_eq!(filter.long_tags.len(), 1);
+    assert_eq!(query(&filter)?, 20);
+    assert_eq!(count(&db, &filter)?.0, 20);
+    let filter = Filter::from_str(&format!(
+        r##"{{"#t": ["{}2"], "#alt": ["alt0"], "limit": 100}}"##,
+        long
+    ))?;
+    assert_eq!(query(&filter)?, 5);
+
+    // the hash index is verified by the original value
+    let mut filter = Filter::from_str(&format!(r##"{{"#t": ["{}0"]}}"##, long))?;
+    filter
+        .long_tags
+        .insert(b"t".to_vec(), vec![format!("{}x", long)].into());
+    assert_eq!(query(&filter)?, 0);
+    assert_eq!(count(&db, &filter)?.0, 0);
+
+    // the index of the additional names is deleted with the event
+    db.batch_del([id(95, 1)])?;
+    let filter = Filter::from_str(r##"{"#alt": ["alt1"]}"##)?;
+    assert_eq!(query(&filter)?, 14);
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use nostr_db::{DataKey, Db, Error, Event, Filter};
+use std::str::FromStr;
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+fn note(i: u8, topic: &str) -> Event {
+    Event::new(
+        [i; 32],
+        [1; 32],
+        10 + i as u64,
+        1,
+        vec![
+            vec!["t".to_owned(), topic.to_owned()],
+            vec!["e".to_owned(), hex::encode([100 + i; 32])],
+        ],
+        format!("secret note {}", i),
+        [0; 64],
+    )
+    .unwrap()
+}
+
+fn query(db: &Db, filter: &str) -> Result<Vec<Event>> {
+    let reader = db.reader()?;
+    let events = db
+        .iter::<Event, _>(&reader, &Filter::from_str(filter)?)?
+        .collect::<Result<Vec<_>>>()?;
+    Ok(events)
+}
+
+#[test]
+pub fn test_encryption() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nostr-db-test-encryption")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    let key = DataKey::generate();
+    db.set_tag_key(Some(&key))?;
+    db.batch_put([note(1, "plain"), note(2, "rust")])?;
+    db.set_data_key(Some(key.clone()));
+    assert_eq!(db.data_key().map(|k| k.id), Some(key.id));
+    db.batch_put([note(3, "rust"), note(4, "nostr")])?;
+
+    let check = |db: &Db| -> Result<()> {
+        assert_eq!(query(db, "{}")?.len(), 4);
+        let events = query(db, r##"{"#t": ["rust"]}"##)?;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].content(), "secret note 3");
+        let filter = format!(r##"{{"#e": ["{}"]}}"##, hex::encode([104u8; 32]));
+        assert_eq!(query(db, &filter)?.len(), 1);
+        Ok(())
+    };
+    check(&db)?;
+    let size = db.stats(0)?.total.bytes;
+
+    // encrypt the old events
+    assert_eq!(db.rekey(1)?, 2);
+    assert_eq!(db.rekey(1)?, 0);
+    assert!(db.stats(0)?.total.bytes > size);
+    check(&db)?;
+
+    // the deletion finds the events by the plain e tag
+    let deletion = Event::new(
+        [5; 32],
+        [1; 32],
+        20,
+        5,
+        vec![vec!["e".to_owned(), hex::encode([4u8; 32])]],
+        String::new(),
+        [0; 64],
+    )?;
+    db.batch_put([deletion])?;
+    assert_eq!(query(&db, r#"{"kinds": [1]}"#)?.len(), 3);
+
+    // rotate the key, the old key is registered to decrypt the data before rekey
+    drop(db);
+    let db = Db::open(dir.path())?;
+    db.set_tag_key(Some(&key))?;
+    let new_key = DataKey::generate();
+    db.set_data_key(Some(new_key));
+    assert_eq!(query(&db, "{}")?.len(), 4);
+    assert_eq!(db.rekey(10)?, 4);
+
+    // decrypt all
+    drop(db);
+    let db = Db::open(dir.path())?;
+    db.set_tag_key(Some(&key))?;
+    assert_eq!(db.rekey(10)?, 4);
+    assert_eq!(query(&db, "{}")?.len(), 4);
+    assert_eq!(query(&db, r##"{"#t": ["nostr"]}"##)?.len(), 0);
+    assert_eq!(query(&db, r##"{"#t": ["rust"]}"##)?.len(), 2);
+
+    // the tag index is built with the key, another key is refused until reindexed
+    assert!(db.set_tag_key(None).is_err());
+    assert!(db.set_tag_key(Some(&DataKey::generate())).is_err());
+    assert_eq!(db.reindex_tags(None, 1)?, 3);
+    assert_eq!(db.reindex_tags(None, 1)?, 0);
+    drop(db);
+    let db = Db::open(dir.path())?;
+    assert!(db.set_tag_key(Some(&key)).is_err());
+    db.set_tag_key(None)?;
+    assert_eq!(query(&db, r##"{"#t": ["rust"]}"##)?.len(), 2);
+    assert_eq!(query(&db, r##"{"#t": ["plain"]}"##)?.len(), 1);
+
+    // the archive blocks are encrypted by the data key
+    db.set_data_key(Some(key));
+    assert_eq!(db.archive_before(20, 10)?, 3);
+    let segment = std::fs::read(&db.archive().segments()[0].path)?;
+    assert!(!segment.windows(6).any(|w| w == b"secret"));
+    let events = query(&db, r#"{"kinds": [1]}"#)?;
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].content(), "secret note 1");
+    assert_eq!(query(&db, r##"{"#t": ["rust"]}"##)?.len(), 2);
+
+    // the archive segments are rewritten by rekey
+    db.set_data_key(None);
+    assert_eq!(db.rekey(10)?, 3);
+    assert_eq!(db.rekey(10)?, 0);
+    assert_ne!(std::fs::read(&db.archive().segments()[0].path)?, segment);
+    assert_eq!(query(&db, r#"{"kinds": [1]}"#)?.len(), 3);
+    Ok(())
+}
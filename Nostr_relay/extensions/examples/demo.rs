@@ -11,6 +11,7 @@ async fn main() -> nostr_relay::Result<()> {
         true,
         Some("NOSTR".to_owned()),
         None,
+        None,
     )?;
     app_data
         .add_extension(nostr_extensions::Metrics::new())
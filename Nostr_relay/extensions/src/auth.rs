@@ -143,7 +143,7 @@ impl Extension for Auth {
 
     fn message(
         &self,
-        msg: ClientMessage,
+        mut msg: ClientMessage,
         session: &mut Session,
         _ctx: &mut <Session as actix::Actor>::Context,
     ) -> ExtensionMessageResult {
@@ -180,6 +180,7 @@ impl Extension for Auth {
                         )
                         .into();
                     }
+                    msg.authenticated = state.and_then(|s| s.pubkey()) == Some(&event.pubkey_str());
                 }
                 IncomingMessage::Req(_) => {
                     if let Err(err) = Self::verify_permission(
@@ -369,6 +370,7 @@ mod tests {
             )?;
         }
         let app = app.add_extension(Auth::new());
+        let db = app.db.clone();
         let app = web::Data::new(app);
 
         let mut srv = actix_test::start(move || create_web_app(app.clone()));
@@ -423,6 +425,24 @@ mod tests {
         let notice: (String, String) = parse_text(&framed.next().await.unwrap()?)?;
         assert!(notice.1.contains("success"));
 
+        // the authors of the events written by the session are recorded if authenticated
+        let other = KeyPair::new_global(&mut rng);
+        for key_pair in [&key_pair, &other] {
+            let event = Event::create(key_pair, now(), 1, vec![], "".to_owned())?;
+            framed
+                .send(ws::Message::Text(
+                    format!(r#"["EVENT", {}]"#, event.to_string()).into(),
+                ))
+                .await?;
+            let ok: (String, String, bool, String) = parse_text(&framed.next().await.unwrap()?)?;
+            assert!(ok.2);
+        }
+        let reader = db.reader()?;
+        let pubkey = |key_pair: &KeyPair| XOnlyPublicKey::from_keypair(key_pair).0.serialize();
+        assert!(db.is_authenticated(&reader, &pubkey(&key_pair))?);
+        assert!(!db.is_authenticated(&reader, &pubkey(&other))?);
+        drop(reader);
+
         framed
             .send(ws::Message::Close(Some(ws::CloseCode::Normal.into())))
             .await?;
@@ -0,0 +1,290 @@
+use crate::check_auth;
+use actix_web::{rt::time::sleep, web, web::Bytes, HttpResponse};
+use futures_util::stream;
+use nostr_relay::{
+    db::{Db, Error},
+    setting::SettingWrapper,
+    App, Extension,
+};
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+
+// the maximum number of changes per chunk
+const BATCH_SIZE: usize = 1000;
+/// The response header of the number of shards, the followers refuse the sharded primary
+pub const SHARDS_HEADER: &str = "X-Shards";
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ChangesSetting {
+    pub enabled: bool,
+    pub auth: Option<String>,
+    /// polling interval of the new changes in milliseconds when following
+    pub interval: u64,
+}
+
+impl Default for ChangesSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            auth: None,
+            interval: 1000,
+        }
+    }
+}
+
+/// Stream the stored and deleted events as jsonl, [`Db::changes_since`]
+pub struct Changes;
+
+impl Changes {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Default for Changes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Extension for Changes {
+    fn name(&self) -> &'static str {
+        "changes"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        let s: ChangesSetting = w.parse_extension(self.name());
+        w.set_extension(s);
+    }
+
+    fn config_web(&mut self, cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(web::resource("/changes").route(web::get().to(route_changes)));
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Info {
+    auth: Option<String>,
+    /// the sequence numbers are per shard, tail each shard by the index
+    shard: usize,
+    /// start from the sequence number (inclusive)
+    since: u64,
+    /// keep the response open for the new changes
+    follow: bool,
+}
+
+// read the next batch of changes as jsonl
+fn read_changes(db: &Db, since: u64) -> Result<(String, u64), Error> {
+    let reader = db.reader()?;
+    let mut next = since;
+    let mut lines = String::new();
+    for change in db
+        .changes_since::<String, _>(&reader, since)
+        .take(BATCH_SIZE)
+    {
+        let change = change?;
+        next = change.seq() + 1;
+        lines.push_str(&change.to_json());
+        lines.push('\n');
+    }
+    Ok((lines, next))
+}
+
+async fn route_changes(
+    app: web::Data<App>,
+    query: web::Query<Info>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let interval = {
+        let setting = app.setting.read();
+        match setting.get_extension::<ChangesSetting>() {
+            // the changes contain all events, only allow with the auth key
+            Some(s) if s.enabled && check_auth(s.auth.as_deref(), query.auth.as_deref()) => {
+                Duration::from_millis(s.interval)
+            }
+            _ => return Ok(HttpResponse::NotFound().finish()),
+        }
+    };
+    let Some(db) = app.shards.shards().get(query.shard) else {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "the shard index should be less than {}",
+            app.shards.num_shards()
+        )));
+    };
+    let db: Arc<Db> = db.clone();
+    let shards = app.shards.num_shards();
+    let follow = query.follow;
+    let body = stream::unfold(Some(query.since), move |since| {
+        let db = db.clone();
+        async move {
+            let since = since?;
+            let changes = web::block(move || read_changes(&db, since))
+                .await
+                .map_err(|e| Error::Message(e.to_string()))
+                .and_then(|r| r);
+            match changes {
+                Ok((lines, next)) if !lines.is_empty() => {
+                    Some((Ok(Bytes::from(lines)), Some(next)))
+                }
+                Ok(_) if follow => {
+                    sleep(interval).await;
+                    // an empty line when idle, the follower times out a silent stream
+                    Some((Ok(Bytes::from_static(b"\n")), Some(since)))
+                }
+                Ok(_) => None,
+                Err(err) => Some((Err(err), None)),
+            }
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .insert_header(("Content-Type", "application/x-ndjson"))
+        .insert_header((SHARDS_HEADER, shards.to_string()))
+        .streaming(body))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Changes, SHARDS_HEADER};
+    use crate::{create_test_app, temp_data_path};
+    use actix_web::{
+        dev::Service,
+        test::{init_service, read_body, TestRequest},
+    };
+    use anyhow::Result;
+    use nostr_relay::{
+        db::{
+            now,
+            secp256k1::{rand::thread_rng, KeyPair},
+            Event,
+        },
+        App,
+    };
+    use std::{
+        net::{TcpListener, TcpStream},
+        time::Duration,
+    };
+    use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+    #[actix_rt::test]
+    async fn changes() -> Result<()> {
+        let data = create_test_app("changes")?;
+        {
+            let mut w = data.setting.write();
+            w.extra = serde_json::from_str(
+                r#"{
+                "changes": {
+                    "enabled": true,
+                    "auth": "auth_key"
+                }
+            }"#,
+            )?;
+        }
+        let data = data.add_extension(Changes::new());
+        let event = Event::new([1; 32], [2; 32], 10, 1, vec![], "".to_owned(), [0; 64])?;
+        data.db.batch_put(vec![event])?;
+        data.db.batch_del(vec![[1; 32]])?;
+
+        let app = init_service(data.web_app()).await;
+        let req = TestRequest::with_uri("/changes").to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 404);
+
+        let req = TestRequest::with_uri("/changes?auth=auth_key&since=1").to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers().get(SHARDS_HEADER).unwrap(), "1");
+        let result = String::from_utf8(read_body(res).await.to_vec())?;
+        assert_eq!(
+            result,
+            format!(
+                "{{\"seq\":1,\"op\":\"delete\",\"uid\":0,\"id\":\"{}\"}}\n",
+                "01".repeat(32)
+            )
+        );
+
+        let req = TestRequest::with_uri("/changes?auth=auth_key&shard=1").to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 400);
+
+        // no access without the auth key in the setting
+        let data = create_test_app("changes_no_auth")?;
+        {
+            let mut w = data.setting.write();
+            w.extra = serde_json::from_str(r#"{"changes": {"enabled": true}}"#)?;
+        }
+        let app = init_service(data.add_extension(Changes::new()).web_app()).await;
+        let req = TestRequest::with_uri("/changes").to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 404);
+        Ok(())
+    }
+
+    // start the relay with the config on a free local port
+    fn start(name: &str, config: &str) -> Result<(u16, tempfile::TempDir)> {
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let dir = temp_data_path(name)?;
+        let file = dir.path().join("rnostr.toml");
+        std::fs::write(&file, format!("[network]\nport = {}\n{}", port, config))?;
+        let app = App::create(Some(file), false, None, Some(dir.path().join("data")), None)?
+            .add_extension(Changes::new());
+        actix_rt::spawn(app.web_server()?);
+        Ok((port, dir))
+    }
+
+    // read until the message starts with the prefix
+    fn read(ws: &mut WebSocket<MaybeTlsStream<TcpStream>>, prefix: &str) -> Result<String> {
+        loop {
+            if let Message::Text(text) = ws.read()? {
+                if text.starts_with(prefix) {
+                    return Ok(text);
+                }
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn follower() -> Result<()> {
+        let (primary, _primary_dir) = start(
+            "changes_primary",
+            "[changes]\nenabled = true\nauth = \"auth_key\"\ninterval = 50\n",
+        )?;
+        let (follower, _follower_dir) = start(
+            "changes_follower",
+            &format!(
+                "[follower]\nprimary = \"ws://127.0.0.1:{}\"\nauth = \"auth_key\"\nreconnect_interval = \"100ms\"\n",
+                primary
+            ),
+        )?;
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let event = Event::create(&key_pair, now(), 1, vec![], "hello follower".to_owned())?;
+        let id = event.id_str();
+
+        actix_rt::task::spawn_blocking(move || -> Result<()> {
+            let (mut ws, _) = tungstenite::connect(format!("ws://127.0.0.1:{}", follower))?;
+            if let MaybeTlsStream::Plain(stream) = ws.get_ref() {
+                stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+            }
+            ws.send(Message::Text(r#"["REQ","live",{}]"#.to_owned()))?;
+            read(&mut ws, r#"["EOSE""#)?;
+
+            // forwarded to the primary
+            ws.send(Message::Text(format!(r#"["EVENT",{}]"#, event)))?;
+            let ok = read(&mut ws, r#"["OK""#)?;
+            assert!(ok.contains(&id) && ok.contains("true"), "{}", ok);
+
+            // replicated from the primary and dispatched
+            let live = read(&mut ws, r#"["EVENT","live""#)?;
+            assert!(live.contains(&id));
+
+            // served from the local database
+            ws.send(Message::Text(r#"["REQ","stored",{}]"#.to_owned()))?;
+            let stored = read(&mut ws, r#"["EVENT","stored""#)?;
+            assert!(stored.contains("hello follower"));
+            Ok(())
+        })
+        .await??;
+        Ok(())
+    }
+}
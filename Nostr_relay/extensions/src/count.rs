@@ -1,13 +1,12 @@
 use metrics::{describe_histogram, histogram};
 use nostr_relay::{
-    db::{Db, Filter},
+    db::{Filter, ShardedDb},
     duration::NonZeroDuration,
     message::{ClientMessage, IncomingMessage, OutgoingMessage},
     setting::SettingWrapper,
     Error, Extension, ExtensionMessageResult, Session,
 };
 use serde::Deserialize;
-use std::sync::Arc;
 use std::time::Instant;
 
 #[derive(Deserialize, Default, Debug)]
@@ -17,11 +16,11 @@ pub struct CountSetting {
 
 pub struct Count {
     setting: CountSetting,
-    db: Arc<Db>,
+    db: ShardedDb,
 }
 
 impl Count {
-    pub fn new(db: Arc<Db>) -> Self {
+    pub fn new(db: ShardedDb) -> Self {
         describe_histogram!("nostr_relay_count_size", "The time of per filter count");
         Self {
             setting: CountSetting::default(),
@@ -32,7 +31,7 @@ impl Count {
     fn count(&self, filter: &Filter, timeout: Option<NonZeroDuration>) -> Result<u64, Error> {
         let reader = self.db.reader()?;
         let start = Instant::now();
-        let mut iter = self.db.iter::<String, _>(&reader, filter)?;
+        let mut iter = self.db.iter::<String>(&reader, filter)?;
         if let Some(time) = timeout {
             iter.scan_time(time.into(), 2000);
         }
@@ -133,7 +132,7 @@ mod tests {
             }"#,
             )?;
         }
-        let db = app.db.clone();
+        let db = app.shards.clone();
         let app = app.add_extension(Count::new(db));
         let app = web::Data::new(app);
 
@@ -0,0 +1,152 @@
+use crate::check_auth;
+use actix_web::{web, HttpResponse};
+use nostr_relay::{db::Filter, setting::SettingWrapper, App, Extension};
+use serde::Deserialize;
+use std::str::FromStr;
+
+#[derive(Deserialize, Default, Debug)]
+pub struct ExplainSetting {
+    pub enabled: bool,
+    pub auth: Option<String>,
+}
+
+/// Show how a filter executes, for tuning slow queries.
+/// https://example.com/explain?auth=auth_key&filter={"kinds":[1]}
+#[derive(Default)]
+pub struct Explain;
+
+impl Explain {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Extension for Explain {
+    fn name(&self) -> &'static str {
+        "explain"
+    }
+
+    fn setting(&mut self, setting: &SettingWrapper) {
+        let mut w = setting.write();
+        let s: ExplainSetting = w.parse_extension(self.name());
+        w.set_extension(s);
+    }
+
+    fn config_web(&mut self, cfg: &mut actix_web::web::ServiceConfig) {
+        cfg.service(web::resource("/explain").route(web::get().to(route_explain)));
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct Info {
+    auth: Option<String>,
+    filter: Option<String>,
+}
+
+async fn route_explain(
+    app: web::Data<App>,
+    query: web::Query<Info>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (allowed, timeout, max_limit) = {
+        let setting = app.setting.read();
+        // the explain will run the query, only allow with the auth key
+        let allowed = setting
+            .get_extension::<ExplainSetting>()
+            .map(|s| s.enabled && check_auth(s.auth.as_deref(), query.auth.as_deref()))
+            .unwrap_or_default();
+        (
+            allowed,
+            setting.data.db_query_timeout,
+            setting.limitation.max_limit,
+        )
+    };
+    if !allowed {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let mut filter = match Filter::from_str(query.filter.as_deref().unwrap_or("{}")) {
+        Ok(filter) => filter,
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err.to_string())),
+    };
+    #[cfg(feature = "search")]
+    filter.build_words();
+    // limit the scan as the requests of the clients
+    filter.default_limit(max_limit);
+    filter.limit = filter.limit.map(|limit| limit.min(max_limit));
+
+    let shards = app.shards.clone();
+    match web::block(move || shards.explain(&filter, timeout.map(Into::into))).await? {
+        // the list of the shards explains if there are multiple shards
+        Ok(mut explains) if explains.len() == 1 => Ok(HttpResponse::Ok().json(explains.remove(0))),
+        Ok(explains) => Ok(HttpResponse::Ok().json(explains)),
+        Err(err) => Ok(HttpResponse::InternalServerError().body(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Explain;
+    use crate::create_test_app;
+    use actix_web::{
+        dev::Service,
+        test::{init_service, read_body, TestRequest},
+    };
+    use anyhow::Result;
+    use nostr_relay::db::Event;
+
+    #[actix_rt::test]
+    async fn explain() -> Result<()> {
+        let data = create_test_app("explain")?;
+        {
+            let mut w = data.setting.write();
+            w.limitation.max_limit = 2;
+            w.extra = serde_json::from_str(
+                r#"{
+                "explain": {
+                    "enabled": true,
+                    "auth": "auth_key"
+                }
+            }"#,
+            )?;
+        }
+        let data = data.add_extension(Explain::new());
+        let events = (0..3u8)
+            .map(|i| Event::new([i; 32], [1; 32], 10, 2, vec![], "".to_owned(), [0; 64]))
+            .collect::<Result<Vec<_>, _>>()?;
+        data.db.batch_put(events)?;
+        let app = init_service(data.web_app()).await;
+
+        let req = TestRequest::with_uri("/explain").to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 404);
+
+        let req =
+            TestRequest::with_uri("/explain?auth=auth_key&filter=%7B%22kinds%22%3A%5B1%5D%7D")
+                .to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let result = read_body(res).await;
+        let result: serde_json::Value = serde_json::from_slice(&result)?;
+        assert_eq!(result["plan"], "kind");
+        assert_eq!(result["size"], 0);
+
+        // the limit is capped by the max limit
+        for filter in ["%7B%7D", "%7B%22limit%22%3A10%7D"] {
+            let req = TestRequest::with_uri(&format!("/explain?auth=auth_key&filter={}", filter))
+                .to_request();
+            let res = app.call(req).await.unwrap();
+            assert_eq!(res.status(), 200);
+            let result: serde_json::Value = serde_json::from_slice(&read_body(res).await)?;
+            assert_eq!(result["size"], 2);
+        }
+
+        let req = TestRequest::with_uri("/explain?auth=auth_kex").to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 404);
+
+        let req = TestRequest::with_uri("/explain?auth=auth_key&filter=invalid").to_request();
+        let res = app.call(req).await.unwrap();
+        assert_eq!(res.status(), 400);
+        Ok(())
+    }
+}
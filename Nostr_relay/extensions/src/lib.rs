@@ -21,6 +21,32 @@ pub mod search;
 #[cfg(feature = "search")]
 pub use search::Search;
 
+#[cfg(feature = "explain")]
+pub mod explain;
+#[cfg(feature = "explain")]
+pub use explain::Explain;
+
+#[cfg(feature = "changes")]
+pub mod changes;
+#[cfg(feature = "changes")]
+pub use changes::Changes;
+
+/// Whether the auth key of the request is the configured key, compared in constant time.
+/// No access without the configured key.
+#[cfg(any(feature = "explain", feature = "changes"))]
+pub(crate) fn check_auth(key: Option<&str>, auth: Option<&str>) -> bool {
+    match (key, auth) {
+        (Some(key), Some(auth)) if key.len() == auth.len() => {
+            let diff = key
+                .bytes()
+                .zip(auth.bytes())
+                .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+            std::hint::black_box(diff) == 0
+        }
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 pub fn temp_data_path(p: &str) -> anyhow::Result<tempfile::TempDir> {
     Ok(tempfile::Builder::new()
@@ -35,5 +61,6 @@ pub fn create_test_app(db_path: &str) -> anyhow::Result<nostr_relay::App> {
         false,
         None,
         Some(temp_data_path(db_path)?),
+        None,
     )?)
 }
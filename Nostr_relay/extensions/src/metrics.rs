@@ -1,17 +1,52 @@
 use actix_web::{web, HttpResponse};
-use metrics::{describe_counter, describe_gauge, describe_histogram};
+use metrics::{describe_counter, describe_gauge, describe_histogram, gauge};
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
-use nostr_relay::{setting::SettingWrapper, App, Extension};
+use nostr_relay::{
+    db::{
+        kv::lmdb::{EnvInfo, Stat},
+        Counter, Db, ShardedDb,
+    },
+    setting::SettingWrapper,
+    App, Extension,
+};
+use parking_lot::Mutex;
 use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+};
+use tracing::error;
 
-#[derive(Deserialize, Default, Debug)]
+#[derive(Deserialize, Debug)]
+#[serde(default)]
 pub struct MetricsSetting {
     pub enabled: bool,
     pub auth: Option<String>,
+    /// number of the top authors by volume exported as gauges, 0 will skip scanning the authors
+    pub top_authors: usize,
+}
+
+impl Default for MetricsSetting {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            auth: None,
+            top_authors: 10,
+        }
+    }
+}
+
+/// The label sets of the event gauges set by the last render,
+/// the ones disappeared since are reset to 0.
+#[derive(Default, Debug)]
+pub struct DbGauges {
+    kinds: HashSet<String>,
+    authors: HashSet<String>,
 }
 
 pub struct Metrics {
     pub handle: web::Data<PrometheusHandle>,
+    pub gauges: web::Data<Mutex<DbGauges>>,
 }
 
 impl Metrics {
@@ -20,6 +55,7 @@ impl Metrics {
         describe_metrics();
         Self {
             handle: web::Data::new(handle),
+            gauges: web::Data::new(Mutex::new(DbGauges::default())),
         }
     }
 }
@@ -43,6 +79,7 @@ impl Extension for Metrics {
 
     fn config_web(&mut self, cfg: &mut actix_web::web::ServiceConfig) {
         cfg.app_data(self.handle.clone())
+            .app_data(self.gauges.clone())
             .service(web::resource("/metrics").route(web::get().to(route_metrics)));
     }
 }
@@ -64,6 +101,182 @@ pub fn describe_metrics() {
     describe_counter!("nostr_relay_new_event", "The total count of new event");
     describe_histogram!("nostr_relay_db_get", "The time of per filter get");
     describe_histogram!("nostr_relay_db_write", "The time of per write transaction");
+    describe_histogram!(
+        "nostr_relay_db_flush",
+        "The time of per flush of the unsynced commits"
+    );
+    describe_histogram!(
+        "nostr_relay_write_latency",
+        "The time from receiving the event to the durable write result"
+    );
+    describe_gauge!(
+        "nostr_relay_pending_writes",
+        "The number of events waiting for the write result"
+    );
+    describe_gauge!(
+        "nostr_relay_verify_pending",
+        "The number of events waiting for the signature verification"
+    );
+    describe_histogram!(
+        "nostr_relay_verify_time",
+        "The time of per event signature verification"
+    );
+    describe_counter!(
+        "nostr_relay_verify_rejected",
+        "The count of events rejected by the full verification queue"
+    );
+    describe_counter!(
+        "nostr_relay_retention_deleted",
+        "The total count of events deleted by the retention rules"
+    );
+    describe_counter!(
+        "nostr_relay_partition_moved",
+        "The total count of events moved to the time partitions"
+    );
+    describe_gauge!(
+        "nostr_relay_db_events",
+        "The number of stored events per kind"
+    );
+    describe_gauge!(
+        "nostr_relay_db_event_bytes",
+        "The bytes of stored event data per kind"
+    );
+    describe_gauge!(
+        "nostr_relay_db_author_events",
+        "The number of stored events of the top authors"
+    );
+    describe_gauge!(
+        "nostr_relay_db_author_bytes",
+        "The bytes of stored event data of the top authors"
+    );
+    describe_gauge!(
+        "nostr_relay_lmdb_map_size",
+        "The size of the lmdb memory map"
+    );
+    describe_gauge!(
+        "nostr_relay_lmdb_map_used",
+        "The bytes of the lmdb memory map used by the pages"
+    );
+    describe_gauge!("nostr_relay_lmdb_readers", "The lmdb reader slots in use");
+    describe_gauge!("nostr_relay_lmdb_max_readers", "The max lmdb reader slots");
+    describe_gauge!(
+        "nostr_relay_lmdb_last_txn_id",
+        "The id of the last committed lmdb transaction"
+    );
+    describe_gauge!(
+        "nostr_relay_lmdb_tree_entries",
+        "The number of entries per lmdb tree"
+    );
+    describe_gauge!(
+        "nostr_relay_lmdb_tree_depth",
+        "The b-tree depth per lmdb tree"
+    );
+    describe_gauge!(
+        "nostr_relay_lmdb_tree_pages",
+        "The number of branch, leaf and overflow pages per lmdb tree"
+    );
+}
+
+// update the event and lmdb statistics gauges before render, summed across the shards
+fn update_db_gauges(shards: &[Arc<Db>], top_authors: usize, gauges: &mut DbGauges) {
+    let mut kinds: BTreeMap<u16, Counter> = BTreeMap::new();
+    let mut authors = vec![];
+    for db in shards {
+        match db.stats(top_authors) {
+            Ok(stats) => {
+                for (kind, counter) in stats.kinds {
+                    let c = kinds.entry(kind).or_default();
+                    c.count += counter.count;
+                    c.bytes += counter.bytes;
+                }
+                // an author is stored in one shard only
+                authors.extend(stats.authors);
+            }
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "failed to get the event statistics"
+                );
+                return;
+            }
+        }
+    }
+    authors.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(&b.0)));
+    authors.truncate(top_authors);
+
+    let mut kind_labels = HashSet::new();
+    for (kind, counter) in kinds {
+        let kind = kind.to_string();
+        gauge!("nostr_relay_db_events", counter.count as f64, "kind" => kind.clone());
+        gauge!("nostr_relay_db_event_bytes", counter.bytes as f64, "kind" => kind.clone());
+        kind_labels.insert(kind);
+    }
+    for kind in gauges.kinds.difference(&kind_labels) {
+        gauge!("nostr_relay_db_events", 0.0, "kind" => kind.clone());
+        gauge!("nostr_relay_db_event_bytes", 0.0, "kind" => kind.clone());
+    }
+    gauges.kinds = kind_labels;
+
+    let mut author_labels = HashSet::new();
+    for (pubkey, counter) in authors {
+        let author = hex::encode(pubkey);
+        gauge!("nostr_relay_db_author_events", counter.count as f64, "author" => author.clone());
+        gauge!("nostr_relay_db_author_bytes", counter.bytes as f64, "author" => author.clone());
+        author_labels.insert(author);
+    }
+    for author in gauges.authors.difference(&author_labels) {
+        gauge!("nostr_relay_db_author_events", 0.0, "author" => author.clone());
+        gauge!("nostr_relay_db_author_bytes", 0.0, "author" => author.clone());
+    }
+    gauges.authors = author_labels;
+
+    let mut info = EnvInfo::default();
+    let mut used = 0;
+    let mut trees: BTreeMap<String, Stat> = BTreeMap::new();
+    for db in shards {
+        match db.env_stats() {
+            Ok(stats) => {
+                used += stats.used_bytes();
+                info.map_size += stats.info.map_size;
+                info.readers += stats.info.readers;
+                info.max_readers += stats.info.max_readers;
+                info.last_txn_id = info.last_txn_id.max(stats.info.last_txn_id);
+                for (tree, stat) in stats.trees {
+                    let t = trees.entry(tree).or_default();
+                    t.depth = t.depth.max(stat.depth);
+                    t.entries += stat.entries;
+                    t.branch_pages += stat.branch_pages;
+                    t.leaf_pages += stat.leaf_pages;
+                    t.overflow_pages += stat.overflow_pages;
+                }
+            }
+            Err(err) => {
+                error!(error = err.to_string(), "failed to get the lmdb statistics");
+                return;
+            }
+        }
+    }
+    gauge!("nostr_relay_lmdb_map_size", info.map_size as f64);
+    gauge!("nostr_relay_lmdb_map_used", used as f64);
+    gauge!("nostr_relay_lmdb_readers", info.readers as f64);
+    gauge!("nostr_relay_lmdb_max_readers", info.max_readers as f64);
+    gauge!("nostr_relay_lmdb_last_txn_id", info.last_txn_id as f64);
+    for (tree, stat) in trees {
+        gauge!("nostr_relay_lmdb_tree_entries", stat.entries as f64, "tree" => tree.clone());
+        gauge!("nostr_relay_lmdb_tree_depth", stat.depth as f64, "tree" => tree.clone());
+        for (kind, pages) in [
+            ("branch", stat.branch_pages),
+            ("leaf", stat.leaf_pages),
+            ("overflow", stat.overflow_pages),
+        ] {
+            gauge!(
+                "nostr_relay_lmdb_tree_pages",
+                pages as f64,
+                "tree" => tree.clone(),
+                "type" => kind
+            );
+        }
+    }
 }
 
 pub fn create_prometheus_handle() -> PrometheusHandle {
@@ -84,18 +297,23 @@ struct Info {
 
 async fn route_metrics(
     handle: web::Data<PrometheusHandle>,
+    gauges: web::Data<Mutex<DbGauges>>,
     app: web::Data<App>,
     query: web::Query<Info>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let setting = app.setting.read();
-    if let Some(s) = setting.get_extension::<MetricsSetting>() {
-        if s.enabled && s.auth == query.auth {
-            return Ok(HttpResponse::Ok()
-                .insert_header(("Content-Type", "text/plain"))
-                .body(handle.render()));
+    let top_authors = {
+        let setting = app.setting.read();
+        match setting.get_extension::<MetricsSetting>() {
+            Some(s) if s.enabled && s.auth == query.auth => s.top_authors,
+            _ => return Ok(HttpResponse::NotFound().finish()),
         }
-    }
-    Ok(HttpResponse::NotFound().finish())
+    };
+    // the statistics scan blocks on lmdb
+    let shards: ShardedDb = app.shards.clone();
+    web::block(move || update_db_gauges(shards.shards(), top_authors, &mut gauges.lock())).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/plain"))
+        .body(handle.render()))
 }
 
 #[cfg(test)]
@@ -140,6 +358,8 @@ pub mod tests {
         let result = read_body(res).await;
         let result = String::from_utf8(result.to_vec())?;
         assert!(result.contains("test_metric"));
+        assert!(result.contains("nostr_relay_lmdb_map_size"));
+        assert!(result.contains(r#"nostr_relay_lmdb_tree_entries{tree="t_data"}"#));
         Ok(())
     }
 }
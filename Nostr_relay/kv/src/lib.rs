@@ -1,7 +1,9 @@
 use std::ffi::NulError;
 
 pub mod lmdb;
+pub mod memory;
 pub mod scanner;
+pub mod store;
 
 #[derive(thiserror::Error, Debug, Clone)]
 pub enum Error {
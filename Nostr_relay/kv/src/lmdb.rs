@@ -1,3 +1,4 @@
+pub use crate::store::{SeekIter, Store, Transaction, Tree, WriteTransaction};
 use crate::Error;
 use libc::{c_char, c_int, c_uint, c_void, size_t, EINVAL};
 pub use lmdb_master_sys as ffi;
@@ -58,59 +59,73 @@ impl Dbi {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Tree {
-    inner: ffi::MDB_dbi,
-    flags: c_uint,
+// the lmdb transaction handle
+trait RawTxn: Sized {
+    fn txn(&self) -> *mut ffi::MDB_txn;
 }
 
-unsafe impl Send for Tree {}
-unsafe impl Sync for Tree {}
+fn txn_commit<T: RawTxn>(txn: T) -> Result<()> {
+    unsafe {
+        let result = lmdb_result(ffi::mdb_txn_commit(txn.txn()));
+        mem::forget(txn);
+        result
+    }
+}
 
-pub trait Transaction: Sized {
-    fn txn(&self) -> *mut ffi::MDB_txn;
+fn txn_get<'txn, T: RawTxn>(txn: &'txn T, tree: &Tree, key: &[u8]) -> Result<Option<&'txn [u8]>> {
+    let mut key_val = ffi::MDB_val {
+        mv_size: key.len() as size_t,
+        mv_data: key.as_ptr() as *mut c_void,
+    };
 
-    fn commit(self) -> Result<()> {
-        unsafe {
-            let result = lmdb_result(ffi::mdb_txn_commit(self.txn()));
-            mem::forget(self);
-            result
+    let mut data_val = MaybeUninit::uninit();
+    unsafe {
+        match ffi::mdb_get(txn.txn(), tree.inner, &mut key_val, data_val.as_mut_ptr()) {
+            ffi::MDB_SUCCESS => Ok(Some(val_to_slice(data_val.assume_init()))),
+            ffi::MDB_NOTFOUND => Ok(None),
+            err_code => Err(lmdb_error(err_code)),
         }
     }
+}
 
-    fn get<'txn, K: AsRef<[u8]>>(&'txn self, tree: &Tree, key: K) -> Result<Option<&'txn [u8]>> {
-        let key = key.as_ref();
-        let mut key_val = ffi::MDB_val {
-            mv_size: key.len() as size_t,
-            mv_data: key.as_ptr() as *mut c_void,
-        };
-
-        let mut data_val = MaybeUninit::uninit();
-        unsafe {
-            match ffi::mdb_get(self.txn(), tree.inner, &mut key_val, data_val.as_mut_ptr()) {
-                ffi::MDB_SUCCESS => Ok(Some(val_to_slice(data_val.assume_init()))),
-                ffi::MDB_NOTFOUND => Ok(None),
-                err_code => Err(lmdb_error(err_code)),
+macro_rules! impl_transaction {
+    ($name:ty) => {
+        impl Transaction for $name {
+            type Iter<'txn>
+                = Iter<'txn>
+            where
+                Self: 'txn;
+
+            fn get<'txn, K: AsRef<[u8]>>(
+                &'txn self,
+                tree: &Tree,
+                key: K,
+            ) -> Result<Option<&'txn [u8]>> {
+                txn_get(self, tree, key.as_ref())
             }
-        }
-    }
 
-    fn iter_from<'txn, K: AsRef<[u8]>>(
-        &'txn self,
-        tree: &Tree,
-        from: Bound<K>,
-        rev: bool,
-    ) -> Iter<'txn> {
-        let mut iter = Iter::new(self, tree);
-        iter.seek(from, rev);
-        iter
-    }
+            fn iter_from<'txn, K: AsRef<[u8]>>(
+                &'txn self,
+                tree: &Tree,
+                from: Bound<K>,
+                rev: bool,
+            ) -> Iter<'txn> {
+                let mut iter = Iter::new(self, tree);
+                iter.seek(from, rev);
+                iter
+            }
 
-    fn iter(&self, tree: &Tree) -> Iter {
-        self.iter_from(tree, Bound::Unbounded::<Vec<u8>>, false)
-    }
+            fn commit(self) -> Result<()> {
+                txn_commit(self)
+            }
+        }
+    };
 }
 
+impl_transaction!(Reader<'_>);
+impl_transaction!(OwnedReader);
+impl_transaction!(Writer<'_>);
+
 pub struct Reader<'env> {
     inner: *mut ffi::MDB_txn,
     _marker: PhantomData<&'env Db>,
@@ -122,7 +137,7 @@ impl<'env> Drop for Reader<'env> {
     }
 }
 
-impl<'env> Transaction for Reader<'env> {
+impl<'env> RawTxn for Reader<'env> {
     fn txn(&self) -> *mut ffi::MDB_txn {
         self.inner
     }
@@ -146,6 +161,25 @@ impl<'env> Reader<'env> {
     }
 }
 
+/// A read transaction which keeps the environment open, it does not borrow the [`Db`].
+pub struct OwnedReader {
+    inner: *mut ffi::MDB_txn,
+    // the transaction must be aborted before the environment is closed
+    _db: Arc<DbInner>,
+}
+
+impl Drop for OwnedReader {
+    fn drop(&mut self) {
+        unsafe { ffi::mdb_txn_abort(self.inner) }
+    }
+}
+
+impl RawTxn for OwnedReader {
+    fn txn(&self) -> *mut ffi::MDB_txn {
+        self.inner
+    }
+}
+
 pub struct Writer<'env> {
     inner: *mut ffi::MDB_txn,
     _marker: PhantomData<&'env Db>,
@@ -157,7 +191,7 @@ impl<'env> Drop for Writer<'env> {
     }
 }
 
-impl<'env> Transaction for Writer<'env> {
+impl<'env> RawTxn for Writer<'env> {
     fn txn(&self) -> *mut ffi::MDB_txn {
         self.inner
     }
@@ -230,6 +264,29 @@ impl<'env> Writer<'env> {
             }
         }
     }
+
+    /// Empty the tree, keep it open
+    pub fn clear(&mut self, tree: &Tree) -> Result<()> {
+        unsafe { lmdb_result(ffi::mdb_drop(self.inner, tree.inner, 0)) }
+    }
+}
+
+impl<'env> WriteTransaction for Writer<'env> {
+    fn put<K, V>(&mut self, tree: &Tree, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        Writer::put(self, tree, key, value)
+    }
+
+    fn del<K: AsRef<[u8]>>(&mut self, tree: &Tree, key: K, value: Option<&[u8]>) -> Result<()> {
+        Writer::del(self, tree, key, value)
+    }
+
+    fn clear(&mut self, tree: &Tree) -> Result<()> {
+        Writer::clear(self, tree)
+    }
 }
 
 fn to_cpath<P: AsRef<Path>>(path: P) -> Result<CString, Error> {
@@ -331,7 +388,7 @@ impl DbInner {
 
         let dbi = Dbi::new(writer.inner, name, flags)?;
         let inner = dbi.inner;
-        writer.commit()?;
+        txn_commit(writer)?;
         dbs.insert(sname, dbi);
         Ok(Tree { flags, inner })
     }
@@ -342,7 +399,7 @@ impl DbInner {
             unsafe {
                 lmdb_result(ffi::mdb_drop(writer.inner, dbi.inner, 1))?;
             }
-            writer.commit()?;
+            txn_commit(writer)?;
             Ok(true)
         } else {
             Ok(false)
@@ -350,6 +407,62 @@ impl DbInner {
     }
 }
 
+/// The b-tree statistics of the environment or a tree, `mdb_stat`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub page_size: u32,
+    pub depth: u32,
+    pub branch_pages: u64,
+    pub leaf_pages: u64,
+    pub overflow_pages: u64,
+    pub entries: u64,
+}
+
+impl Stat {
+    pub fn pages(&self) -> u64 {
+        self.branch_pages + self.leaf_pages + self.overflow_pages
+    }
+
+    /// The bytes of the pages used by the b-tree
+    pub fn bytes(&self) -> u64 {
+        self.pages() * self.page_size as u64
+    }
+}
+
+impl From<ffi::MDB_stat> for Stat {
+    fn from(stat: ffi::MDB_stat) -> Self {
+        Self {
+            page_size: stat.ms_psize,
+            depth: stat.ms_depth,
+            branch_pages: stat.ms_branch_pages as u64,
+            leaf_pages: stat.ms_leaf_pages as u64,
+            overflow_pages: stat.ms_overflow_pages as u64,
+            entries: stat.ms_entries as u64,
+        }
+    }
+}
+
+/// The information of the environment, `mdb_env_info`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EnvInfo {
+    /// the size of the memory map
+    pub map_size: u64,
+    /// the id of the last used page
+    pub last_page: u64,
+    /// the id of the last committed transaction
+    pub last_txn_id: u64,
+    pub max_readers: u32,
+    /// the reader slots in use
+    pub readers: u32,
+}
+
+impl EnvInfo {
+    /// The bytes of the map used by the pages
+    pub fn used_bytes(&self, page_size: u32) -> u64 {
+        (self.last_page + 1) * page_size as u64
+    }
+}
+
 #[derive(Clone)]
 pub struct Db {
     inner: Arc<DbInner>,
@@ -391,12 +504,123 @@ impl Db {
         Reader::new(&self.inner)
     }
 
+    pub fn owned_reader(&self) -> Result<OwnedReader> {
+        let reader = Reader::new(&self.inner)?;
+        let inner = reader.inner;
+        mem::forget(reader);
+        Ok(OwnedReader {
+            inner,
+            _db: self.inner.clone(),
+        })
+    }
+
     pub fn flush(&self) -> Result<()> {
         unsafe {
             lmdb_result(ffi::mdb_env_sync(self.inner.inner, 1))?;
         }
         Ok(())
     }
+
+    /// Set or clear the environment flags which can be changed at any time,
+    /// such as `MDB_NOSYNC` and `MDB_NOMETASYNC`
+    pub fn set_flags(&self, flags: u32, on: bool) -> Result<()> {
+        unsafe {
+            lmdb_result(ffi::mdb_env_set_flags(self.inner.inner, flags, on as c_int))?;
+        }
+        Ok(())
+    }
+
+    /// The environment flags
+    pub fn flags(&self) -> Result<u32> {
+        let mut flags = 0;
+        unsafe {
+            lmdb_result(ffi::mdb_env_get_flags(self.inner.inner, &mut flags))?;
+        }
+        Ok(flags)
+    }
+
+    /// The statistics of the main tree of the environment
+    pub fn stat(&self) -> Result<Stat> {
+        let mut stat = MaybeUninit::uninit();
+        unsafe {
+            lmdb_result(ffi::mdb_env_stat(self.inner.inner, stat.as_mut_ptr()))?;
+            Ok(stat.assume_init().into())
+        }
+    }
+
+    pub fn info(&self) -> Result<EnvInfo> {
+        let mut info = MaybeUninit::<ffi::MDB_envinfo>::uninit();
+        let info = unsafe {
+            lmdb_result(ffi::mdb_env_info(self.inner.inner, info.as_mut_ptr()))?;
+            info.assume_init()
+        };
+        Ok(EnvInfo {
+            map_size: info.me_mapsize as u64,
+            last_page: info.me_last_pgno as u64,
+            last_txn_id: info.me_last_txnid as u64,
+            max_readers: info.me_maxreaders,
+            readers: info.me_numreaders,
+        })
+    }
+
+    /// The statistics of the tree in the committed snapshot
+    pub fn tree_stat(&self, tree: &Tree) -> Result<Stat> {
+        let reader = self.reader()?;
+        txn_stat(&reader, tree.inner)
+    }
+
+    /// The statistics of the opened named trees, sorted by name
+    pub fn tree_stats(&self) -> Result<Vec<(String, Stat)>> {
+        let reader = self.reader()?;
+        let mut stats = self
+            .inner
+            .dbs
+            .read()
+            .iter()
+            .filter_map(|(name, dbi)| name.clone().map(|name| (name, dbi.inner)))
+            .map(|(name, dbi)| Ok((name, txn_stat(&reader, dbi)?)))
+            .collect::<Result<Vec<_>>>()?;
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(stats)
+    }
+}
+
+fn txn_stat<T: RawTxn>(txn: &T, dbi: ffi::MDB_dbi) -> Result<Stat> {
+    let mut stat = MaybeUninit::uninit();
+    unsafe {
+        lmdb_result(ffi::mdb_stat(txn.txn(), dbi, stat.as_mut_ptr()))?;
+        Ok(stat.assume_init().into())
+    }
+}
+
+impl Store for Db {
+    type Reader<'env> = Reader<'env>;
+    type Writer<'env> = Writer<'env>;
+    type OwnedReader = OwnedReader;
+
+    fn open_tree(&self, name: Option<&str>, flags: u32) -> Result<Tree> {
+        Db::open_tree(self, name, flags)
+    }
+
+    fn drop_tree(&self, name: Option<&str>) -> Result<bool> {
+        Db::drop_tree(self, name)
+    }
+
+    fn reader(&self) -> Result<Reader<'_>> {
+        Db::reader(self)
+    }
+
+    fn owned_reader(&self) -> Result<OwnedReader> {
+        Db::owned_reader(self)
+    }
+
+    fn writer(&self) -> Result<Writer<'_>> {
+        Db::writer(self)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Db::flush(self)
+    }
 }
 
 pub struct Iter<'txn> {
@@ -409,7 +633,7 @@ pub struct Iter<'txn> {
 }
 
 impl<'txn> Iter<'txn> {
-    fn new<T: Transaction>(txn: &'txn T, tree: &Tree) -> Self {
+    fn new<T: RawTxn>(txn: &'txn T, tree: &Tree) -> Self {
         let dup = tree.flags & ffi::MDB_DUPSORT == ffi::MDB_DUPSORT;
 
         let inner = IterInner::new(txn, tree.inner);
@@ -533,6 +757,12 @@ impl<'txn> Iter<'txn> {
     }
 }
 
+impl<'txn> SeekIter<'txn> for Iter<'txn> {
+    fn seek<K: AsRef<[u8]>>(&mut self, from: Bound<K>, rev: bool) {
+        Iter::seek(self, from, rev)
+    }
+}
+
 impl<'txn> Iterator for Iter<'txn> {
     type Item = Result<(&'txn [u8], &'txn [u8]), Error>;
     fn next(&mut self) -> Option<Self::Item> {
@@ -575,7 +805,7 @@ struct IterInner<'txn> {
 type Item<'a> = Result<Option<(&'a [u8], &'a [u8])>>;
 
 impl<'txn> IterInner<'txn> {
-    fn new<T: Transaction>(txn: &'txn T, dbi: ffi::MDB_dbi) -> Result<Self> {
+    fn new<T: RawTxn>(txn: &'txn T, dbi: ffi::MDB_dbi) -> Result<Self> {
         let mut cursor: *mut ffi::MDB_cursor = ptr::null_mut();
         unsafe {
             lmdb_result(ffi::mdb_cursor_open(txn.txn(), dbi, &mut cursor))?;
@@ -0,0 +1,327 @@
+//! The in-memory backend on sorted maps.
+//!
+//! The readers share the committed snapshot, a writer copies the trees on the first change,
+//! it suits the tests and the small embedded databases.
+
+use crate::{
+    store::{SeekIter, Store, Transaction, Tree, WriteTransaction},
+    Error,
+};
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Bound,
+    sync::Arc,
+};
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+// key -> sorted values, the tree without duplicate values has one value per key
+type TreeData = BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>;
+type Snapshot = Arc<Vec<Arc<TreeData>>>;
+
+#[derive(Default)]
+struct MemoryInner {
+    trees: RwLock<HashMap<Option<String>, Tree>>,
+    // the committed data, indexed by the tree id
+    data: RwLock<Snapshot>,
+    write: Mutex<()>,
+}
+
+/// The in-memory store
+#[derive(Clone, Default)]
+pub struct Memory {
+    inner: Arc<MemoryInner>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        self.inner.data.read().clone()
+    }
+}
+
+impl Store for Memory {
+    type Reader<'env> = Reader;
+    type Writer<'env> = Writer<'env>;
+    type OwnedReader = Reader;
+
+    fn open_tree(&self, name: Option<&str>, flags: u32) -> Result<Tree> {
+        let mut trees = self.inner.trees.write();
+        let name = name.map(ToOwned::to_owned);
+        if let Some(tree) = trees.get(&name) {
+            return Ok(tree.clone());
+        }
+        let mut data = self.inner.data.write();
+        let tree = Tree {
+            inner: data.len() as u32,
+            flags,
+        };
+        Arc::make_mut(&mut data).push(Default::default());
+        trees.insert(name, tree.clone());
+        Ok(tree)
+    }
+
+    fn drop_tree(&self, name: Option<&str>) -> Result<bool> {
+        let _lock = self.inner.write.lock();
+        match self
+            .inner
+            .trees
+            .write()
+            .remove(&name.map(ToOwned::to_owned))
+        {
+            Some(tree) => {
+                let mut data = self.inner.data.write();
+                Arc::make_mut(&mut data)[tree.inner as usize] = Default::default();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn reader(&self) -> Result<Reader> {
+        Ok(Reader {
+            data: self.snapshot(),
+        })
+    }
+
+    fn owned_reader(&self) -> Result<Reader> {
+        self.reader()
+    }
+
+    fn writer(&self) -> Result<Writer<'_>> {
+        let lock = self.inner.write.lock();
+        Ok(Writer {
+            data: self.snapshot().as_ref().clone(),
+            store: self,
+            _lock: lock,
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn get<'txn>(data: &'txn [Arc<TreeData>], tree: &Tree, key: &[u8]) -> Option<&'txn [u8]> {
+    data.get(tree.inner as usize)
+        .and_then(|t| t.get(key))
+        .and_then(|values| values.first())
+        .map(|v| v.as_slice())
+}
+
+fn iter_from<'txn, K: AsRef<[u8]>>(
+    data: &'txn [Arc<TreeData>],
+    tree: &Tree,
+    from: Bound<K>,
+    rev: bool,
+) -> Iter<'txn> {
+    let mut iter = Iter {
+        tree: data.get(tree.inner as usize).map(|t| t.as_ref()),
+        next: None,
+        rev,
+    };
+    iter.seek(from, rev);
+    iter
+}
+
+/// The read transaction of the committed snapshot, it does not borrow the store
+pub struct Reader {
+    data: Snapshot,
+}
+
+impl Transaction for Reader {
+    type Iter<'txn> = Iter<'txn>;
+
+    fn get<'txn, K: AsRef<[u8]>>(&'txn self, tree: &Tree, key: K) -> Result<Option<&'txn [u8]>> {
+        Ok(get(&self.data, tree, key.as_ref()))
+    }
+
+    fn iter_from<'txn, K: AsRef<[u8]>>(
+        &'txn self,
+        tree: &Tree,
+        from: Bound<K>,
+        rev: bool,
+    ) -> Iter<'txn> {
+        iter_from(&self.data, tree, from, rev)
+    }
+
+    fn commit(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The write transaction holds the write lock until it is committed or dropped
+pub struct Writer<'env> {
+    data: Vec<Arc<TreeData>>,
+    store: &'env Memory,
+    _lock: MutexGuard<'env, ()>,
+}
+
+impl<'env> Writer<'env> {
+    fn tree_mut(&mut self, tree: &Tree) -> Result<&mut TreeData> {
+        let id = tree.inner as usize;
+        if id >= self.data.len() {
+            // opened after the writer began
+            let data = self.store.snapshot();
+            self.data.extend(data.iter().skip(self.data.len()).cloned());
+        }
+        self.data
+            .get_mut(id)
+            .map(Arc::make_mut)
+            .ok_or_else(|| Error::Message("tree not found".to_owned()))
+    }
+}
+
+impl<'env> Transaction for Writer<'env> {
+    type Iter<'txn>
+        = Iter<'txn>
+    where
+        Self: 'txn;
+
+    fn get<'txn, K: AsRef<[u8]>>(&'txn self, tree: &Tree, key: K) -> Result<Option<&'txn [u8]>> {
+        Ok(get(&self.data, tree, key.as_ref()))
+    }
+
+    fn iter_from<'txn, K: AsRef<[u8]>>(
+        &'txn self,
+        tree: &Tree,
+        from: Bound<K>,
+        rev: bool,
+    ) -> Iter<'txn> {
+        iter_from(&self.data, tree, from, rev)
+    }
+
+    fn commit(mut self) -> Result<()> {
+        let mut data = self.store.inner.data.write();
+        // keep the trees opened after the writer began
+        self.data.extend(data.iter().skip(self.data.len()).cloned());
+        *data = Arc::new(std::mem::take(&mut self.data));
+        Ok(())
+    }
+}
+
+impl<'env> WriteTransaction for Writer<'env> {
+    fn put<K, V>(&mut self, tree: &Tree, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let dup = tree.is_dup();
+        let values = self
+            .tree_mut(tree)?
+            .entry(key.as_ref().to_vec())
+            .or_default();
+        if !dup {
+            values.clear();
+        }
+        values.insert(value.as_ref().to_vec());
+        Ok(())
+    }
+
+    fn del<K: AsRef<[u8]>>(&mut self, tree: &Tree, key: K, value: Option<&[u8]>) -> Result<()> {
+        let dup = tree.is_dup();
+        let data = self.tree_mut(tree)?;
+        let key = key.as_ref();
+        match value {
+            Some(value) if dup => {
+                if let Some(values) = data.get_mut(key) {
+                    values.remove(value);
+                    if values.is_empty() {
+                        data.remove(key);
+                    }
+                }
+            }
+            // the value is ignored without duplicate values, the same as lmdb
+            _ => {
+                data.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, tree: &Tree) -> Result<()> {
+        self.tree_mut(tree)?.clear();
+        Ok(())
+    }
+}
+
+/// The iterator of a tree in the snapshot
+pub struct Iter<'txn> {
+    tree: Option<&'txn TreeData>,
+    next: Option<(&'txn [u8], &'txn [u8])>,
+    rev: bool,
+}
+
+type Entry<'txn> = (&'txn [u8], &'txn [u8]);
+
+fn first<'txn>(
+    mut range: impl DoubleEndedIterator<Item = (&'txn Vec<u8>, &'txn BTreeSet<Vec<u8>>)>,
+    rev: bool,
+) -> Option<Entry<'txn>> {
+    let (k, values) = if rev {
+        range.next_back()?
+    } else {
+        range.next()?
+    };
+    let v = if rev { values.last() } else { values.first() }?;
+    Some((k.as_slice(), v.as_slice()))
+}
+
+impl<'txn> Iter<'txn> {
+    // the item after the current one in the iteration direction
+    fn step(&self, tree: &'txn TreeData, (key, value): Entry<'txn>) -> Option<Entry<'txn>> {
+        let values = tree.get(key)?;
+        let next = if self.rev {
+            values
+                .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(value)))
+                .next_back()
+        } else {
+            values
+                .range::<[u8], _>((Bound::Excluded(value), Bound::Unbounded))
+                .next()
+        };
+        match next {
+            Some(v) => Some((key, v.as_slice())),
+            None if self.rev => first(
+                tree.range::<[u8], _>((Bound::Unbounded, Bound::Excluded(key))),
+                true,
+            ),
+            None => first(
+                tree.range::<[u8], _>((Bound::Excluded(key), Bound::Unbounded)),
+                false,
+            ),
+        }
+    }
+}
+
+impl<'txn> SeekIter<'txn> for Iter<'txn> {
+    fn seek<K: AsRef<[u8]>>(&mut self, from: Bound<K>, rev: bool) {
+        self.rev = rev;
+        self.next = self.tree.and_then(|tree| {
+            let from = match &from {
+                Bound::Included(k) => Bound::Included(k.as_ref()),
+                Bound::Excluded(k) => Bound::Excluded(k.as_ref()),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            if rev {
+                first(tree.range::<[u8], _>((Bound::Unbounded, from)), true)
+            } else {
+                first(tree.range::<[u8], _>((from, Bound::Unbounded)), false)
+            }
+        });
+    }
+}
+
+impl<'txn> Iterator for Iter<'txn> {
+    type Item = Result<(&'txn [u8], &'txn [u8]), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let cur = self.next.take()?;
+        self.next = self.tree.and_then(|tree| self.step(tree, cur));
+        Some(Ok(cur))
+    }
+}
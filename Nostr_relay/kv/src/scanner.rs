@@ -1,7 +1,15 @@
-use crate::{lmdb::Iter, Error};
+use crate::{lmdb::Iter, store::SeekIter, Error};
+use parking_lot::Mutex;
 use std::{
     cmp::Ordering,
     ops::{Bound, Deref, DerefMut},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+        Arc,
+    },
+    thread,
 };
 
 /// The time base index key
@@ -327,6 +335,217 @@ impl<'txn, K: TimeKey, E: From<Error>> GroupItem<'txn, K, E> for Group<'txn, K,
     }
 }
 
+/// The sending side of a [`Prefetch`], used in the scanning thread
+pub struct PrefetchSender<K, E>(SyncSender<(Result<K, E>, u64)>);
+
+impl<K, E> PrefetchSender<K, E> {
+    /// Send the key with the scan times, return false if the prefetch is dropped
+    pub fn send(&self, item: Result<K, E>, times: u64) -> bool {
+        self.0.send((item, times)).is_ok()
+    }
+
+    /// Send all the keys of the group item in order, stop after an error or the prefetch is dropped
+    pub fn send_all<'txn, G: GroupItem<'txn, K, E>>(&self, mut item: G) {
+        while let Some(next) = item.next() {
+            let err = next.is_err();
+            if !self.send(next, item.cur_times()) || err {
+                break;
+            }
+        }
+    }
+}
+
+/// The keys scanned ahead in another thread, buffered in a bounded channel.
+/// The scanning thread reads its own snapshot, it stops when the prefetch is dropped.
+pub struct Prefetch<K, E> {
+    receiver: Receiver<(Result<K, E>, u64)>,
+    cur_times: u64,
+}
+
+impl<K, E> Prefetch<K, E>
+where
+    K: Send + 'static,
+    E: Send + 'static,
+{
+    /// Run the scan in a new thread, buffer at most `buffer` keys
+    pub fn spawn<F>(buffer: usize, scan: F) -> Result<Self, Error>
+    where
+        F: FnOnce(PrefetchSender<K, E>) + Send + 'static,
+    {
+        let (sender, prefetch) = Self::channel(buffer);
+        thread::Builder::new()
+            .name("prefetch".to_owned())
+            .spawn(move || scan(sender))
+            .map_err(|e| Error::Message(e.to_string()))?;
+        Ok(prefetch)
+    }
+
+    fn channel(buffer: usize) -> (PrefetchSender<K, E>, Self) {
+        let (sender, receiver) = sync_channel(buffer);
+        (
+            PrefetchSender(sender),
+            Self {
+                receiver,
+                cur_times: 0,
+            },
+        )
+    }
+}
+
+impl<K, E> Iterator for Prefetch<K, E> {
+    type Item = Result<K, E>;
+    fn next(&mut self) -> Option<Self::Item> {
+        // the sender is dropped after the scan
+        let (item, times) = self.receiver.recv().ok()?;
+        self.cur_times = times;
+        Some(item)
+    }
+}
+
+impl<'txn, K, E> GroupItem<'txn, K, E> for Prefetch<K, E> {
+    fn watcher(&mut self, _watcher: ScannerWatcherType<E>) {
+        // the scan times are counted by the group
+    }
+
+    fn cur_times(&self) -> u64 {
+        self.cur_times
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct PoolInner {
+    sender: Mutex<Sender<Job>>,
+    idle: AtomicUsize,
+}
+
+impl PoolInner {
+    fn release(&self, threads: usize) {
+        self.idle.fetch_add(threads, AtomicOrdering::AcqRel);
+    }
+}
+
+/// The fixed threads running the prefetch scans of all the queries.
+/// A query reserves the idle threads before running its scans, so a scan never waits
+/// behind a scan blocked on its buffer, and a query without enough idle threads scans serially.
+/// The threads exit when the pool is dropped.
+#[derive(Clone)]
+pub struct PrefetchPool {
+    inner: Arc<PoolInner>,
+    threads: usize,
+}
+
+impl std::fmt::Debug for PrefetchPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrefetchPool")
+            .field("threads", &self.threads)
+            .field("idle", &self.idle())
+            .finish()
+    }
+}
+
+impl PrefetchPool {
+    /// Start the pool of `threads` threads
+    pub fn new(threads: usize) -> Result<Self, Error> {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name("prefetch".to_owned())
+                .spawn(move || loop {
+                    let job = receiver.lock().recv();
+                    match job {
+                        // keep the thread for the next scans after a panic
+                        Ok(job) => {
+                            let _ = catch_unwind(AssertUnwindSafe(job));
+                        }
+                        Err(_) => break,
+                    }
+                })
+                .map_err(|e| Error::Message(e.to_string()))?;
+        }
+        Ok(Self {
+            inner: Arc::new(PoolInner {
+                sender: Mutex::new(sender),
+                idle: AtomicUsize::new(threads),
+            }),
+            threads,
+        })
+    }
+
+    /// The number of threads
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// The number of the threads not reserved
+    pub fn idle(&self) -> usize {
+        self.inner.idle.load(AtomicOrdering::Acquire)
+    }
+
+    /// Reserve `threads` idle threads for a query, none if there are not enough
+    pub fn reserve(&self, threads: usize) -> Option<PrefetchPermit> {
+        self.inner
+            .idle
+            .fetch_update(AtomicOrdering::AcqRel, AtomicOrdering::Acquire, |idle| {
+                idle.checked_sub(threads)
+            })
+            .ok()?;
+        Some(PrefetchPermit {
+            inner: self.inner.clone(),
+            threads,
+        })
+    }
+}
+
+/// The threads reserved in a [`PrefetchPool`], the unused ones are released when dropped
+pub struct PrefetchPermit {
+    inner: Arc<PoolInner>,
+    threads: usize,
+}
+
+impl PrefetchPermit {
+    /// The number of the reserved threads not used
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Run the scan in a reserved thread, buffer at most `buffer` keys.
+    /// The thread is released after the scan.
+    pub fn spawn<K, E, F>(&mut self, buffer: usize, scan: F) -> Result<Prefetch<K, E>, Error>
+    where
+        K: Send + 'static,
+        E: Send + 'static,
+        F: FnOnce(PrefetchSender<K, E>) + Send + 'static,
+    {
+        if self.threads == 0 {
+            return Err(Error::Message("no reserved prefetch thread".to_owned()));
+        }
+        self.threads -= 1;
+        let release = PrefetchPermit {
+            inner: self.inner.clone(),
+            threads: 1,
+        };
+        let (sender, prefetch) = Prefetch::channel(buffer);
+        self.inner
+            .sender
+            .lock()
+            .send(Box::new(move || {
+                let _release = release;
+                scan(sender)
+            }))
+            .map_err(|e| Error::Message(e.to_string()))?;
+        Ok(prefetch)
+    }
+}
+
+impl Drop for PrefetchPermit {
+    fn drop(&mut self) {
+        self.inner.release(self.threads);
+    }
+}
+
 // pub enum GroupType<'txn, K, E>
 // where
 //     K: TimeKey,
@@ -344,8 +563,8 @@ impl<'txn, K: TimeKey, E: From<Error>> GroupItem<'txn, K, E> for Group<'txn, K,
 //     And(Vec<Scanner<'txn, K, E>>, SortedKeyList<ShortItemType, K>),
 // }
 
-type ScannerMatcher<'txn, K, E> =
-    Box<dyn Fn(&Scanner<K, E>, (&'txn [u8], &'txn [u8])) -> Result<MatchResult<K>, E>>;
+type ScannerMatcher<'txn, K, E, I> =
+    Box<dyn Fn(&Scanner<K, E, I>, (&'txn [u8], &'txn [u8])) -> Result<MatchResult<K>, E>>;
 
 pub enum MatchResult<K> {
     Continue,
@@ -353,41 +572,44 @@ pub enum MatchResult<K> {
     Stop,
 }
 
-/// time base scanner
-pub struct Scanner<'txn, K, E>
+/// time base scanner on the iterator of any storage backend
+pub struct Scanner<'txn, K, E, I = Iter<'txn>>
 where
     E: From<Error>,
 {
-    pub inner: Iter<'txn>,
+    pub inner: I,
     // search key bytes
     pub prefix: Vec<u8>,
     // search key
     pub key: Vec<u8>,
     // range start from
     // start: Vec<u8>,
-    matcher: ScannerMatcher<'txn, K, E>,
+    matcher: ScannerMatcher<'txn, K, E, I>,
     reverse: bool,
     since: Option<u64>,
     until: Option<u64>,
+    // skip the keys not after it, resume scanning from a cursor
+    after: Option<K>,
     // total scan times
     times: u64,
     // current next scan times
     cur_times: u64,
 }
 
-impl<'txn, K, E> Scanner<'txn, K, E>
+impl<'txn, K, E, I> Scanner<'txn, K, E, I>
 where
     K: TimeKey,
     E: From<Error>,
+    I: SeekIter<'txn>,
 {
     pub fn new(
-        iter: Iter<'txn>,
+        iter: I,
         key: Vec<u8>,
         prefix: Vec<u8>,
         reverse: bool,
         since: Option<u64>,
         until: Option<u64>,
-        matcher: ScannerMatcher<'txn, K, E>,
+        matcher: ScannerMatcher<'txn, K, E, I>,
     ) -> Self {
         Self {
             matcher,
@@ -397,11 +619,26 @@ where
             reverse,
             since,
             until,
+            after: None,
             times: 0,
             cur_times: 0,
         }
     }
 
+    /// Start exactly after the key, the time range is narrowed to seek to the key time
+    pub fn resume_after(mut self, key: Option<K>) -> Self {
+        if let Some(key) = &key {
+            let time = key.time();
+            if self.reverse {
+                self.until = Some(self.until.map_or(time, |t| t.min(time)));
+            } else {
+                self.since = Some(self.since.map_or(time, |t| t.max(time)));
+            }
+        }
+        self.after = key;
+        self
+    }
+
     fn next_inner(&mut self) -> Result<Option<K>, E> {
         self.cur_times = 0;
         loop {
@@ -463,6 +700,18 @@ where
                                 }
                             }
                         }
+                        if let Some(after) = &self.after {
+                            // only the keys of the same time are skipped after seeking
+                            let passed = if self.reverse {
+                                key.cmp(after).is_lt()
+                            } else {
+                                key.cmp(after).is_gt()
+                            };
+                            if !passed {
+                                continue;
+                            }
+                            self.after = None;
+                        }
                         return Ok(Some(key));
                     }
                 }
@@ -473,10 +722,11 @@ where
     }
 }
 
-impl<'txn, K, E> Iterator for Scanner<'txn, K, E>
+impl<'txn, K, E, I> Iterator for Scanner<'txn, K, E, I>
 where
     K: TimeKey,
     E: From<Error>,
+    I: SeekIter<'txn>,
 {
     type Item = Result<K, E>;
     fn next(&mut self) -> Option<Self::Item> {
@@ -484,7 +734,9 @@ where
     }
 }
 
-impl<'txn, K: TimeKey, E: From<Error>> GroupItem<'txn, K, E> for Scanner<'txn, K, E> {
+impl<'txn, K: TimeKey, E: From<Error>, I: SeekIter<'txn>> GroupItem<'txn, K, E>
+    for Scanner<'txn, K, E, I>
+{
     fn watcher(&mut self, _watcher: ScannerWatcherType<E>) {
         // ignore
     }
@@ -499,28 +751,29 @@ mod tests {
     use super::*;
     use anyhow::Result;
 
-    #[test]
-    fn sorted_key_list() -> Result<()> {
-        struct Key {
-            time: u64,
-        }
+    #[derive(Debug, PartialEq)]
+    struct Key {
+        time: u64,
+    }
 
-        impl Key {
-            fn new(time: u64) -> Self {
-                Self { time }
-            }
+    impl Key {
+        fn new(time: u64) -> Self {
+            Self { time }
         }
+    }
 
-        impl TimeKey for Key {
-            fn time(&self) -> u64 {
-                self.time
-            }
+    impl TimeKey for Key {
+        fn time(&self) -> u64 {
+            self.time
+        }
 
-            fn change_time(&self, _key: &[u8], _time: u64) -> Vec<u8> {
-                vec![]
-            }
+        fn change_time(&self, _key: &[u8], _time: u64) -> Vec<u8> {
+            vec![]
         }
+    }
 
+    #[test]
+    fn sorted_key_list() -> Result<()> {
         // reverse
         let mut sl = SortedKeyList::new(true);
         sl.add(vec![1], Key::new(1));
@@ -546,4 +799,82 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn prefetch() -> Result<()> {
+        let mut group = Group::<Key, Error>::new(true, false, false);
+        for start in 0..8u64 {
+            let prefetch = Prefetch::spawn(2, move |sender| {
+                for time in (0..100u64).rev().filter(|t| t % 8 == start) {
+                    if !sender.send(Ok(Key::new(time)), 3) {
+                        break;
+                    }
+                }
+            })?;
+            group.add(Box::new(prefetch))?;
+        }
+        let times = group
+            .by_ref()
+            .take(50)
+            .map(|k| k.map(|k| k.time))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(times, (50..100u64).rev().collect::<Vec<_>>());
+        assert_eq!(group.scan_times, 58 * 3);
+
+        // the scan stops when the prefetch is dropped
+        let (tx, rx) = std::sync::mpsc::channel();
+        let prefetch = Prefetch::<Key, Error>::spawn(1, move |sender| {
+            let mut time = 0;
+            while sender.send(Ok(Key::new(time)), 1) {
+                time += 1;
+            }
+            tx.send(time).unwrap();
+        })?;
+        drop(prefetch);
+        assert!(rx.recv()? <= 2);
+        Ok(())
+    }
+
+    #[test]
+    fn prefetch_pool() -> Result<()> {
+        let pool = PrefetchPool::new(4)?;
+        let mut permit = pool.reserve(3).unwrap();
+        assert_eq!(pool.idle(), 1);
+        assert!(pool.reserve(2).is_none());
+
+        let mut group = Group::<Key, Error>::new(false, false, false);
+        for start in 0..3u64 {
+            let prefetch = permit.spawn(2, move |sender| {
+                for time in (0..30u64).filter(|t| t % 3 == start) {
+                    if !sender.send(Ok(Key::new(time)), 1) {
+                        break;
+                    }
+                }
+            })?;
+            group.add(Box::new(prefetch))?;
+        }
+        assert!(permit.spawn::<Key, Error, _>(1, |_| {}).is_err());
+        let times = group
+            .by_ref()
+            .map(|k| k.map(|k| k.time))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(times, (0..30u64).collect::<Vec<_>>());
+
+        // the threads are released after the scans and the unused ones when dropped
+        drop(permit);
+        let start = std::time::Instant::now();
+        while pool.idle() < 4 && start.elapsed().as_secs() < 5 {
+            thread::yield_now();
+        }
+        assert_eq!(pool.idle(), 4);
+        let mut permit = pool.reserve(4).unwrap();
+        let prefetch = permit.spawn::<Key, Error, _>(1, |_| panic!("scan"))?;
+        assert_eq!(prefetch.count(), 0);
+        drop(permit);
+        while pool.idle() < 4 && start.elapsed().as_secs() < 5 {
+            thread::yield_now();
+        }
+        assert_eq!(pool.idle(), 4);
+        Ok(())
+    }
 }
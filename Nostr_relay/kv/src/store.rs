@@ -0,0 +1,98 @@
+//! The storage backend api.
+//!
+//! A backend stores the named trees of sorted keys, a tree opened with the
+//! [`crate::lmdb::ffi::MDB_DUPSORT`] flag keeps multiple sorted values per key.
+//! The [`crate::lmdb`] backend persists the data, the [`crate::memory`] backend keeps
+//! it in memory for tests and embedded use.
+
+use crate::Error;
+use std::ops::Bound;
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+/// A tree opened by the backend
+#[derive(Debug, Clone)]
+pub struct Tree {
+    pub(crate) inner: u32,
+    pub(crate) flags: u32,
+}
+
+impl Tree {
+    /// Whether the tree keeps multiple values per key
+    pub fn is_dup(&self) -> bool {
+        self.flags & crate::lmdb::ffi::MDB_DUPSORT == crate::lmdb::ffi::MDB_DUPSORT
+    }
+}
+
+/// The ordered iterator of the key value pairs in a tree
+pub trait SeekIter<'txn>: Iterator<Item = Result<(&'txn [u8], &'txn [u8])>> {
+    /// Move to the first item in the bound, the last item if reverse.
+    /// The duplicate values of a key are iterated in the same direction.
+    fn seek<K: AsRef<[u8]>>(&mut self, from: Bound<K>, rev: bool);
+}
+
+/// A read transaction sees a consistent snapshot of the trees
+pub trait Transaction: Sized {
+    type Iter<'txn>: SeekIter<'txn>
+    where
+        Self: 'txn;
+
+    /// Get the value of the key, the first value if the tree keeps duplicate values
+    fn get<'txn, K: AsRef<[u8]>>(&'txn self, tree: &Tree, key: K) -> Result<Option<&'txn [u8]>>;
+
+    fn iter_from<'txn, K: AsRef<[u8]>>(
+        &'txn self,
+        tree: &Tree,
+        from: Bound<K>,
+        rev: bool,
+    ) -> Self::Iter<'txn>;
+
+    fn iter(&self, tree: &Tree) -> Self::Iter<'_> {
+        self.iter_from(tree, Bound::Unbounded::<Vec<u8>>, false)
+    }
+
+    fn commit(self) -> Result<()>;
+}
+
+/// A write transaction, the changes are discarded if it is dropped without commit
+pub trait WriteTransaction: Transaction {
+    /// Set the value of the key, add the value if the tree keeps duplicate values
+    fn put<K, V>(&mut self, tree: &Tree, key: K, value: V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>;
+
+    /// Delete the key, only the value if it is set and the tree keeps duplicate values
+    fn del<K: AsRef<[u8]>>(&mut self, tree: &Tree, key: K, value: Option<&[u8]>) -> Result<()>;
+
+    /// Empty the tree, keep it open
+    fn clear(&mut self, tree: &Tree) -> Result<()>;
+}
+
+/// The storage backend
+pub trait Store: Clone + Send + Sync + 'static {
+    type Reader<'env>: Transaction
+    where
+        Self: 'env;
+    type Writer<'env>: WriteTransaction
+    where
+        Self: 'env;
+    /// The read transaction which does not borrow the store
+    type OwnedReader: Transaction;
+
+    /// Open or create the tree with the lmdb flags
+    fn open_tree(&self, name: Option<&str>, flags: u32) -> Result<Tree>;
+
+    /// Delete the tree, return false if it is not opened
+    fn drop_tree(&self, name: Option<&str>) -> Result<bool>;
+
+    fn reader(&self) -> Result<Self::Reader<'_>>;
+
+    fn owned_reader(&self) -> Result<Self::OwnedReader>;
+
+    /// Begin the write transaction, only one writer at a time
+    fn writer(&self) -> Result<Self::Writer<'_>>;
+
+    /// Flush the committed data to the disk
+    fn flush(&self) -> Result<()>;
+}
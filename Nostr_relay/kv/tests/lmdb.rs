@@ -121,6 +121,18 @@ pub fn test_put_get_del() -> Result<()> {
         assert!(reader.get(&t1, "exist")?.is_none());
     }
 
+    // clear keeps the tree open
+    let mut writer = db.writer()?;
+    writer.put(&t1, b"exist", b"ok")?;
+    writer.clear(&t1)?;
+    writer.put(&t1, b"k1", b"v1")?;
+    writer.commit()?;
+    {
+        let reader = db.reader()?;
+        assert!(reader.get(&t1, "exist")?.is_none());
+        assert_eq!(reader.get(&t1, "k1")?.unwrap(), b"v1");
+    }
+
     Ok(())
 }
 
@@ -376,3 +388,93 @@ pub fn test_dup() -> Result<()> {
     }
     Ok(())
 }
+
+#[test]
+pub fn test_owned_reader() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-owned-reader")
+        .tempdir()
+        .unwrap();
+    let reader = {
+        let db = Db::open(dir.path())?;
+        let t1 = db.open_tree(Some("t1"), 0)?;
+        let mut writer = db.writer()?;
+        writer.put(&t1, b"k1", b"v1")?;
+        writer.commit()?;
+        (db.owned_reader()?, t1)
+    };
+    // the environment is kept open by the reader
+    assert_eq!(reader.0.get(&reader.1, "k1")?.unwrap(), b"v1");
+    Ok(())
+}
+
+#[test]
+pub fn test_stat() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-stat")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    let t1 = db.open_tree(Some("t1"), 0)?;
+    let t2 = db.open_tree(Some("t2"), 0)?;
+    let info = db.info()?;
+    let mut writer = db.writer()?;
+    for i in 0u32..1000 {
+        writer.put(&t1, i.to_be_bytes(), [0u8; 100])?;
+    }
+    writer.put(&t2, b"big", vec![0u8; 100_000])?;
+    writer.commit()?;
+
+    let stat = db.tree_stat(&t1)?;
+    assert_eq!(stat.entries, 1000);
+    assert!(stat.depth > 1);
+    assert!(stat.branch_pages > 0 && stat.leaf_pages > 0);
+    assert_eq!(stat.overflow_pages, 0);
+    assert_eq!(stat.bytes(), stat.pages() * stat.page_size as u64);
+    assert!(db.tree_stat(&t2)?.overflow_pages > 0);
+
+    let stats = db.tree_stats()?;
+    assert_eq!(
+        stats.iter().map(|s| s.0.as_str()).collect::<Vec<_>>(),
+        vec!["t1", "t2"]
+    );
+    assert_eq!(stats[0].1, stat);
+    // the named trees in the main tree
+    assert_eq!(db.stat()?.entries, 2);
+
+    let reader = db.reader()?;
+    let now = db.info()?;
+    assert!(now.last_txn_id > info.last_txn_id);
+    assert!(now.last_page > info.last_page);
+    assert_eq!(now.readers, 1);
+    assert_eq!(now.max_readers, 100);
+    assert_eq!(now.map_size, 1_000_000_000_000);
+    assert!(now.used_bytes(stat.page_size) < now.map_size);
+    drop(reader);
+    Ok(())
+}
+
+#[test]
+pub fn test_flags() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-lmdb-flags")
+        .tempdir()
+        .unwrap();
+    let db = Db::open(dir.path())?;
+    assert_eq!(db.flags()? & ffi::MDB_NOSYNC, 0);
+    db.set_flags(ffi::MDB_NOSYNC | ffi::MDB_NOMETASYNC, true)?;
+    assert_eq!(
+        db.flags()? & (ffi::MDB_NOSYNC | ffi::MDB_NOMETASYNC),
+        ffi::MDB_NOSYNC | ffi::MDB_NOMETASYNC
+    );
+    let t1 = db.open_tree(Some("t1"), 0)?;
+    let mut writer = db.writer()?;
+    writer.put(&t1, b"k", b"v")?;
+    writer.commit()?;
+    db.flush()?;
+    db.set_flags(ffi::MDB_NOSYNC, false)?;
+    assert_eq!(db.flags()? & ffi::MDB_NOSYNC, 0);
+    // not changeable at runtime
+    assert!(db.set_flags(ffi::MDB_RDONLY, true).is_err());
+    Ok(())
+}
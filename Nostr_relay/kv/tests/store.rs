@@ -0,0 +1,125 @@
+use anyhow::Result;
+use nostr_kv::{
+    lmdb::{ffi, Db},
+    memory::Memory,
+    store::*,
+};
+use std::ops::Bound;
+
+fn keys<'txn, I: SeekIter<'txn>>(iter: I) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    Ok(iter
+        .map(|item| item.map(|(k, v)| (k.to_vec(), v.to_vec())))
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+fn kv(k: &str, v: &str) -> (Vec<u8>, Vec<u8>) {
+    (k.as_bytes().to_vec(), v.as_bytes().to_vec())
+}
+
+// the backends behave the same
+fn check_store<S: Store>(store: S) -> Result<()> {
+    let t1 = store.open_tree(Some("t1"), 0)?;
+    let dup = store.open_tree(Some("dup"), ffi::MDB_DUPSORT)?;
+
+    let mut writer = store.writer()?;
+    for k in ["k1", "k3", "k2", "k5"] {
+        writer.put(&t1, k, k.replace('k', "v"))?;
+    }
+    writer.put(&t1, "k2", "v22")?;
+    for (k, v) in [
+        ("i1", "1"),
+        ("i1", "3"),
+        ("i1", "2"),
+        ("i1", "2"),
+        ("k3", "1"),
+    ] {
+        writer.put(&dup, k, v)?;
+    }
+    writer.put(&dup, "i4", "1")?;
+    writer.del(&dup, "i4", Some(b"1"))?;
+    writer.del(&dup, "i1", Some(b"3"))?;
+    assert_eq!(writer.get(&t1, "k2")?, Some(&b"v22"[..]));
+    {
+        let reader = store.reader()?;
+        assert!(reader.get(&t1, "k2")?.is_none());
+    }
+    writer.commit()?;
+
+    // discarded
+    let mut writer = store.writer()?;
+    writer.put(&t1, "k4", "v4")?;
+    writer.del(&t1, "k1", None)?;
+    drop(writer);
+
+    let reader = store.reader()?;
+    assert_eq!(reader.get(&t1, "k1")?, Some(&b"v1"[..]));
+    assert_eq!(reader.get(&dup, "i1")?, Some(&b"1"[..]));
+    assert!(reader.get(&t1, "k4")?.is_none());
+    assert!(reader.get(&dup, "i4")?.is_none());
+
+    assert_eq!(
+        keys(reader.iter(&t1))?,
+        vec![
+            kv("k1", "v1"),
+            kv("k2", "v22"),
+            kv("k3", "v3"),
+            kv("k5", "v5")
+        ]
+    );
+    assert_eq!(
+        keys(reader.iter_from(&t1, Bound::Excluded("k4"), true))?,
+        vec![kv("k3", "v3"), kv("k2", "v22"), kv("k1", "v1")]
+    );
+    assert_eq!(
+        keys(reader.iter_from(&t1, Bound::Included("k3"), false))?,
+        vec![kv("k3", "v3"), kv("k5", "v5")]
+    );
+    assert_eq!(
+        keys(reader.iter_from(&t1, Bound::Excluded("k3"), false))?,
+        vec![kv("k5", "v5")]
+    );
+    assert!(keys(reader.iter_from(&t1, Bound::Included("k6"), false))?.is_empty());
+
+    assert_eq!(
+        keys(reader.iter(&dup))?,
+        vec![kv("i1", "1"), kv("i1", "2"), kv("k3", "1")]
+    );
+    assert_eq!(
+        keys(reader.iter_from(&dup, Bound::Included("i1"), true))?,
+        vec![kv("i1", "2"), kv("i1", "1")]
+    );
+    assert_eq!(
+        keys(reader.iter_from(&dup, Bound::Excluded("k3"), true))?,
+        vec![kv("i1", "2"), kv("i1", "1")]
+    );
+    assert_eq!(
+        keys(reader.iter_from(&dup, Bound::Excluded("i1"), false))?,
+        vec![kv("k3", "1")]
+    );
+
+    let mut iter = reader.iter(&dup);
+    iter.seek(Bound::Included("m"), true);
+    assert_eq!(keys(iter)?[0], kv("k3", "1"));
+    drop(reader);
+
+    let mut writer = store.writer()?;
+    writer.clear(&dup)?;
+    writer.commit()?;
+    assert!(store.reader()?.get(&dup, "i1")?.is_none());
+
+    assert!(store.drop_tree(Some("t1"))?);
+    let t1 = store.open_tree(Some("t1"), 0)?;
+    assert!(store.reader()?.get(&t1, "k1")?.is_none());
+    Ok(())
+}
+
+#[test]
+pub fn test_store() -> Result<()> {
+    let dir = tempfile::Builder::new()
+        .prefix("nokv-test-store")
+        .tempdir()
+        .unwrap();
+    check_store(Db::open(dir.path())?)?;
+    check_store(Memory::new())?;
+    Ok(())
+}
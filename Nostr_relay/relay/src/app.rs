@@ -1,4 +1,6 @@
-use crate::{setting::SettingWrapper, Extension, Extensions, Result, Server, Setting};
+use crate::{
+    setting::SettingWrapper, Error, Extension, Extensions, Result, Server, Setting, VerifierPool,
+};
 use actix::Addr;
 use actix_cors::Cors;
 use actix_web::{
@@ -6,7 +8,7 @@ use actix_web::{
     dev::{ServiceFactory, ServiceRequest},
     web, App as WebApp, HttpServer,
 };
-use nostr_db::Db;
+use nostr_db::{register_key, Db, ShardedDb};
 use parking_lot::RwLock;
 use std::{path::Path, sync::Arc};
 use tracing::info;
@@ -100,21 +102,29 @@ pub mod route {
 /// App with data
 pub struct App {
     pub server: Addr<Server>,
+    /// the first shard
     pub db: Arc<Db>,
+    /// all shards of the events
+    pub shards: ShardedDb,
+    /// verify the events before sending to the server
+    pub verifier: VerifierPool,
     pub setting: SettingWrapper,
     pub extensions: Arc<RwLock<Extensions>>,
 }
 
 impl App {
     /// data_path: overwrite setting data path
+    /// primary: overwrite setting `follower.primary`, kept when the setting is reloaded
     pub fn create<P: AsRef<Path>>(
         setting_path: Option<P>,
         watch: bool,
         setting_env_prefix: Option<String>,
         data_path: Option<P>,
+        primary: Option<String>,
     ) -> Result<Self> {
         let extensions = Arc::new(RwLock::new(Extensions::default()));
         let c_extensions = Arc::clone(&extensions);
+        let c_primary = primary.clone();
         let env_notice = setting_env_prefix
             .as_ref()
             .map(|s| {
@@ -129,6 +139,9 @@ impl App {
             let path = setting_path.as_ref().unwrap().as_ref();
             info!("Watch config file {:?}{}", path, env_notice);
             SettingWrapper::watch(path, setting_env_prefix, move |s| {
+                if c_primary.is_some() {
+                    s.write().follower.primary = c_primary.clone();
+                }
                 let mut w = c_extensions.write();
                 w.call_setting(s);
             })?
@@ -143,25 +156,60 @@ impl App {
             Setting::default().into()
         };
 
+        if primary.is_some() {
+            setting.write().follower.primary = primary;
+        }
+
         {
             info!("{:?}", setting.read());
         }
 
         let r = setting.read();
+        let index_tags = r.data.index_tags.clone();
         let path = data_path
             .map(|p| p.as_ref().to_path_buf())
             .unwrap_or_else(|| r.data.path.clone())
             .join("events");
+        let shards = r.data.shards;
+        if let Some((primary, _)) = r.follower.urls()? {
+            if shards > 1 {
+                return Err(Error::Str("the follower doesn't support shards"));
+            }
+            info!("Run as a read-only follower of {}", primary);
+        }
+        let history = r.data.history.options()?;
+        let parallel_scan = r.data.parallel_scan.options();
+        let durability = r.data.write.durability;
+        let encryption = &r.data.encryption;
+        let data_key = encryption.data_key()?;
+        for key in encryption.old_keys()? {
+            register_key(key);
+        }
+        let tag_key = encryption.tag_key()?;
         drop(r);
-        let db = Arc::new(Db::open(path)?);
-        db.check_schema()?;
+        let shards = ShardedDb::open(path, shards)?;
+        for db in shards.shards() {
+            db.set_history(history.clone());
+            db.set_data_key(data_key.clone());
+            db.set_tag_key(tag_key.as_ref())?;
+            db.set_parallel_scan(parallel_scan.clone())?;
+            db.set_durability(durability)?;
+            db.set_index_tags(index_tags.clone())?;
+        }
+        if shards.num_shards() > 1 {
+            info!("Split the events into {} shards", shards.num_shards());
+        }
+        let db = Arc::clone(&shards.shards()[0]);
 
-        let server = Server::create_with(db.clone(), setting.clone());
+        let server = Server::create_with(shards.clone(), setting.clone());
+        let verifier = VerifierPool::start(server.clone().recipient(), setting.clone());
 
         Ok(Self {
             server,
+            verifier,
             setting,
             db,
+            shards,
             extensions,
         })
     }
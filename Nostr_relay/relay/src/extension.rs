@@ -5,6 +5,8 @@ use crate::{
 };
 use actix_web::web::ServiceConfig;
 
+// the client message is passed through without boxing
+#[allow(clippy::large_enum_variant)]
 pub enum ExtensionMessageResult {
     /// Continue run the next extension message method, the server takes over finally.
     Continue(ClientMessage),
@@ -0,0 +1,371 @@
+use crate::{
+    message::*,
+    setting::{self, SettingWrapper},
+    Error, Result,
+};
+use actix::prelude::*;
+use metrics::{counter, histogram};
+use nostr_db::{Change, CheckEventResult, Db, Event};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, ErrorKind},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+use tungstenite::{stream::MaybeTlsStream, Message, WebSocket};
+
+// wait for the OK messages of the primary
+const READ_TIMEOUT_MS: u64 = 50;
+// read the connection for the ping messages when idle
+const IDLE_INTERVAL_MS: u64 = 1000;
+// the number of shards of the primary in the response of the changes extension
+const SHARDS_HEADER: &str = "X-Shards";
+
+/// Read-only follower of a primary relay, used instead of the [`crate::Writer`].
+///
+/// The changes of the primary are tailed from the `changes` extension into the local database,
+/// the stored events are dispatched to the local subscribers.
+/// The events from the clients are forwarded to the primary by a websocket connection,
+/// the OK messages of the primary are sent back to the clients.
+pub struct Follower {
+    pub db: Arc<Db>,
+    pub addr: Recipient<WriteEventResult>,
+    pub subscriber: Recipient<Dispatch>,
+    pub setting: SettingWrapper,
+    forward: Option<Sender<WriteEvent>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Follower {
+    pub fn new(
+        db: Arc<Db>,
+        addr: Recipient<WriteEventResult>,
+        subscriber: Recipient<Dispatch>,
+        setting: SettingWrapper,
+    ) -> Self {
+        Self {
+            db,
+            addr,
+            subscriber,
+            setting,
+            forward: None,
+            stopped: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn start_threads(&mut self) -> Result<()> {
+        let follower = self.setting.read().follower.clone();
+        let (ws_url, http_url) = follower
+            .urls()?
+            .ok_or(Error::Str("the primary relay is not set"))?;
+        info!("Follow the primary relay {}", ws_url);
+
+        let db = self.db.clone();
+        let subscriber = self.subscriber.clone();
+        let stopped = self.stopped.clone();
+        thread::Builder::new()
+            .name("follower-tail".to_owned())
+            .spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    if let Err(err) = tail(&db, &http_url, &follower, &subscriber, &stopped) {
+                        warn!(error = err.to_string(), "tail the primary changes error");
+                    }
+                    thread::sleep(*follower.reconnect_interval);
+                }
+            })?;
+
+        let (sender, receiver) = channel();
+        let addr = self.addr.clone();
+        let setting = self.setting.clone();
+        thread::Builder::new()
+            .name("follower-forward".to_owned())
+            .spawn(move || forward(&ws_url, &setting, receiver, &addr))?;
+        self.forward = Some(sender);
+        Ok(())
+    }
+}
+
+impl Actor for Follower {
+    type Context = Context<Self>;
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!("Actor follower started");
+        if let Err(err) = self.start_threads() {
+            error!(error = err.to_string(), "start follower error");
+        }
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        info!("Actor follower stopped");
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Handler<WriteEvent> for Follower {
+    type Result = ();
+    fn handle(&mut self, msg: WriteEvent, _: &mut Self::Context) {
+        match &self.forward {
+            Some(sender) => {
+                if let Err(err) = sender.send(msg) {
+                    fail(&self.addr, err.0, "primary relay unavailable");
+                }
+            }
+            None => fail(&self.addr, msg, "primary relay unavailable"),
+        }
+    }
+}
+
+fn fail(addr: &Recipient<WriteEventResult>, msg: WriteEvent, reason: &str) {
+    let eid = msg.event.id_str();
+    addr.do_send(WriteEventResult::Message {
+        id: msg.id,
+        event: msg.event,
+        msg: OutgoingMessage::ok(&eid, false, &format!("error: {}", reason)),
+    });
+}
+
+/// Apply the changes to the local database, dispatch the stored events
+pub fn apply(db: &Db, changes: Vec<Change<Event>>, subscriber: &Recipient<Dispatch>) -> Result<()> {
+    let start = Instant::now();
+    let num = changes.len();
+    let mut events = vec![];
+    let mut writer = db.writer()?;
+    for change in changes {
+        let result = db.apply_change(&mut writer, &change)?;
+        if let (Some(CheckEventResult::Ok(_)), Change::Put { event, .. }) = (result, change) {
+            events.push(event);
+        }
+    }
+    db.commit(writer)?;
+    counter!("nostr_relay_follower_change", num as u64);
+    histogram!("nostr_relay_follower_apply", start.elapsed());
+    for event in events {
+        subscriber.do_send(Dispatch { id: 0, event });
+    }
+    Ok(())
+}
+
+// follow the changes of the primary since the applied position until disconnected,
+// the sharded primary is refused, the local database keeps a single sequence number
+fn tail(
+    db: &Db,
+    url: &str,
+    setting: &setting::Follower,
+    subscriber: &Recipient<Dispatch>,
+    stopped: &AtomicBool,
+) -> Result<()> {
+    let since = db.replica_since(&db.reader()?)?;
+    let batch_size = setting.batch_size.max(1);
+    // the read of a silent connection fails instead of blocking forever
+    let agent = ureq::AgentBuilder::new()
+        .timeout_read(*setting.read_timeout)
+        .build();
+    let mut request = agent
+        .get(&format!("{}/changes", url))
+        .query("shard", "0")
+        .query("since", &since.to_string())
+        .query("follow", "true");
+    if let Some(auth) = &setting.auth {
+        request = request.query("auth", auth);
+    }
+    let response = request
+        .call()
+        .map_err(|e| Error::Message(format!("request {}/changes: {}", url, e)))?;
+    let shards = response
+        .header(SHARDS_HEADER)
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(1);
+    if shards > 1 {
+        return Err(Error::Message(format!(
+            "the primary has {} shards, the follower doesn't support shards",
+            shards
+        )));
+    }
+    info!("Tail the primary changes since {}", since);
+
+    let mut reader = BufReader::new(response.into_reader());
+    let mut changes = vec![];
+    let mut line = String::new();
+    while !stopped.load(Ordering::Relaxed) {
+        line.clear();
+        let eof = reader.read_line(&mut line)? == 0;
+        if !line.trim().is_empty() {
+            changes.push(line.parse::<Change<Event>>()?);
+        }
+        // write when no more buffered changes
+        if !changes.is_empty() && (eof || changes.len() >= batch_size || reader.buffer().is_empty())
+        {
+            apply(db, std::mem::take(&mut changes), subscriber)?;
+        }
+        if eof {
+            return Err(Error::Str("the primary closed the changes stream"));
+        }
+    }
+    Ok(())
+}
+
+// the event id of the OK message
+fn ok_event_id(text: &str) -> Option<String> {
+    let value: Value = serde_json::from_str(text).ok()?;
+    let array = value.as_array()?;
+    if array.first()?.as_str()? == "OK" {
+        Some(array.get(1)?.as_str()?.to_owned())
+    } else {
+        None
+    }
+}
+
+type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+fn connect(url: &str) -> Result<Socket> {
+    let (socket, _) =
+        tungstenite::connect(url).map_err(|e| Error::Message(format!("connect {}: {}", url, e)))?;
+    let stream = match socket.get_ref() {
+        MaybeTlsStream::Plain(stream) => stream,
+        MaybeTlsStream::Rustls(stream) => stream.get_ref(),
+        _ => return Err(Error::Str("unsupported stream of the primary relay")),
+    };
+    stream.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MS)))?;
+    info!("Connected to the primary relay {}", url);
+    Ok(socket)
+}
+
+// forward the events to the primary until the follower stopped
+fn forward(
+    url: &str,
+    setting: &SettingWrapper,
+    receiver: Receiver<WriteEvent>,
+    addr: &Recipient<WriteEventResult>,
+) {
+    let mut socket: Option<Socket> = None;
+    // the waiting events by id
+    let mut pending: HashMap<String, Vec<(WriteEvent, Instant)>> = HashMap::new();
+    loop {
+        let (timeout, reconnect) = {
+            let r = setting.read();
+            (*r.follower.forward_timeout, *r.follower.reconnect_interval)
+        };
+        let Some(ws) = socket.as_mut() else {
+            match connect(url) {
+                Ok(ws) => socket = Some(ws),
+                Err(err) => {
+                    warn!(error = err.to_string(), "forward to the primary error");
+                    // reject the events until reconnected
+                    let until = Instant::now() + reconnect;
+                    while let Some(wait) = until.checked_duration_since(Instant::now()) {
+                        match receiver.recv_timeout(wait) {
+                            Ok(msg) => fail(addr, msg, "primary relay unavailable"),
+                            Err(RecvTimeoutError::Timeout) => break,
+                            Err(RecvTimeoutError::Disconnected) => return,
+                        }
+                    }
+                }
+            }
+            continue;
+        };
+
+        // send the new events, wait for a while when idle
+        let mut messages = vec![];
+        if pending.is_empty() {
+            match receiver.recv_timeout(Duration::from_millis(IDLE_INTERVAL_MS)) {
+                Ok(msg) => messages.push(msg),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        loop {
+            match receiver.try_recv() {
+                Ok(msg) => messages.push(msg),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        let mut result = Ok(());
+        for msg in messages {
+            let text = format!(r#"["EVENT",{}]"#, msg.event);
+            pending
+                .entry(msg.event.id_str())
+                .or_default()
+                .push((msg, Instant::now()));
+            if result.is_ok() {
+                result = ws.send(Message::Text(text));
+            }
+        }
+
+        // read the OK messages
+        if result.is_ok() {
+            result = loop {
+                match ws.read() {
+                    Ok(Message::Text(text)) => {
+                        if let Some(eid) = ok_event_id(&text) {
+                            for (msg, _) in pending.remove(&eid).unwrap_or_default() {
+                                addr.do_send(WriteEventResult::Message {
+                                    id: msg.id,
+                                    event: msg.event,
+                                    msg: OutgoingMessage(text.clone()),
+                                });
+                            }
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(tungstenite::Error::Io(err))
+                        if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                    {
+                        break Ok(());
+                    }
+                    Err(err) => break Err(err),
+                }
+            };
+        }
+
+        let reason = match result {
+            Ok(_) => {
+                // the events not answered in time
+                let now = Instant::now();
+                for list in pending.values_mut() {
+                    let (expired, waiting) = std::mem::take(list)
+                        .into_iter()
+                        .partition(|(_, time)| now.duration_since(*time) >= timeout);
+                    *list = waiting;
+                    for (msg, _) in expired {
+                        fail(addr, msg, "primary relay timeout");
+                    }
+                }
+                pending.retain(|_, list| !list.is_empty());
+                continue;
+            }
+            Err(err) => {
+                warn!(error = err.to_string(), "primary relay disconnected");
+                socket = None;
+                "primary relay disconnected"
+            }
+        };
+        for (_, list) in pending.drain() {
+            for (msg, _) in list {
+                fail(addr, msg, reason);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ok_message() {
+        assert_eq!(
+            ok_event_id(r#"["OK","abcd",true,""]"#),
+            Some("abcd".to_owned())
+        );
+        assert_eq!(ok_event_id(r#"["NOTICE","abcd"]"#), None);
+        assert_eq!(ok_event_id("invalid"), None);
+    }
+}
@@ -25,6 +25,7 @@ pub type Result<T, E = Error> = core::result::Result<T, E>;
 mod app;
 pub mod duration;
 mod extension;
+mod follower;
 mod hash;
 mod list;
 pub mod message;
@@ -33,13 +34,15 @@ mod server;
 mod session;
 pub mod setting;
 mod subscriber;
+mod verifier;
 mod writer;
 
 pub use metrics;
 pub use nostr_db as db;
 pub use {
-    app::*, extension::*, list::List, reader::Reader, server::Server, server::*, session::Session,
-    setting::Setting, subscriber::Subscriber, writer::Writer,
+    app::*, extension::*, follower::Follower, list::List, reader::Reader, server::Server,
+    server::*, session::Session, setting::Setting, subscriber::Subscriber, verifier::Verifier,
+    verifier::VerifierPool, writer::Writer,
 };
 
 #[cfg(test)]
@@ -56,5 +59,6 @@ pub fn create_test_app(db_path: &str) -> anyhow::Result<App> {
         false,
         None,
         Some(temp_data_path(db_path)?),
+        None,
     )?)
 }
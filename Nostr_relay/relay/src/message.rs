@@ -1,11 +1,11 @@
 use actix::{Message, MessageResponse, Recipient};
 use bytestring::ByteString;
-use nostr_db::{now, CheckEventResult, Event, Filter};
+use nostr_db::{now, CheckEventResult, Event, Filter, IndexTags};
 use serde::{
     de::{self, SeqAccess, Visitor},
     Deserialize, Deserializer,
 };
-use serde_json::{json, Value};
+use serde_json::{json, value::RawValue, Value};
 use std::fmt::Display;
 use std::{fmt, marker::PhantomData};
 
@@ -35,6 +35,8 @@ pub struct ClientMessage {
     pub text: String,
     /// parsed message
     pub msg: IncomingMessage,
+    /// the author of the event is authenticated by the session (NIP-42), set by the auth extension
+    pub authenticated: bool,
 }
 
 macro_rules! check_max {
@@ -54,13 +56,14 @@ macro_rules! check_min {
 }
 
 impl ClientMessage {
+    /// The cheap checks by the limitation, the event signature is checked by [`Self::verify`]
     pub fn validate(&mut self, limitation: &Limitation) -> Result<(), Error> {
         check_max!(self.text.as_bytes().len(), limitation.max_message_length);
 
         match &mut self.msg {
             IncomingMessage::Event(event) => {
                 check_max!(event.tags().len(), limitation.max_event_tags);
-                event.validate(
+                event.check(
                     now(),
                     limitation.max_event_time_older_than_now,
                     limitation.max_event_time_newer_than_now,
@@ -75,8 +78,15 @@ impl ClientMessage {
                     // fill default limit
                     f.default_limit(limitation.max_limit);
                     check_max!(f.limit.unwrap(), limitation.max_limit);
-                    for id in f.ids.iter() {
-                        check_min!(id.len(), limitation.min_prefix);
+                    // the prefix length is the requested hex length
+                    if let Some(len) = f.prefix_len {
+                        check_min!(len, limitation.min_prefix);
+                    }
+                    // the history versions are read by the replace keys of the authors and kinds
+                    if f.history && !f.is_history_bounded() {
+                        return Err(Error::Invalid(
+                            "the history query requires the full authors and kinds".to_owned(),
+                        ));
                     }
                 }
             }
@@ -84,6 +94,28 @@ impl ClientMessage {
         }
         Ok(())
     }
+
+    /// Index the event and query the filters by the additional tag names of the database
+    pub fn index_tags(&mut self, index_tags: &IndexTags) -> Result<(), Error> {
+        match &mut self.msg {
+            IncomingMessage::Event(event) => event.reindex(index_tags)?,
+            IncomingMessage::Req(sub) | IncomingMessage::Count(sub) => {
+                for f in &mut sub.filters {
+                    f.index_tags(index_tags);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Verify the event id and signature, run in the [`crate::Verifier`] threads
+    pub fn verify(&self) -> Result<(), Error> {
+        if let IncomingMessage::Event(event) = &self.msg {
+            event.verify()?;
+        }
+        Ok(())
+    }
 }
 
 // #[derive(Deserialize, Clone, Debug)]
@@ -156,10 +188,7 @@ impl<'de> Visitor<'de> for MessageVisitor {
             .next_element()?
             .ok_or_else(|| de::Error::invalid_length(0, &self))?;
         match t {
-            "EVENT" => Ok(IncomingMessage::Event(
-                seq.next_element()?
-                    .ok_or_else(|| de::Error::invalid_length(0, &self))?,
-            )),
+            "EVENT" => Ok(IncomingMessage::Event(next_event(&mut seq, &self)?)),
             "CLOSE" => Ok(IncomingMessage::Close(
                 seq.next_element()?
                     .ok_or_else(|| de::Error::invalid_length(0, &self))?,
@@ -171,10 +200,7 @@ impl<'de> Visitor<'de> for MessageVisitor {
                 let r = Vec::<Filter>::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
                 Ok(IncomingMessage::Req(Subscription { id: t, filters: r }))
             }
-            "AUTH" => Ok(IncomingMessage::Auth(
-                seq.next_element()?
-                    .ok_or_else(|| de::Error::invalid_length(0, &self))?,
-            )),
+            "AUTH" => Ok(IncomingMessage::Auth(next_event(&mut seq, &self)?)),
             "COUNT" => {
                 let t = seq
                     .next_element()?
@@ -190,6 +216,18 @@ impl<'de> Visitor<'de> for MessageVisitor {
     }
 }
 
+// parse the event and keep the original json text of the event object
+fn next_event<'de, A, V>(seq: &mut A, visitor: &V) -> Result<Event, A::Error>
+where
+    A: SeqAccess<'de>,
+    V: Visitor<'de>,
+{
+    let raw: Box<RawValue> = seq
+        .next_element()?
+        .ok_or_else(|| de::Error::invalid_length(0, visitor))?;
+    Event::from_raw(Box::<str>::from(raw).into()).map_err(de::Error::custom)
+}
+
 impl<'de> Deserialize<'de> for IncomingMessage {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -262,6 +300,11 @@ impl OutgoingMessage {
         Self(format!(r#"["EOSE","{}"]"#, sub_id))
     }
 
+    /// The position of the last event of a paginated filter, `["CURSOR", <sub_id>, <cursor>]`
+    pub fn cursor(sub_id: &str, cursor: &str) -> Self {
+        Self(json!(["CURSOR", sub_id, cursor]).to_string())
+    }
+
     pub fn event(sub_id: &str, event: &str) -> Self {
         Self(format!(r#"["EVENT","{}",{}]"#, sub_id, event))
     }
@@ -295,6 +338,23 @@ impl From<OutgoingMessage> for ByteString {
 pub struct WriteEvent {
     pub id: usize,
     pub event: Event,
+    /// the author is authenticated by the session
+    pub authenticated: bool,
+}
+
+/// Verify the event of the client message, the result is sent to the session if failed
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct VerifyEvent {
+    pub msg: ClientMessage,
+    pub session: Recipient<OutgoingMessage>,
+}
+
+/// Apply the deletion event stored in another shard to the delegated events
+#[derive(Message, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct DelReferenced {
+    pub event: Event,
 }
 
 #[derive(Message, Clone, Debug)]
@@ -396,6 +456,13 @@ mod tests {
         )?;
         assert!(matches!(msg, IncomingMessage::Event( ref event ) if event.kind() == 1));
 
+        // keep the original json text of the event
+        let raw = r#"{"id":"332747c0fab8a1a92def4b0937e177be6df4382ce6dd7724f86dc4710b7d4d7d","pubkey":"7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef","created_at":1680690006,"kind":1,"tags":[["t","nostr"]],"content":"Good morning everyone \ud83d\ude03","sig":"ef4ff4f69ac387239eb1401fb07d7a44a5d5d57127e0dc3466a0403cf7d5486b668608ebfcbe9ff1f8d3b5d710545999fe08ee767284ec0b474e4cf92537678f"}"#;
+        let msg: IncomingMessage = serde_json::from_str(&format!(r#"["EVENT", {}]"#, raw))?;
+        assert!(
+            matches!(msg, IncomingMessage::Event( ref event ) if event.raw() == Some(raw) && event.to_string() == raw)
+        );
+
         // let sub: Subscription = serde_json::from_str(r#"["sub_id1", {}, {}]"#)?;
         // assert_eq!(sub.id, "sub_id1");
         // assert_eq!(sub.filters.len(), 2);
@@ -433,6 +500,60 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn validate_min_prefix() -> Result<()> {
+        let limitation = Limitation {
+            min_prefix: 4,
+            ..Default::default()
+        };
+        let validate = |text: &str| {
+            ClientMessage {
+                id: 0,
+                text: text.to_string(),
+                msg: serde_json::from_str(text).unwrap(),
+                authenticated: false,
+            }
+            .validate(&limitation)
+        };
+        assert!(validate(r#"["REQ", "sub_id1", {"ids": ["abcd"]}]"#).is_ok());
+        // the odd length prefix is expanded to 2 bytes
+        assert!(validate(r#"["REQ", "sub_id1", {"ids": ["abc"]}]"#).is_err());
+        assert!(validate(r#"["REQ", "sub_id1", {"authors": ["abcd", "ab"]}]"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn validate_history() -> Result<()> {
+        let limitation = Limitation::default();
+        let validate = |text: &str| {
+            ClientMessage {
+                id: 0,
+                text: text.to_string(),
+                msg: serde_json::from_str(text).unwrap(),
+                authenticated: false,
+            }
+            .validate(&limitation)
+        };
+        let author = "7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef";
+        assert!(validate(&format!(
+            r#"["REQ", "sub_id1", {{"authors": ["{}"], "kinds": [0], "history": true}}]"#,
+            author
+        ))
+        .is_ok());
+        assert!(validate(r#"["REQ", "sub_id1", {"kinds": [0], "history": true}]"#).is_err());
+        assert!(validate(&format!(
+            r#"["REQ", "sub_id1", {{"authors": ["{}"], "history": true}}]"#,
+            author
+        ))
+        .is_err());
+        assert!(validate(&format!(
+            r#"["REQ", "sub_id1", {{"authors": ["{}"], "kinds": [0], "history": true}}]"#,
+            &author[0..20]
+        ))
+        .is_err());
+        Ok(())
+    }
+
     #[test]
     fn se_outgoing_message() -> Result<()> {
         let msg = OutgoingMessage::notice("hello");
@@ -1,19 +1,19 @@
 use crate::{message::*, setting::SettingWrapper, Result};
 use actix::prelude::*;
 use metrics::histogram;
-use nostr_db::Db;
-use std::{sync::Arc, time::Instant};
+use nostr_db::ShardedDb;
+use std::time::Instant;
 
 /// Requst by filter
-/// Concurrent read events from db
+/// Concurrent read events from db, merged across the shards
 pub struct Reader {
-    pub db: Arc<Db>,
+    pub db: ShardedDb,
     pub addr: Recipient<ReadEventResult>,
     pub setting: SettingWrapper,
 }
 
 impl Reader {
-    pub fn new(db: Arc<Db>, addr: Recipient<ReadEventResult>, setting: SettingWrapper) -> Self {
+    pub fn new(db: ShardedDb, addr: Recipient<ReadEventResult>, setting: SettingWrapper) -> Self {
         Self { db, addr, setting }
     }
 
@@ -22,11 +22,11 @@ impl Reader {
         let timeout = self.setting.read().data.db_query_timeout;
         for filter in &msg.subscription.filters {
             let start = Instant::now();
-            let mut iter = self.db.iter::<String, _>(&reader, filter)?;
+            let mut iter = self.db.iter::<String>(&reader, filter)?;
             if let Some(time) = timeout {
                 iter.scan_time(time.into(), 2000);
             }
-            for event in iter {
+            for event in iter.by_ref() {
                 let event = event?;
                 self.addr.do_send(ReadEventResult {
                     id: msg.id,
@@ -34,6 +34,15 @@ impl Reader {
                     msg: OutgoingMessage::event(&msg.subscription.id, &event),
                 });
             }
+            if filter.paginate {
+                if let Some(cursor) = iter.cursor() {
+                    self.addr.do_send(ReadEventResult {
+                        id: msg.id,
+                        sub_id: msg.subscription.id.clone(),
+                        msg: OutgoingMessage::cursor(&msg.subscription.id, &cursor.to_string()),
+                    });
+                }
+            }
             histogram!("nostr_relay_db_get", start.elapsed());
         }
         self.addr.do_send(ReadEventResult {
@@ -70,9 +79,9 @@ mod tests {
     use crate::{temp_data_path, Setting};
     use actix_rt::time::sleep;
     use anyhow::Result;
-    use nostr_db::{Event, Filter};
+    use nostr_db::{Db, Event, Filter};
     use parking_lot::RwLock;
-    use std::{str::FromStr, time::Duration};
+    use std::{str::FromStr, sync::Arc, time::Duration};
 
     #[derive(Default)]
     struct Receiver(Arc<RwLock<Vec<ReadEventResult>>>);
@@ -110,7 +119,11 @@ mod tests {
         let addr = receiver.recipient();
 
         let reader = SyncArbiter::start(3, move || {
-            Reader::new(Arc::clone(&db), addr.clone(), Setting::default().into())
+            Reader::new(
+                ShardedDb::single(Arc::clone(&db)),
+                addr.clone(),
+                Setting::default().into(),
+            )
         });
 
         for i in 0..4 {
@@ -126,10 +139,26 @@ mod tests {
                 })
                 .await?;
         }
+        reader
+            .send(ReadEvent {
+                id: 4,
+                subscription: Subscription {
+                    id: "page".to_owned(),
+                    filters: vec![Filter::from_str(r#"{"cursor": ""}"#)?],
+                },
+            })
+            .await?;
 
         sleep(Duration::from_millis(100)).await;
         let r = messages.read();
-        assert_eq!(r.len(), 8);
+        assert_eq!(r.len(), 11);
+        let page = r
+            .iter()
+            .filter(|m| m.sub_id == "page")
+            .map(|m| m.msg.0.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(page.len(), 3);
+        assert!(page[1].starts_with(r#"["CURSOR","page",""#));
         Ok(())
     }
 }
@@ -1,6 +1,7 @@
-use crate::{message::*, setting::SettingWrapper, Reader, Subscriber, Writer};
+use crate::{message::*, setting::SettingWrapper, Follower, Reader, Subscriber, Writer};
 use actix::prelude::*;
-use nostr_db::{CheckEventResult, Db};
+use metrics::gauge;
+use nostr_db::{CheckEventResult, ShardedDb};
 use std::{collections::HashMap, sync::Arc};
 use tracing::info;
 
@@ -8,41 +9,96 @@ use tracing::info;
 #[derive(Debug)]
 pub struct Server {
     id: usize,
-    writer: Addr<Writer>,
+    // the follower forwards the events to the primary instead of writing
+    // one writer per shard, the events are routed by the pubkey hash
+    writers: Vec<Recipient<WriteEvent>>,
+    // the writers apply the deletion events of the other shards
+    deleters: Vec<Recipient<DelReferenced>>,
+    // a thread per writer, the commit of a shard doesn't block the server and the other shards
+    arbiters: Vec<Arbiter>,
+    db: ShardedDb,
     reader: Addr<Reader>,
     subscriber: Addr<Subscriber>,
     sessions: HashMap<usize, Recipient<OutgoingMessage>>,
+    setting: SettingWrapper,
+    // the number of events waiting for the write result
+    pending_writes: usize,
 }
 
 impl Server {
-    pub fn create_with(db: Arc<Db>, setting: SettingWrapper) -> Addr<Server> {
+    pub fn create_with(db: ShardedDb, setting: SettingWrapper) -> Addr<Server> {
         let r = setting.read();
         let num = if r.thread.reader == 0 {
             num_cpus::get()
         } else {
             r.thread.reader
         };
+        let follower = r.follower.primary.is_some();
         drop(r);
 
         Server::create(|ctx| {
-            let writer = Writer::new(Arc::clone(&db), ctx.address().recipient()).start();
             let subscriber = Subscriber::new(ctx.address().recipient(), setting.clone()).start();
+            let mut writers = vec![];
+            let mut deleters = vec![];
+            let mut arbiters = vec![];
+            if follower {
+                writers.push(
+                    Follower::new(
+                        Arc::clone(&db.shards()[0]),
+                        ctx.address().recipient(),
+                        subscriber.clone().recipient(),
+                        setting.clone(),
+                    )
+                    .start()
+                    .recipient(),
+                );
+            } else {
+                info!("starting {} writers", db.num_shards());
+                for shard in db.shards() {
+                    let writer = Writer::new(
+                        Arc::clone(shard),
+                        ctx.address().recipient(),
+                        setting.clone(),
+                    );
+                    let arbiter = Arbiter::new();
+                    let writer = Writer::start_in_arbiter(&arbiter.handle(), |_| writer);
+                    writers.push(writer.clone().recipient());
+                    deleters.push(writer.recipient());
+                    arbiters.push(arbiter);
+                }
+            }
             let addr = ctx.address().recipient();
+            let server_setting = setting.clone();
+            let reader_db = db.clone();
             info!("starting {} reader workers", num);
             let reader = SyncArbiter::start(num, move || {
-                Reader::new(Arc::clone(&db), addr.clone(), setting.clone())
+                Reader::new(reader_db.clone(), addr.clone(), setting.clone())
             });
 
             Server {
                 id: 0,
-                writer,
+                writers,
+                deleters,
+                arbiters,
+                db,
                 reader,
                 subscriber,
                 sessions: HashMap::new(),
+                setting: server_setting,
+                pending_writes: 0,
             }
         })
     }
 
+    // the writer of the shard which stores the events of the pubkey
+    fn shard_of(&self, pubkey: &[u8]) -> usize {
+        if self.writers.len() > 1 {
+            self.db.shard_of(pubkey)
+        } else {
+            0
+        }
+    }
+
     fn send_to_client(&self, id: usize, msg: OutgoingMessage) {
         if let Some(addr) = self.sessions.get(&id) {
             addr.do_send(msg);
@@ -59,6 +115,12 @@ impl Actor for Server {
         ctx.set_mailbox_capacity(10000);
         info!("Actor server started");
     }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        for arbiter in &self.arbiters {
+            arbiter.stop();
+        }
+    }
 }
 
 /// Handler for Connect message.
@@ -99,9 +161,29 @@ impl Handler<ClientMessage> for Server {
     fn handle(&mut self, msg: ClientMessage, ctx: &mut Self::Context) {
         match msg.msg {
             IncomingMessage::Event(event) => {
+                // back-pressure, reject the new events when the writer is behind
+                let max_pending = self.setting.read().data.write.max_pending;
+                if self.pending_writes >= max_pending {
+                    self.send_to_client(
+                        msg.id,
+                        OutgoingMessage::ok(
+                            &event.id_str(),
+                            false,
+                            "rate-limited: too many pending writes",
+                        ),
+                    );
+                    return;
+                }
+                self.pending_writes += 1;
+                gauge!("nostr_relay_pending_writes", self.pending_writes as f64);
                 // save all event
                 // save ephemeral for check duplicate, disconnection recovery, will be deleted
-                self.writer.do_send(WriteEvent { id: msg.id, event })
+                let shard = self.shard_of(event.pubkey());
+                self.writers[shard].do_send(WriteEvent {
+                    id: msg.id,
+                    event,
+                    authenticated: msg.authenticated,
+                })
             }
             IncomingMessage::Close(id) => self.subscriber.do_send(Unsubscribe {
                 id: msg.id,
@@ -161,6 +243,8 @@ impl Handler<ClientMessage> for Server {
 impl Handler<WriteEventResult> for Server {
     type Result = ();
     fn handle(&mut self, msg: WriteEventResult, _: &mut Self::Context) {
+        self.pending_writes = self.pending_writes.saturating_sub(1);
+        gauge!("nostr_relay_pending_writes", self.pending_writes as f64);
         match msg {
             WriteEventResult::Write { id, event, result } => {
                 let event_id = event.id_str();
@@ -182,6 +266,17 @@ impl Handler<WriteEventResult> for Server {
                 self.send_to_client(id, out_msg);
                 // dispatch event to subscriber
                 if let CheckEventResult::Ok(_num) = result {
+                    // the delegated events may be stored in the other shards
+                    if event.kind() == 5 && self.deleters.len() > 1 {
+                        let shard = self.shard_of(event.pubkey());
+                        for (i, deleter) in self.deleters.iter().enumerate() {
+                            if i != shard {
+                                deleter.do_send(DelReferenced {
+                                    event: event.clone(),
+                                });
+                            }
+                        }
+                    }
                     self.subscriber.do_send(Dispatch { id, event });
                 }
             }
@@ -212,8 +307,9 @@ mod tests {
     use crate::{temp_data_path, Setting};
     use actix_rt::time::sleep;
     use anyhow::Result;
+    use nostr_db::{Db, Event};
     use parking_lot::RwLock;
-    use std::time::Duration;
+    use std::{str::FromStr, time::Duration};
 
     #[derive(Default)]
     struct Receiver(Arc<RwLock<Vec<OutgoingMessage>>>);
@@ -230,7 +326,7 @@ mod tests {
 
     #[actix_rt::test]
     async fn message() -> Result<()> {
-        let db = Arc::new(Db::open(temp_data_path("server")?)?);
+        let db = ShardedDb::single(Arc::new(Db::open(temp_data_path("server")?)?));
         let note = r#"
         {
             "content": "Good morning everyone 😃",
@@ -259,7 +355,8 @@ mod tests {
         let receiver = receiver.start();
         let addr = receiver.recipient();
 
-        let server = Server::create_with(db, Setting::default().into());
+        let server_setting: SettingWrapper = Setting::default().into();
+        let server = Server::create_with(db, server_setting.clone());
 
         let id = server.send(Connect { addr }).await?;
         assert_eq!(id, 1);
@@ -268,13 +365,18 @@ mod tests {
         {
             let text = r#"["UNKNOWN"]"#.to_owned();
             let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-            let client_msg = ClientMessage { id, text, msg };
+            let client_msg = ClientMessage {
+                id,
+                text,
+                msg,
+                authenticated: false,
+            };
             server.send(client_msg).await?;
             sleep(Duration::from_millis(50)).await;
             {
                 let mut w = messages.write();
                 assert_eq!(w.len(), 1);
-                assert!(w.get(0).unwrap().0.contains("Unsupported"));
+                assert!(w.first().unwrap().0.contains("Unsupported"));
                 w.clear();
             }
         }
@@ -283,26 +385,36 @@ mod tests {
         {
             let text = r#"["REQ", "1", {}]"#.to_owned();
             let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-            let client_msg = ClientMessage { id, text, msg };
+            let client_msg = ClientMessage {
+                id,
+                text,
+                msg,
+                authenticated: false,
+            };
             server.send(client_msg).await?;
             sleep(Duration::from_millis(50)).await;
             {
                 let mut w = messages.write();
                 assert_eq!(w.len(), 1);
-                assert!(w.get(0).unwrap().0.contains("EOSE"));
+                assert!(w.first().unwrap().0.contains("EOSE"));
                 w.clear();
             }
 
             // write
             let text = format!(r#"["EVENT", {}]"#, note);
             let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-            let client_msg = ClientMessage { id, text, msg };
+            let client_msg = ClientMessage {
+                id,
+                text,
+                msg,
+                authenticated: false,
+            };
             server.send(client_msg.clone()).await?;
             sleep(Duration::from_millis(200)).await;
             {
                 let mut w = messages.write();
                 assert_eq!(w.len(), 2);
-                assert!(w.get(0).unwrap().0.contains("OK"));
+                assert!(w.first().unwrap().0.contains("OK"));
                 // subscription message
                 assert!(w.get(1).unwrap().0.contains("EVENT"));
                 w.clear();
@@ -313,7 +425,7 @@ mod tests {
             {
                 let mut w = messages.write();
                 assert_eq!(w.len(), 1);
-                assert!(w.get(0).unwrap().0.contains("OK"));
+                assert!(w.first().unwrap().0.contains("OK"));
                 // No subscription message because the message is duplicated
                 w.clear();
             }
@@ -322,13 +434,18 @@ mod tests {
             {
                 let text = format!(r#"["EVENT", {}]"#, ephemeral_note);
                 let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-                let client_msg = ClientMessage { id, text, msg };
+                let client_msg = ClientMessage {
+                    id,
+                    text,
+                    msg,
+                    authenticated: false,
+                };
                 server.send(client_msg.clone()).await?;
                 sleep(Duration::from_millis(200)).await;
                 {
                     let mut w = messages.write();
                     assert_eq!(w.len(), 2);
-                    assert!(w.get(0).unwrap().0.contains("OK"));
+                    assert!(w.first().unwrap().0.contains("OK"));
                     // subscription message
                     assert!(w.get(1).unwrap().0.contains("EVENT"));
                     w.clear();
@@ -339,7 +456,7 @@ mod tests {
                 {
                     let mut w = messages.write();
                     assert_eq!(w.len(), 1);
-                    assert!(w.get(0).unwrap().0.contains("OK"));
+                    assert!(w.first().unwrap().0.contains("OK"));
                     // No subscription message because the message is duplicated
                     w.clear();
                 }
@@ -349,28 +466,60 @@ mod tests {
 
             let text = r#"["CLOSE", "1"]"#.to_owned();
             let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-            let client_msg = ClientMessage { id, text, msg };
+            let client_msg = ClientMessage {
+                id,
+                text,
+                msg,
+                authenticated: false,
+            };
             server.send(client_msg).await?;
             sleep(Duration::from_millis(50)).await;
             {
                 let mut w = messages.write();
                 // assert_eq!(w.len(), 1);
-                // assert!(w.get(0).unwrap().0.contains("EOSE"));
+                // assert!(w.first().unwrap().0.contains("EOSE"));
+                w.clear();
+            }
+        }
+
+        // back-pressure
+        {
+            server_setting.write().data.write.max_pending = 0;
+            let text = format!(r#"["EVENT", {}]"#, note);
+            let msg = serde_json::from_str::<IncomingMessage>(&text)?;
+            let client_msg = ClientMessage {
+                id,
+                text,
+                msg,
+                authenticated: false,
+            };
+            server.send(client_msg).await?;
+            sleep(Duration::from_millis(50)).await;
+            {
+                let mut w = messages.write();
+                assert_eq!(w.len(), 1);
+                assert!(w.first().unwrap().0.contains("rate-limited"));
                 w.clear();
             }
+            server_setting.write().data.write.max_pending = 10;
         }
 
         // get
         {
             let text = r#"["REQ", "1", {}]"#.to_owned();
             let msg = serde_json::from_str::<IncomingMessage>(&text)?;
-            let client_msg = ClientMessage { id, text, msg };
+            let client_msg = ClientMessage {
+                id,
+                text,
+                msg,
+                authenticated: false,
+            };
             server.send(client_msg).await?;
             sleep(Duration::from_millis(50)).await;
             {
                 let mut w = messages.write();
                 assert_eq!(w.len(), 3);
-                assert!(w.get(0).unwrap().0.contains("EVENT"));
+                assert!(w.first().unwrap().0.contains("EVENT"));
                 assert!(w.get(1).unwrap().0.contains("EVENT"));
                 assert!(w.get(2).unwrap().0.contains("EOSE"));
                 w.clear();
@@ -379,4 +528,89 @@ mod tests {
 
         Ok(())
     }
+
+    #[actix_rt::test]
+    async fn sharded() -> Result<()> {
+        let db = ShardedDb::open(temp_data_path("server_sharded")?, 3)?;
+        let pubkey = |i: u8| hex::encode([i; 32]);
+        let delegator = (2..20u8)
+            .find(|i| db.shard_of(&[*i; 32]) != db.shard_of(&[1; 32]))
+            .unwrap();
+        let event = |id: u8, pubkey: String, kind: u16, tags: String| -> Result<String> {
+            let json = format!(
+                r#"{{"content":"","created_at":1680690006,"id":"{}","kind":{},"pubkey":"{}","sig":"{}","tags":{}}}"#,
+                hex::encode([id; 32]),
+                kind,
+                pubkey,
+                "00".repeat(64),
+                tags
+            );
+            Event::from_str(&json)?;
+            Ok(json)
+        };
+        let delegated = event(
+            1,
+            pubkey(1),
+            1,
+            format!(r#"[["delegation","{}","",""]]"#, pubkey(delegator)),
+        )?;
+        let note = event(2, pubkey(delegator), 1, "[]".to_owned())?;
+        let deletion = event(
+            3,
+            pubkey(delegator),
+            5,
+            format!(r#"[["e","{}"]]"#, hex::encode([1u8; 32])),
+        )?;
+
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let addr = receiver.start().recipient();
+        let server = Server::create_with(db.clone(), Setting::default().into());
+        let id = server.send(Connect { addr }).await?;
+
+        let send = |text: String| {
+            let msg = serde_json::from_str::<IncomingMessage>(&text).unwrap();
+            server.send(ClientMessage {
+                id,
+                text,
+                msg,
+                authenticated: false,
+            })
+        };
+        for e in [&delegated, &note] {
+            send(format!(r#"["EVENT", {}]"#, e)).await?;
+        }
+        sleep(Duration::from_millis(300)).await;
+        send(format!(r#"["EVENT", {}]"#, deletion)).await?;
+        sleep(Duration::from_millis(500)).await;
+        {
+            let mut w = messages.write();
+            assert_eq!(w.len(), 3);
+            assert!(w
+                .iter()
+                .all(|m| m.0.contains(r#""OK""#) && m.0.contains("true")));
+            w.clear();
+        }
+        // each writer stores the events of its shard
+        let counts = db
+            .shards()
+            .iter()
+            .map(|s| s.stats(0).map(|s| s.total.count))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(counts.iter().sum::<u64>(), 2);
+        assert_eq!(counts[db.shard_of(&[delegator; 32])], 2);
+
+        // merged from the shards
+        send(r#"["REQ", "1", {}]"#.to_owned()).await?;
+        sleep(Duration::from_millis(100)).await;
+        {
+            let w = messages.read();
+            assert_eq!(w.len(), 3);
+            assert!(w[2].0.contains("EOSE"));
+            assert!(!w
+                .iter()
+                .any(|m| m.0.contains(&hex::encode([1u8; 32])) && m.0.contains(r#""kind":1"#)));
+        }
+        Ok(())
+    }
 }
@@ -115,10 +115,14 @@ impl Session {
                     id: self.id,
                     text,
                     msg,
+                    authenticated: false,
                 };
                 {
                     let r = self.app.setting.read();
-                    if let Err(err) = msg.validate(&r.limitation) {
+                    if let Err(err) = msg
+                        .validate(&r.limitation)
+                        .and_then(|_| msg.index_tags(self.app.db.index_tags()))
+                    {
                         if let IncomingMessage::Event(event) = &msg.msg {
                             ctx.text(OutgoingMessage::ok(
                                 &event.id_str(),
@@ -140,7 +144,12 @@ impl Session {
                     .call_message(msg, self, ctx)
                 {
                     crate::ExtensionMessageResult::Continue(msg) => {
-                        self.server.do_send(msg);
+                        // the signature is verified after the cheap checks by the extensions
+                        if let IncomingMessage::Event(_) = &msg.msg {
+                            self.app.verifier.verify(msg, ctx.address().recipient());
+                        } else {
+                            self.server.do_send(msg);
+                        }
                     }
                     crate::ExtensionMessageResult::Stop(out) => {
                         ctx.text(out);
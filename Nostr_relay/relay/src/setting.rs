@@ -1,6 +1,7 @@
 use crate::Error;
 use crate::{duration::NonZeroDuration, hash::NoOpHasherDefault, Result};
 use config::{Config, Environment, File, FileFormat};
+use nostr_db::{DataKey, Durability, HistoryOptions, ParallelScan, Period};
 use notify::{event::ModifyKind, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use parking_lot::RwLock;
 use serde::de::DeserializeOwned;
@@ -62,6 +63,27 @@ pub struct Data {
 
     /// Query filter timeout time
     pub db_query_timeout: Option<NonZeroDuration>,
+
+    /// Additional indexed tag names, the single-letter tags are always indexed
+    pub index_tags: Vec<String>,
+
+    /// Keep the superseded versions of the replaceable events
+    pub history: History,
+
+    /// Encrypt the event data at rest
+    pub encryption: Encryption,
+
+    /// Scan the large OR filters in parallel threads
+    pub parallel_scan: Parallel,
+
+    /// Commit and durability of the written events
+    pub write: Write,
+
+    /// Split the events by the pubkey hash, each shard has its own writer
+    pub shards: usize,
+
+    /// Move the old events to an environment per period
+    pub partition: Partition,
 }
 
 impl Default for Data {
@@ -69,18 +91,219 @@ impl Default for Data {
         Self {
             path: PathBuf::from("./data"),
             db_query_timeout: None,
+            index_tags: vec![],
+            history: History::default(),
+            encryption: Encryption::default(),
+            parallel_scan: Parallel::default(),
+            write: Write::default(),
+            shards: 1,
+            partition: Partition::default(),
         }
     }
 }
 
-/// number of threads config
+/// history of the replaceable events config
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
 #[serde(default)]
+pub struct History {
+    pub enabled: bool,
+    /// retention of the versions, default keep forever
+    pub retention: Option<NonZeroDuration>,
+    /// retention of the kinds instead of the default, the kind number as the key
+    pub kinds: HashMap<String, NonZeroDuration>,
+    /// the kinds not keeping the versions
+    pub skip_kinds: Vec<u16>,
+}
+
+impl History {
+    /// The db options, none if disabled
+    pub fn options(&self) -> Result<Option<HistoryOptions>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let mut kinds = HashMap::new();
+        for (kind, retention) in &self.kinds {
+            let kind = kind
+                .parse::<u16>()
+                .map_err(|_| Error::Invalid(format!("history kind {}", kind)))?;
+            kinds.insert(kind, Some(retention.as_secs()));
+        }
+        for kind in &self.skip_kinds {
+            kinds.insert(*kind, Some(0));
+        }
+        Ok(Some(HistoryOptions {
+            retention: self.retention.map(|r| r.as_secs()),
+            kinds,
+        }))
+    }
+}
+
+/// encryption of the event data config, the keys are 64 hex characters
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(default)]
+pub struct Encryption {
+    pub enabled: bool,
+    pub key: Option<String>,
+    /// read the key from the file instead
+    pub key_file: Option<PathBuf>,
+    /// the keys before the rotation, decrypt the data until `rnostr rekey` is done
+    pub old_keys: Vec<String>,
+    /// index the tag values by the keyed hash, rebuild the tag index by `rnostr rekey --tag-key` to change it
+    pub tag_key: Option<String>,
+}
+
+// hide the keys in the log
+impl std::fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Encryption")
+            .field("enabled", &self.enabled)
+            .field("key_file", &self.key_file)
+            .field("old_keys", &self.old_keys.len())
+            .field("tag_key", &self.tag_key.is_some())
+            .finish()
+    }
+}
+
+impl Encryption {
+    /// The key for encrypting the new events, none if disabled
+    pub fn data_key(&self) -> Result<Option<DataKey>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        Ok(Some(match (&self.key, &self.key_file) {
+            (Some(key), _) => key.parse()?,
+            (None, Some(file)) => DataKey::from_file(file)?,
+            (None, None) => {
+                return Err(Error::Invalid("encryption key is not set".to_owned()));
+            }
+        }))
+    }
+
+    pub fn old_keys(&self) -> Result<Vec<DataKey>> {
+        Ok(self
+            .old_keys
+            .iter()
+            .map(|k| k.parse())
+            .collect::<Result<Vec<_>, _>>()?)
+    }
+
+    pub fn tag_key(&self) -> Result<Option<DataKey>> {
+        Ok(self.tag_key.as_deref().map(str::parse).transpose()?)
+    }
+}
+
+/// write path config, the OK messages are sent after the events are durable
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Write {
+    /// sync, no_meta_sync or no_sync. default sync
+    pub durability: Durability,
+    /// commit the pending events every interval. default 100ms
+    pub interval: NonZeroDuration,
+    /// commit when the number of pending events reaches it. default 1000
+    pub batch_size: usize,
+    /// flush the database every interval when the commit is not synced. default 1s
+    pub flush_interval: NonZeroDuration,
+    /// reject the new events when the number of the events waiting for the OK reaches it. default 10000
+    pub max_pending: usize,
+}
+
+impl Default for Write {
+    fn default() -> Self {
+        Self {
+            durability: Durability::Sync,
+            interval: Duration::from_millis(100).try_into().unwrap(),
+            batch_size: 1000,
+            flush_interval: Duration::from_secs(1).try_into().unwrap(),
+            max_pending: 10000,
+        }
+    }
+}
+
+/// time partitions config, the writer moves the events of the closed periods in the background
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Partition {
+    pub enabled: bool,
+    /// day, month or year. default month
+    pub period: Period,
+    /// maximum number of events scanned per move. default 1000
+    pub batch_size: usize,
+}
+
+impl Default for Partition {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            period: Period::Month,
+            batch_size: 1000,
+        }
+    }
+}
+
+/// parallel scan of the large OR filters config
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Parallel {
+    pub enabled: bool,
+    /// number of threads per query
+    pub threads: usize,
+    /// max number of the queries scanning in parallel at the same time
+    pub max_queries: usize,
+    /// min number of the authors or ids to scan in parallel
+    pub min_scanners: usize,
+    /// number of keys buffered per thread
+    pub buffer: usize,
+}
+
+impl Default for Parallel {
+    fn default() -> Self {
+        let options = ParallelScan::default();
+        Self {
+            enabled: false,
+            threads: options.threads,
+            max_queries: options.max_queries,
+            min_scanners: options.min_scanners,
+            buffer: options.buffer,
+        }
+    }
+}
+
+impl Parallel {
+    /// The db options, none if disabled
+    pub fn options(&self) -> Option<ParallelScan> {
+        self.enabled.then(|| ParallelScan {
+            threads: self.threads,
+            max_queries: self.max_queries.max(1),
+            min_scanners: self.min_scanners,
+            buffer: self.buffer.max(1),
+        })
+    }
+}
+
+/// number of threads config
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
 pub struct Thread {
     /// number of http server threads
     pub http: usize,
     /// number of read event threads
     pub reader: usize,
+    /// number of event signature verification threads
+    pub verifier: usize,
+    /// reject the new events when the number of the events waiting for verification reaches it
+    pub verifier_queue: usize,
+}
+
+impl Default for Thread {
+    fn default() -> Self {
+        Self {
+            http: 0,
+            reader: 0,
+            verifier: 0,
+            verifier_queue: 10000,
+        }
+    }
 }
 
 /// network config
@@ -157,6 +380,97 @@ impl Default for Limitation {
     }
 }
 
+/// retention config, the writer deletes the matched events in the background
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Retention {
+    /// maximum number of events deleted by each rule per time. default 1000
+    pub batch_size: usize,
+    pub rules: Vec<RetentionRule>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            batch_size: 1000,
+            rules: Vec::new(),
+        }
+    }
+}
+
+/// follower config, tail the changes of the primary relay and serve the reads locally
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Follower {
+    /// url of the primary relay, ie: wss://relay.example.com, run as a read-only follower if set.
+    /// The primary must have a single shard, the changes of each shard have their own sequence numbers.
+    pub primary: Option<String>,
+    /// auth key of the changes extension of the primary
+    pub auth: Option<String>,
+    /// maximum number of changes applied per write. default 1000
+    pub batch_size: usize,
+    /// wait before reconnecting to the primary
+    pub reconnect_interval: NonZeroDuration,
+    /// reconnect when nothing is read from the changes of the primary in time
+    pub read_timeout: NonZeroDuration,
+    /// timeout of the events forwarded to the primary
+    pub forward_timeout: NonZeroDuration,
+}
+
+impl Default for Follower {
+    fn default() -> Self {
+        Self {
+            primary: None,
+            auth: None,
+            batch_size: 1000,
+            reconnect_interval: Duration::from_secs(5).try_into().unwrap(),
+            read_timeout: Duration::from_secs(30).try_into().unwrap(),
+            forward_timeout: Duration::from_secs(10).try_into().unwrap(),
+        }
+    }
+}
+
+impl Follower {
+    /// The websocket url and the http url of the primary, none if not a follower.
+    pub fn urls(&self) -> Result<Option<(String, String)>> {
+        let Some(primary) = &self.primary else {
+            return Ok(None);
+        };
+        let primary = primary.trim_end_matches('/');
+        let (scheme, rest) = primary
+            .split_once("://")
+            .ok_or_else(|| Error::Invalid(format!("primary url {}", primary)))?;
+        let (ws, http) = match scheme {
+            "ws" | "http" => ("ws", "http"),
+            "wss" | "https" => ("wss", "https"),
+            _ => return Err(Error::Invalid(format!("primary url {}", primary))),
+        };
+        Ok(Some((
+            format!("{}://{}", ws, rest),
+            format!("{}://{}", http, rest),
+        )))
+    }
+}
+
+/// retention rule, `older_than` and `max_per_author` are applied separately
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Default)]
+#[serde(default)]
+pub struct RetentionRule {
+    /// name of the rule, used by metrics
+    pub name: String,
+    /// only the kinds, empty for all kinds
+    pub kinds: Vec<u16>,
+    /// delete the events older than the duration
+    pub older_than: Option<NonZeroDuration>,
+    /// keep at most the number of latest events per author
+    pub max_per_author: Option<u64>,
+    /// the rule skips the events of the authors (hex pubkey)
+    pub exempt_authors: Vec<String>,
+    /// only the authors never written by a NIP-42 authenticated session,
+    /// all authors if the auth extension is disabled, the events forwarded by the followers are not authenticated
+    pub unauthenticated: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 #[serde(default)]
 pub struct Setting {
@@ -165,6 +479,8 @@ pub struct Setting {
     pub thread: Thread,
     pub network: Network,
     pub limitation: Limitation,
+    pub retention: Retention,
+    pub follower: Follower,
 
     /// flatten extensions setting to json::Value
     #[serde(flatten)]
@@ -190,6 +506,8 @@ impl PartialEq for Setting {
             && self.thread == other.thread
             && self.network == other.network
             && self.limitation == other.limitation
+            && self.retention == other.retention
+            && self.follower == other.follower
             && self.extra == other.extra
     }
 }
@@ -443,6 +761,213 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn retention() -> Result<()> {
+        let setting = Setting::from_str(
+            r#"
+        [retention]
+        batch_size = 10
+        [[retention.rules]]
+        name = "reactions"
+        kinds = [7]
+        older_than = "30d"
+        [[retention.rules]]
+        name = "notes"
+        kinds = [1]
+        max_per_author = 1000
+        exempt_authors = ["7abf57d516b1ff7308ca3bd5650ea6a4674d469c7c5057b1d005fb13d218bfef"]
+        unauthenticated = true
+        "#,
+            FileFormat::Toml,
+        )?;
+        let retention = setting.retention;
+        assert_eq!(retention.batch_size, 10);
+        assert_eq!(retention.rules.len(), 2);
+        assert_eq!(retention.rules[0].kinds, vec![7]);
+        assert_eq!(
+            retention.rules[0].older_than,
+            Some(Duration::from_secs(30 * 24 * 60 * 60).try_into().unwrap())
+        );
+        assert_eq!(retention.rules[0].max_per_author, None);
+        assert_eq!(retention.rules[1].max_per_author, Some(1000));
+        assert_eq!(retention.rules[1].exempt_authors.len(), 1);
+        assert!(!retention.rules[0].unauthenticated);
+        assert!(retention.rules[1].unauthenticated);
+        assert_eq!(Setting::default().retention.batch_size, 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn follower() -> Result<()> {
+        assert!(Setting::default().follower.urls()?.is_none());
+        let setting = Setting::from_str(
+            r#"
+        [follower]
+        primary = "http://relay.example.com/"
+        auth = "auth_key"
+        "#,
+            FileFormat::Toml,
+        )?;
+        assert_eq!(
+            setting.follower.urls()?,
+            Some((
+                "ws://relay.example.com".to_owned(),
+                "http://relay.example.com".to_owned()
+            ))
+        );
+        assert_eq!(setting.follower.batch_size, 1000);
+        assert_eq!(*setting.follower.read_timeout, Duration::from_secs(30));
+        let follower = Follower {
+            primary: Some("127.0.0.1:8080".to_owned()),
+            ..Default::default()
+        };
+        assert!(follower.urls().is_err());
+        let follower = Follower {
+            primary: Some("wss://relay.example.com".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            follower.urls()?,
+            Some((
+                "wss://relay.example.com".to_owned(),
+                "https://relay.example.com".to_owned()
+            ))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn history() -> Result<()> {
+        let setting = Setting::from_str(
+            r#"
+        [data.history]
+        enabled = true
+        retention = "30d"
+        kinds = { "0" = "7d" }
+        skip_kinds = [3]
+        "#,
+            FileFormat::Toml,
+        )?;
+        let options = setting.data.history.options()?.unwrap();
+        assert_eq!(options.retention, Some(30 * 24 * 60 * 60));
+        assert_eq!(options.retention(0), Some(7 * 24 * 60 * 60));
+        assert!(!options.keep(3));
+        assert!(Setting::default().data.history.options()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn write() -> Result<()> {
+        assert_eq!(Setting::default().data.write.durability, Durability::Sync);
+        let setting = Setting::from_str(
+            r#"
+        [data.write]
+        durability = "no_sync"
+        interval = "50ms"
+        flush_interval = "2s"
+        "#,
+            FileFormat::Toml,
+        )?;
+        let write = setting.data.write;
+        assert_eq!(write.durability, Durability::NoSync);
+        assert_eq!(*write.interval, Duration::from_millis(50));
+        assert_eq!(*write.flush_interval, Duration::from_secs(2));
+        assert_eq!(write.max_pending, 10000);
+        Ok(())
+    }
+
+    #[test]
+    fn verifier() -> Result<()> {
+        assert_eq!(Setting::default().thread.verifier_queue, 10000);
+        let setting = Setting::from_str(
+            r#"
+        [thread]
+        verifier = 2
+        "#,
+            FileFormat::Toml,
+        )?;
+        assert_eq!(setting.thread.verifier, 2);
+        assert_eq!(setting.thread.verifier_queue, 10000);
+        Ok(())
+    }
+
+    #[test]
+    fn shards() -> Result<()> {
+        assert_eq!(Setting::default().data.shards, 1);
+        let setting = Setting::from_str(
+            r#"
+        [data]
+        shards = 4
+        "#,
+            FileFormat::Toml,
+        )?;
+        assert_eq!(setting.data.shards, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_scan() -> Result<()> {
+        assert!(Setting::default().data.parallel_scan.options().is_none());
+        let setting = Setting::from_str(
+            r#"
+        [data.parallel_scan]
+        enabled = true
+        threads = 8
+        max_queries = 2
+        "#,
+            FileFormat::Toml,
+        )?;
+        assert_eq!(
+            setting.data.parallel_scan.options(),
+            Some(ParallelScan {
+                threads: 8,
+                max_queries: 2,
+                ..Default::default()
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn encryption() -> Result<()> {
+        let key = "11".repeat(32);
+        let setting = Setting::from_str(
+            &format!(
+                r#"
+        [data.encryption]
+        enabled = true
+        key = "{}"
+        old_keys = ["{}"]
+        "#,
+                key,
+                "22".repeat(32)
+            ),
+            FileFormat::Toml,
+        )?;
+        let encryption = &setting.data.encryption;
+        assert_eq!(encryption.data_key()?.unwrap().to_hex(), key);
+        assert_eq!(encryption.old_keys()?.len(), 1);
+        assert!(encryption.tag_key()?.is_none());
+        assert!(!format!("{:?}", setting).contains(&key));
+
+        let file = Builder::new()
+            .prefix("nostr-relay-config-test-key")
+            .tempfile()?;
+        fs::write(file.path(), format!("{}\n", key))?;
+        let encryption = Encryption {
+            enabled: true,
+            key_file: Some(file.path().to_path_buf()),
+            ..Default::default()
+        };
+        assert_eq!(encryption.data_key()?.unwrap().to_hex(), key);
+
+        let mut default = Setting::default().data.encryption;
+        assert!(default.data_key()?.is_none());
+        default.enabled = true;
+        assert!(default.data_key().is_err());
+        Ok(())
+    }
+
     #[test]
     fn render() -> Result<()> {
         let mut def = Setting::default();
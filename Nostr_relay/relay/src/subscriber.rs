@@ -5,7 +5,7 @@ use std::{
 
 use crate::{message::*, setting::SettingWrapper};
 use actix::prelude::*;
-use nostr_db::{EventIndex, Filter};
+use nostr_db::{EventIndex, Filter, SortList};
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 struct Key {
@@ -32,13 +32,18 @@ where
     [key.as_ref(), val.as_ref()].concat()
 }
 
+// only the full ids can be indexed, the filter with prefixes is indexed by other conditions
+fn indexed(ids: &SortList<Vec<u8>>) -> bool {
+    !ids.is_empty() && Filter::is_full(ids)
+}
+
 // index for fast filter
 #[derive(Debug, Default)]
 pub struct SubscriberIndex {
     /// map session_id -> subscription_id -> filters
     subscriptions: HashMap<usize, HashMap<String, Vec<Rc<Filter>>>>,
-    ids: HashMap<[u8; 32], HashMap<Key, Weak<Filter>>>,
-    authors: HashMap<[u8; 32], HashMap<Key, Weak<Filter>>>,
+    ids: HashMap<Vec<u8>, HashMap<Key, Weak<Filter>>>,
+    authors: HashMap<Vec<u8>, HashMap<Key, Weak<Filter>>>,
     tags: HashMap<Vec<u8>, HashMap<Key, Weak<Filter>>>,
     kinds: HashMap<u16, HashMap<Key, Weak<Filter>>>,
     others: HashMap<Key, Weak<Filter>>,
@@ -47,16 +52,16 @@ pub struct SubscriberIndex {
 impl SubscriberIndex {
     fn install_index(&mut self, session_id: usize, sub_id: String, filters: &[Rc<Filter>]) {
         for (index, filter) in filters.iter().enumerate() {
-            if !filter.ids.is_empty() {
+            if indexed(&filter.ids) {
                 for key in filter.ids.iter() {
-                    self.ids.entry(*key).or_default().insert(
+                    self.ids.entry(key.clone()).or_default().insert(
                         Key::new(session_id, sub_id.clone(), index),
                         Rc::downgrade(filter),
                     );
                 }
-            } else if !filter.authors.is_empty() {
+            } else if indexed(&filter.authors) {
                 for key in filter.authors.iter() {
-                    self.authors.entry(*key).or_default().insert(
+                    self.authors.entry(key.clone()).or_default().insert(
                         Key::new(session_id, sub_id.clone(), index),
                         Rc::downgrade(filter),
                     );
@@ -95,7 +100,7 @@ impl SubscriberIndex {
                     }
                 }
                 for (index, filter) in filters.iter().enumerate() {
-                    if !filter.ids.is_empty() {
+                    if indexed(&filter.ids) {
                         for key in filter.ids.iter() {
                             if let Some(map) = self.ids.get_mut(key) {
                                 map.remove(&Key::new(session_id, sub_id.clone(), index));
@@ -104,7 +109,7 @@ impl SubscriberIndex {
                                 }
                             }
                         }
-                    } else if !filter.authors.is_empty() {
+                    } else if indexed(&filter.authors) {
                         for key in filter.authors.iter() {
                             if let Some(map) = self.authors.get_mut(key) {
                                 map.remove(&Key::new(session_id, sub_id.clone(), index));
@@ -210,13 +215,16 @@ impl SubscriberIndex {
             }
         }
 
-        fn scan<T: std::cmp::Eq + std::hash::Hash>(
+        fn scan<T, Q>(
             map: &HashMap<T, HashMap<Key, Weak<Filter>>>,
-            key: &T,
+            key: &Q,
             event: &EventIndex,
             dup: &mut HashMap<(usize, String), bool>,
             mut f: impl FnMut(&usize, &String),
-        ) {
+        ) where
+            T: std::borrow::Borrow<Q> + std::cmp::Eq + std::hash::Hash,
+            Q: std::cmp::Eq + std::hash::Hash + ?Sized,
+        {
             if let Some(map) = map.get(key) {
                 for (k, filter) in map {
                     check(k.session_id, &k.sub_id, filter, event, dup, &mut f);
@@ -224,8 +232,8 @@ impl SubscriberIndex {
             }
         }
 
-        scan(&self.ids, event.id(), event, &mut dup, &mut f);
-        scan(&self.authors, event.pubkey(), event, &mut dup, &mut f);
+        scan(&self.ids, &event.id()[..], event, &mut dup, &mut f);
+        scan(&self.authors, &event.pubkey()[..], event, &mut dup, &mut f);
         scan(&self.kinds, &event.kind(), event, &mut dup, &mut f);
         for (key, val) in event.tags() {
             scan(&self.tags, &concat_tag(key, val), event, &mut dup, &mut f);
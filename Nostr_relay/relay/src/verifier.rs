@@ -0,0 +1,223 @@
+use crate::{message::*, setting::SettingWrapper};
+use actix::prelude::*;
+use metrics::{gauge, histogram, increment_counter};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tracing::info;
+
+/// Verify the event id and signature in the worker threads,
+/// the session threads keep serving the websocket I/O under the write floods.
+/// The verified events are sent to the server, the failed are answered to the session.
+pub struct Verifier {
+    pub server: Recipient<ClientMessage>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl Actor for Verifier {
+    type Context = SyncContext<Self>;
+}
+
+impl Handler<VerifyEvent> for Verifier {
+    type Result = ();
+    fn handle(&mut self, msg: VerifyEvent, _: &mut Self::Context) {
+        let pending = self.pending.fetch_sub(1, Ordering::Relaxed) - 1;
+        gauge!("nostr_relay_verify_pending", pending as f64);
+        let start = Instant::now();
+        let result = msg.msg.verify();
+        histogram!("nostr_relay_verify_time", start.elapsed());
+        match result {
+            Ok(_) => self.server.do_send(msg.msg),
+            Err(err) => {
+                if let IncomingMessage::Event(event) = &msg.msg.msg {
+                    msg.session.do_send(OutgoingMessage::ok(
+                        &event.id_str(),
+                        false,
+                        &err.to_string(),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// The bounded pool of the verifiers, a worker thread per verifier.
+/// The messages of a session are verified by the same worker, so they are kept in order.
+#[derive(Clone)]
+pub struct VerifierPool {
+    workers: Vec<Addr<Verifier>>,
+    pending: Arc<AtomicUsize>,
+    setting: SettingWrapper,
+}
+
+impl VerifierPool {
+    pub fn start(server: Recipient<ClientMessage>, setting: SettingWrapper) -> Self {
+        let num = match setting.read().thread.verifier {
+            0 => num_cpus::get(),
+            n => n,
+        };
+        info!("starting {} verifier workers", num);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let workers = (0..num)
+            .map(|_| {
+                let server = server.clone();
+                let pending = pending.clone();
+                SyncArbiter::start(1, move || Verifier {
+                    server: server.clone(),
+                    pending: pending.clone(),
+                })
+            })
+            .collect();
+        Self {
+            workers,
+            pending,
+            setting,
+        }
+    }
+
+    /// The number of the events waiting for verification
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Queue the event message to the worker of the session, reject it when the queue is full
+    pub fn verify(&self, msg: ClientMessage, session: Recipient<OutgoingMessage>) {
+        let max = self.setting.read().thread.verifier_queue;
+        let queued = self
+            .pending
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < max).then_some(n + 1)
+            });
+        let Ok(pending) = queued else {
+            increment_counter!("nostr_relay_verify_rejected");
+            if let IncomingMessage::Event(event) = &msg.msg {
+                session.do_send(OutgoingMessage::ok(
+                    &event.id_str(),
+                    false,
+                    "rate-limited: too many events waiting for verification",
+                ));
+            }
+            return;
+        };
+        gauge!("nostr_relay_verify_pending", (pending + 1) as f64);
+        let worker = &self.workers[msg.id % self.workers.len()];
+        worker.do_send(VerifyEvent { msg, session });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Setting;
+    use actix_rt::time::sleep;
+    use anyhow::Result;
+    use nostr_db::{
+        secp256k1::{rand::thread_rng, KeyPair},
+        Event,
+    };
+    use parking_lot::RwLock;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Receiver(Arc<RwLock<Vec<String>>>);
+    impl Actor for Receiver {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<OutgoingMessage> for Receiver {
+        type Result = ();
+        fn handle(&mut self, msg: OutgoingMessage, _ctx: &mut Self::Context) {
+            self.0.write().push(msg.0);
+        }
+    }
+
+    impl Handler<ClientMessage> for Receiver {
+        type Result = ();
+        fn handle(&mut self, msg: ClientMessage, _ctx: &mut Self::Context) {
+            self.0.write().push(msg.text);
+        }
+    }
+
+    fn message(event: Event) -> ClientMessage {
+        let text = format!(r#"["EVENT",{}]"#, event);
+        ClientMessage {
+            id: 1,
+            text,
+            msg: IncomingMessage::Event(event),
+            authenticated: false,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn verify() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let valid = Event::create(&key_pair, 10, 1, vec![], "valid".to_owned())?;
+        let other = Event::create(&key_pair, 10, 1, vec![], "other".to_owned())?;
+        // the signature of another event
+        let forged = Event::new(
+            *valid.id(),
+            *valid.pubkey(),
+            10,
+            1,
+            vec![],
+            "valid".to_owned(),
+            *other.sig(),
+        )?;
+
+        let server = Receiver::default();
+        let forwarded = server.0.clone();
+        let server = server.start();
+        let session = Receiver::default();
+        let answered = session.0.clone();
+        let session = session.start();
+
+        let setting: SettingWrapper = Setting::default().into();
+        setting.write().thread.verifier = 2;
+        let pool = VerifierPool::start(server.recipient(), setting.clone());
+        pool.verify(message(valid), session.clone().recipient());
+        pool.verify(message(forged), session.clone().recipient());
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(forwarded.read().len(), 1);
+        assert!(forwarded.read()[0].contains("valid"));
+        assert_eq!(answered.read().len(), 1);
+        assert!(answered.read()[0].contains("false"));
+        assert_eq!(pool.pending(), 0);
+
+        // overload
+        setting.write().thread.verifier_queue = 0;
+        pool.verify(message(other), session.recipient());
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(forwarded.read().len(), 1);
+        assert!(answered.read()[1].contains("rate-limited"));
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn order() -> Result<()> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let server = Receiver::default();
+        let forwarded = server.0.clone();
+        let server = server.start();
+        let session = Receiver::default().start();
+
+        // the events of a session are forwarded in order by the workers
+        let setting: SettingWrapper = Setting::default().into();
+        setting.write().thread.verifier = 4;
+        let pool = VerifierPool::start(server.recipient(), setting);
+        let mut texts = vec![];
+        for i in 0..50 {
+            let event = Event::create(&key_pair, 10, 1, vec![], i.to_string())?;
+            let msg = message(event);
+            texts.push(msg.text.clone());
+            pool.verify(msg, session.clone().recipient());
+        }
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(*forwarded.read(), texts);
+        assert_eq!(pool.pending(), 0);
+        Ok(())
+    }
+}
@@ -1,44 +1,90 @@
-use crate::{message::*, Result};
+use crate::{
+    message::*,
+    setting::{RetentionRule, SettingWrapper},
+    Result,
+};
 use actix::prelude::*;
-use metrics::{histogram, increment_counter};
-use nostr_db::{now, CheckEventResult, Db};
+use metrics::{counter, histogram, increment_counter};
+use nostr_db::{now, CheckEventResult, Cursor, Db, Durability, Event, Filter};
 use std::{
+    collections::{HashMap, HashSet},
     sync::Arc,
     time::{Duration, Instant},
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 /// Single-threaded write events, delete expired events
 /// Batch write can improve tps
+/// The results are sent after the commit, or after the flush when the commit is not synced
 
-const WRITE_INTERVAL_MS: u64 = 100;
 const DEL_INTERVAL_SECONDS: u64 = 60;
 const EPHEMERAL_EXPIRED_SECONDS: u64 = 60 * 5;
 
 pub struct Writer {
     pub db: Arc<Db>,
     pub addr: Recipient<WriteEventResult>,
-    pub events: Vec<WriteEvent>,
+    pub setting: SettingWrapper,
+    /// the pending events with the received time
+    pub events: Vec<(WriteEvent, Instant)>,
     pub write_interval_ms: u64,
+    /// commit when the number of pending events reaches it
+    pub batch_size: usize,
+    pub flush_interval_ms: u64,
     pub del_interval_seconds: u64,
+    durability: Durability,
+    // the deletion events stored in the other shards
+    deletions: Vec<Event>,
+    // the committed results waiting for the flush
+    unflushed: Vec<(WriteEventResult, Instant)>,
+    last_flush: Instant,
+    // the position of the last scanned event by the older_than rule, continue after it next time
+    retention_cursor: HashMap<RetentionRule, Cursor>,
+    // the last author scanned by the max_per_author rule, `None` after the scan of all authors finished
+    author_cursor: HashMap<RetentionRule, Option<[u8; 32]>>,
+    // the authors may exceed the max_per_author rule, checked by the next retention
+    retention_authors: HashMap<RetentionRule, HashSet<[u8; 32]>>,
+    // the authors of the events written since the last retention
+    written_authors: HashSet<[u8; 32]>,
+    // the position of the last scanned event by the partition move, continue after it next time
+    partition_cursor: Option<Cursor>,
 }
 
 impl Writer {
-    pub fn new(db: Arc<Db>, addr: Recipient<WriteEventResult>) -> Self {
+    pub fn new(db: Arc<Db>, addr: Recipient<WriteEventResult>, setting: SettingWrapper) -> Self {
+        let write = setting.read().data.write.clone();
+        let durability = db.durability().unwrap_or_default();
         Self {
             db,
             addr,
+            setting,
             events: Vec::new(),
-            write_interval_ms: WRITE_INTERVAL_MS,
+            write_interval_ms: write.interval.as_millis() as u64,
+            batch_size: write.batch_size.max(1),
+            flush_interval_ms: write.flush_interval.as_millis() as u64,
             del_interval_seconds: DEL_INTERVAL_SECONDS,
+            durability,
+            deletions: Vec::new(),
+            unflushed: Vec::new(),
+            last_flush: Instant::now(),
+            retention_cursor: HashMap::new(),
+            author_cursor: HashMap::new(),
+            retention_authors: HashMap::new(),
+            written_authors: HashSet::new(),
+            partition_cursor: None,
         }
     }
 
     pub fn write(&mut self) -> Result<()> {
-        if !self.events.is_empty() {
+        if !self.events.is_empty() || !self.deletions.is_empty() {
             let start = Instant::now();
+            let mut results = Vec::with_capacity(self.events.len());
             let mut writer = self.db.writer()?;
-            while let Some(event) = self.events.pop() {
+            for event in std::mem::take(&mut self.deletions) {
+                if let Err(err) = self.db.del_referenced(&mut writer, &event) {
+                    error!(error = err.to_string(), "delete referenced events error");
+                }
+            }
+            while let Some((event, received)) = self.events.pop() {
                 let res = self.db.put(&mut writer, &event.event);
                 debug!(
                     "write event: {} {} {:?}",
@@ -47,40 +93,97 @@ impl Writer {
                     res,
                 );
 
-                match res {
-                    Ok(result) => {
-                        if let CheckEventResult::Ok(_num) = result {
-                            increment_counter!("nostr_relay_new_event");
+                if let Ok(CheckEventResult::Ok(_)) = res {
+                    self.written_authors.insert(*event.event.pubkey());
+                    if event.authenticated {
+                        if let Err(err) =
+                            self.db.put_authenticated(&mut writer, event.event.pubkey())
+                        {
+                            error!(error = err.to_string(), "record authenticated author error");
                         }
-                        self.addr.do_send(WriteEventResult::Write {
-                            id: event.id,
-                            event: event.event,
-                            result,
-                        });
                     }
+                }
+                let result = match res {
+                    Ok(result) => WriteEventResult::Write {
+                        id: event.id,
+                        event: event.event,
+                        result,
+                    },
                     Err(err) => {
                         error!(error = err.to_string(), "write event error");
                         let eid = event.event.id_str();
-                        self.addr.do_send(WriteEventResult::Message {
+                        WriteEventResult::Message {
                             id: event.id,
                             event: event.event,
                             msg: OutgoingMessage::ok(&eid, false, "write event error"),
-                        });
+                        }
                     }
+                };
+                results.push((result, received));
+            }
+            if let Err(err) = self.db.commit(writer) {
+                // nothing is stored
+                for (result, _) in results {
+                    let (id, event) = match result {
+                        WriteEventResult::Write { id, event, .. } => (id, event),
+                        WriteEventResult::Message { id, event, .. } => (id, event),
+                    };
+                    let eid = event.id_str();
+                    self.addr.do_send(WriteEventResult::Message {
+                        id,
+                        event,
+                        msg: OutgoingMessage::ok(&eid, false, "write event error"),
+                    });
                 }
+                return Err(err.into());
+            }
+            histogram!("nostr_relay_db_write", start.elapsed(), "durability" => self.durability.as_str());
+            self.unflushed.extend(results);
+            if self.durability.sync_on_commit()
+                || self.last_flush.elapsed() >= Duration::from_millis(self.flush_interval_ms)
+            {
+                self.flush()?;
             }
-            self.db.commit(writer)?;
-            histogram!("nostr_relay_db_write", start.elapsed());
         }
         Ok(())
     }
 
+    /// Flush the committed events to the disk if the commit is not synced, then send the results
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.unflushed.is_empty() {
+            if !self.durability.sync_on_commit() {
+                let start = Instant::now();
+                self.db.flush()?;
+                histogram!("nostr_relay_db_flush", start.elapsed(), "durability" => self.durability.as_str());
+            }
+            for (result, received) in self.unflushed.drain(..) {
+                if let WriteEventResult::Write {
+                    result: CheckEventResult::Ok(_),
+                    ..
+                } = result
+                {
+                    increment_counter!("nostr_relay_new_event");
+                }
+                histogram!("nostr_relay_write_latency", received.elapsed(), "durability" => self.durability.as_str());
+                self.addr.do_send(result);
+            }
+        }
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
     pub fn do_write(&mut self) {
         if let Err(err) = self.write() {
             error!(error = err.to_string(), "write events error");
         }
     }
 
+    pub fn do_flush(&mut self) {
+        if let Err(err) = self.flush() {
+            error!(error = err.to_string(), "flush events error");
+        }
+    }
+
     pub fn del_expired(&self) -> Result<()> {
         let reader = self.db.reader()?;
         let iter = self
@@ -109,16 +212,207 @@ impl Writer {
         Ok(())
     }
 
-    pub fn do_del(&self) {
+    /// Delete the events by the retention rules, return the number of deleted events per rule
+    pub fn del_retention(&mut self) -> Result<Vec<usize>> {
+        let retention = self.setting.read().retention.clone();
+        let batch_size = retention.batch_size.max(1);
+        let written = std::mem::take(&mut self.written_authors);
+        let mut result = vec![];
+        for rule in retention.rules.iter() {
+            let exempt = parse_authors(&rule.exempt_authors);
+            let mut num = 0;
+            if let Some(older_than) = rule.older_than {
+                let until = now().saturating_sub(older_than.as_secs());
+                num += self.del_older_than(rule, until, &exempt, batch_size)?;
+            }
+            if let Some(max) = rule.max_per_author {
+                self.retention_authors
+                    .entry(rule.clone())
+                    .or_default()
+                    .extend(written.iter().filter(|a| !exempt.contains(a)));
+                num += self.del_per_author(rule, max, &exempt, batch_size)?;
+            }
+            if num > 0 {
+                counter!("nostr_relay_retention_deleted", num as u64, "rule" => rule.name.clone());
+                info!("retention rule {} deleted {} events", rule.name, num);
+            }
+            result.push(num);
+        }
+        // remove the cursor of the changed rules
+        self.retention_cursor
+            .retain(|rule, _| retention.rules.contains(rule));
+        self.author_cursor
+            .retain(|rule, _| retention.rules.contains(rule));
+        self.retention_authors
+            .retain(|rule, _| retention.rules.contains(rule));
+        Ok(result)
+    }
+
+    fn del_older_than(
+        &mut self,
+        rule: &RetentionRule,
+        until: u64,
+        exempt: &[[u8; 32]],
+        batch_size: usize,
+    ) -> Result<usize> {
+        let filter = Filter {
+            kinds: rule.kinds.clone().into(),
+            until: Some(until),
+            cursor: self.retention_cursor.get(rule).copied(),
+            ..Default::default()
+        };
+        let reader = self.db.reader()?;
+        // the archived events are immutable, rescanning them each time deletes nothing
+        let mut iter = self.db.iter_deletable::<Event, _>(&reader, &filter)?;
+        let mut ids = vec![];
+        let mut scanned = 0;
+        let mut cursor = None;
+        for event in iter.by_ref() {
+            let event = event?;
+            scanned += 1;
+            let author = event.pubkey();
+            let skip = exempt.contains(author)
+                || (rule.unauthenticated && self.db.is_authenticated(&reader, author)?);
+            if !skip {
+                ids.push(event.id().to_vec());
+            }
+            // the exempt events also count, avoid scanning too many each time
+            if ids.len() >= batch_size || scanned >= batch_size * 10 {
+                cursor = iter.cursor();
+                break;
+            }
+        }
+        match cursor {
+            Some(cursor) => self.retention_cursor.insert(rule.clone(), cursor),
+            None => self.retention_cursor.remove(rule),
+        };
+        Ok(self.db.batch_del(&ids)?)
+    }
+
+    // check the authors of the written events and a part of all authors by the per author and kind counters
+    fn del_per_author(
+        &mut self,
+        rule: &RetentionRule,
+        max: u64,
+        exempt: &[[u8; 32]],
+        batch_size: usize,
+    ) -> Result<usize> {
+        let reader = self.db.reader()?;
+        let mut authors = self.retention_authors.remove(rule).unwrap_or_default();
+        authors.retain(|author| {
+            self.db
+                .count_author(&reader, author, &rule.kinds)
+                .map_or(true, |count| count > max)
+        });
+        // scan all authors once in parts, the new events are checked by the written authors
+        let cursor = self.author_cursor.get(rule).copied();
+        if cursor != Some(None) {
+            let after = cursor.flatten();
+            let (found, next) =
+                self.db
+                    .authors_over(&reader, &rule.kinds, max, after.as_ref(), batch_size * 10)?;
+            authors.extend(found.into_iter().filter(|a| !exempt.contains(a)));
+            self.author_cursor.insert(rule.clone(), next);
+        }
+
+        let mut authors = authors.into_iter().collect::<Vec<_>>();
+        authors.sort();
+        let mut ids = vec![];
+        let mut remaining = HashSet::new();
+        for author in authors {
+            if rule.unauthenticated && self.db.is_authenticated(&reader, &author)? {
+                continue;
+            }
+            if ids.len() >= batch_size {
+                remaining.insert(author);
+                continue;
+            }
+            let filter = Filter {
+                authors: vec![author].into(),
+                kinds: rule.kinds.clone().into(),
+                desc: true,
+                ..Default::default()
+            };
+            let iter = self.db.iter_deletable::<Vec<u8>, _>(&reader, &filter)?;
+            for id in iter.skip(max as usize) {
+                if ids.len() >= batch_size {
+                    remaining.insert(author);
+                    break;
+                }
+                ids.push(id?);
+            }
+        }
+        if !remaining.is_empty() {
+            self.retention_authors.insert(rule.clone(), remaining);
+        }
+        Ok(self.db.batch_del(&ids)?)
+    }
+
+    /// Move the events of the closed periods to the partitions, return the number of moved events
+    pub fn move_partitions(&mut self) -> Result<usize> {
+        let partition = self.setting.read().data.partition.clone();
+        if !partition.enabled {
+            return Ok(0);
+        }
+        let before = now();
+        let mut total = 0;
+        // limit the time of each run
+        for _ in 0..10 {
+            let (num, next) = self.db.partition_before(
+                partition.period,
+                before,
+                self.partition_cursor,
+                partition.batch_size,
+            )?;
+            total += num;
+            // the new events may be written before the cursor by the clients, start over after the end
+            self.partition_cursor = next;
+            if next.is_none() {
+                break;
+            }
+        }
+        if total > 0 {
+            counter!("nostr_relay_partition_moved", total as u64);
+            info!("moved {} events to the partitions", total);
+        }
+        Ok(total)
+    }
+
+    pub fn do_del(&mut self) {
         if let Err(err) = self.del_expired() {
             error!(error = err.to_string(), "delete expired events error");
         }
         if let Err(err) = self.del_ephemeral() {
             error!(error = err.to_string(), "delete ephemeral events error");
         }
+        if let Err(err) = self.del_retention() {
+            error!(error = err.to_string(), "delete retention events error");
+        }
+        if let Err(err) = self.db.prune_history(now()) {
+            error!(error = err.to_string(), "prune history versions error");
+        }
+        if let Err(err) = self.move_partitions() {
+            error!(error = err.to_string(), "move partition events error");
+        }
     }
 }
 
+fn parse_authors(authors: &[String]) -> Vec<[u8; 32]> {
+    authors
+        .iter()
+        .filter_map(|s| {
+            let mut pubkey = [0u8; 32];
+            match hex::decode_to_slice(s, &mut pubkey) {
+                Ok(_) => Some(pubkey),
+                Err(err) => {
+                    warn!(error = err.to_string(), "invalid retention author {}", s);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
 impl Actor for Writer {
     type Context = Context<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
@@ -130,7 +424,16 @@ impl Actor for Writer {
                 act.do_write();
             },
         );
-        // delete expired and ephemeral events
+        // flush the unsynced commits
+        if !self.durability.sync_on_commit() {
+            ctx.run_interval(
+                Duration::from_millis(self.flush_interval_ms),
+                |act, _ctx| {
+                    act.do_flush();
+                },
+            );
+        }
+        // delete expired, ephemeral and retention events
         ctx.run_interval(
             Duration::from_secs(self.del_interval_seconds),
             |act, _ctx| {
@@ -143,13 +446,25 @@ impl Actor for Writer {
         info!("Actor writer stopped");
         // save event when stopped
         self.do_write();
+        self.do_flush();
     }
 }
 
 impl Handler<WriteEvent> for Writer {
     type Result = ();
     fn handle(&mut self, msg: WriteEvent, _: &mut Self::Context) {
-        self.events.push(msg);
+        self.events.push((msg, Instant::now()));
+        // commit before the interval when the batch is full
+        if self.events.len() >= self.batch_size {
+            self.do_write();
+        }
+    }
+}
+
+impl Handler<DelReferenced> for Writer {
+    type Result = ();
+    fn handle(&mut self, msg: DelReferenced, _: &mut Self::Context) {
+        self.deletions.push(msg.event);
     }
 }
 
@@ -158,7 +473,7 @@ mod tests {
     use std::{str::FromStr, time::Duration};
 
     use super::*;
-    use crate::temp_data_path;
+    use crate::{temp_data_path, Setting};
     use actix_rt::time::sleep;
     use anyhow::Result;
     use nostr_db::{Event, Filter};
@@ -198,7 +513,7 @@ mod tests {
         let receiver = receiver.start();
         let addr = receiver.recipient();
 
-        let mut writer = Writer::new(Arc::clone(&db), addr.clone());
+        let mut writer = Writer::new(Arc::clone(&db), addr.clone(), Setting::default().into());
         writer.del_interval_seconds = 1;
         writer.write_interval_ms = 100;
         let writer = writer.start();
@@ -208,6 +523,7 @@ mod tests {
                 .send(WriteEvent {
                     id: i,
                     event: event.clone(),
+                    authenticated: false,
                 })
                 .await?;
         }
@@ -227,6 +543,7 @@ mod tests {
                   "tags": [["t", "nostr"]]
                 }
               "#)?,
+              authenticated: false,
           })
           .await?;
         // ephemeral
@@ -244,6 +561,7 @@ mod tests {
                   "tags": [["t", "nostr"]]
                 }}
               "#, now()))?,
+              authenticated: false,
           })
           .await?;
 
@@ -262,6 +580,7 @@ mod tests {
                   "tags": [["t", "nostr"], ["expiration", "10"]]
                 }
               "#)?,
+              authenticated: false,
           })
           .await?;
 
@@ -293,4 +612,304 @@ mod tests {
 
         Ok(())
     }
+    #[actix_rt::test]
+    async fn durability() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("writer_durability")?)?);
+        db.set_durability(Durability::NoSync)?;
+        let event = |id: u8| Event::new([id; 32], [1; 32], 10, 1, vec![], "".to_owned(), [0; 64]);
+
+        let receiver = Receiver::default();
+        let messages = receiver.0.clone();
+        let mut writer = Writer::new(
+            Arc::clone(&db),
+            receiver.start().recipient(),
+            Setting::default().into(),
+        );
+        writer.write_interval_ms = 10_000;
+        writer.flush_interval_ms = 300;
+        writer.batch_size = 2;
+        let writer = writer.start();
+
+        writer
+            .send(WriteEvent {
+                id: 1,
+                event: event(1)?,
+                authenticated: false,
+            })
+            .await?;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            db.iter::<Event, _>(&db.reader()?, &Filter::default())?
+                .count(),
+            0
+        );
+
+        // committed by the batch size, the results wait for the flush
+        writer
+            .send(WriteEvent {
+                id: 2,
+                event: event(2)?,
+                authenticated: false,
+            })
+            .await?;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            db.iter::<Event, _>(&db.reader()?, &Filter::default())?
+                .count(),
+            2
+        );
+        assert_eq!(messages.read().len(), 0);
+
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(messages.read().len(), 2);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn retention() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("writer_retention")?)?);
+        let event = |id: u8, pubkey: u8, kind: u16, created_at: u64| {
+            Event::new(
+                [id; 32],
+                [pubkey; 32],
+                created_at,
+                kind,
+                vec![],
+                "".to_owned(),
+                [0; 64],
+            )
+        };
+        let mut events = vec![];
+        // old reactions
+        for i in 0..5 {
+            events.push(event(i, 1, 7, 10 + i as u64)?);
+        }
+        // recent reaction
+        events.push(event(10, 1, 7, now())?);
+        // exempt author
+        events.push(event(11, 3, 7, 10)?);
+        // notes
+        for i in 0..3 {
+            events.push(event(20 + i, 2, 1, 10 + i as u64)?);
+        }
+        // the authors of more events than the notes author, only recent reactions
+        for i in 0..4 {
+            events.push(event(30 + i, 4, 7, now())?);
+            events.push(event(40 + i, 5, 7, now())?);
+        }
+        db.batch_put(&events)?;
+
+        let setting = Setting::from_str(
+            &format!(
+                r#"
+        [retention]
+        batch_size = 2
+        [[retention.rules]]
+        name = "reactions"
+        kinds = [7]
+        older_than = "1d"
+        exempt_authors = ["{}"]
+        [[retention.rules]]
+        name = "notes"
+        kinds = [1]
+        max_per_author = 1
+        "#,
+                hex::encode([3u8; 32])
+            ),
+            config::FileFormat::Toml,
+        )?;
+
+        let receiver = Receiver::default().start();
+        let mut writer = Writer::new(Arc::clone(&db), receiver.recipient(), setting.into());
+        assert_eq!(writer.del_retention()?, vec![2, 2]);
+        assert_eq!(writer.del_retention()?, vec![2, 0]);
+        assert_eq!(writer.del_retention()?, vec![1, 0]);
+        assert_eq!(writer.del_retention()?, vec![0, 0]);
+
+        let ids = |kind: u16| -> Result<Vec<u8>> {
+            let filter = Filter {
+                kinds: vec![kind].into(),
+                ..Default::default()
+            };
+            let txn = db.reader()?;
+            let iter = db.iter::<Event, _>(&txn, &filter)?;
+            Ok(iter.map(|e| e.unwrap().id()[0]).collect())
+        };
+        assert_eq!(ids(1)?, vec![22]);
+        assert_eq!(ids(7)?.len(), 10);
+
+        // the authors of the written events are checked after the scan of all authors finished
+        for (i, created_at) in [(50, 20), (51, 21)] {
+            writer.events.push((
+                WriteEvent {
+                    id: i as usize,
+                    event: event(i, 2, 1, created_at)?,
+                    authenticated: false,
+                },
+                Instant::now(),
+            ));
+        }
+        writer.write()?;
+        assert_eq!(ids(1)?, vec![22, 50, 51]);
+        assert_eq!(writer.del_retention()?, vec![0, 2]);
+        assert_eq!(ids(1)?, vec![51]);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn retention_cursor() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("writer_retention_cursor")?)?);
+        let event = |id: u8, pubkey: u8| {
+            Event::new(
+                [id; 32],
+                [pubkey; 32],
+                10,
+                7,
+                vec![],
+                "".to_owned(),
+                [0; 64],
+            )
+        };
+        // more exempt events of the same second than a scan
+        let mut events = (0..25)
+            .map(|i| event(i, 3))
+            .collect::<Result<Vec<_>, _>>()?;
+        events.push(event(100, 1)?);
+        db.batch_put(&events)?;
+
+        let setting = Setting::from_str(
+            &format!(
+                r#"
+        [retention]
+        batch_size = 1
+        [[retention.rules]]
+        name = "reactions"
+        kinds = [7]
+        older_than = "1d"
+        exempt_authors = ["{}"]
+        "#,
+                hex::encode([3u8; 32])
+            ),
+            config::FileFormat::Toml,
+        )?;
+        let receiver = Receiver::default().start();
+        let mut writer = Writer::new(Arc::clone(&db), receiver.recipient(), setting.into());
+        assert_eq!(writer.del_retention()?, vec![0]);
+        assert_eq!(writer.del_retention()?, vec![0]);
+        assert_eq!(writer.del_retention()?, vec![1]);
+        assert_eq!(
+            db.iter::<Event, _>(&db.reader()?, &Filter::default())?
+                .count(),
+            25
+        );
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn retention_unauthenticated() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path(
+            "writer_retention_unauthenticated",
+        )?)?);
+        let setting = Setting::from_str(
+            r#"
+        [retention]
+        batch_size = 10
+        [[retention.rules]]
+        name = "reactions"
+        kinds = [7]
+        older_than = "1d"
+        unauthenticated = true
+        [[retention.rules]]
+        name = "notes"
+        kinds = [1]
+        max_per_author = 1
+        unauthenticated = true
+        "#,
+            config::FileFormat::Toml,
+        )?;
+        let receiver = Receiver::default().start();
+        let mut writer = Writer::new(Arc::clone(&db), receiver.recipient(), setting.into());
+        // the author 1 is authenticated by one of the events
+        for (i, pubkey, kind, authenticated) in [
+            (1, 1, 7, true),
+            (2, 1, 7, false),
+            (3, 2, 7, false),
+            (4, 1, 1, false),
+            (5, 1, 1, false),
+            (6, 2, 1, false),
+            (7, 2, 1, false),
+        ] {
+            writer.events.push((
+                WriteEvent {
+                    id: i as usize,
+                    event: Event::new(
+                        [i; 32],
+                        [pubkey; 32],
+                        10 + i as u64,
+                        kind,
+                        vec![],
+                        "".to_owned(),
+                        [0; 64],
+                    )?,
+                    authenticated,
+                },
+                Instant::now(),
+            ));
+        }
+        writer.write()?;
+        assert!(db.is_authenticated(&db.reader()?, &[1; 32])?);
+        assert!(!db.is_authenticated(&db.reader()?, &[2; 32])?);
+
+        assert_eq!(writer.del_retention()?, vec![1, 1]);
+        assert_eq!(writer.del_retention()?, vec![0, 0]);
+        let ids = db
+            .iter::<Event, _>(&db.reader()?, &Filter::default())?
+            .map(|e| e.unwrap().id()[0])
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2, 4, 5, 7]);
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn retention_archived() -> Result<()> {
+        let db = Arc::new(Db::open(temp_data_path("writer_retention_archived")?)?);
+        let events = (0..5)
+            .map(|i| {
+                Event::new(
+                    [i; 32],
+                    [1; 32],
+                    10 + i as u64,
+                    7,
+                    vec![],
+                    "".to_owned(),
+                    [0; 64],
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        db.batch_put(&events)?;
+        assert_eq!(db.archive_before(13, 10)?, 3);
+
+        let setting = Setting::from_str(
+            r#"
+        [retention]
+        batch_size = 10
+        [[retention.rules]]
+        name = "reactions"
+        kinds = [7]
+        older_than = "1d"
+        "#,
+            config::FileFormat::Toml,
+        )?;
+        let receiver = Receiver::default().start();
+        let mut writer = Writer::new(Arc::clone(&db), receiver.recipient(), setting.into());
+        // only the events in the hot storage are deleted and counted
+        assert_eq!(writer.del_retention()?, vec![2]);
+        assert_eq!(writer.del_retention()?, vec![0]);
+        assert_eq!(
+            db.iter::<Event, _>(&db.reader()?, &Filter::default())?
+                .count(),
+            3
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,54 @@
+use crate::{each_shard, KeyOpts, Result};
+use clap::Parser;
+use nostr_db::{now, Db};
+use std::path::PathBuf;
+
+/// archive options
+#[derive(Debug, Clone, Parser)]
+pub struct ArchiveOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// move the events created before the unix timestamp
+    #[arg(long, value_name = "TIMESTAMP", conflicts_with = "days")]
+    pub before: Option<u64>,
+
+    /// move the events older than the number of days
+    #[arg(long, value_name = "NUM", required_unless_present = "before")]
+    pub days: Option<u64>,
+
+    /// number of events per archive segment file
+    #[arg(long, value_name = "NUM", default_value = "100000")]
+    pub segment_size: usize,
+
+    #[command(flatten)]
+    pub keys: KeyOpts,
+}
+
+pub fn archive_opts(opts: ArchiveOpts) -> anyhow::Result<usize> {
+    let before = opts
+        .before
+        .unwrap_or_else(|| now().saturating_sub(opts.days.unwrap_or_default() * 86_400));
+    let db = opts.keys.open(&opts.path)?;
+    let counts = each_shard(&db, |db| archive(db, before, opts.segment_size))?;
+    Ok(counts.iter().sum())
+}
+
+/// Move the old events to the cold archive, the archived events are still queryable.
+/// The replaceable and expiring events are kept in the hot storage.
+/// The archive blocks are encrypted if the data key is set.
+pub fn archive(db: &Db, before: u64, segment_size: usize) -> Result<usize> {
+    let count = db.archive_before(before, segment_size.max(1))?;
+    db.flush()?;
+    for segment in db.archive().segments() {
+        println!(
+            "{} {} events, created_at {} - {}",
+            segment.path.display(),
+            segment.events,
+            segment.since,
+            segment.until
+        );
+    }
+    Ok(count)
+}
@@ -1,9 +1,10 @@
-use crate::Result;
+use crate::{each_shard, KeyOpts, Result};
 use clap::Parser;
-use nostr_db::{Db, Filter, Stats};
+use nostr_db::{Explain, Filter, ShardedDb, Stats};
 use rayon::prelude::*;
 use std::{
     path::PathBuf,
+    str::FromStr,
     time::{Duration, Instant},
 };
 
@@ -16,23 +17,37 @@ pub struct BenchOpts {
 
     /// [NIP-01](https://nips.be/1) Filter
     #[arg(short = 'f', long, value_name = "FILTER", default_value = "{}")]
-    pub filter: Filter,
+    pub filter: String,
 
     /// only bench the count method
     #[arg(long, value_name = "BOOL")]
     pub count: bool,
+
+    /// show how the filter executes instead of benchmarking
+    #[arg(long, value_name = "BOOL")]
+    pub explain: bool,
+
+    #[command(flatten)]
+    pub keys: KeyOpts,
 }
 
-pub fn bench_opts(mut opts: BenchOpts) -> anyhow::Result<u64> {
-    opts.filter.build_words();
-    let count = bench(&opts.path, &opts.filter, opts.count)?;
+pub fn bench_opts(opts: BenchOpts) -> anyhow::Result<u64> {
+    // parse the filter after the tag key is set by the open
+    let db = opts.keys.open(&opts.path)?;
+    let mut filter = Filter::from_str(&opts.filter)?;
+    filter.build_words();
+    if opts.explain {
+        let explains = explain(&db, &filter)?;
+        return Ok(explains.iter().map(|e| e.size).sum());
+    }
+    let count = bench(&db, &filter, opts.count)?;
     Ok(count)
 }
 
-pub fn bench(path: &PathBuf, filter: &Filter, count: bool) -> Result<u64> {
-    fn once(db: &Db, filter: &Filter, count: bool) -> Result<(u64, Stats)> {
+pub fn bench(db: &ShardedDb, filter: &Filter, count: bool) -> Result<u64> {
+    fn once(db: &ShardedDb, filter: &Filter, count: bool) -> Result<(u64, Stats)> {
         let reader = db.reader()?;
-        let mut iter = db.iter::<String, _>(&reader, filter)?;
+        let mut iter = db.iter::<String>(&reader, filter)?;
         if count {
             Ok(iter.size()?)
         } else {
@@ -45,9 +60,8 @@ pub fn bench(path: &PathBuf, filter: &Filter, count: bool) -> Result<u64> {
         }
     }
 
-    let db = Db::open(path)?;
     let now = Instant::now();
-    let res = once(&db, filter, count)?;
+    let res = once(db, filter, count)?;
     let elapsed = now.elapsed();
 
     println!("{:?}", filter);
@@ -62,7 +76,7 @@ pub fn bench(path: &PathBuf, filter: &Filter, count: bool) -> Result<u64> {
     println!("Bench prepare");
     let now = Instant::now();
     for _i in 0..times {
-        let _r = once(&db, filter, count)?;
+        let _r = once(db, filter, count)?;
     }
     let elapsed = now.elapsed();
     println!(
@@ -80,7 +94,7 @@ pub fn bench(path: &PathBuf, filter: &Filter, count: bool) -> Result<u64> {
 
     let now = Instant::now();
     for _i in 0..times {
-        let _r = once(&db, filter, count)?;
+        let _r = once(db, filter, count)?;
     }
     let elapsed = now.elapsed();
     println!(
@@ -92,7 +106,7 @@ pub fn bench(path: &PathBuf, filter: &Filter, count: bool) -> Result<u64> {
     println!("Bench multi-threaded");
     let now = Instant::now();
     (0..times).into_par_iter().for_each(|_| {
-        let _r = once(&db, filter, count);
+        let _r = once(db, filter, count);
         if let Err(e) = _r {
             println!("{:?}", e);
         }
@@ -106,6 +120,39 @@ pub fn bench(path: &PathBuf, filter: &Filter, count: bool) -> Result<u64> {
     Ok(res.0)
 }
 
+/// Explain the filter in each shard
+pub fn explain(db: &ShardedDb, filter: &Filter) -> Result<Vec<Explain>> {
+    println!("{:?}", filter);
+    each_shard(db, |db| {
+        let now = Instant::now();
+        let explain = db.explain(filter, None)?;
+        print_explain(&explain, now.elapsed());
+        Ok(explain)
+    })
+}
+
+fn print_explain(explain: &Explain, elapsed: Duration) {
+    println!("Plan: {:?}, tree: {}", explain.plan, explain.tree);
+    println!(
+        "Group: {}, scanners: {}, post filter: {}",
+        explain.group, explain.scanners, explain.post_filter
+    );
+    println!(
+        "Estimated: scan_index: {}, get_index: {}, cost: {}",
+        explain.estimate.scan_index, explain.estimate.get_index, explain.estimate.cost
+    );
+    println!(
+        "Actual: scan_index: {}, get_index: {}, get_data: {}",
+        explain.actual.scan_index, explain.actual.get_index, explain.actual.get_data
+    );
+    println!("Size: {}", explain.size);
+    println!("Time: {:?}", elapsed);
+    println!("Candidates:");
+    for e in &explain.candidates {
+        println!("  {:?}", e);
+    }
+}
+
 pub fn fmt_num(count: f64) -> String {
     if count < 1_000.0 {
         format!("{:.1}", count)
@@ -0,0 +1,54 @@
+use crate::{each_shard, KeyOpts, Result};
+use clap::Parser;
+use nostr_db::Db;
+use std::path::PathBuf;
+
+/// compress options
+#[derive(Debug, Clone, Parser)]
+pub struct CompressOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// number of the latest events for training the dictionary
+    #[arg(long, value_name = "NUM", default_value = "10000")]
+    pub samples: usize,
+
+    /// maximum size of the dictionary in bytes
+    #[arg(long, value_name = "BYTES", default_value = "112640")]
+    pub dict_size: usize,
+
+    /// rewrite the events with the current dictionary without training a new one
+    #[arg(long, value_name = "BOOL")]
+    pub no_train: bool,
+
+    /// number of events per write transaction
+    #[arg(long, value_name = "NUM", default_value = "10000")]
+    pub batch: usize,
+
+    #[command(flatten)]
+    pub keys: KeyOpts,
+}
+
+pub fn compress_opts(opts: CompressOpts) -> anyhow::Result<usize> {
+    let db = opts.keys.open(&opts.path)?;
+    let train = (!opts.no_train).then_some((opts.samples, opts.dict_size));
+    let counts = each_shard(&db, |db| compress(db, train, opts.batch))?;
+    Ok(counts.iter().sum())
+}
+
+/// Train a zstd dictionary from the stored events and rewrite the events with it
+pub fn compress(db: &Db, train: Option<(usize, usize)>, batch: usize) -> Result<usize> {
+    if let Some((samples, dict_size)) = train {
+        let id = db.train_dictionary(samples, dict_size)?;
+        println!("trained dictionary {:08x}", id);
+    }
+    let count = db.compress(batch)?;
+    db.flush()?;
+    let stats = db.stats(0)?;
+    println!(
+        "{} bytes, {} bytes uncompressed",
+        stats.total.bytes, stats.raw_bytes
+    );
+    Ok(count)
+}
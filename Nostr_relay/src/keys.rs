@@ -0,0 +1,49 @@
+use crate::Result;
+use clap::Args;
+use nostr_db::{register_key, DataKey, ShardedDb};
+use std::path::{Path, PathBuf};
+
+/// the keys of the encrypted events, the same as the `[data.encryption]` setting of the relay
+#[derive(Debug, Clone, Default, Args)]
+pub struct KeyOpts {
+    /// the key encrypting the events of 64 hex characters, the written events are encrypted with it
+    #[arg(long, value_name = "KEY", conflicts_with = "key_file")]
+    pub key: Option<DataKey>,
+
+    /// read the key from the file
+    #[arg(long, value_name = "FILE")]
+    pub key_file: Option<PathBuf>,
+
+    /// the keys before the rotation, decrypt the events until `rnostr rekey` is done
+    #[arg(long, value_name = "KEY")]
+    pub old_key: Vec<DataKey>,
+
+    /// the key of the tag values indexed by the keyed hash
+    #[arg(long, value_name = "KEY")]
+    pub tag_key: Option<DataKey>,
+}
+
+impl KeyOpts {
+    /// The key for encrypting the written events, none if not set
+    pub fn data_key(&self) -> Result<Option<DataKey>> {
+        Ok(match (&self.key, &self.key_file) {
+            (Some(key), _) => Some(key.clone()),
+            (None, Some(file)) => Some(DataKey::from_file(file)?),
+            (None, None) => None,
+        })
+    }
+
+    /// Register the keys and open the shards of the database
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<ShardedDb> {
+        for key in &self.old_key {
+            register_key(key.clone());
+        }
+        let db = ShardedDb::open_existing(path)?;
+        let key = self.data_key()?;
+        for shard in db.shards() {
+            shard.set_data_key(key.clone());
+            shard.set_tag_key(self.tag_key.as_ref())?;
+        }
+        Ok(db)
+    }
+}
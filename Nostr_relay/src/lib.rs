@@ -1,19 +1,35 @@
 use clap::Parser;
 use clio::{Input, Output};
+use flate2::read::MultiGzDecoder;
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use nostr_db::{Db, Event, Filter, FromEventData};
+use nostr_db::{CheckEventResult, Cursor, Db, Event, Filter, FromEventData, ShardedDb};
 use rayon::prelude::*;
 use std::{
     fs::File,
-    io::{BufRead, BufReader, Write},
+    io::{BufRead, BufReader, Read, Write},
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
+mod archive;
 mod bench;
+mod compress;
+mod keys;
+mod partition;
+mod rekey;
 mod relay;
+mod stats;
+mod tail;
 
+pub use archive::*;
 pub use bench::*;
+pub use compress::*;
+pub use keys::*;
+pub use partition::*;
+pub use rekey::*;
 pub use relay::*;
+pub use stats::*;
+pub use tail::*;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -44,9 +60,24 @@ pub struct ImportOpts {
     #[arg(long, value_name = "BOOL")]
     pub search: bool,
 
-    /// input jsonl data file, use '-' for stdin
+    /// verify the event id and signature in parallel
+    #[arg(long)]
+    pub verify: bool,
+
+    /// skip the invalid events without printing them, only count them in the report
+    #[arg(long, conflicts_with = "fail_fast")]
+    pub skip_invalid: bool,
+
+    /// stop at the first invalid event, the events before it are imported
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// input jsonl data file, use '-' for stdin. The gzip and zstd compressed input is detected
     #[clap(value_parser, default_value = "-")]
     pub input: Input,
+
+    #[command(flatten)]
+    pub keys: KeyOpts,
 }
 
 /// export options
@@ -58,110 +89,249 @@ pub struct ExportOpts {
 
     /// [NIP-01](https://nips.be/1) Filter
     #[arg(short = 'f', long, value_name = "FILTER", default_value = "{}")]
-    pub filter: Filter,
+    pub filter: String,
 
     /// overwrite order in the filter, By default, if the filter provides a limit, it will order by time descending, otherwise ascending
     #[arg(long, value_name = "BOOL")]
     pub desc: Option<bool>,
 
+    /// resume after the cursor printed by the previous export with the same filter and order
+    #[arg(long, value_name = "CURSOR")]
+    pub resume_from: Option<Cursor>,
+
     /// output jsonl data file, use '-' for stdout
     #[clap(value_parser, default_value = "-")]
     pub output: Output,
+
+    #[command(flatten)]
+    pub keys: KeyOpts,
+}
+
+/// How to handle the invalid events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnInvalid {
+    /// print the invalid events to stderr and continue
+    #[default]
+    Print,
+    Skip,
+    Fail,
+}
+
+/// The import summary by the [`CheckEventResult`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// the number of read lines
+    pub total: usize,
+    pub imported: usize,
+    /// the imported events which replaced an older version
+    pub replaced: usize,
+    pub duplicate: usize,
+    /// deleted by a stored deletion event
+    pub deleted: usize,
+    /// a newer version of the replaceable event is stored
+    pub ignored: usize,
+    /// unparsable, failed the verification or rejected by the db
+    pub invalid: usize,
+}
+
+impl std::fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "read {} events: imported {} (replaced {}), duplicate {}, deleted {}, ignored {}, invalid {}",
+            self.total,
+            self.imported,
+            self.replaced,
+            self.duplicate,
+            self.deleted,
+            self.ignored,
+            self.invalid
+        )
+    }
 }
 
 /// import
-pub fn import_opts(opts: ImportOpts) -> anyhow::Result<usize> {
-    fn run_import_opts<F: Fn(usize)>(opts: ImportOpts, f: F) -> anyhow::Result<usize> {
-        let count = import(&opts.path, opts.input, 10000, opts.search, f)?;
-        Ok(count)
+pub fn import_opts(opts: ImportOpts) -> anyhow::Result<ImportReport> {
+    fn run_import_opts<F: Fn(usize)>(opts: ImportOpts, f: F) -> anyhow::Result<ImportReport> {
+        // indexed by the tag names stored in the database
+        let db = opts.keys.open(&opts.path)?;
+        let on_invalid = if opts.fail_fast {
+            OnInvalid::Fail
+        } else if opts.skip_invalid {
+            OnInvalid::Skip
+        } else {
+            OnInvalid::Print
+        };
+        let report = import(
+            &db,
+            opts.input,
+            10000,
+            opts.search,
+            opts.verify,
+            on_invalid,
+            f,
+        )?;
+        Ok(report)
     }
 
     if matches!(opts.input, Input::File(_, _)) {
         let path = opts.input.path();
         let total_size = count_lines(path)? as u64;
         let pb = create_pb(total_size);
-        let total = run_import_opts(opts, |c| {
+        let report = run_import_opts(opts, |c| {
             if c % 1000 == 0 {
                 pb.set_position(c as u64);
             }
         })?;
         pb.finish_with_message("finished");
-        Ok(total)
+        Ok(report)
     } else {
         run_import_opts(opts, |_| {})
     }
 }
 
-fn count_lines<P: AsRef<Path>>(path: P) -> std::io::Result<usize> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+fn count_lines<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let reader = decode_input(File::open(path)?)?;
     let lines = reader.lines();
     Ok(lines.count())
 }
 
+/// Decompress the input by the magic bytes, the gzip and zstd are detected
+pub fn decode_input<R: Read + 'static>(input: R) -> Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(input);
+    let magic = reader.fill_buf()?;
+    Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?))
+    } else {
+        Box::new(reader)
+    })
+}
+
+// parse and verify the lines in parallel, keep the order
+fn parse_events(lines: &[&str], search: bool, verify: bool) -> Vec<Result<Event, String>> {
+    lines
+        .par_iter()
+        .map(|s| {
+            let mut event = Event::from_data(s.as_bytes()).map_err(|e| e.to_string())?;
+            if verify {
+                event.verify().map_err(|e| e.to_string())?;
+            }
+            if search {
+                event.build_note_words();
+            }
+            Ok(event)
+        })
+        .collect()
+}
+
+/// Import the jsonl events to the shards of the authors, commit every batch of events
 pub fn import<F: Fn(usize)>(
-    path: &PathBuf,
+    db: &ShardedDb,
     input: Input,
     batch: usize,
     search: bool,
+    verify: bool,
+    on_invalid: OnInvalid,
     f: F,
-) -> Result<usize> {
-    let db = Db::open(path)?;
-    db.check_schema()?;
-    let reader = BufReader::new(input);
-    let lines = reader.lines();
-    let mut batches = vec![];
-    let mut count = 0;
+) -> Result<ImportReport> {
+    let reader = decode_input(input)?;
+    let mut report = ImportReport::default();
+    let parse_batch = 1000;
+    let mut writers = db.writers()?;
+    let mut uncommitted = 0;
+    let mut iter = reader.lines();
+    loop {
+        let mut lines = Vec::with_capacity(parse_batch);
+        for line in iter.by_ref() {
+            let line = line?;
+            report.total += 1;
+            f(report.total);
+            if !line.trim().is_empty() {
+                lines.push((report.total, line));
+            }
+            if lines.len() >= parse_batch {
+                break;
+            }
+        }
+        if lines.is_empty() {
+            break;
+        }
 
-    fn parse_events(batches: &Vec<String>, search: bool) -> Vec<Event> {
-        batches
-            .par_iter()
-            .filter_map(|s| {
-                let event = Event::from_data(s.as_bytes());
-                match event {
-                    Ok(mut event) => {
-                        if search {
-                            event.build_note_words();
+        let texts = lines.iter().map(|(_, s)| s.as_str()).collect::<Vec<_>>();
+        let events = parse_events(&texts, search, verify);
+        for ((num, text), event) in lines.iter().zip(events) {
+            let error = match event {
+                Ok(event) => match db.put(&mut writers, &event)? {
+                    CheckEventResult::Ok(count) => {
+                        report.imported += 1;
+                        // the count of the other events is the deleted events of a deletion
+                        // or the older version of a replaceable event
+                        if count > 1 && event.kind() != 5 {
+                            report.replaced += 1;
                         }
-                        Some(event)
+                        None
                     }
-                    Err(e) => {
-                        println!("error: {} {}", s, e);
+                    CheckEventResult::Duplicate => {
+                        report.duplicate += 1;
                         None
                     }
+                    CheckEventResult::Deleted => {
+                        report.deleted += 1;
+                        None
+                    }
+                    CheckEventResult::ReplaceIgnored => {
+                        report.ignored += 1;
+                        None
+                    }
+                    CheckEventResult::Invald(msg) => Some(msg),
+                },
+                Err(err) => Some(err),
+            };
+            if let Some(error) = error {
+                report.invalid += 1;
+                match on_invalid {
+                    OnInvalid::Print => {
+                        eprintln!("invalid event at line {}: {} {}", num, error, text)
+                    }
+                    OnInvalid::Skip => {}
+                    OnInvalid::Fail => {
+                        db.commit(writers)?;
+                        db.flush()?;
+                        return Err(Error::Message(format!(
+                            "invalid event at line {}: {}",
+                            num, error
+                        )));
+                    }
                 }
-            })
-            .collect()
-    }
-    let parse_batch = 30;
-    let mut writer = db.writer()?;
-    for item in lines.enumerate() {
-        let line = item.1?;
-        let index = item.0;
-        if index > 0 && index % parse_batch == 0 {
-            // batch write
-            // count += db.batch_put()?;
-            let events = parse_events(&batches, search);
-            for event in events {
-                db.put(&mut writer, event)?;
-                count += 1;
             }
-            batches.clear();
-        }
-        batches.push(line);
-        if index > 0 && index % batch == 0 {
-            db.commit(writer)?;
-            writer = db.writer()?;
+            uncommitted += 1;
+            if uncommitted >= batch {
+                db.commit(writers)?;
+                writers = db.writers()?;
+                uncommitted = 0;
+            }
         }
-        f(index);
     }
 
-    db.commit(writer)?;
-
-    db.batch_put(parse_events(&batches, search))?;
-    count += batches.len();
+    db.commit(writers)?;
     db.flush()?;
-    Ok(count)
+    Ok(report)
+}
+
+/// Run the command in each shard, print the shard index before its output if there are multiple shards
+pub fn each_shard<T, F: FnMut(&Db) -> Result<T>>(db: &ShardedDb, mut f: F) -> Result<Vec<T>> {
+    db.shards()
+        .iter()
+        .enumerate()
+        .map(|(i, shard)| {
+            if db.num_shards() > 1 {
+                println!("Shard {}:", i);
+            }
+            f(shard)
+        })
+        .collect()
 }
 
 fn create_pb(total: u64) -> ProgressBar {
@@ -184,48 +354,51 @@ fn create_pb(total: u64) -> ProgressBar {
 }
 
 pub fn export_opts(opts: ExportOpts) -> anyhow::Result<usize> {
-    fn run_export_opts<F: Fn(usize)>(mut opts: ExportOpts, f: F) -> anyhow::Result<usize> {
-        opts.filter.build_words();
-        if let Some(desc) = opts.desc {
-            opts.filter.desc = desc;
-        }
-        let count = export(&opts.path, opts.output, &opts.filter, f)?;
-        Ok(count)
+    // parse the filter after the tag key is set by the open
+    let db = opts.keys.open(&opts.path)?;
+    let mut filter = Filter::from_str(&opts.filter)?;
+    filter.build_words();
+    if let Some(desc) = opts.desc {
+        filter.desc = desc;
+    }
+    if opts.resume_from.is_some() {
+        filter.cursor = opts.resume_from;
     }
 
-    if matches!(opts.output, Output::File(_, _)) {
-        let total_size = count(&opts.path, &opts.filter)?;
-        let pb = create_pb(total_size);
-        let total = run_export_opts(opts, |c| {
+    let (total, cursor) = if matches!(opts.output, Output::File(_, _)) {
+        let pb = create_pb(count(&db, &filter)?);
+        let result = export(&db, opts.output, &filter, |c| {
             if c % 1000 == 0 {
                 pb.set_position(c as u64);
             }
         })?;
         pb.finish_with_message("finished");
-        Ok(total)
+        result
     } else {
-        run_export_opts(opts, |_| {})
+        export(&db, opts.output, &filter, |_| {})?
+    };
+    if let Some(cursor) = cursor {
+        eprintln!("resume from cursor {}", cursor);
     }
+    Ok(total)
 }
 
-pub fn count(path: &PathBuf, filter: &Filter) -> Result<u64> {
-    let db = Db::open(path)?;
+pub fn count(db: &ShardedDb, filter: &Filter) -> Result<u64> {
     let reader = db.reader()?;
-    let iter = db.iter::<String, _>(&reader, filter)?;
+    let iter = db.iter::<String>(&reader, filter)?;
     Ok(iter.size()?.0)
 }
 
 pub fn export<F: Fn(usize)>(
-    path: &PathBuf,
+    db: &ShardedDb,
     mut output: Output,
     filter: &Filter,
     f: F,
-) -> Result<usize> {
-    let db = Db::open(path)?;
+) -> Result<(usize, Option<Cursor>)> {
     let reader = db.reader()?;
-    let iter = db.iter::<String, _>(&reader, filter)?;
+    let mut iter = db.iter::<String>(&reader, filter)?;
     let mut count = 0;
-    for event in iter {
+    for event in iter.by_ref() {
         count += 1;
         let mut json: String = event?;
         json.push('\n');
@@ -233,5 +406,157 @@ pub fn export<F: Fn(usize)>(
         f(count);
     }
     output.finish()?;
-    Ok(count)
+    Ok((count, iter.cursor()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use flate2::{write::GzEncoder, Compression};
+    use nostr_db::{
+        now,
+        secp256k1::{rand::thread_rng, KeyPair},
+    };
+    use std::fs;
+
+    // the valid, duplicate, replaced, ignored, deleted, forged and unparsable events
+    fn fixture() -> Result<String> {
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let time = now();
+        let event = |created_at, kind, tags, content: &str| {
+            Event::create(&key_pair, created_at, kind, tags, content.to_owned())
+        };
+        let note = event(time, 1, vec![], "hello")?;
+        let deleted = event(time, 1, vec![], "deleted")?;
+        let deletion = event(time, 5, vec![vec!["e".to_owned(), deleted.id_str()]], "")?;
+        let forged = event(time, 1, vec![], "forged")?
+            .to_string()
+            .replace("forged", "changed");
+        let lines = [
+            note.to_string(),
+            note.to_string(),
+            event(time - 20, 0, vec![], "oldest")?.to_string(),
+            event(time, 0, vec![], "newer")?.to_string(),
+            event(time - 10, 0, vec![], "older")?.to_string(),
+            deletion.to_string(),
+            deleted.to_string(),
+            forged,
+            "invalid".to_owned(),
+        ];
+        Ok(lines.join("\n"))
+    }
+
+    fn run(
+        name: &str,
+        data: &[u8],
+        verify: bool,
+        on_invalid: OnInvalid,
+    ) -> Result<(crate::Result<ImportReport>, u64)> {
+        let dir = tempfile::Builder::new()
+            .prefix(&format!("rnostr-test-import-{}", name))
+            .tempdir()?;
+        let file = dir.path().join("events.jsonl");
+        fs::write(&file, data)?;
+        let db = ShardedDb::open(dir.path().join("events"), 1)?;
+        let report = import(
+            &db,
+            Input::new(&file)?,
+            2,
+            false,
+            verify,
+            on_invalid,
+            |_| {},
+        );
+        let stored = count(&db, &Filter::default())?;
+        Ok((report, stored))
+    }
+
+    #[test]
+    fn import_report() -> Result<()> {
+        let data = fixture()?;
+        let (report, stored) = run("plain", data.as_bytes(), false, OnInvalid::Skip)?;
+        // the forged event is imported without the verification
+        assert_eq!(
+            report?,
+            ImportReport {
+                total: 9,
+                imported: 5,
+                replaced: 1,
+                duplicate: 1,
+                deleted: 1,
+                ignored: 1,
+                invalid: 1,
+            }
+        );
+        // the note, the newer version, the deletion and the forged event
+        assert_eq!(stored, 4);
+
+        let verified = ImportReport {
+            total: 9,
+            imported: 4,
+            replaced: 1,
+            duplicate: 1,
+            deleted: 1,
+            ignored: 1,
+            invalid: 2,
+        };
+        let (report, stored) = run("verify", data.as_bytes(), true, OnInvalid::Skip)?;
+        assert_eq!(report?, verified);
+        assert_eq!(stored, 3);
+
+        let mut gzip = GzEncoder::new(vec![], Compression::default());
+        gzip.write_all(data.as_bytes())?;
+        let (report, _) = run("gzip", &gzip.finish()?, true, OnInvalid::Skip)?;
+        assert_eq!(report?, verified);
+
+        let zstd = zstd::encode_all(data.as_bytes(), 0)?;
+        let (report, _) = run("zstd", &zstd, true, OnInvalid::Skip)?;
+        assert_eq!(report?, verified);
+        Ok(())
+    }
+
+    #[test]
+    fn import_fail_fast() -> Result<()> {
+        let data = fixture()?;
+        let (report, stored) = run("fail-fast", data.as_bytes(), true, OnInvalid::Fail)?;
+        let err = report.unwrap_err().to_string();
+        assert!(err.starts_with("invalid event at line 8"), "{}", err);
+        // the events before the invalid one are committed
+        assert_eq!(stored, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn import_index_tags() -> Result<()> {
+        let dir = tempfile::Builder::new()
+            .prefix("rnostr-test-import-index-tags")
+            .tempdir()?;
+        let path = dir.path().join("events");
+        let db = ShardedDb::open(&path, 1)?;
+        db.shards()[0].set_index_tags(["alt"])?;
+        drop(db);
+
+        // the tag names stored by the relay are indexed
+        let key_pair = KeyPair::new_global(&mut thread_rng());
+        let tags = vec![vec!["alt".to_owned(), "text".to_owned()]];
+        let event = Event::create(&key_pair, now(), 1, tags, "alt".to_owned())?;
+        let file = dir.path().join("events.jsonl");
+        fs::write(&file, event.to_string())?;
+        let db = KeyOpts::default().open(&path)?;
+        assert_eq!(db.shards()[0].index_tags().names(), vec!["alt"]);
+        let report = import(
+            &db,
+            Input::new(&file)?,
+            10,
+            false,
+            true,
+            OnInvalid::Fail,
+            |_| {},
+        )?;
+        assert_eq!(report.imported, 1);
+        let filter = Filter::from_str(r##"{"#alt": ["text"]}"##)?;
+        assert_eq!(count(&db, &filter)?, 1);
+        Ok(())
+    }
 }
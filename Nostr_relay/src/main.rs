@@ -25,6 +25,24 @@ enum Commands {
     /// Benchmark filter
     #[command(arg_required_else_help = true)]
     Bench(BenchOpts),
+    /// Show the number of events and bytes per kind, top authors and the lmdb statistics
+    #[command(arg_required_else_help = true)]
+    Stats(StatsOpts),
+    /// Move the old events to the compressed cold archive
+    #[command(arg_required_else_help = true)]
+    Archive(ArchiveOpts),
+    /// Move the events of the closed periods to the time partitions, list, drop or archive the partitions
+    #[command(arg_required_else_help = true)]
+    Partition(PartitionOpts),
+    /// Train a zstd dictionary and compress the stored events with it
+    #[command(arg_required_else_help = true)]
+    Compress(CompressOpts),
+    /// Encrypt the stored events with the new key, or decrypt them
+    #[command(arg_required_else_help = true)]
+    Rekey(RekeyOpts),
+    /// Output the stored and deleted events in order as jsonl, or truncate the change log
+    #[command(arg_required_else_help = true)]
+    Tail(TailOpts),
     /// Start nostr relay server
     Relay(RelayOpts),
 }
@@ -33,8 +51,8 @@ fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
     match args.command {
         Commands::Import(opts) => {
-            let total = import_opts(opts)?;
-            println!("imported {} events", total);
+            let report = import_opts(opts)?;
+            println!("{}", report);
         }
         Commands::Export(opts) => {
             export_opts(opts)?;
@@ -42,8 +60,35 @@ fn main() -> anyhow::Result<()> {
         Commands::Bench(opts) => {
             bench_opts(opts)?;
         }
+        Commands::Stats(opts) => {
+            stats_opts(opts)?;
+        }
+        Commands::Archive(opts) => {
+            let total = archive_opts(opts)?;
+            println!("archived {} events", total);
+        }
+        Commands::Partition(opts) => {
+            let total = partition_opts(opts)?;
+            println!("moved {} events", total);
+        }
+        Commands::Compress(opts) => {
+            let total = compress_opts(opts)?;
+            println!("compressed {} events", total);
+        }
+        Commands::Rekey(opts) => {
+            let total = rekey_opts(opts)?;
+            println!("rekeyed {} events", total);
+        }
+        Commands::Tail(opts) => {
+            if opts.truncate.is_some() {
+                let count = truncate_opts(opts)?;
+                println!("truncated {} changes", count);
+            } else {
+                tail_opts(opts)?;
+            }
+        }
         Commands::Relay(opts) => {
-            relay(&opts.config, opts.watch)?;
+            relay(&opts.config, opts.watch, opts.follower)?;
         }
     }
     Ok(())
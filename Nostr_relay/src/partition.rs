@@ -0,0 +1,108 @@
+use crate::{each_shard, KeyOpts, Result};
+use clap::Parser;
+use nostr_db::{now, Db, Period};
+use std::path::PathBuf;
+
+/// partition options
+#[derive(Debug, Clone, Parser)]
+pub struct PartitionOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// move the events of the periods closed before the unix timestamp
+    #[arg(long, value_name = "TIMESTAMP", conflicts_with = "days")]
+    pub before: Option<u64>,
+
+    /// move the events of the periods closed before the number of days
+    #[arg(long, value_name = "NUM")]
+    pub days: Option<u64>,
+
+    /// the period of the new partitions: day, month or year
+    #[arg(long, value_name = "PERIOD", default_value = "month")]
+    pub period: Period,
+
+    /// number of events scanned per move
+    #[arg(long, value_name = "NUM", default_value = "10000")]
+    pub batch_size: usize,
+
+    /// remove the partition by the name
+    #[arg(long, value_name = "NAME")]
+    pub drop: Option<String>,
+
+    /// move the environment of the partition by the name to the directory of `--to`
+    #[arg(long, value_name = "NAME", requires = "to")]
+    pub archive: Option<String>,
+
+    /// the directory of the archived partitions
+    #[arg(long, value_name = "DIR")]
+    pub to: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub keys: KeyOpts,
+}
+
+pub fn partition_opts(opts: PartitionOpts) -> anyhow::Result<usize> {
+    let db = opts.keys.open(&opts.path)?;
+    let before = opts
+        .before
+        .or_else(|| opts.days.map(|days| now().saturating_sub(days * 86_400)));
+    let mut found = false;
+    let mut index = 0;
+    let counts = each_shard(&db, |shard| {
+        let mut count = 0;
+        if let Some(before) = before {
+            count = partition(shard, opts.period, before, opts.batch_size)?;
+        }
+        // the shards have the partitions of the same names
+        let has = |name: &str| shard.partitions().iter().any(|p| p.name == name);
+        if let Some(name) = opts.drop.as_deref().filter(|name| has(name)) {
+            let part = shard.drop_partition(name)?;
+            println!("dropped {}", part.name);
+            found = true;
+        }
+        if let (Some(name), Some(to)) = (opts.archive.as_deref().filter(|name| has(name)), &opts.to)
+        {
+            let to = if db.num_shards() > 1 {
+                to.join(format!("shard-{}", index))
+            } else {
+                to.clone()
+            };
+            let part = shard.archive_partition(name, to)?;
+            println!("archived {} to {}", part.name, part.path.display());
+            found = true;
+        }
+        for part in shard.partitions() {
+            println!(
+                "{} created_at {} - {}",
+                part.path.display(),
+                part.start,
+                part.end - 1
+            );
+        }
+        index += 1;
+        Ok(count)
+    })?;
+    if let Some(name) = opts.drop.as_ref().or(opts.archive.as_ref()) {
+        if !found {
+            anyhow::bail!("partition {} not found", name);
+        }
+    }
+    Ok(counts.iter().sum())
+}
+
+/// Move the events of the closed periods to the partitions, the moved events are still queryable
+pub fn partition(db: &Db, period: Period, before: u64, batch_size: usize) -> Result<usize> {
+    let mut total = 0;
+    let mut cursor = None;
+    loop {
+        let (num, next) = db.partition_before(period, before, cursor, batch_size)?;
+        total += num;
+        cursor = next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    db.flush()?;
+    Ok(total)
+}
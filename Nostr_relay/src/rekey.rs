@@ -0,0 +1,95 @@
+use crate::{each_shard, Result};
+use clap::Parser;
+use nostr_db::{register_key, DataKey, ShardedDb};
+use std::path::PathBuf;
+
+/// rekey options
+#[derive(Debug, Clone, Parser)]
+pub struct RekeyOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// the new key of 64 hex characters
+    #[arg(long, value_name = "KEY", conflicts_with_all = ["key_file", "decrypt"])]
+    pub key: Option<DataKey>,
+
+    /// read the new key from the file
+    #[arg(long, value_name = "FILE", conflicts_with = "decrypt")]
+    pub key_file: Option<PathBuf>,
+
+    /// the keys encrypting the stored events
+    #[arg(long, value_name = "KEY")]
+    pub old_key: Vec<DataKey>,
+
+    /// read the key encrypting the stored events from the file
+    #[arg(long, value_name = "FILE")]
+    pub old_key_file: Vec<PathBuf>,
+
+    /// store the events unencrypted
+    #[arg(long, value_name = "BOOL")]
+    pub decrypt: bool,
+
+    /// rebuild the tag index with the tag key of 64 hex characters
+    #[arg(long, value_name = "KEY", conflicts_with = "plain_tags")]
+    pub tag_key: Option<DataKey>,
+
+    /// rebuild the tag index with the plain tag values
+    #[arg(long, value_name = "BOOL")]
+    pub plain_tags: bool,
+
+    /// number of events per write transaction
+    #[arg(long, value_name = "NUM", default_value = "10000")]
+    pub batch: usize,
+}
+
+pub fn rekey_opts(opts: RekeyOpts) -> anyhow::Result<usize> {
+    let key = match (opts.key, &opts.key_file) {
+        (Some(key), _) => Some(key),
+        (None, Some(file)) => Some(DataKey::from_file(file)?),
+        (None, None) if opts.decrypt => None,
+        (None, None) => {
+            anyhow::bail!("set the new key by --key or --key-file, or --decrypt")
+        }
+    };
+    let mut old_keys = opts.old_key;
+    for file in &opts.old_key_file {
+        old_keys.push(DataKey::from_file(file)?);
+    }
+    let tag_key = match (opts.tag_key, opts.plain_tags) {
+        (Some(key), _) => Some(Some(key)),
+        (None, true) => Some(None),
+        (None, false) => None,
+    };
+    let count = rekey(&opts.path, key, old_keys, tag_key, opts.batch)?;
+    Ok(count)
+}
+
+/// Encrypt the stored events with the new key, or decrypt them if no key.
+/// Rebuild the tag index with the tag key if `tag_key` is set, none in it for the plain values.
+pub fn rekey(
+    path: &PathBuf,
+    key: Option<DataKey>,
+    old_keys: Vec<DataKey>,
+    tag_key: Option<Option<DataKey>>,
+    batch: usize,
+) -> Result<usize> {
+    for key in old_keys {
+        register_key(key);
+    }
+    let db = ShardedDb::open_existing(path)?;
+    if let Some(key) = &key {
+        println!("encrypt with key {:08x}", key.id);
+    }
+    let counts = each_shard(&db, |db| {
+        db.set_data_key(key.clone());
+        if let Some(tag_key) = &tag_key {
+            let count = db.reindex_tags(tag_key.as_ref(), batch)?;
+            println!("reindexed the tags of {} events", count);
+        }
+        let count = db.rekey(batch)?;
+        db.flush()?;
+        Ok(count)
+    })?;
+    Ok(counts.iter().sum())
+}
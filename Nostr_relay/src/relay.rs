@@ -18,24 +18,35 @@ pub struct RelayOpts {
     /// Auto reload when config changed
     #[arg(long, value_name = "BOOL")]
     pub watch: bool,
+
+    /// Run as a read-only follower of the primary relay url, ie: ws://127.0.0.1:8080
+    #[arg(long, value_name = "URL")]
+    pub follower: Option<String>,
 }
 
 #[actix_rt::main]
-pub async fn relay(config: &PathBuf, watch: bool) -> Result<()> {
+pub async fn relay(config: &PathBuf, watch: bool, follower: Option<String>) -> Result<()> {
     tracing_subscriber::fmt::init();
     info!("Start relay server");
-
     // actix_rt::System::new().block_on(async {
     // });
 
-    let app_data = App::create(Some(config), watch, Some("RNOSTR".to_owned()), None)?;
-    let db = app_data.db.clone();
+    let app_data = App::create(
+        Some(config),
+        watch,
+        Some("RNOSTR".to_owned()),
+        None,
+        follower,
+    )?;
+    let db = app_data.shards.clone();
     app_data
         .add_extension(nostr_extensions::Metrics::new())
         .add_extension(nostr_extensions::Auth::new())
         .add_extension(nostr_extensions::Ratelimiter::new())
         .add_extension(nostr_extensions::Count::new(db))
         .add_extension(nostr_extensions::Search::new())
+        .add_extension(nostr_extensions::Explain::new())
+        .add_extension(nostr_extensions::Changes::new())
         .web_server()?
         .await?;
     info!("Relay server shutdown");
@@ -0,0 +1,112 @@
+use crate::{each_shard, KeyOpts, Result};
+use clap::Parser;
+use nostr_db::{Db, EnvStats, EventStats};
+use std::path::PathBuf;
+
+/// stats options
+#[derive(Debug, Clone, Parser)]
+pub struct StatsOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// number of top authors by volume, 0 will skip scanning the authors
+    #[arg(long, value_name = "NUM", default_value = "10")]
+    pub top: usize,
+
+    /// skip the lmdb environment and tree statistics
+    #[arg(long, value_name = "BOOL")]
+    pub no_env: bool,
+
+    #[command(flatten)]
+    pub keys: KeyOpts,
+}
+
+/// The stats of each shard
+pub fn stats_opts(opts: StatsOpts) -> anyhow::Result<Vec<EventStats>> {
+    let db = opts.keys.open(&opts.path)?;
+    let stats = each_shard(&db, |db| stats(db, opts.top, !opts.no_env))?;
+    Ok(stats)
+}
+
+pub fn stats(db: &Db, top: usize, env: bool) -> Result<EventStats> {
+    let stats = db.stats(top)?;
+
+    println!(
+        "Total: {} events, {} bytes",
+        stats.total.count, stats.total.bytes
+    );
+    if stats.raw_bytes > 0 {
+        let saved = stats.raw_bytes as i64 - stats.total.bytes as i64;
+        println!(
+            "Compression: {} bytes uncompressed, {} bytes saved ({:.1}%)",
+            stats.raw_bytes,
+            saved,
+            saved as f64 * 100.0 / stats.raw_bytes as f64
+        );
+    }
+    if let Some(id) = stats.dictionary {
+        println!(
+            "Dictionary: {:08x}, {} events, {} bytes",
+            id, stats.compressed.count, stats.compressed.bytes
+        );
+    }
+    println!("Kinds:");
+    println!("{:>8} {:>12} {:>16}", "kind", "events", "bytes");
+    for (kind, counter) in &stats.kinds {
+        println!("{:>8} {:>12} {:>16}", kind, counter.count, counter.bytes);
+    }
+    if !stats.authors.is_empty() {
+        println!("Top authors:");
+        println!("{:>64} {:>12} {:>16}", "pubkey", "events", "bytes");
+        for (pubkey, counter) in &stats.authors {
+            println!(
+                "{:>64} {:>12} {:>16}",
+                hex_string(pubkey),
+                counter.count,
+                counter.bytes
+            );
+        }
+    }
+    if env {
+        print_env_stats(&db.env_stats()?);
+    }
+    Ok(stats)
+}
+
+fn print_env_stats(stats: &EnvStats) {
+    let info = &stats.info;
+    let used = stats.used_bytes();
+    println!(
+        "Map: {} bytes, {} bytes used ({:.1}%), page size {}",
+        info.map_size,
+        used,
+        used as f64 * 100.0 / info.map_size.max(1) as f64,
+        stats.env.page_size
+    );
+    println!(
+        "Readers: {} of {}, last txn id {}",
+        info.readers, info.max_readers, info.last_txn_id
+    );
+    println!("Trees:");
+    println!(
+        "{:>16} {:>12} {:>6} {:>10} {:>10} {:>10} {:>16}",
+        "tree", "entries", "depth", "branch", "leaf", "overflow", "bytes"
+    );
+    for (name, stat) in &stats.trees {
+        println!(
+            "{:>16} {:>12} {:>6} {:>10} {:>10} {:>10} {:>16}",
+            name,
+            stat.entries,
+            stat.depth,
+            stat.branch_pages,
+            stat.leaf_pages,
+            stat.overflow_pages,
+            stat.bytes()
+        );
+    }
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,103 @@
+use crate::{Error, KeyOpts, Result};
+use clap::Parser;
+use clio::Output;
+use nostr_db::{Db, ShardedDb};
+use std::{io::Write, path::PathBuf, thread::sleep, time::Duration};
+
+/// tail options
+#[derive(Debug, Clone, Parser)]
+pub struct TailOpts {
+    /// Nostr events data directory path. The "rnostr.example.toml" default setting is "data/events"
+    #[arg(value_name = "PATH")]
+    pub path: PathBuf,
+
+    /// start from the sequence number (inclusive)
+    #[arg(long, value_name = "SEQ", default_value = "0")]
+    pub since: u64,
+
+    /// keep waiting for the new changes
+    #[arg(short = 'f', long, value_name = "BOOL")]
+    pub follow: bool,
+
+    /// polling interval in milliseconds when following
+    #[arg(long, value_name = "MS", default_value = "1000")]
+    pub interval: u64,
+
+    /// the index of the shard, the sequence numbers are per shard
+    #[arg(long, value_name = "INDEX", default_value = "0")]
+    pub shard: usize,
+
+    /// delete the change log of the deletions before the sequence number instead of the output,
+    /// the followers must have applied them
+    #[arg(long, value_name = "SEQ", conflicts_with = "follow")]
+    pub truncate: Option<u64>,
+
+    #[command(flatten)]
+    pub keys: KeyOpts,
+
+    /// output jsonl data file, use '-' for stdout
+    #[clap(value_parser, default_value = "-")]
+    pub output: Output,
+}
+
+pub fn tail_opts(mut opts: TailOpts) -> anyhow::Result<u64> {
+    let interval = opts.follow.then(|| Duration::from_millis(opts.interval));
+    let db = opts.keys.open(&opts.path)?;
+    let count = tail(
+        shard(&db, opts.shard)?,
+        opts.since,
+        interval,
+        &mut opts.output,
+    )?;
+    opts.output.finish()?;
+    Ok(count)
+}
+
+/// Delete the change log of the shard by [`TailOpts::truncate`]
+pub fn truncate_opts(opts: TailOpts) -> anyhow::Result<usize> {
+    let db = opts.keys.open(&opts.path)?;
+    let before = opts.truncate.unwrap_or_default();
+    Ok(truncate(shard(&db, opts.shard)?, before)?)
+}
+
+fn shard(db: &ShardedDb, index: usize) -> Result<&Db> {
+    db.shards().get(index).map(|db| db.as_ref()).ok_or_else(|| {
+        Error::Message(format!(
+            "the shard index should be less than {}",
+            db.num_shards()
+        ))
+    })
+}
+
+/// Write the changes since the sequence number as jsonl, keep polling if the interval is set
+pub fn tail<W: Write>(
+    db: &Db,
+    since: u64,
+    interval: Option<Duration>,
+    writer: &mut W,
+) -> Result<u64> {
+    let mut next = since;
+    let mut count = 0;
+    loop {
+        {
+            let reader = db.reader()?;
+            for change in db.changes_since::<String, _>(&reader, next) {
+                let change = change?;
+                next = change.seq() + 1;
+                writer.write_all(change.to_json().as_bytes())?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+        }
+        writer.flush()?;
+        match interval {
+            Some(interval) => sleep(interval),
+            None => return Ok(count),
+        }
+    }
+}
+
+/// Delete the change log of the deletions before the sequence number, return the number of deleted logs
+pub fn truncate(db: &Db, before: u64) -> Result<usize> {
+    Ok(db.truncate_changes(before)?)
+}